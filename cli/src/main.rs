@@ -47,6 +47,9 @@ fn main() {
     // main simply invokes a run() where all the work is done.
     // This code just captures any errors.
     if let Err(e) = run() {
+        if let Some(status) = e.downcast_ref::<bootc_lib::cli::ExitStatus>() {
+            std::process::exit(status.0);
+        }
         tracing::error!("{:#}", e);
         std::process::exit(1);
     }
@@ -31,7 +31,14 @@ BuildRequires: libzstd-devel
 %{_bindir}/bootc
 %{_prefix}/lib/systemd/system-generators/*
 %{_prefix}/lib/bootc
+%{_prefix}/lib/bootupd/grub2-static/configs.d/08_bootc_fallback_counting.cfg
 %{_unitdir}/*
+%{_datadir}/dbus-1/system.d/org.containers.bootc.conf
+%{_datadir}/dbus-1/system-services/org.containers.bootc.service
+%{_datadir}/polkit-1/actions/org.containers.bootc.policy
+%{_datadir}/bash-completion/completions/bootc
+%{_datadir}/zsh/site-functions/_bootc
+%{_datadir}/fish/vendor_completions.d/bootc.fish
 %{_mandir}/man*/bootc*
 
 %prep
@@ -0,0 +1,426 @@
+//! # Automatic updates
+//!
+//! `bootc-update.timer` periodically runs `bootc update-worker`, which
+//! applies the policy in `bootc/update-policy.toml` (in `/etc` or `/usr/lib`):
+//! how often to check for updates, with how much randomized jitter, and whether
+//! to only check, to stage updates, or to also reboot into them (optionally
+//! restricted to reboot windows).
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::NaiveTime;
+use fn_error_context::context;
+use ostree_ext::sysroot::LockMode;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::UpgradeOpts;
+
+/// Configuration files, in order of precedence.
+const CONFIG_PATHS: &[&str] = &[
+    "etc/bootc/update-policy.toml",
+    "usr/lib/bootc/update-policy.toml",
+];
+/// Where we record the state of the worker.
+const STATE_PATH: &str = "var/lib/bootc/update-worker.json";
+/// Where we record the result of the most recent `bootc upgrade`.
+const RESULT_PATH: &str = "var/lib/bootc/last-update.json";
+
+/// A duration in the configuration, e.g. `30m`, `8h` or `1d`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub(crate) struct ConfigDuration(pub(crate) Duration);
+
+impl TryFrom<String> for ConfigDuration {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        let (n, unit) = s.split_at(
+            s.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(s.as_bytes().len()),
+        );
+        let n: u64 = n
+            .parse()
+            .with_context(|| format!("Invalid duration: {s}"))?;
+        let multiplier = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            o => anyhow::bail!("Invalid duration unit {o:?} in {s}; expected one of s, m, h, d"),
+        };
+        Ok(Self(Duration::from_secs(n * multiplier)))
+    }
+}
+
+/// A daily window of local time, e.g. `02:00-04:00`; windows may span midnight.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub(crate) struct RebootWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TryFrom<String> for RebootWindow {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("Invalid reboot window {s}; expected HH:MM-HH:MM"))?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .with_context(|| format!("Invalid time {t:?} in reboot window {s}"))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == end {
+            anyhow::bail!("Empty reboot window: {s}");
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl RebootWindow {
+    /// Returns true if the provided time is within this window.
+    fn contains(&self, t: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+}
+
+/// What to do when an update is available.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum UpdateMode {
+    /// Only check for updates.
+    Check,
+    /// Download and stage updates, to be applied on the next reboot.
+    #[default]
+    Stage,
+    /// Stage updates, and reboot into them (within a reboot window, if any).
+    Reboot,
+}
+
+fn default_interval() -> ConfigDuration {
+    ConfigDuration(Duration::from_secs(8 * 60 * 60))
+}
+
+/// The automatic update policy.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct UpdatePolicy {
+    /// The minimum time between checks for updates.
+    #[serde(default = "default_interval")]
+    pub(crate) interval: ConfigDuration,
+    /// The maximum randomized delay before checking for updates.
+    #[serde(default)]
+    pub(crate) jitter: ConfigDuration,
+    /// What to do when an update is available.
+    #[serde(default)]
+    pub(crate) mode: UpdateMode,
+    /// If not empty, only reboot within one of these windows.
+    #[serde(default)]
+    pub(crate) reboot_windows: Vec<RebootWindow>,
+}
+
+impl Default for UpdatePolicy {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            jitter: Default::default(),
+            mode: Default::default(),
+            reboot_windows: Default::default(),
+        }
+    }
+}
+
+impl UpdatePolicy {
+    /// Returns true if a reboot is allowed at the provided local time.
+    fn reboot_allowed_at(&self, t: NaiveTime) -> bool {
+        self.reboot_windows.is_empty() || self.reboot_windows.iter().any(|w| w.contains(t))
+    }
+}
+
+/// Persistent state of the update worker.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkerState {
+    /// When we last successfully checked for updates
+    last_check: chrono::DateTime<chrono::Utc>,
+}
+
+/// The result of the most recent `bootc upgrade` (including `--check`).
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpdateResult {
+    /// When the update finished
+    pub(crate) timestamp: chrono::DateTime<chrono::Utc>,
+    /// Whether the update succeeded
+    pub(crate) success: bool,
+    /// The error, if the update failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Load the update policy from the provided root; returns `None` if there
+/// is no policy.
+#[context("Loading update policy")]
+pub(crate) fn load_policy(root: &Dir) -> Result<Option<UpdatePolicy>> {
+    for path in CONFIG_PATHS {
+        let Some(f) = root.open_optional(path)? else {
+            continue;
+        };
+        let buf = std::io::read_to_string(f)?;
+        let policy: UpdatePolicy =
+            toml::from_str(&buf).with_context(|| format!("Parsing {path}"))?;
+        if policy.interval.0.is_zero() {
+            anyhow::bail!("{path}: interval must be non-zero");
+        }
+        return Ok(Some(policy));
+    }
+    Ok(None)
+}
+
+fn read_state(root: &Dir) -> Result<Option<WorkerState>> {
+    let Some(f) = root.open_optional(STATE_PATH)? else {
+        return Ok(None);
+    };
+    let r = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {STATE_PATH}"))?;
+    Ok(Some(r))
+}
+
+fn write_state(root: &Dir, state: &WorkerState) -> Result<()> {
+    root.create_dir_all(STATE_PATH.rsplit_once('/').unwrap().0)?;
+    root.atomic_replace_with(STATE_PATH, |w| {
+        serde_json::to_writer(w, state).map_err(anyhow::Error::new)
+    })
+}
+
+/// Read the result of the most recent update, if any.
+#[context("Reading update result")]
+pub(crate) fn read_update_result(root: &Dir) -> Result<Option<UpdateResult>> {
+    let Some(f) = root.open_optional(RESULT_PATH)? else {
+        return Ok(None);
+    };
+    let r = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {RESULT_PATH}"))?;
+    Ok(Some(r))
+}
+
+fn write_update_result(root: &Dir, r: &UpdateResult) -> Result<()> {
+    root.create_dir_all(RESULT_PATH.rsplit_once('/').unwrap().0)?;
+    root.atomic_replace_with(RESULT_PATH, |w| {
+        serde_json::to_writer(w, r).map_err(anyhow::Error::new)
+    })
+}
+
+/// Record the result of an update; failures to do so are only logged, as
+/// they should not affect the update itself.
+pub(crate) fn record_update_result(r: &Result<()>) {
+    let record = UpdateResult {
+        timestamp: chrono::Utc::now(),
+        success: r.is_ok(),
+        error: r.as_ref().err().map(|e| format!("{e:#}")),
+    };
+    let root = Dir::open_ambient_dir("/", cap_std::ambient_authority());
+    if let Err(e) = root
+        .map_err(anyhow::Error::new)
+        .and_then(|root| write_update_result(&root, &record))
+    {
+        tracing::warn!("Failed to record update result: {e:#}");
+    }
+}
+
+/// A uniformly random delay of at most `max`.
+fn random_delay(max: Duration) -> Result<Duration> {
+    if max.is_zero() {
+        return Ok(max);
+    }
+    let mut buf = [0u8; 8];
+    openssl::rand::rand_bytes(&mut buf)?;
+    Ok(Duration::from_secs(
+        u64::from_ne_bytes(buf) % (max.as_secs() + 1),
+    ))
+}
+
+/// Implementation of `bootc update-worker`, invoked by `bootc-update.service`.
+#[context("Automatic update")]
+pub(crate) async fn update_worker() -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let policy = load_policy(root)?.unwrap_or_default();
+    tracing::debug!("Update policy: {policy:?}");
+
+    let now = chrono::Utc::now();
+    let due = match read_state(root)? {
+        Some(state) => (now - state.last_check)
+            .to_std()
+            .map_or(true, |elapsed| elapsed >= policy.interval.0),
+        None => true,
+    };
+    if due {
+        let delay = random_delay(policy.jitter.0)?;
+        if !delay.is_zero() {
+            println!("Delaying update check by {}s", delay.as_secs());
+            tokio::time::sleep(delay).await;
+        }
+        let opts = UpgradeOpts {
+            quiet: true,
+            check: policy.mode == UpdateMode::Check,
+            format: None,
+            apply: false,
+            progress_fd: None,
+            from_cache: false,
+            soft_reboot: false,
+        };
+        crate::cli::upgrade(opts).await?;
+        let state = WorkerState {
+            last_check: chrono::Utc::now(),
+        };
+        write_state(root, &state)?;
+    } else {
+        tracing::debug!("Update check is not yet due");
+    }
+
+    if policy.mode != UpdateMode::Reboot {
+        return Ok(());
+    }
+    if crate::maintenance::load_config(root)?.is_some() {
+        // Staging already scheduled a reboot in the next maintenance window
+        tracing::debug!("Reboots are scheduled via maintenance windows");
+        return Ok(());
+    }
+    let staged = {
+        let sysroot = crate::cli::get_storage(LockMode::Shared).await?;
+        sysroot.staged_deployment().is_some()
+    };
+    if !staged {
+        return Ok(());
+    }
+    if !policy.reboot_allowed_at(chrono::Local::now().time()) {
+        println!("Update staged; waiting for a reboot window");
+        return Ok(());
+    }
+    crate::reboot::reboot()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_duration() {
+        for (s, secs) in [
+            ("30", 30),
+            ("30s", 30),
+            ("15m", 900),
+            ("8h", 28800),
+            ("1d", 86400),
+        ] {
+            assert_eq!(
+                ConfigDuration::try_from(s.to_owned()).unwrap().0,
+                Duration::from_secs(secs)
+            );
+        }
+        for invalid in ["", "h", "8x", "-1h", "1.5h"] {
+            assert!(
+                ConfigDuration::try_from(invalid.to_owned()).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_reboot_window() {
+        let w = RebootWindow::try_from("02:00-04:00".to_owned()).unwrap();
+        assert!(w.contains(time("02:00")));
+        assert!(w.contains(time("03:59")));
+        assert!(!w.contains(time("04:00")));
+        assert!(!w.contains(time("12:00")));
+        // Spanning midnight
+        let w = RebootWindow::try_from("23:00-01:30".to_owned()).unwrap();
+        assert!(w.contains(time("23:30")));
+        assert!(w.contains(time("00:15")));
+        assert!(!w.contains(time("01:30")));
+        assert!(!w.contains(time("22:59")));
+        for invalid in ["02:00", "02:00-02:00", "25:00-26:00", "2am-4am"] {
+            assert!(
+                RebootWindow::try_from(invalid.to_owned()).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_policy() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(load_policy(td)?, None);
+        td.create_dir_all("usr/lib/bootc")?;
+        td.write("usr/lib/bootc/update-policy.toml", "")?;
+        let policy = load_policy(td)?.unwrap();
+        assert_eq!(policy, UpdatePolicy::default());
+        assert!(policy.reboot_allowed_at(time("12:00")));
+
+        td.create_dir_all("etc/bootc")?;
+        td.write(
+            "etc/bootc/update-policy.toml",
+            indoc::indoc! { r#"
+                interval = "1d"
+                jitter = "30m"
+                mode = "reboot"
+                reboot-windows = ["02:00-04:00", "22:00-23:00"]
+            "# },
+        )?;
+        let policy = load_policy(td)?.unwrap();
+        assert_eq!(policy.interval.0, Duration::from_secs(86400));
+        assert_eq!(policy.jitter.0, Duration::from_secs(1800));
+        assert_eq!(policy.mode, UpdateMode::Reboot);
+        assert!(policy.reboot_allowed_at(time("03:00")));
+        assert!(policy.reboot_allowed_at(time("22:30")));
+        assert!(!policy.reboot_allowed_at(time("12:00")));
+
+        for invalid in [
+            "interval = \"0h\"\n",
+            "mode = \"yolo\"\n",
+            "reboot-windows = [\"nope\"]\n",
+            "intervals = \"1h\"\n",
+        ] {
+            td.write("etc/bootc/update-policy.toml", invalid)?;
+            assert!(load_policy(td).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_result() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(read_update_result(td)?, None);
+        let r = UpdateResult {
+            timestamp: chrono::DateTime::from_timestamp(1700000000, 0).unwrap(),
+            success: false,
+            error: Some("Fetching: connection refused".into()),
+        };
+        write_update_result(td, &r)?;
+        assert_eq!(read_update_result(td)?, Some(r));
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_delay() {
+        assert_eq!(random_delay(Duration::ZERO).unwrap(), Duration::ZERO);
+        let max = Duration::from_secs(60);
+        for _ in 0..10 {
+            assert!(random_delay(max).unwrap() <= max);
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 use std::env;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use std::process::Command;
 use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
@@ -11,6 +13,7 @@ use regex::Regex;
 use serde::Deserialize;
 
 use crate::install::run_in_host_mountns;
+use crate::mount::SourceTag;
 use crate::task::Task;
 use bootc_utils::CommandRunExt;
 
@@ -255,7 +258,7 @@ pub(crate) fn udev_settle() -> Result<()> {
     // udevd hasn't yet received updates from the kernel, settle will return
     // immediately, and lsblk won't pick up partition labels.  Try to sleep
     // our way out of this.
-    std::thread::sleep(std::time::Duration::from_millis(200));
+    std::thread::sleep(Duration::from_millis(200));
 
     let st = run_in_host_mountns("udevadm").arg("settle").status()?;
     if !st.success() {
@@ -264,6 +267,124 @@ pub(crate) fn udev_settle() -> Result<()> {
     Ok(())
 }
 
+/// How long we wait by default for a block device to show up.
+pub(crate) const DEVICE_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A block device we may need to wait for.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DeviceSpec<'a> {
+    /// A device node path, e.g. `/dev/vda3` or `/dev/mapper/root`
+    Path(&'a Utf8Path),
+    /// A filesystem UUID, resolved via `/dev/disk/by-uuid`
+    #[allow(dead_code)]
+    Uuid(&'a str),
+    /// A filesystem label, resolved via `/dev/disk/by-label`
+    #[allow(dead_code)]
+    Label(&'a str),
+}
+
+impl DeviceSpec<'_> {
+    /// The path at which udev will create the device node (or a link to it).
+    fn device_path(&self) -> Utf8PathBuf {
+        match self {
+            DeviceSpec::Path(p) => (*p).to_owned(),
+            DeviceSpec::Uuid(v) => SourceTag::Uuid(v).device_link().into(),
+            DeviceSpec::Label(v) => SourceTag::Label(v).device_link().into(),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceSpec<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSpec::Path(p) => write!(f, "{p}"),
+            DeviceSpec::Uuid(v) => write!(f, "{}", SourceTag::Uuid(v)),
+            DeviceSpec::Label(v) => write!(f, "{}", SourceTag::Label(v)),
+        }
+    }
+}
+
+fn is_block_device(path: &Utf8Path) -> bool {
+    std::fs::metadata(path)
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or_default()
+}
+
+/// Wait for a block device to appear, returning the canonical path to the
+/// device node. In between checks we ask udev to settle (exiting early if
+/// the target shows up), because right after e.g. partitioning there is a
+/// window where neither the kernel nor udev have caught up.
+#[context("Waiting for device {spec}")]
+pub(crate) fn wait_for_device(spec: DeviceSpec, timeout: Duration) -> Result<Utf8PathBuf> {
+    let path = spec.device_path();
+    let deadline = Instant::now() + timeout;
+    loop {
+        if is_block_device(&path) {
+            return path.canonicalize_utf8().map_err(Into::into);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            anyhow::bail!("Timed out after {timeout:?} waiting for {path}");
+        }
+        // udevadm settle only takes whole seconds; we cap each round so that we
+        // recheck periodically even if udev thinks it is idle.
+        let settle_timeout = remaining.as_secs().clamp(1, 5);
+        let st = run_in_host_mountns("udevadm")
+            .arg("settle")
+            .arg(format!("--timeout={settle_timeout}"))
+            .arg(format!("--exit-if-exists={path}"))
+            .status()?;
+        // A nonzero exit here just means the settle timed out; we loop and check again.
+        if !st.success() {
+            tracing::debug!("udevadm settle: {st:?}");
+        }
+        std::thread::sleep(Duration::from_millis(100).min(remaining));
+    }
+}
+
+/// Parse the `KEY=VALUE` lines output by `mdadm --detail --export`.
+fn parse_mdadm_export(buf: &str) -> HashMap<&str, &str> {
+    buf.lines().filter_map(|l| l.split_once('=')).collect()
+}
+
+/// Create a RAID1 (mirror) array named `name` from the provided member
+/// devices, returning the path to the array device and the array UUID.
+/// Note that metadata version 1.0 places the superblock at the end of
+/// the members, which is needed for filesystems read by firmware or the bootloader.
+#[context("Creating RAID1 array {name}")]
+pub(crate) fn create_raid1(
+    name: &str,
+    metadata: &str,
+    members: &[&Utf8Path],
+) -> Result<(Utf8PathBuf, String)> {
+    let dev = Utf8PathBuf::from(format!("/dev/md/{name}"));
+    Task::new(format!("Creating RAID1 array {name}"), "mdadm")
+        .args(["--create", dev.as_str(), "--run", "--level=1"])
+        .arg(format!("--metadata={metadata}"))
+        .arg(format!("--raid-devices={}", members.len()))
+        .args(members)
+        .verbose()
+        .quiet_output()
+        .run()?;
+    let dev = wait_for_device(DeviceSpec::Path(&dev), DEVICE_WAIT_TIMEOUT)?;
+    let detail = Task::new_quiet("mdadm")
+        .args(["--detail", "--export", dev.as_str()])
+        .read()?;
+    let uuid = parse_mdadm_export(&detail)
+        .get("MD_UUID")
+        .map(|&s| s.to_owned())
+        .ok_or_else(|| anyhow!("Missing MD_UUID for {dev}"))?;
+    Ok((dev, uuid))
+}
+
+/// Stop a RAID array.
+pub(crate) fn stop_raid(dev: &Utf8Path) -> Result<()> {
+    Task::new(format!("Stopping RAID array {dev}"), "mdadm")
+        .args(["--stop", dev.as_str()])
+        .quiet_output()
+        .run()
+}
+
 /// Parse key-value pairs from lsblk --pairs.
 /// Newer versions of lsblk support JSON but the one in CentOS 7 doesn't.
 fn split_lsblk_line(line: &str) -> HashMap<String, String> {
@@ -356,6 +477,39 @@ fn test_parse_size_mib() {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_parse_mdadm_export() {
+        let fixture = indoc::indoc! { "
+            MD_LEVEL=raid1
+            MD_DEVICES=2
+            MD_METADATA=1.2
+            MD_UUID=3b2d0f7c:5e1a4b2d:9c8e7f6a:1d2c3b4a
+            MD_NAME=any:root
+        " };
+        let r = parse_mdadm_export(fixture);
+        assert_eq!(r["MD_UUID"], "3b2d0f7c:5e1a4b2d:9c8e7f6a:1d2c3b4a");
+        assert_eq!(r["MD_DEVICES"], "2");
+    }
+
+    #[test]
+    fn test_device_spec() {
+        let spec = DeviceSpec::Path(Utf8Path::new("/dev/vda3"));
+        assert_eq!(spec.to_string(), "/dev/vda3");
+        assert_eq!(spec.device_path(), "/dev/vda3");
+        let spec = DeviceSpec::Uuid("f7436547-20ac-43cb-aa2f-eac9632183f6");
+        assert_eq!(
+            spec.to_string(),
+            "UUID=f7436547-20ac-43cb-aa2f-eac9632183f6"
+        );
+        assert_eq!(
+            spec.device_path(),
+            "/dev/disk/by-uuid/f7436547-20ac-43cb-aa2f-eac9632183f6"
+        );
+        let spec = DeviceSpec::Label("EFI System");
+        assert_eq!(spec.to_string(), "LABEL=EFI System");
+        assert_eq!(spec.device_path(), r"/dev/disk/by-label/EFI\x20System");
+    }
+
     #[test]
     fn test_parse_sfdisk() -> Result<()> {
         let fixture = indoc::indoc! { r#"
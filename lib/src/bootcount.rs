@@ -0,0 +1,231 @@
+//! # Boot counting and automatic rollback
+//!
+//! When enabled via `bootc/boot-counting.toml` (in `/etc` or `/usr/lib` of
+//! the new deployment), staging a deployment arms a boot counter in the GRUB
+//! environment block.  GRUB decrements the counter on each boot attempt, and
+//! once it is exhausted boots the previous deployment instead (see
+//! `grub/08_bootc_fallback_counting.cfg`, which is included in the static GRUB
+//! configuration installed by bootupd).
+//!
+//! `bootc-boot-complete.service` is enabled by the bootc systemd generator.
+//! Successfully reaching `boot-complete.target`, which health checks can
+//! be ordered before, runs `bootc internals boot-complete`. That runs the
+//! checks in `/usr/lib/bootc/health.d` (see [`crate::health`]) and, if they
+//! pass, disarms the counter; if any fails, the counter is exhausted and the
+//! system rebooted so that GRUB falls back.  If GRUB fell back, the rollback
+//! is made permanent and the event recorded so it shows up in `bootc status`.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Deserialize;
+
+use crate::spec::AutomaticRollback;
+use crate::store::Storage;
+
+/// The GRUB environment block, relative to /boot.
+const GRUBENV: &str = "grub2/grubenv";
+/// Configuration files, in order of precedence.
+const CONFIG_PATHS: &[&str] = &[
+    "etc/bootc/boot-counting.toml",
+    "usr/lib/bootc/boot-counting.toml",
+];
+/// Where we record that an automatic rollback happened.
+const ROLLBACK_RECORD: &str = "var/lib/bootc/automatic-rollback.json";
+/// The value GRUB sets the counter to when it falls back.
+const COUNTER_FALLBACK: &str = "-1";
+/// The journal message ID for automatic rollbacks.
+const AUTOMATIC_ROLLBACK_JOURNAL_ID: &str = "0b39b5bb5b5a4b1e9c44d4b2d5ad7d8a";
+
+fn default_attempts() -> u32 {
+    3
+}
+
+/// The boot counting configuration.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// The number of boot attempts before falling back to the previous deployment.
+    #[serde(default = "default_attempts")]
+    pub(crate) attempts: u32,
+}
+
+/// Load the boot counting configuration from the provided root; returns
+/// `None` if boot counting is not enabled.
+#[context("Loading boot counting configuration")]
+pub(crate) fn load_config(root: &Dir) -> Result<Option<Config>> {
+    for path in CONFIG_PATHS {
+        let Some(f) = root.open_optional(path)? else {
+            continue;
+        };
+        let buf = std::io::read_to_string(f)?;
+        let config: Config = toml::from_str(&buf).with_context(|| format!("Parsing {path}"))?;
+        if config.attempts == 0 {
+            anyhow::bail!("{path}: attempts must be at least 1");
+        }
+        return Ok(Some(config));
+    }
+    Ok(None)
+}
+
+/// Parse the contents of a GRUB environment block.
+fn parse_grubenv(buf: &str) -> BTreeMap<&str, &str> {
+    buf.lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .collect()
+}
+
+/// Run `grub2-editenv` against the environment block in the provided /boot.
+fn grub_editenv(boot: &Dir, args: &[&str]) -> Result<()> {
+    Command::new("grub2-editenv")
+        .arg(GRUBENV)
+        .args(args)
+        .cwd_dir(boot.try_clone()?)
+        .run()
+}
+
+/// Arm the boot counter for a new deployment, if boot counting is enabled in it;
+/// `root` is the booted root, providing `/boot` and `/var`.
+#[context("Arming boot counter")]
+pub(crate) fn arm(root: &Dir, deployment_root: &Dir) -> Result<()> {
+    let Some(config) = load_config(deployment_root)? else {
+        return Ok(());
+    };
+    let boot = &root.open_dir("boot").context("Opening /boot")?;
+    if !boot.try_exists(GRUBENV)? {
+        crate::journal::journal_print(
+            libsystemd::logging::Priority::Warning,
+            &format!("Boot counting is enabled, but /boot/{GRUBENV} does not exist"),
+        );
+        return Ok(());
+    }
+    let counter = format!("boot_counter={}", config.attempts);
+    grub_editenv(boot, &["set", "boot_success=0", counter.as_str()])?;
+    // Any previous automatic rollback is superseded by the new deployment
+    root.remove_file_optional(ROLLBACK_RECORD)?;
+    Ok(())
+}
+
+/// Read the record of an automatic rollback, if any.
+pub(crate) fn read_rollback_record(root: &Dir) -> Result<Option<AutomaticRollback>> {
+    let Some(f) = root.open_optional(ROLLBACK_RECORD)? else {
+        return Ok(None);
+    };
+    let r = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {ROLLBACK_RECORD}"))?;
+    Ok(Some(r))
+}
+
+/// Implementation of `bootc internals boot-complete`, invoked when the system
+/// has reached `boot-complete.target`.
+#[context("Completing boot")]
+pub(crate) async fn boot_complete(sysroot: &Storage, root: &Dir) -> Result<()> {
+    let boot = &root.open_dir("boot").context("Opening /boot")?;
+    let Some(f) = boot.open_optional(GRUBENV)? else {
+        return Ok(());
+    };
+    let buf = std::io::read_to_string(f)?;
+    let env = parse_grubenv(&buf);
+    let Some(&counter) = env.get("boot_counter") else {
+        tracing::debug!("Boot counter is not armed");
+        return Ok(());
+    };
+    if counter != COUNTER_FALLBACK {
+        let failed = crate::health::run(root)?;
+        if !failed.is_empty() {
+            crate::journal::journal_print(
+                libsystemd::logging::Priority::Warning,
+                &format!(
+                    "Health checks failed: {}; rebooting into the previous deployment",
+                    failed.join(", ")
+                ),
+            );
+            // GRUB falls back once the counter is exhausted
+            grub_editenv(boot, &["set", "boot_counter=0"])?;
+            return crate::reboot::reboot();
+        }
+    } else {
+        let (_, _, host) = crate::status::get_status_require_booted(sysroot)?;
+        // GRUB booted the previous deployment, so the failed one is queued as
+        // the rollback; make the booted deployment the default again.
+        let failed_image_digest = host
+            .status
+            .rollback
+            .as_ref()
+            .and_then(|r| r.image.as_ref())
+            .map(|i| i.image_digest.clone());
+        let record = AutomaticRollback {
+            timestamp: chrono::Utc::now(),
+            failed_image_digest,
+        };
+        let msg = format!(
+            "Boot attempts exhausted; automatically rolled back from {}",
+            record
+                .failed_image_digest
+                .as_deref()
+                .unwrap_or("unknown image")
+        );
+        libsystemd::logging::journal_send(
+            libsystemd::logging::Priority::Warning,
+            &msg,
+            [("MESSAGE_ID", AUTOMATIC_ROLLBACK_JOURNAL_ID)].into_iter(),
+        )?;
+        if host.status.rollback_queued {
+            crate::deploy::rollback(sysroot).await?;
+        }
+        root.create_dir_all(ROLLBACK_RECORD.rsplit_once('/').unwrap().0)?;
+        root.atomic_replace_with(ROLLBACK_RECORD, |w| {
+            serde_json::to_writer(w, &record).map_err(anyhow::Error::new)
+        })?;
+    }
+    grub_editenv(boot, &["set", "boot_success=1"])?;
+    grub_editenv(boot, &["unset", "boot_counter"])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_grubenv() {
+        let env = indoc::indoc! { "
+            # GRUB Environment Block
+            # WARNING: Do not edit this file by tools other than grub-editenv!!!
+            saved_entry=ostree-1-fedora
+            boot_success=0
+            boot_counter=-1
+            ########################################
+        " };
+        let env = parse_grubenv(env);
+        assert_eq!(env.len(), 3);
+        assert_eq!(env["boot_counter"], COUNTER_FALLBACK);
+        assert_eq!(env["saved_entry"], "ostree-1-fedora");
+    }
+
+    #[test]
+    fn test_load_config() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(load_config(td)?, None);
+        td.create_dir_all("usr/lib/bootc")?;
+        td.write("usr/lib/bootc/boot-counting.toml", "")?;
+        assert_eq!(load_config(td)?, Some(Config { attempts: 3 }));
+        td.create_dir_all("etc/bootc")?;
+        td.write("etc/bootc/boot-counting.toml", "attempts = 5\n")?;
+        assert_eq!(load_config(td)?, Some(Config { attempts: 5 }));
+        td.write("etc/bootc/boot-counting.toml", "attempts = 0\n")?;
+        assert!(load_config(td).is_err());
+        td.write("etc/bootc/boot-counting.toml", "tries = 3\n")?;
+        assert!(load_config(td).is_err());
+        Ok(())
+    }
+}
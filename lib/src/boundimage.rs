@@ -6,20 +6,25 @@
 //! is considered ready.
 
 use anyhow::{Context, Result};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
 use cap_std_ext::dirext::CapStdExtDirExt;
 use fn_error_context::context;
 #[cfg(feature = "install")]
 use ostree_ext::containers_image_proxy;
+use ostree_ext::gio;
+use ostree_ext::ostree;
 use ostree_ext::ostree::Deployment;
+use ostree_ext::prelude::*;
 
 use crate::imgstorage::PullMode;
 use crate::store::Storage;
 
 /// The path in a root for bound images; this directory should only contain
-/// symbolic links to `.container` or `.image` files.
+/// `.image` files, or symbolic links to `.container` or `.image` files.
 const BOUND_IMAGE_DIR: &str = "usr/lib/bootc/bound-images.d";
+/// The maximum number of symbolic links followed when reading from a commit.
+const MAX_SYMLINKS: usize = 40;
 
 /// A subset of data parsed from a `.image` or `.container` file with
 /// the minimal information necessary to fetch the image.
@@ -65,14 +70,16 @@ pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
     // handle absolute symlinks.
     let absroot = &root.open_dir_rooted_ext(".")?;
 
-    let mut bound_images = Vec::new();
+    let mut bound_images: Vec<BoundImage> = Vec::new();
 
-    for entry in bound_images_dir
+    // Process entries in a stable order, so that images are pulled deterministically.
+    let mut entries = bound_images_dir
         .entries()
         .context("Unable to read entries")?
-    {
-        //validate entry is a symlink with correct extension
-        let entry = entry?;
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        //validate entry is a symlink or regular file with correct extension
         let file_name = entry.file_name();
         let file_name = if let Some(n) = file_name.to_str() {
             n
@@ -80,30 +87,167 @@ pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
             anyhow::bail!("Invalid non-UTF8 filename: {file_name:?} in {}", spec_dir);
         };
 
-        if !entry.file_type()?.is_symlink() {
-            anyhow::bail!("Not a symlink: {file_name}");
-        }
+        let file_type = entry.file_type()?;
+        check_file_type(file_name, file_type.is_file(), file_type.is_symlink())?;
 
-        //parse the file contents
         let path = Utf8Path::new(spec_dir).join(file_name);
         let file_contents = absroot.read_to_string(&path)?;
+        let bound_image = parse_bound_image(&path, &file_contents)?;
 
-        let file_ini = tini::Ini::from_string(&file_contents).context("Parse to ini")?;
-        let file_extension = Utf8Path::new(file_name).extension();
-        let bound_image = match file_extension {
-            Some("image") => parse_image_file(&file_ini).with_context(|| format!("Parsing {path}")),
-            Some("container") => {
-                parse_container_file(&file_ini).with_context(|| format!("Parsing {path}"))
-            }
-            _ => anyhow::bail!("Invalid file extension: {file_name}"),
-        }?;
-
+        // The same image may be referenced by multiple files
+        if bound_images.iter().any(|i| i.image == bound_image.image) {
+            continue;
+        }
         bound_images.push(bound_image);
     }
 
     Ok(bound_images)
 }
 
+/// Query the bound images of an ostree commit, e.g. one which was fetched but
+/// not yet deployed.
+#[context("Querying bound images of {commit}")]
+pub(crate) fn query_bound_images_for_commit(
+    repo: &ostree::Repo,
+    commit: &str,
+) -> Result<Vec<BoundImage>> {
+    let cancellable = gio::Cancellable::NONE;
+    let (root, _) = repo.read_commit(commit, cancellable)?;
+    let dir = root.resolve_relative_path(BOUND_IMAGE_DIR);
+    if !dir.query_exists(cancellable) {
+        tracing::debug!("Missing {BOUND_IMAGE_DIR}");
+        return Ok(Default::default());
+    }
+    let queryattrs = "standard::name,standard::type";
+    let queryflags = gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS;
+    let mut names = Vec::new();
+    let iter = dir.enumerate_children(queryattrs, queryflags, cancellable)?;
+    while let Some(info) = iter.next_file(cancellable)? {
+        let name = info.name();
+        let Some(name) = name.to_str() else {
+            anyhow::bail!("Invalid non-UTF8 filename: {name:?} in {BOUND_IMAGE_DIR}");
+        };
+        let file_type = info.file_type();
+        check_file_type(
+            name,
+            file_type == gio::FileType::Regular,
+            file_type == gio::FileType::SymbolicLink,
+        )?;
+        names.push(name.to_owned());
+    }
+    // Process entries in a stable order, as for a deployment
+    names.sort();
+
+    let mut bound_images: Vec<BoundImage> = Vec::new();
+    for name in names {
+        let path = Utf8Path::new(BOUND_IMAGE_DIR).join(&name);
+        let file_contents = read_commit_file(&root, &path)?;
+        let bound_image = parse_bound_image(&path, &file_contents)?;
+        if bound_images.iter().any(|i| i.image == bound_image.image) {
+            continue;
+        }
+        bound_images.push(bound_image);
+    }
+    Ok(bound_images)
+}
+
+/// Verify that an entry of the bound images directory is a symbolic link, or
+/// a regular `.image` file.
+fn check_file_type(file_name: &str, is_file: bool, is_symlink: bool) -> Result<()> {
+    if is_file {
+        // Regular files are only supported for `.image`; a `.container` would
+        // not be a functional unit in this directory.
+        if Utf8Path::new(file_name).extension() != Some("image") {
+            anyhow::bail!("Not a symlink or .image file: {file_name}");
+        }
+    } else if !is_symlink {
+        anyhow::bail!("Not a symlink: {file_name}");
+    }
+    Ok(())
+}
+
+/// Parse the contents of a `.image` or `.container` file.
+fn parse_bound_image(path: &Utf8Path, file_contents: &str) -> Result<BoundImage> {
+    let file_ini = tini::Ini::from_string(file_contents).context("Parse to ini")?;
+    match path.extension() {
+        Some("image") => parse_image_file(&file_ini).with_context(|| format!("Parsing {path}")),
+        Some("container") => {
+            parse_container_file(&file_ini).with_context(|| format!("Parsing {path}"))
+        }
+        _ => anyhow::bail!("Invalid file extension: {path}"),
+    }
+}
+
+/// Join a symbolic link target to the directory containing the link, resolving
+/// `.` and `..` components; the result is relative to the root.
+fn resolve_link(parent: &Utf8Path, target: &Utf8Path) -> Utf8PathBuf {
+    let mut r = Utf8PathBuf::new();
+    let base = if target.is_absolute() {
+        Utf8Path::new("")
+    } else {
+        parent
+    };
+    for component in base.components().chain(target.components()) {
+        match component {
+            camino::Utf8Component::Normal(c) => r.push(c),
+            camino::Utf8Component::ParentDir => {
+                r.pop();
+            }
+            _ => {}
+        }
+    }
+    r
+}
+
+/// Read a file from an ostree commit, following symbolic links within it.
+fn read_commit_file(root: &gio::File, path: &Utf8Path) -> Result<String> {
+    let cancellable = gio::Cancellable::NONE;
+    let queryattrs = "standard::type,standard::symlink-target";
+    let queryflags = gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS;
+    let mut path = path.to_owned();
+    for _ in 0..MAX_SYMLINKS {
+        let f = root.resolve_relative_path(&path);
+        let info = f
+            .query_info(queryattrs, queryflags, cancellable)
+            .with_context(|| format!("Querying {path}"))?;
+        if info.file_type() != gio::FileType::SymbolicLink {
+            let (contents, _) = f
+                .load_contents(cancellable)
+                .with_context(|| format!("Reading {path}"))?;
+            return String::from_utf8(contents.to_vec()).with_context(|| format!("Reading {path}"));
+        }
+        let target = info
+            .symlink_target()
+            .ok_or_else(|| anyhow::anyhow!("Missing symlink target for {path}"))?;
+        let target = Utf8PathBuf::try_from(target)?;
+        // SAFETY: The path is within the root, so it has a parent
+        let parent = path.parent().unwrap();
+        path = resolve_link(parent, &target);
+    }
+    anyhow::bail!("Too many levels of symbolic links: {path}")
+}
+
+/// Verify that the provided bound images are already present (e.g. prefetched
+/// by `bootc fetch`), so that staging a deployment does not access the network.
+#[context("Checking bound images")]
+pub(crate) async fn require_present(sysroot: &Storage, bound_images: &[BoundImage]) -> Result<()> {
+    if bound_images.is_empty() {
+        return Ok(());
+    }
+    let imgstore = sysroot.get_imgstore_if_exists()?;
+    for bound_image in bound_images {
+        let image = &bound_image.image;
+        let present = match imgstore {
+            Some(imgstore) => imgstore.exists(image).await?,
+            None => false,
+        };
+        if !present {
+            anyhow::bail!("Bound image {image} has not been fetched; use `bootc fetch`");
+        }
+    }
+    Ok(())
+}
+
 #[cfg(feature = "install")]
 impl ResolvedBoundImage {
     #[context("resolving bound image {}", src.image)]
@@ -231,6 +375,24 @@ mod tests {
     use super::*;
     use cap_std_ext::cap_std;
 
+    #[test]
+    fn test_resolve_link() {
+        let parent = Utf8Path::new("usr/lib/bootc/bound-images.d");
+        for (target, expected) in [
+            (
+                "/usr/share/containers/systemd/foo.image",
+                "usr/share/containers/systemd/foo.image",
+            ),
+            (
+                "../../../share/containers/systemd/foo.container",
+                "usr/share/containers/systemd/foo.container",
+            ),
+            ("./bar.image", "usr/lib/bootc/bound-images.d/bar.image"),
+        ] {
+            assert_eq!(resolve_link(parent, Utf8Path::new(target)), expected);
+        }
+    }
+
     #[test]
     fn test_parse_spec_dir() -> Result<()> {
         const CONTAINER_IMAGE_DIR: &str = "usr/share/containers/systemd";
@@ -280,6 +442,35 @@ mod tests {
         assert_eq!(images[0].image, "quay.io/bar/bar:latest");
         assert_eq!(images[1].image, "quay.io/foo/foo:latest");
 
+        // Regular .image files are also supported, and duplicates are ignored
+        td.write(
+            format!("{BOUND_IMAGE_DIR}/baz.image"),
+            "[Image]\nImage=quay.io/baz/baz:latest\n",
+        )?;
+        td.write(
+            format!("{BOUND_IMAGE_DIR}/foo-again.image"),
+            "[Image]\nImage=quay.io/foo/foo:latest\n",
+        )?;
+        let images = query_bound_images(td).unwrap();
+        let images = images.iter().map(|i| i.image.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            images,
+            [
+                "quay.io/bar/bar:latest",
+                "quay.io/baz/baz:latest",
+                "quay.io/foo/foo:latest"
+            ]
+        );
+        td.remove_file(format!("{BOUND_IMAGE_DIR}/foo-again.image"))?;
+
+        // But not regular .container files
+        td.write(
+            format!("{BOUND_IMAGE_DIR}/app.container"),
+            "[Container]\nImage=quay.io/app/app:latest\n",
+        )?;
+        assert!(query_bound_images(td).is_err());
+        td.remove_file(format!("{BOUND_IMAGE_DIR}/app.container"))?;
+
         // Invalid symlink should return an error
         td.symlink("./blah", format!("{BOUND_IMAGE_DIR}/blah.image"))
             .unwrap();
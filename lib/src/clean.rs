@@ -0,0 +1,233 @@
+//! # Garbage collection
+//!
+//! `bootc clean` removes previous deployments according to a retention
+//! policy, then garbage collects the container images and layers (and,
+//! optionally, the logically bound images) which are no longer used by any
+//! remaining deployment.  Everything happens with the sysroot locked, so it
+//! cannot race with an upgrade; with `--dry-run`, what would be removed is
+//! printed instead.
+
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::ostree;
+use ostree_ext::ostree::Deployment;
+use ostree_ext::sysroot::LockMode;
+
+use crate::store::Storage;
+
+/// Options for `bootc clean`.
+#[derive(Debug, clap::Parser, PartialEq, Eq)]
+pub(crate) struct CleanOpts {
+    /// Keep at most this many previous deployments (i.e. neither booted nor staged),
+    /// starting with the rollback deployment.  Pinned deployments are always kept, and
+    /// are not counted.  By default, all deployments are kept.
+    #[clap(long)]
+    pub(crate) keep_previous: Option<usize>,
+
+    /// Also remove logically bound images which are not referenced by a remaining
+    /// deployment.
+    #[clap(long)]
+    pub(crate) prune_bound_images: bool,
+
+    /// Print what would be removed, without changing the system.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+}
+
+/// The role of a deployment, in the order of the deployment list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Staged,
+    Booted,
+    Pinned,
+    Previous,
+}
+
+/// Compute whether each deployment is retained, given their roles in order.
+fn retained(roles: &[Role], keep_previous: Option<usize>) -> Vec<bool> {
+    let mut previous = 0;
+    roles
+        .iter()
+        .map(|role| match role {
+            Role::Previous => {
+                previous += 1;
+                keep_previous.map_or(true, |n| previous <= n)
+            }
+            Role::Staged | Role::Booted | Role::Pinned => true,
+        })
+        .collect()
+}
+
+/// What a garbage collection pass removes.
+#[derive(Debug, Default)]
+struct Plan {
+    /// The deployments to keep, in order.
+    retained: Vec<Deployment>,
+    /// The deployments to remove.
+    removed: Vec<Deployment>,
+    /// The container images which are not used by a retained deployment.
+    images: Vec<ostree_container::ImageReference>,
+    /// The logically bound images which are not used by a retained deployment.
+    bound_images: Vec<String>,
+}
+
+/// A short description of a deployment.
+fn describe(deployment: &Deployment) -> Result<String> {
+    let image = deployment
+        .origin()
+        .map(|origin| crate::status::get_image_origin(&origin))
+        .transpose()?
+        .flatten()
+        .map(|imgref| imgref.imgref.to_string())
+        .unwrap_or_else(|| "(not container image based)".into());
+    Ok(format!(
+        "{}/{}.{}: {image}",
+        deployment.osname(),
+        deployment.csum(),
+        deployment.deployserial()
+    ))
+}
+
+/// Compute the garbage collection plan.
+#[context("Computing cleanup")]
+async fn plan(sysroot: &Storage, opts: &CleanOpts) -> Result<Plan> {
+    let booted = sysroot.require_booted_deployment()?;
+    let deployments = sysroot.deployments();
+    let roles = deployments
+        .iter()
+        .map(|d| {
+            if d.is_staged() {
+                Role::Staged
+            } else if d.equal(&booted) {
+                Role::Booted
+            } else if d.is_pinned() {
+                Role::Pinned
+            } else {
+                Role::Previous
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut plan = Plan::default();
+    for (deployment, retain) in deployments
+        .into_iter()
+        .zip(retained(&roles, opts.keep_previous))
+    {
+        if retain {
+            plan.retained.push(deployment);
+        } else {
+            plan.removed.push(deployment);
+        }
+    }
+
+    let repo = &sysroot.repo();
+    let mut used_images = HashSet::new();
+    for deployment in plan.retained.iter() {
+        let Some(origin) = deployment.origin() else {
+            continue;
+        };
+        if let Some(imgref) = crate::status::get_image_origin(&origin)? {
+            used_images.insert(imgref.imgref);
+        }
+    }
+    plan.images = ostree_container::store::list_images(repo)?
+        .into_iter()
+        .filter_map(|img| ostree_container::ImageReference::try_from(img.as_str()).ok())
+        .filter(|img| !used_images.contains(img))
+        .collect();
+
+    // Don't create the image storage here, as this is also used for a dry run
+    let imgstore = if opts.prune_bound_images {
+        sysroot.get_imgstore_if_exists()?
+    } else {
+        None
+    };
+    if let Some(imgstore) = imgstore {
+        let mut roots = HashSet::new();
+        for deployment in plan.retained.iter() {
+            let bound = crate::boundimage::query_bound_images_for_deployment(sysroot, deployment)?;
+            roots.extend(bound.into_iter().map(|img| img.image));
+        }
+        plan.bound_images = imgstore
+            .list_images()
+            .await?
+            .into_iter()
+            .flat_map(|img| img.names.unwrap_or_default())
+            .filter(|name| !roots.contains(name))
+            .collect();
+    }
+
+    Ok(plan)
+}
+
+/// Print what the plan removes.
+fn print_plan(plan: &Plan, dry_run: bool) -> Result<()> {
+    let verb = if dry_run { "Would remove" } else { "Removing" };
+    if plan.removed.is_empty() && plan.images.is_empty() && plan.bound_images.is_empty() {
+        println!("Nothing to remove");
+        return Ok(());
+    }
+    for deployment in plan.removed.iter() {
+        println!("{verb} deployment {}", describe(deployment)?);
+    }
+    for image in plan.images.iter() {
+        println!("{verb} image {image}");
+    }
+    for image in plan.bound_images.iter() {
+        println!("{verb} bound image {image}");
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc clean`.
+#[context("Cleaning")]
+pub(crate) async fn clean(opts: CleanOpts) -> Result<()> {
+    let lock = if opts.dry_run {
+        LockMode::Shared
+    } else {
+        LockMode::Exclusive
+    };
+    let sysroot = &crate::cli::get_storage(lock).await?;
+    let plan = plan(sysroot, &opts).await?;
+    print_plan(&plan, opts.dry_run)?;
+    if opts.dry_run {
+        return Ok(());
+    }
+
+    if !plan.removed.is_empty() {
+        sysroot
+            .write_deployments(&plan.retained, ostree::gio::Cancellable::NONE)
+            .context("Writing deployments")?;
+    }
+    // The images and layers are collected from the deployments as written,
+    // so this also covers the ones used only by the removed deployments.
+    crate::deploy::prune_repo(sysroot).await?;
+    if opts.prune_bound_images && sysroot.get_imgstore_if_exists()?.is_some() {
+        crate::deploy::prune_container_store(sysroot).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retained() {
+        use Role::*;
+        let roles = [Staged, Booted, Previous, Pinned, Previous, Previous];
+        assert_eq!(retained(&roles, None), [true; 6]);
+        assert_eq!(
+            retained(&roles, Some(0)),
+            [true, true, false, true, false, false]
+        );
+        assert_eq!(
+            retained(&roles, Some(2)),
+            [true, true, true, true, true, false]
+        );
+        assert_eq!(retained(&roles, Some(5)), [true; 6]);
+        assert_eq!(retained(&[Booted], Some(0)), [true]);
+    }
+}
@@ -3,9 +3,8 @@
 //! Command line tool to manage bootable ostree-based containers.
 
 use std::ffi::{CString, OsStr, OsString};
-use std::io::Seek;
-use std::os::unix::process::CommandExt;
-use std::process::Command;
+use std::io::{Seek, Write};
+use std::os::fd::RawFd;
 
 use anyhow::{Context, Result};
 use camino::Utf8PathBuf;
@@ -20,11 +19,13 @@ use ostree_ext::container as ostree_container;
 use ostree_ext::container_utils::ostree_booted;
 use ostree_ext::keyfileext::KeyFileExt;
 use ostree_ext::ostree;
+use ostree_ext::sysroot::{LockMode, LockOpts, SysrootLock};
 use schemars::schema_for;
 use serde::{Deserialize, Serialize};
 
 use crate::deploy::RequiredHostSpec;
 use crate::lints;
+use crate::progress_jsonl::ProgressWriter;
 use crate::spec::Host;
 use crate::spec::ImageReference;
 use crate::utils::sigpolicy_from_opts;
@@ -45,13 +46,42 @@ pub(crate) struct UpgradeOpts {
     #[clap(long, conflicts_with = "apply")]
     pub(crate) check: bool,
 
+    /// The output format for `--check`.
+    #[clap(long, requires = "check")]
+    pub(crate) format: Option<OutputFormat>,
+
     /// Restart or reboot into the new target image.
     ///
-    /// Currently, this option always reboots.  In the future this command
-    /// will detect the case where no kernel changes are queued, and perform
-    /// a userspace-only restart.
+    /// By default, this option performs a full reboot; see `--soft-reboot`.
     #[clap(long, conflicts_with = "check")]
     pub(crate) apply: bool,
+
+    /// With `--apply`, avoid a full firmware reboot: if the kernel, initramfs and
+    /// kernel arguments are unchanged, only userspace is restarted (via systemd
+    /// soft-reboot); otherwise the new kernel is booted directly via kexec.
+    #[clap(long, requires = "apply")]
+    pub(crate) soft_reboot: bool,
+
+    /// Write progress events in JSON lines format to this file descriptor.
+    #[clap(long)]
+    pub(crate) progress_fd: Option<RawFd>,
+
+    /// Stage the image previously downloaded via `bootc fetch`, without accessing
+    /// the network.
+    #[clap(long, conflicts_with = "check")]
+    pub(crate) from_cache: bool,
+}
+
+/// Perform a fetch operation
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct FetchOpts {
+    /// Don't display progress
+    #[clap(long)]
+    pub(crate) quiet: bool,
+
+    /// Write progress events in JSON lines format to this file descriptor.
+    #[clap(long)]
+    pub(crate) progress_fd: Option<RawFd>,
 }
 
 /// Perform an switch operation
@@ -63,12 +93,16 @@ pub(crate) struct SwitchOpts {
 
     /// Restart or reboot into the new target image.
     ///
-    /// Currently, this option always reboots.  In the future this command
-    /// will detect the case where no kernel changes are queued, and perform
-    /// a userspace-only restart.
+    /// By default, this option performs a full reboot; see `--soft-reboot`.
     #[clap(long)]
     pub(crate) apply: bool,
 
+    /// With `--apply`, avoid a full firmware reboot: if the kernel, initramfs and
+    /// kernel arguments are unchanged, only userspace is restarted (via systemd
+    /// soft-reboot); otherwise the new kernel is booted directly via kexec.
+    #[clap(long, requires = "apply")]
+    pub(crate) soft_reboot: bool,
+
     /// The transport; e.g. oci, oci-archive, containers-storage.  Defaults to `registry`.
     #[clap(long, default_value = "registry")]
     pub(crate) transport: String,
@@ -95,10 +129,23 @@ pub(crate) struct SwitchOpts {
     #[clap(long, hide = true)]
     pub(crate) mutate_in_place: bool,
 
+    /// Only rewrite the image reference of the booted deployment, without
+    /// fetching the image or creating a new deployment.
+    ///
+    /// This is intended for pointing the system at e.g. a mirror or renamed
+    /// repository; the target must serve the identical image (manifest digest)
+    /// as the booted one, which is verified by fetching only its manifest.
+    #[clap(long, conflicts_with_all = ["mutate_in_place", "retain", "apply"])]
+    pub(crate) in_place: bool,
+
     /// Retain reference to currently booted image
     #[clap(long)]
     pub(crate) retain: bool,
 
+    /// Write progress events in JSON lines format to this file descriptor.
+    #[clap(long)]
+    pub(crate) progress_fd: Option<RawFd>,
+
     /// Target image to use for the next boot.
     pub(crate) target: String,
 }
@@ -107,6 +154,55 @@ pub(crate) struct SwitchOpts {
 #[derive(Debug, Parser, PartialEq, Eq)]
 pub(crate) struct RollbackOpts {}
 
+/// Options controlling a factory reset
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct ResetOpts {
+    /// Copy this path (which must be below `/etc` or `/var`) from the current
+    /// system into the reset system.  This option can be provided multiple times.
+    #[clap(long)]
+    pub(crate) preserve: Vec<Utf8PathBuf>,
+
+    /// Preserve the network configuration (NetworkManager connections and
+    /// systemd-networkd configuration).
+    #[clap(long)]
+    pub(crate) preserve_network: bool,
+
+    /// Preserve `/etc/machine-id`, so the reset system keeps its identity.
+    #[clap(long)]
+    pub(crate) preserve_machine_id: bool,
+
+    /// The name of the new stateroot; by default, it is derived from the current
+    /// stateroot and the current time.
+    #[clap(long)]
+    pub(crate) stateroot: Option<String>,
+
+    /// Reboot into the reset system.
+    #[clap(long)]
+    pub(crate) apply: bool,
+}
+
+/// Options for extracting the SBOM of the booted image
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct ImageSbomOpts {
+    /// Only output SBOMs in this format.
+    #[clap(long, value_enum)]
+    pub(crate) format: Option<crate::sbom::SbomFormat>,
+
+    /// Only look for SBOMs in the image itself, without querying the registry
+    /// for attached artifacts.
+    #[clap(long)]
+    pub(crate) no_registry: bool,
+
+    /// List the SBOMs found and where they were found, instead of outputting them.
+    #[clap(long, conflicts_with = "output_dir")]
+    pub(crate) list: bool,
+
+    /// Write all SBOMs found to this directory; by default, the single SBOM found
+    /// is written to standard output.
+    #[clap(long)]
+    pub(crate) output_dir: Option<Utf8PathBuf>,
+}
+
 /// Perform an edit operation
 #[derive(Debug, Parser, PartialEq, Eq)]
 pub(crate) struct EditOpts {
@@ -114,6 +210,12 @@ pub(crate) struct EditOpts {
     #[clap(long, short = 'f')]
     pub(crate) filename: Option<String>,
 
+    /// Apply a JSON merge patch (RFC 7386) to the current host specification,
+    /// e.g. `--patch '{"spec":{"image":{"image":"quay.io/example/os:latest"}}}'`.
+    /// Fields set to `null` are removed.
+    #[clap(long, conflicts_with = "filename")]
+    pub(crate) patch: Option<String>,
+
     /// Don't display progress
     #[clap(long)]
     pub(crate) quiet: bool,
@@ -143,16 +245,29 @@ pub(crate) struct StatusOpts {
     #[clap(long)]
     pub(crate) format: Option<OutputFormat>,
 
-    /// The desired format version. There is currently one supported
-    /// version, which is exposed as both `0` and `1`. Pass this
-    /// option to explicitly request it; it is possible that another future
-    /// version 2 or newer will be supported in the future.
+    /// The desired format version. The original version is exposed as both
+    /// `0` and `1`, and is the default. Version `2` additionally includes
+    /// bound images, storage usage, the result of the most recent storage check,
+    /// and rollback availability.
     #[clap(long)]
     pub(crate) format_version: Option<u32>,
 
-    /// Only display status for the booted deployment.
-    #[clap(long)]
+    /// Print the booted image and its digest; exit with status 77 if the system
+    /// is not booted via bootc.  With `--json` or `--format`, only display status
+    /// for the booted deployment.
+    #[clap(long, group = "query")]
     pub(crate) booted: bool,
+
+    /// Print the staged image and its digest; exit with status 1 if there is
+    /// none, or 77 if the system is not booted via bootc.
+    #[clap(long, group = "query", conflicts_with_all = ["json", "format"])]
+    pub(crate) staged: bool,
+
+    /// Print the update found by the most recent check (e.g. `bootc upgrade --check`)
+    /// if it is not yet staged; exit with status 1 if there is none, or 77 if the
+    /// system is not booted via bootc.  The registry is not contacted.
+    #[clap(long, group = "query", conflicts_with_all = ["json", "format"])]
+    pub(crate) update_available: bool,
 }
 
 #[cfg(feature = "install")]
@@ -166,8 +281,9 @@ pub(crate) enum InstallOpts {
     ///
     /// The default storage layout uses the root filesystem type configured
     /// in the container image, alongside any required system partitions such as
-    /// the EFI system partition. Use `install to-filesystem` for anything more
-    /// complex such as RAID, LVM, LUKS etc.
+    /// the EFI system partition. Basic TPM2-bound LUKS and RAID1 mirroring
+    /// (`--mirror`) are supported; use `install to-filesystem` for anything more
+    /// complex such as LVM etc.
     ToDisk(crate::install::InstallToDiskOpts),
     /// Install to an externally created filesystem structure.
     ///
@@ -183,6 +299,14 @@ pub(crate) enum InstallOpts {
     /// will be wiped, but the content of the existing root will otherwise be retained, and will
     /// need to be cleaned up if desired when rebooted into the new root.
     ToExistingRoot(crate::install::InstallToExistingRootOpts),
+    /// Validate that an installation can proceed, without making any changes.
+    ///
+    /// This checks the execution environment, the target block device (if provided),
+    /// the SELinux policy of the image, the boot firmware, and the binaries required
+    /// for `install to-disk`, and outputs a report; it returns an error if any check
+    /// failed.  Like other install commands, it must be invoked inside of the container
+    /// which will be installed.
+    Preflight(crate::install::preflight::InstallPreflightOpts),
     /// Intended for use in environments that are performing an ostree-based installation, not bootc.
     ///
     /// In this scenario the installation may be missing bootc specific features such as
@@ -217,7 +341,18 @@ pub(crate) enum ContainerOpts {
     ///
     /// This is intended to be invoked via e.g. `RUN bootc container lint` as part
     /// of a build process; it will error if any problems are detected.
-    Lint,
+    ///
+    /// Problems are either fatal, or warnings for content which is likely to
+    /// be a mistake; only fatal problems cause an error by default.
+    Lint {
+        /// The output format; `json` is suitable for processing e.g. in CI.
+        #[clap(long, value_enum, default_value = "humanreadable")]
+        format: OutputFormat,
+
+        /// Return an error if any warnings are found, too.
+        #[clap(long)]
+        fatal_warnings: bool,
+    },
 }
 
 /// Subcommands which operate on images.
@@ -245,6 +380,80 @@ pub(crate) enum ImageCmdOpts {
     },
 }
 
+/// The deployment(s) whose kernel arguments are operated on.
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum KargsTarget {
+    /// The staged deployment
+    Staged,
+    /// The booted deployment
+    Booted,
+    /// Both the staged and booted deployments
+    All,
+}
+
+/// Options for modifying kernel arguments.
+#[derive(Debug, clap::Args, PartialEq, Eq)]
+pub(crate) struct KargsEditOpts {
+    /// The deployment(s) to modify; if unset, the staged deployment is used if there
+    /// is one, otherwise the booted deployment.
+    #[clap(long, value_enum)]
+    pub(crate) apply_to: Option<KargsTarget>,
+
+    /// Allow modifying kernel arguments which are required to boot the system,
+    /// such as `root=` and `ostree=`.
+    #[clap(long)]
+    pub(crate) force: bool,
+
+    /// Kernel arguments, e.g. `console=ttyS0` or `nosmt`.
+    #[clap(required = true)]
+    pub(crate) kargs: Vec<String>,
+}
+
+/// Subcommands which operate on kernel arguments.
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum KargsOpts {
+    /// Print the kernel arguments.
+    Get {
+        /// The deployment(s) to query; if unset, the staged deployment is used if there
+        /// is one, otherwise the booted deployment.
+        #[clap(long, value_enum)]
+        apply_to: Option<KargsTarget>,
+    },
+    /// Set kernel arguments, replacing any existing values for the same key.
+    Set(KargsEditOpts),
+    /// Append kernel arguments.
+    Append(KargsEditOpts),
+    /// Delete kernel arguments.
+    ///
+    /// An argument of the form `key=value` deletes only that exact value, whereas
+    /// `key` deletes all values for the key.
+    Delete(KargsEditOpts),
+}
+
+/// Subcommands which operate on `/etc`.
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum EtcOpts {
+    /// Show the files in `/etc` which were added, modified or deleted relative to
+    /// the defaults shipped in the image (`/usr/etc`).
+    Diff {
+        /// The output format.
+        #[clap(long)]
+        format: Option<OutputFormat>,
+    },
+    /// Reset files in `/etc` to the defaults shipped in the image.
+    ///
+    /// Added files are removed, and modified or deleted files are restored.  The
+    /// reset applies to the current `/etc`, and hence also to the next deployment.
+    Reset {
+        /// The paths to reset, e.g. `/etc/ssh/sshd_config`; directories are reset recursively.
+        #[clap(required = true)]
+        paths: Vec<Utf8PathBuf>,
+        /// Print the changes which would be reset, without resetting them.
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
 #[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum ImageListType {
@@ -278,6 +487,19 @@ impl std::fmt::Display for ImageListFormat {
     }
 }
 
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ImageInspectFormat {
+    /// JSON format
+    #[default]
+    Json,
+}
+impl std::fmt::Display for ImageInspectFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
 /// Subcommands which operate on images.
 #[derive(Debug, clap::Subcommand, PartialEq, Eq)]
 pub(crate) enum ImageOpts {
@@ -323,10 +545,53 @@ pub(crate) enum ImageOpts {
     PullFromDefaultStorage {
         /// The image to pull
         image: String,
+
+        /// Don't display progress
+        #[clap(long)]
+        quiet: bool,
+
+        /// Write progress events in JSON lines format to this file descriptor.
+        #[clap(long)]
+        progress_fd: Option<RawFd>,
+    },
+    /// Output the manifest, configuration and digest of an image in the bootc storage.
+    Inspect {
+        /// The image name or ID
+        image: String,
+        #[clap(long = "format")]
+        #[arg(default_value_t)]
+        format: ImageInspectFormat,
+    },
+    /// Verify the integrity of the bootc container storage.
+    ///
+    /// This verifies the checksums of all layers of images in the storage.
+    Check {
+        /// Remove images with corrupted layers and re-pull them from the
+        /// name they were stored with.
+        #[clap(long)]
+        repair: bool,
     },
+    /// Output the software bill of materials (SBOM) of the booted image.
+    ///
+    /// SBOMs are searched for in well-known directories of the image
+    /// (`/usr/share/buildinfo`, `/usr/share/sbom` and `/usr/lib/sbom`), and
+    /// among the artifacts attached to the image in the registry, as found via
+    /// the OCI referrers tag schema.  Both SPDX and CycloneDX are supported.
+    Sbom(ImageSbomOpts),
+    /// Generate a delta artifact for updating systems from one image to another.
+    ///
+    /// Both images must be stored in the provided ostree repository, e.g. via
+    /// `bootc internals ostree-container image pull`.  The artifact is written as
+    /// an OCI layout directory, along with the tag at which it must be published
+    /// in the repository of the target image; systems updating from the source image
+    /// will then fetch the deltas instead of the changed layers, where possible.
+    GenerateDelta(crate::delta::GenerateDeltaOpts),
     /// List fetched images stored in the bootc storage.
     ///
     /// Note that these are distinct from images stored via e.g. `podman`.
+    ///
+    /// When invoked as an unprivileged user, this operates on a per-user
+    /// storage in `$XDG_DATA_HOME/bootc/storage` instead.
     #[clap(subcommand)]
     Cmd(ImageCmdOpts),
 }
@@ -342,10 +607,23 @@ pub(crate) enum InternalsOpts {
         late_dir: Option<Utf8PathBuf>,
     },
     FixupEtcFstab,
+    /// Configure the bootc storage as an additional image store for the default
+    /// container storage; invoked from a unit enabled by the generator.
+    WriteStorageConfig,
     /// Should only be used by `make update-generated`
     PrintJsonSchema,
     /// Perform cleanup actions
     Cleanup,
+    /// Invoked once the system has reached `boot-complete.target`.
+    BootComplete,
+    /// Reset the SELinux labels of the `/etc` and `/var` of a deployment to those
+    /// defined by the policy shipped in its image.
+    Relabel(crate::relabel::RelabelOpts),
+    /// Output the shell completion candidates for the last of the provided words.
+    Complete {
+        #[clap(allow_hyphen_values = true)]
+        words: Vec<String>,
+    },
     /// Proxy frontend for the `ostree-ext` CLI.
     OstreeExt {
         #[clap(allow_hyphen_values = true)]
@@ -405,6 +683,13 @@ pub(crate) enum Opt {
     /// do *not* automatically apply the update in addition.
     #[clap(alias = "update")]
     Upgrade(UpgradeOpts),
+    /// Download an updated container image, without queuing it to apply.
+    ///
+    /// The image and its logically bound images are fetched and verified, but no
+    /// deployment is created; use `bootc upgrade --from-cache` to stage it later
+    /// without accessing the network.  This allows separating the bandwidth-heavy
+    /// download from the deployment.
+    Fetch(FetchOpts),
     /// Target a new container image reference to boot.
     ///
     /// This is almost exactly the same operation as `upgrade`, but additionally changes the container image reference
@@ -428,16 +713,56 @@ pub(crate) enum Opt {
     /// A systemd journal message will be logged with `MESSAGE_ID=26f3b1eb24464d12aa5e7b544a6b5468` in
     /// order to detect a rollback invocation.
     Rollback(RollbackOpts),
+    /// Return the system to its defaults by re-deploying the booted image into a fresh
+    /// stateroot.
+    ///
+    /// The new deployment starts with the default `/etc` from the image and an empty `/var`;
+    /// use the `--preserve` options to carry over e.g. network configuration.  The current
+    /// stateroot is not modified, and remains available via `bootc rollback` until it is
+    /// garbage collected.
+    Reset(ResetOpts),
+    /// Remove previous deployments according to a retention policy, and garbage collect
+    /// the container images which are no longer used.
+    ///
+    /// By default all deployments are kept, and only unused images and layers are removed;
+    /// use `--keep-previous` to also remove older deployments.  The booted, staged and
+    /// pinned deployments are never removed.  Use `--dry-run` to print what would be
+    /// removed without changing the system.
+    Clean(crate::clean::CleanOpts),
+    /// Place `/var` on a new LUKS2 volume bound to the TPM2 device.
+    ///
+    /// The provided block device is formatted, the current contents of `/var` are
+    /// copied into it, and `/etc/crypttab` and `/etc/fstab` are updated such that it
+    /// is unlocked and mounted on `/var` from the next boot.  This provides data-at-rest
+    /// protection for the local state, while the operating system content stays
+    /// unencrypted.
+    ///
+    /// If LUKS volumes are selected via the `luks.uuid=` kernel argument, the new
+    /// volume is added to the kernel arguments of the booted and staged deployments.
+    #[cfg(feature = "install")]
+    EncryptVar(crate::varluks::EncryptVarOpts),
     /// Apply full changes to the host specification.
     ///
     /// This command operates very similarly to `kubectl apply`; if invoked interactively,
     /// then the current host specification will be presented in the system default `$EDITOR`
     /// for interactive changes.
     ///
-    /// It is also possible to directly provide new contents via `bootc edit --filename`.
+    /// It is also possible to directly provide new contents via `bootc edit --filename`,
+    /// or to change individual fields via `bootc edit --patch`.
+    ///
+    /// The new host specification is parsed strictly: unknown fields and values of
+    /// the wrong type are rejected, with the path to the offending field.
     ///
     /// Only changes to the `spec` section are honored.
     Edit(EditOpts),
+    /// Output Prometheus metrics describing the state of the system.
+    ///
+    /// The metrics include the booted and staged images, whether an update is available,
+    /// the result of the most recent `bootc upgrade` and `bootc image check`, and the
+    /// storage usage.  By default they are written to standard output; use `--textfile`
+    /// to write them for the node_exporter textfile collector, or `--listen` to serve
+    /// them over HTTP.
+    Metrics(crate::metrics::MetricsOpts),
     /// Display status
     ///
     /// If standard output is a terminal, this will output a description of the bootc system state.
@@ -453,6 +778,12 @@ pub(crate) enum Opt {
     /// ## Programmatically detecting whether the system is deployed via bootc
     ///
     /// Invoke e.g. `bootc status --json`, and check if `status.booted` is not `null`.
+    ///
+    /// ## Querying the state in scripts
+    ///
+    /// `--booted`, `--staged` and `--update-available` print just the corresponding
+    /// image, and use the exit status to answer the query: `0` if the image is present,
+    /// `1` if it is not, and `77` if the system is not booted via bootc.
     Status(StatusOpts),
     /// Adds a transient writable overlayfs on `/usr` that will be discarded on reboot.
     ///
@@ -469,13 +800,37 @@ pub(crate) enum Opt {
     /// there will persist.  It is common for package installations to modify these
     /// directories.
     ///
+    /// ## Status
+    ///
+    /// While the overlay is mounted, `bootc status` reports that `/usr` is unlocked
+    /// (`status.usrOverlay` is `true`).
+    ///
     /// ## Unmounting
     ///
-    /// Almost always, a system process will hold a reference to the open mount point.
-    /// You can however invoke `umount -l /usr` to perform a "lazy unmount".
+    /// The overlay and its contents are held in memory, and automatically discarded
+    /// on reboot.  Almost always, a system process will hold a reference to the open
+    /// mount point. You can however invoke `umount -l /usr` to perform a "lazy unmount".
     ///
     #[clap(alias = "usroverlay")]
     UsrOverlay,
+    /// Manage kernel arguments.
+    ///
+    /// Changes are made to the bootloader entries of existing deployments, and
+    /// are carried forward by subsequent upgrades.  By default the staged deployment
+    /// is modified if there is one, otherwise the booted deployment; changes to the
+    /// booted deployment take effect on the next boot.
+    ///
+    /// Arguments which are required to boot the system (such as `root=`) cannot
+    /// be modified without `--force`.
+    #[clap(subcommand)]
+    Kargs(KargsOpts),
+    /// Inspect and reset local changes to `/etc`.
+    ///
+    /// On upgrade, the changes made to `/etc` relative to the defaults of the current
+    /// image are applied to the defaults of the new image (a 3-way merge).  These
+    /// commands show those changes, and revert selected ones to the image defaults.
+    #[clap(subcommand)]
+    Etc(EtcOpts),
     /// Install the running container to a target.
     ///
     /// ## Understanding installations
@@ -499,6 +854,20 @@ pub(crate) enum Opt {
     /// Operations which can be executed as part of a container build.
     #[clap(subcommand)]
     Container(ContainerOpts),
+    /// Output a shell completion script.
+    ///
+    /// Besides subcommands and options, image references are completed from the images
+    /// known locally, i.e. those of the deployments and the other host images
+    /// (see `bootc image list --type host`).
+    ///
+    /// For example, to enable completion in the current bash session:
+    ///
+    /// `source <(bootc completions bash)`
+    Completions {
+        /// The shell to generate the script for
+        #[clap(value_enum)]
+        shell: crate::completions::Shell,
+    },
     /// Operations on container images
     ///
     /// Stability: This interface is not declared stable and may change or be removed
@@ -512,6 +881,14 @@ pub(crate) enum Opt {
         #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<OsString>,
     },
+    /// Check for and apply updates according to the automatic update policy;
+    /// invoked by `bootc-update.service`.
+    #[clap(hide = true)]
+    UpdateWorker,
+    /// Export a D-Bus interface for querying and updating the system; invoked by
+    /// `bootc-dbus.service`.
+    #[clap(hide = true)]
+    Service,
     /// Modify the state of the system
     #[clap(hide = true)]
     #[clap(subcommand)]
@@ -555,26 +932,80 @@ pub(crate) fn ensure_self_unshared_mount_namespace() -> Result<()> {
     crate::reexec::reexec_with_guardenv(recurse_env, &["unshare", "-m", "--"])
 }
 
-/// Acquire a locked sysroot.
+/// If set, the maximum number of seconds to wait for the sysroot lock.
+const LOCK_TIMEOUT_ENV: &str = "BOOTC_LOCK_TIMEOUT";
+
+/// Parse the value of [`LOCK_TIMEOUT_ENV`].
+fn parse_lock_timeout(v: &str) -> Result<std::time::Duration> {
+    let secs = v
+        .trim()
+        .parse()
+        .with_context(|| format!("Parsing {LOCK_TIMEOUT_ENV}={v}"))?;
+    Ok(std::time::Duration::from_secs(secs))
+}
+
+/// Acquire a locked sysroot; read-only operations should use a shared lock
+/// so that they can run concurrently with each other.
 /// TODO drain this and the above into SysrootLock
 #[context("Acquiring sysroot")]
-pub(crate) async fn get_locked_sysroot() -> Result<ostree_ext::sysroot::SysrootLock> {
+pub(crate) async fn get_locked_sysroot(mode: LockMode) -> Result<SysrootLock> {
+    // Hooks run while the invoking bootc holds the lock; don't wait for it forever
+    if let Some(hook) = std::env::var_os(crate::hooks::HOOK_ENV) {
+        anyhow::bail!("bootc cannot be invoked from the {hook:?} hook");
+    }
     prepare_for_write()?;
     let sysroot = ostree::Sysroot::new_default();
     sysroot.set_mount_namespace_in_use();
-    let sysroot = ostree_ext::sysroot::SysrootLock::new_from_sysroot(&sysroot).await?;
+    let mut opts = LockOpts::new(mode);
+    if let Some(v) = std::env::var_os(LOCK_TIMEOUT_ENV) {
+        let v = v
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 {LOCK_TIMEOUT_ENV}"))?;
+        opts = opts.timeout(parse_lock_timeout(v)?);
+    }
+    let sysroot = SysrootLock::new_with_opts(&sysroot, &opts).await?;
     sysroot.load(gio::Cancellable::NONE)?;
     Ok(sysroot)
 }
 
 /// Load global storage state, expecting that we're booted into a bootc system.
 #[context("Initializing storage")]
-pub(crate) async fn get_storage() -> Result<crate::store::Storage> {
+pub(crate) async fn get_storage(mode: LockMode) -> Result<crate::store::Storage> {
     let global_run = Dir::open_ambient_dir("/run", cap_std::ambient_authority())?;
-    let sysroot = get_locked_sysroot().await?;
+    let sysroot = get_locked_sysroot(mode).await?;
     crate::store::Storage::new(sysroot, &global_run)
 }
 
+/// The container image storage used by `bootc image`.
+enum ImageStorage {
+    /// The system storage, locked in the provided mode
+    System(crate::store::Storage, LockMode),
+    /// When invoked unprivileged, a per-user storage
+    User(crate::imgstorage::Storage),
+}
+
+impl ImageStorage {
+    /// Open the storage; operations which modify it require [`LockMode::Exclusive`].
+    async fn new(mode: LockMode) -> Result<Self> {
+        if rustix::process::getuid().is_root() {
+            Ok(Self::System(get_storage(mode).await?, mode))
+        } else {
+            Ok(Self::User(crate::imgstorage::Storage::open_user()?))
+        }
+    }
+
+    fn get(&self) -> Result<&crate::imgstorage::Storage> {
+        match self {
+            ImageStorage::System(storage, LockMode::Exclusive) => storage.get_ensure_imgstore(),
+            // Don't initialize the storage without holding the exclusive lock
+            ImageStorage::System(storage, _) => storage
+                .get_imgstore_if_exists()?
+                .ok_or_else(|| anyhow::anyhow!("The bootc image storage is not initialized")),
+            ImageStorage::User(storage) => Ok(storage),
+        }
+    }
+}
+
 #[context("Querying root privilege")]
 pub(crate) fn require_root() -> Result<()> {
     let uid = rustix::process::getuid();
@@ -592,7 +1023,7 @@ pub(crate) fn require_root() -> Result<()> {
 /// IMPORTANT: This may end up re-executing the current process,
 /// so anything that happens before this should be idempotent.
 #[context("Preparing for write")]
-fn prepare_for_write() -> Result<()> {
+pub(crate) fn prepare_for_write() -> Result<()> {
     use std::sync::atomic::{AtomicBool, Ordering};
 
     // This is intending to give "at most once" semantics to this
@@ -625,10 +1056,80 @@ fn prepare_for_write() -> Result<()> {
     Ok(())
 }
 
-/// Implementation of the `bootc upgrade` CLI command.
+/// The result of `bootc upgrade --check`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UpgradeCheck<'a> {
+    /// The image which was checked.
+    image: String,
+    /// True if the image differs from what we have locally.
+    update_available: bool,
+    /// True if the image is already staged.
+    staged: bool,
+    /// The version of the image, if any.
+    version: Option<&'a str>,
+    /// The kernel version in the image, if any.
+    kernel: Option<&'a str>,
+    /// The changelog of the image, if any.
+    changelog: Option<&'a str>,
+    /// The manifest digest of the image.
+    digest: String,
+    /// The layer differences from the booted image, if there is an update.
+    diff: Option<ostree_container::ManifestDiff<'a>>,
+}
+
+impl UpgradeCheck<'_> {
+    fn print(&self) {
+        let image = &self.image;
+        if !self.update_available {
+            if self.staged {
+                println!("Update already staged for: {image}");
+            } else {
+                println!("No changes in: {image}");
+            }
+            return;
+        }
+        println!("Update available for: {image}");
+        if let Some(version) = self.version {
+            println!("  Version: {version}");
+        }
+        if let Some(kernel) = self.kernel {
+            println!("  Kernel: {kernel}");
+        }
+        println!("  Digest: {}", self.digest);
+        if let Some(changelog) = self.changelog {
+            println!("  Changelog:");
+            for line in changelog.trim_end().lines() {
+                println!("    {line}");
+            }
+        }
+        if let Some(diff) = self.diff.as_ref() {
+            diff.print();
+        }
+    }
+}
+
+/// Implementation of the `bootc upgrade` CLI command; the result is recorded
+/// for `bootc metrics`, and the notifications of pending updates are refreshed.
+pub(crate) async fn upgrade(opts: UpgradeOpts) -> Result<()> {
+    let r = upgrade_impl(opts).await;
+    crate::autoupdate::record_update_result(&r);
+    refresh_notifications_after(r).await
+}
+
+/// Refresh the notifications of pending updates after a successful operation
+/// which may have changed the deployments.
+async fn refresh_notifications_after(r: Result<()>) -> Result<()> {
+    if r.is_ok() {
+        crate::notify::refresh_after_update().await;
+    }
+    r
+}
+
 #[context("Upgrading")]
-async fn upgrade(opts: UpgradeOpts) -> Result<()> {
-    let sysroot = &get_storage().await?;
+async fn upgrade_impl(opts: UpgradeOpts) -> Result<()> {
+    let prog = &ProgressWriter::from_opt_fd(opts.progress_fd)?;
+    let sysroot = &get_storage(LockMode::Exclusive).await?;
     let repo = &sysroot.repo();
     let (booted_deployment, _deployments, host) =
         crate::status::get_status_require_booted(sysroot)?;
@@ -669,27 +1170,62 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
     if opts.check {
         let imgref = imgref.clone().into();
         let mut imp = crate::deploy::new_importer(repo, &imgref).await?;
-        match imp.prepare().await? {
-            PrepareResult::AlreadyPresent(_) => {
-                println!("No changes in: {imgref:#}");
-            }
+        let prep = imp.prepare().await?;
+        let (digest, version, manifest, config) = match &prep {
+            PrepareResult::AlreadyPresent(state) => (
+                &state.manifest_digest,
+                state.version(),
+                &state.manifest,
+                &state.configuration,
+            ),
             PrepareResult::Ready(r) => {
                 crate::deploy::check_bootc_label(&r.config);
-                println!("Update available for: {imgref:#}");
-                if let Some(version) = r.version() {
-                    println!("  Version: {version}");
-                }
-                println!("  Digest: {}", r.manifest_digest);
-                changed = true;
-                if let Some(previous_image) = booted_image.as_ref() {
-                    let diff =
-                        ostree_container::ManifestDiff::new(&previous_image.manifest, &r.manifest);
-                    diff.print();
-                }
+                (&r.manifest_digest, r.version(), &r.manifest, &r.config)
+            }
+        };
+        let digest = digest.to_string();
+        let diff = match (&prep, booted_image.as_ref()) {
+            (PrepareResult::Ready(r), Some(previous_image)) => Some(
+                ostree_container::ManifestDiff::new(&previous_image.manifest, &r.manifest),
+            ),
+            _ => None,
+        };
+        let check = UpgradeCheck {
+            image: format!("{imgref:#}"),
+            update_available: matches!(prep, PrepareResult::Ready(_)),
+            staged: staged_image.is_some_and(|s| s.image_digest == digest),
+            version,
+            kernel: crate::metadata::image_kernel(config),
+            changelog: crate::metadata::image_changelog(manifest, config),
+            digest,
+            diff,
+        };
+        changed = check.update_available;
+        let mut out = std::io::stdout().lock();
+        match opts.format.unwrap_or(OutputFormat::HumanReadable) {
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut out, &check)?;
+                writeln!(out)?;
             }
+            OutputFormat::Yaml => serde_yaml::to_writer(&mut out, &check)?,
+            OutputFormat::HumanReadable => check.print(),
         }
     } else {
-        let fetched = crate::deploy::pull(repo, imgref, None, opts.quiet).await?;
+        let fetched = if opts.from_cache {
+            let sysroot_dir = &Dir::reopen_dir(&crate::utils::sysroot_fd(sysroot))?;
+            let fetched =
+                crate::deploy::query_fetched(sysroot_dir, repo, imgref)?.ok_or_else(|| {
+                    anyhow::anyhow!("Image {imgref:#} has not been fetched; use `bootc fetch`")
+                })?;
+            let bound_images =
+                crate::boundimage::query_bound_images_for_commit(repo, &fetched.ostree_commit)?;
+            crate::boundimage::require_present(sysroot, &bound_images).await?;
+            fetched
+        } else {
+            let policy = crate::sigpolicy::load_host_policy()?;
+            crate::deploy::pull_with_mirrors(repo, imgref, policy.as_ref(), opts.quiet, prog)
+                .await?
+        };
         let staged_digest = staged_image.map(|s| s.digest().expect("valid digest in status"));
         let fetched_digest = &fetched.manifest_digest;
         tracing::debug!("staged: {staged_digest:?}");
@@ -706,13 +1242,13 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
             println!("Staged update present, not changed.");
 
             if opts.apply {
-                crate::reboot::reboot()?;
+                crate::reboot::apply(sysroot, opts.soft_reboot)?;
             }
         } else if booted_unchanged {
             println!("No update available.")
         } else {
             let osname = booted_deployment.osname();
-            crate::deploy::stage(sysroot, &osname, &fetched, &spec).await?;
+            crate::deploy::stage(sysroot, &osname, &fetched, &spec, prog).await?;
             changed = true;
             if let Some(prev) = booted_image.as_ref() {
                 if let Some(fetched_manifest) = fetched.get_manifest(repo)? {
@@ -725,7 +1261,7 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
     }
     if changed {
         if opts.apply {
-            crate::reboot::reboot()?;
+            crate::reboot::apply(sysroot, opts.soft_reboot)?;
         }
     } else {
         tracing::debug!("No changes");
@@ -734,6 +1270,35 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
     Ok(())
 }
 
+/// Implementation of the `bootc fetch` CLI command.
+#[context("Fetching")]
+async fn fetch(opts: FetchOpts) -> Result<()> {
+    let prog = &ProgressWriter::from_opt_fd(opts.progress_fd)?;
+    let sysroot = &get_storage(LockMode::Exclusive).await?;
+    let repo = &sysroot.repo();
+    let (_booted_deployment, _deployments, host) =
+        crate::status::get_status_require_booted(sysroot)?;
+    let spec = RequiredHostSpec::from_spec(&host.spec)?;
+    let policy = crate::sigpolicy::load_host_policy()?;
+    let fetched =
+        crate::deploy::pull_with_mirrors(repo, spec.image, policy.as_ref(), opts.quiet, prog)
+            .await?;
+    let bound_images =
+        crate::boundimage::query_bound_images_for_commit(repo, &fetched.ostree_commit)?;
+    crate::boundimage::pull_images(sysroot, bound_images).await?;
+    let sysroot_dir = &Dir::reopen_dir(&crate::utils::sysroot_fd(sysroot))?;
+    crate::deploy::write_fetch_record(sysroot_dir, &fetched)?;
+    println!("Fetched: {:#}", spec.image);
+    println!("  Digest: {}", fetched.manifest_digest);
+    if let Some(source) = fetched.source.as_ref() {
+        println!("  Fetched from: {}:{}", source.transport, source.image);
+    }
+    if let Some(version) = fetched.version.as_deref() {
+        println!("  Version: {version}");
+    }
+    Ok(())
+}
+
 /// Implementation of the `bootc switch` CLI command.
 #[context("Switching")]
 async fn switch(opts: SwitchOpts) -> Result<()> {
@@ -766,7 +1331,8 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
 
     let cancellable = gio::Cancellable::NONE;
 
-    let sysroot = &get_storage().await?;
+    let prog = &ProgressWriter::from_opt_fd(opts.progress_fd)?;
+    let sysroot = &get_storage(LockMode::Exclusive).await?;
     let repo = &sysroot.repo();
     let (booted_deployment, _deployments, host) =
         crate::status::get_status_require_booted(sysroot)?;
@@ -781,9 +1347,33 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
         println!("Image specification is unchanged.");
         return Ok(());
     }
+
+    let policy = crate::sigpolicy::load_host_policy()?;
+    if opts.in_place {
+        if host.status.staged.is_some() {
+            anyhow::bail!("Cannot switch in place with a staged deployment");
+        }
+        let booted_image = host
+            .status
+            .booted
+            .as_ref()
+            .and_then(|b| b.image.as_ref())
+            .ok_or_else(|| anyhow::anyhow!("Booted deployment is not a container image"))?;
+        return crate::deploy::switch_inplace(
+            sysroot,
+            &booted_deployment,
+            &booted_image.image,
+            &booted_image.image_digest,
+            &target,
+            policy.as_ref(),
+        )
+        .await;
+    }
+
     let new_spec = RequiredHostSpec::from_spec(&new_spec)?;
 
-    let fetched = crate::deploy::pull(repo, &target, None, opts.quiet).await?;
+    let fetched =
+        crate::deploy::pull(repo, &target, None, policy.as_ref(), opts.quiet, prog).await?;
 
     if !opts.retain {
         // By default, we prune the previous ostree ref so it will go away after later upgrades
@@ -797,10 +1387,10 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
     }
 
     let stateroot = booted_deployment.osname();
-    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec).await?;
+    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec, prog).await?;
 
     if opts.apply {
-        crate::reboot::reboot()?;
+        crate::reboot::apply(sysroot, opts.soft_reboot)?;
     }
 
     Ok(())
@@ -809,27 +1399,34 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
 /// Implementation of the `bootc rollback` CLI command.
 #[context("Rollback")]
 async fn rollback(_opts: RollbackOpts) -> Result<()> {
-    let sysroot = &get_storage().await?;
+    let sysroot = &get_storage(LockMode::Exclusive).await?;
     crate::deploy::rollback(sysroot).await
 }
 
 /// Implementation of the `bootc edit` CLI command.
 #[context("Editing spec")]
 async fn edit(opts: EditOpts) -> Result<()> {
-    let sysroot = &get_storage().await?;
+    let sysroot = &get_storage(LockMode::Exclusive).await?;
     let repo = &sysroot.repo();
 
     let (booted_deployment, _deployments, host) =
         crate::status::get_status_require_booted(sysroot)?;
-    let new_host: Host = if let Some(filename) = opts.filename {
-        let mut r = std::io::BufReader::new(std::fs::File::open(filename)?);
-        serde_yaml::from_reader(&mut r)?
+    let new_host = if let Some(patch) = opts.patch.as_deref() {
+        let patch: serde_json::Value = serde_json::from_str(patch).context("Parsing patch")?;
+        let mut doc = serde_json::to_value(&host)?;
+        crate::k8sapitypes::merge_patch(&mut doc, &patch);
+        Host::from_str_strict(&doc.to_string()).context("Applying patch")?
+    } else if let Some(filename) = opts.filename {
+        let buf =
+            std::fs::read_to_string(&filename).with_context(|| format!("Reading {filename}"))?;
+        Host::from_str_strict(&buf).with_context(|| format!("Parsing {filename}"))?
     } else {
         let tmpf = tempfile::NamedTempFile::new()?;
         serde_yaml::to_writer(std::io::BufWriter::new(tmpf.as_file()), &host)?;
         crate::utils::spawn_editor(&tmpf)?;
         tmpf.as_file().seek(std::io::SeekFrom::Start(0))?;
-        serde_yaml::from_reader(&mut tmpf.as_file())?
+        let buf = std::io::read_to_string(tmpf.as_file())?;
+        Host::from_str_strict(&buf).context("Parsing edited host")?
     };
 
     if new_host.spec == host.spec {
@@ -845,26 +1442,48 @@ async fn edit(opts: EditOpts) -> Result<()> {
         return crate::deploy::rollback(sysroot).await;
     }
 
-    let fetched = crate::deploy::pull(repo, new_spec.image, None, opts.quiet).await?;
+    let prog = &ProgressWriter::default();
+    let policy = crate::sigpolicy::load_host_policy()?;
+    let fetched =
+        crate::deploy::pull_with_mirrors(repo, new_spec.image, policy.as_ref(), opts.quiet, prog)
+            .await?;
 
     // TODO gc old layers here
 
     let stateroot = booted_deployment.osname();
-    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec).await?;
+    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec, prog).await?;
 
     Ok(())
 }
 
 /// Implementation of `bootc usroverlay`
-async fn usroverlay() -> Result<()> {
-    // This is just a pass-through today.  At some point we may make this a libostree API
-    // or even oxidize it.
-    Err(Command::new("ostree")
-        .args(["admin", "unlock"])
-        .exec()
-        .into())
+async fn usroverlay(root: &Dir) -> Result<()> {
+    // Note that we intentionally don't use prepare_for_write() here, as the
+    // overlay needs to be mounted in the host mount namespace.
+    if ostree_ext::container_utils::running_in_container() {
+        anyhow::bail!("Detected container; this command requires a booted host system.");
+    }
+    anyhow::ensure!(
+        ostree_booted()?,
+        "This command requires an ostree-booted host system"
+    );
+    require_root()?;
+    crate::usroverlay::usroverlay(root)
+}
+
+/// An error which only sets the exit status of the process, for commands which
+/// answer a query with it (e.g. `bootc status --staged`); no message is printed.
+#[derive(Debug)]
+pub struct ExitStatus(pub i32);
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Exiting with status {}", self.0)
+    }
 }
 
+impl std::error::Error for ExitStatus {}
+
 /// Perform process global initialization. This should be called as early as possible
 /// in the standard `main` function.
 pub fn global_init() -> Result<()> {
@@ -949,19 +1568,33 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
     let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
     match opt {
         Opt::Upgrade(opts) => upgrade(opts).await,
-        Opt::Switch(opts) => switch(opts).await,
-        Opt::Rollback(opts) => rollback(opts).await,
-        Opt::Edit(opts) => edit(opts).await,
-        Opt::UsrOverlay => usroverlay().await,
+        Opt::Fetch(opts) => fetch(opts).await,
+        Opt::UpdateWorker => crate::autoupdate::update_worker().await,
+        Opt::Service => crate::service::service().await,
+        Opt::Switch(opts) => refresh_notifications_after(switch(opts).await).await,
+        Opt::Rollback(opts) => refresh_notifications_after(rollback(opts).await).await,
+        Opt::Reset(opts) => refresh_notifications_after(crate::reset::reset(opts).await).await,
+        Opt::Clean(opts) => crate::clean::clean(opts).await,
+        #[cfg(feature = "install")]
+        Opt::EncryptVar(opts) => crate::varluks::encrypt_var(opts).await,
+        Opt::Edit(opts) => refresh_notifications_after(edit(opts).await).await,
+        Opt::Metrics(opts) => crate::metrics::metrics(opts).await,
+        Opt::UsrOverlay => usroverlay(root).await,
+        Opt::Kargs(opts) => crate::kargs::kargs_entrypoint(opts).await,
+        Opt::Etc(opts) => crate::etc::etc_entrypoint(opts).await,
+        Opt::Completions { shell } => crate::completions::print_script(shell),
         Opt::Container(opts) => match opts {
-            ContainerOpts::Lint => {
+            ContainerOpts::Lint {
+                format,
+                fatal_warnings,
+            } => {
                 if !ostree_ext::container_utils::is_ostree_container()? {
                     anyhow::bail!(
                         "Not in a ostree container, this command only verifies ostree containers."
                     );
                 }
 
-                lints::lint(root)?;
+                lints::lint(root, &format, fatal_warnings)?;
                 Ok(())
             }
         },
@@ -973,16 +1606,46 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
             ImageOpts::CopyToStorage { source, target } => {
                 crate::image::push_entrypoint(source.as_deref(), target.as_deref()).await
             }
-            ImageOpts::PullFromDefaultStorage { image } => {
-                let sysroot = get_storage().await?;
-                sysroot
-                    .get_ensure_imgstore()?
-                    .pull_from_host_storage(&image)
-                    .await
+            ImageOpts::PullFromDefaultStorage {
+                image,
+                quiet,
+                progress_fd,
+            } => {
+                let prog = &ProgressWriter::from_opt_fd(progress_fd)?;
+                let storage = ImageStorage::new(LockMode::Exclusive).await?;
+                storage
+                    .get()?
+                    .pull_from_host_storage(&image, quiet, prog)
+                    .await?;
+                Ok(())
             }
+            ImageOpts::Inspect { image, format } => {
+                let storage = ImageStorage::new(LockMode::Shared).await?;
+                crate::image::inspect_entrypoint(storage.get()?, &image, format)
+            }
+            ImageOpts::Check { repair } => {
+                let storage = get_storage(LockMode::Exclusive).await?;
+                let r = storage.get_ensure_imgstore()?.check(repair).await;
+                let record = crate::spec::StorageCheck {
+                    timestamp: chrono::Utc::now(),
+                    success: r.is_ok(),
+                    repair,
+                };
+                let sysroot_dir = Dir::reopen_dir(&crate::utils::sysroot_fd(&storage))?;
+                crate::imgstorage::write_check_record(&sysroot_dir, &record)?;
+                r
+            }
+            ImageOpts::Sbom(opts) => crate::sbom::sbom(opts).await,
+            ImageOpts::GenerateDelta(opts) => crate::delta::generate(opts),
             ImageOpts::Cmd(opt) => {
-                let storage = get_storage().await?;
-                let imgstore = storage.get_ensure_imgstore()?;
+                let mode = match opt {
+                    ImageCmdOpts::List { .. } => LockMode::Shared,
+                    ImageCmdOpts::Build { .. }
+                    | ImageCmdOpts::Pull { .. }
+                    | ImageCmdOpts::Push { .. } => LockMode::Exclusive,
+                };
+                let storage = ImageStorage::new(mode).await?;
+                let imgstore = storage.get()?;
                 match opt {
                     ImageCmdOpts::List { args } => {
                         crate::image::imgcmd_entrypoint(imgstore, "list", &args).await
@@ -1003,11 +1666,12 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
         Opt::Install(opts) => match opts {
             InstallOpts::ToDisk(opts) => crate::install::install_to_disk(opts).await,
             InstallOpts::ToFilesystem(opts) => {
-                crate::install::install_to_filesystem(opts, false).await
+                crate::install::install_to_filesystem(opts, false, None).await
             }
             InstallOpts::ToExistingRoot(opts) => {
                 crate::install::install_to_existing_root(opts).await
             }
+            InstallOpts::Preflight(opts) => crate::install::preflight::preflight(opts),
             InstallOpts::PrintConfiguration => crate::install::print_configuration(),
             InstallOpts::EnsureCompletion {} => {
                 let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
@@ -1020,6 +1684,8 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
         }
         Opt::Status(opts) => super::status::status(opts).await,
         Opt::Internals(opts) => match opts {
+            InternalsOpts::Relabel(opts) => crate::relabel::relabel(opts).await,
+            InternalsOpts::Complete { words } => crate::completions::print_candidates(&words),
             InternalsOpts::SystemdGenerator {
                 normal_dir,
                 early_dir: _,
@@ -1040,6 +1706,9 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 .await
             }
             InternalsOpts::FixupEtcFstab => crate::deploy::fixup_etc_fstab(&root),
+            InternalsOpts::WriteStorageConfig => {
+                crate::imgstorage::write_additional_store_config(root)
+            }
             InternalsOpts::PrintJsonSchema => {
                 let schema = schema_for!(crate::spec::Host);
                 let mut stdout = std::io::stdout().lock();
@@ -1047,9 +1716,19 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 Ok(())
             }
             InternalsOpts::Cleanup => {
-                let sysroot = get_storage().await?;
+                let sysroot = get_storage(LockMode::Exclusive).await?;
                 crate::deploy::cleanup(&sysroot).await
             }
+            InternalsOpts::BootComplete => {
+                let sysroot = get_storage(LockMode::Exclusive).await?;
+                let rootfs = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+                let r = crate::bootcount::boot_complete(&sysroot, &rootfs).await;
+                // Re-announce an update found before the reboot, which is not yet
+                // staged; this is independent of the boot counting.
+                let (_, _, host) = crate::status::get_status_require_booted(&sysroot)?;
+                crate::notify::refresh(&rootfs, &host)?;
+                r
+            }
             #[cfg(feature = "install")]
             InternalsOpts::BootcInstallCompletion { sysroot, stateroot } => {
                 let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
@@ -1104,6 +1783,20 @@ fn test_callname() {
     }
 }
 
+#[test]
+fn test_parse_lock_timeout() {
+    assert_eq!(
+        parse_lock_timeout("30").unwrap(),
+        std::time::Duration::from_secs(30)
+    );
+    assert_eq!(
+        parse_lock_timeout(" 0\n").unwrap(),
+        std::time::Duration::ZERO
+    );
+    assert!(parse_lock_timeout("").is_err());
+    assert!(parse_lock_timeout("5s").is_err());
+}
+
 #[test]
 fn test_parse_install_args() {
     // Verify we still process the legacy --target-no-signature-verification
@@ -1128,6 +1821,33 @@ fn test_parse_install_args() {
     );
 }
 
+#[test]
+fn test_parse_install_to_existing_root() {
+    let o = Opt::try_parse_from([
+        "bootc",
+        "install",
+        "to-existing-root",
+        "--migrate-etc",
+        "--migrate-etc-path=/etc/chrony.conf",
+    ])
+    .unwrap();
+    let o = match o {
+        Opt::Install(InstallOpts::ToExistingRoot(opts)) => opts,
+        o => panic!("Expected existing root opts, not {o:?}"),
+    };
+    assert!(o.migrate_opts.migrate_etc);
+    assert_eq!(o.migrate_opts.migrate_etc_paths, ["/etc/chrony.conf"]);
+    assert_eq!(o.root_path.as_str(), "/target");
+    // Additional paths are only migrated along with the defaults
+    assert!(Opt::try_parse_from([
+        "bootc",
+        "install",
+        "to-existing-root",
+        "--migrate-etc-path=/etc/chrony.conf",
+    ])
+    .is_err());
+}
+
 #[test]
 fn test_parse_opts() {
     assert!(matches!(
@@ -1136,7 +1856,9 @@ fn test_parse_opts() {
             json: false,
             format: None,
             format_version: None,
-            booted: false
+            booted: false,
+            staged: false,
+            update_available: false,
         })
     ));
     assert!(matches!(
@@ -1146,6 +1868,348 @@ fn test_parse_opts() {
             ..
         })
     ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "status", "--format-version=2"]),
+        Opt::Status(StatusOpts {
+            format_version: Some(2),
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "status", "--update-available"]),
+        Opt::Status(StatusOpts {
+            update_available: true,
+            ..
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "status", "--booted", "--json"]),
+        Opt::Status(StatusOpts {
+            booted: true,
+            json: true,
+            ..
+        })
+    ));
+    // Only one query can be made at a time, and it has no output format
+    for args in [
+        ["bootc", "status", "--booted", "--staged"],
+        ["bootc", "status", "--staged", "--format=json"],
+        ["bootc", "status", "--update-available", "--json"],
+    ] {
+        assert!(Opt::try_parse_from(args).is_err(), "{args:?}");
+    }
+}
+
+#[test]
+fn test_parse_switch_in_place() {
+    let o =
+        Opt::try_parse_from(["bootc", "switch", "--in-place", "mirror.example.com/os:1"]).unwrap();
+    assert!(matches!(o, Opt::Switch(SwitchOpts { in_place: true, .. })));
+    assert!(Opt::try_parse_from([
+        "bootc",
+        "switch",
+        "--in-place",
+        "--retain",
+        "mirror.example.com/os:1"
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_parse_fetch() {
+    let o = Opt::try_parse_from(["bootc", "fetch", "--quiet"]).unwrap();
+    assert!(matches!(o, Opt::Fetch(FetchOpts { quiet: true, .. })));
+    let o = Opt::try_parse_from(["bootc", "upgrade", "--from-cache", "--apply"]).unwrap();
+    assert!(matches!(
+        o,
+        Opt::Upgrade(UpgradeOpts {
+            from_cache: true,
+            apply: true,
+            ..
+        })
+    ));
+    assert!(Opt::try_parse_from(["bootc", "upgrade", "--from-cache", "--check"]).is_err());
+}
+
+#[test]
+fn test_parse_soft_reboot() {
+    let o = Opt::try_parse_from(["bootc", "upgrade", "--apply", "--soft-reboot"]).unwrap();
+    assert!(matches!(
+        o,
+        Opt::Upgrade(UpgradeOpts {
+            apply: true,
+            soft_reboot: true,
+            ..
+        })
+    ));
+    let o = Opt::try_parse_from([
+        "bootc",
+        "switch",
+        "--apply",
+        "--soft-reboot",
+        "quay.io/example/os",
+    ])
+    .unwrap();
+    assert!(matches!(
+        o,
+        Opt::Switch(SwitchOpts {
+            soft_reboot: true,
+            ..
+        })
+    ));
+    assert!(Opt::try_parse_from(["bootc", "upgrade", "--soft-reboot"]).is_err());
+}
+
+#[test]
+fn test_parse_reset() {
+    let o = Opt::try_parse_from([
+        "bootc",
+        "reset",
+        "--preserve-network",
+        "--preserve",
+        "/etc/hostname",
+        "--preserve",
+        "/var/lib/myapp",
+    ])
+    .unwrap();
+    let Opt::Reset(opts) = o else {
+        panic!("Expected reset, found {o:?}");
+    };
+    assert!(opts.preserve_network);
+    assert!(!opts.preserve_machine_id);
+    assert_eq!(opts.preserve, ["/etc/hostname", "/var/lib/myapp"]);
+}
+
+#[test]
+#[cfg(feature = "install")]
+fn test_parse_install_preflight() {
+    let o = Opt::try_parse_from(["bootc", "install", "preflight"]).unwrap();
+    let Opt::Install(InstallOpts::Preflight(opts)) = o else {
+        panic!("Expected install preflight, found {o:?}");
+    };
+    assert_eq!(opts.device, None);
+    assert_eq!(opts.format, OutputFormat::HumanReadable);
+    let o = Opt::try_parse_from([
+        "bootc",
+        "install",
+        "preflight",
+        "--filesystem",
+        "xfs",
+        "--format",
+        "json",
+        "/dev/vda",
+    ])
+    .unwrap();
+    let Opt::Install(InstallOpts::Preflight(opts)) = o else {
+        panic!("Expected install preflight, found {o:?}");
+    };
+    assert_eq!(
+        opts.device.as_deref(),
+        Some(camino::Utf8Path::new("/dev/vda"))
+    );
+    assert_eq!(opts.format, OutputFormat::Json);
+}
+
+#[test]
+fn test_parse_container_lint() {
+    let o = Opt::try_parse_from(["bootc", "container", "lint"]).unwrap();
+    assert_eq!(
+        o,
+        Opt::Container(ContainerOpts::Lint {
+            format: OutputFormat::HumanReadable,
+            fatal_warnings: false,
+        })
+    );
+    let o = Opt::try_parse_from([
+        "bootc",
+        "container",
+        "lint",
+        "--format",
+        "json",
+        "--fatal-warnings",
+    ])
+    .unwrap();
+    assert_eq!(
+        o,
+        Opt::Container(ContainerOpts::Lint {
+            format: OutputFormat::Json,
+            fatal_warnings: true,
+        })
+    );
+}
+
+#[test]
+fn test_parse_image_generate_delta() {
+    let o = Opt::try_parse_from([
+        "bootc",
+        "image",
+        "generate-delta",
+        "--repo",
+        "/srv/repo",
+        "--from",
+        "quay.io/example/os:1",
+        "--to",
+        "quay.io/example/os:2",
+        "/srv/delta",
+    ])
+    .unwrap();
+    let Opt::Image(ImageOpts::GenerateDelta(opts)) = o else {
+        panic!("Expected image generate-delta, found {o:?}");
+    };
+    assert_eq!(opts.transport, "registry");
+    assert_eq!(opts.to, "quay.io/example/os:2");
+    assert_eq!(opts.output, "/srv/delta");
+}
+
+#[test]
+fn test_parse_image_sbom() {
+    let o = Opt::try_parse_from(["bootc", "image", "sbom", "--format", "spdx"]).unwrap();
+    let Opt::Image(ImageOpts::Sbom(opts)) = o else {
+        panic!("Expected image sbom, found {o:?}");
+    };
+    assert_eq!(opts.format, Some(crate::sbom::SbomFormat::Spdx));
+    assert!(!opts.no_registry);
+    assert!(
+        Opt::try_parse_from(["bootc", "image", "sbom", "--list", "--output-dir", "/tmp"]).is_err()
+    );
+}
+
+#[test]
+#[cfg(feature = "install")]
+fn test_parse_encrypt_var() {
+    let o =
+        Opt::try_parse_from(["bootc", "encrypt-var", "--tpm2-pcrs", "7+14", "/dev/vdb"]).unwrap();
+    let Opt::EncryptVar(opts) = o else {
+        panic!("Expected encrypt-var, found {o:?}");
+    };
+    assert_eq!(opts.device, "/dev/vdb");
+    assert_eq!(opts.tpm2_pcrs, [7, 14]);
+    assert!(!opts.wipe);
+}
+
+#[test]
+fn test_parse_clean() {
+    let o = Opt::try_parse_from(["bootc", "clean", "--keep-previous", "1", "--dry-run"]).unwrap();
+    let Opt::Clean(opts) = o else {
+        panic!("Expected clean, found {o:?}");
+    };
+    assert_eq!(opts.keep_previous, Some(1));
+    assert!(opts.dry_run);
+    assert!(!opts.prune_bound_images);
+    assert!(Opt::try_parse_from(["bootc", "clean", "--keep-previous", "-1"]).is_err());
+}
+
+#[test]
+fn test_parse_relabel() {
+    let o = Opt::try_parse_from(["bootc", "internals", "relabel"]).unwrap();
+    let Opt::Internals(InternalsOpts::Relabel(opts)) = o else {
+        panic!("Expected relabel, found {o:?}");
+    };
+    assert_eq!(opts.deployment, None);
+    assert!(!opts.dry_run);
+    let o = Opt::try_parse_from([
+        "bootc",
+        "internals",
+        "relabel",
+        "--deployment",
+        "abcd.0",
+        "--dry-run",
+    ])
+    .unwrap();
+    let Opt::Internals(InternalsOpts::Relabel(opts)) = o else {
+        panic!("Expected relabel, found {o:?}");
+    };
+    assert_eq!(opts.deployment.as_deref(), Some("abcd.0"));
+    assert!(opts.dry_run);
+}
+
+#[test]
+fn test_parse_completions() {
+    let o = Opt::try_parse_from(["bootc", "completions", "zsh"]).unwrap();
+    assert_eq!(
+        o,
+        Opt::Completions {
+            shell: crate::completions::Shell::Zsh
+        }
+    );
+    assert!(Opt::try_parse_from(["bootc", "completions", "tcsh"]).is_err());
+    // The words being completed are passed through as-is
+    let o = Opt::try_parse_from([
+        "bootc",
+        "internals",
+        "complete",
+        "--",
+        "bootc",
+        "switch",
+        "--q",
+    ])
+    .unwrap();
+    let Opt::Internals(InternalsOpts::Complete { words }) = o else {
+        panic!("Expected complete, found {o:?}");
+    };
+    assert_eq!(words, ["bootc", "switch", "--q"]);
+}
+
+#[test]
+fn test_parse_metrics() {
+    let o = Opt::try_parse_from(["bootc", "metrics", "--listen", "127.0.0.1:9470"]).unwrap();
+    let Opt::Metrics(opts) = o else {
+        panic!("Expected metrics, found {o:?}");
+    };
+    assert_eq!(opts.listen, Some("127.0.0.1:9470".parse().unwrap()));
+    assert!(Opt::try_parse_from([
+        "bootc",
+        "metrics",
+        "--listen",
+        "127.0.0.1:9470",
+        "--textfile",
+        "/run/bootc.prom"
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_parse_edit_patch() {
+    let o = Opt::try_parse_from(["bootc", "edit", "--patch", r#"{"spec":{}}"#]).unwrap();
+    assert!(matches!(o, Opt::Edit(EditOpts { patch: Some(_), .. })));
+    assert!(
+        Opt::try_parse_from(["bootc", "edit", "--patch", "{}", "--filename", "host.yaml"]).is_err()
+    );
+}
+
+#[test]
+fn test_parse_kargs() {
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "kargs", "get"]),
+        Opt::Kargs(KargsOpts::Get { apply_to: None })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "kargs", "append", "--apply-to=all", "nosmt"]),
+        Opt::Kargs(KargsOpts::Append(KargsEditOpts {
+            apply_to: Some(KargsTarget::All),
+            force: false,
+            kargs,
+        })) if kargs == ["nosmt"]
+    ));
+    assert!(Opt::try_parse_from(["bootc", "kargs", "delete"]).is_err());
+}
+
+#[test]
+fn test_parse_etc() {
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "etc", "diff", "--format=json"]),
+        Opt::Etc(EtcOpts::Diff {
+            format: Some(OutputFormat::Json)
+        })
+    ));
+    assert!(matches!(
+        Opt::parse_including_static(["bootc", "etc", "reset", "--dry-run", "/etc/motd"]),
+        Opt::Etc(EtcOpts::Reset {
+            paths,
+            dry_run: true,
+        }) if paths == ["/etc/motd"]
+    ));
+    assert!(Opt::try_parse_from(["bootc", "etc", "reset"]).is_err());
 }
 
 #[test]
@@ -0,0 +1,333 @@
+//! # Shell completion
+//!
+//! `bootc completions <shell>` outputs a completion script for the shell.
+//! The scripts are thin wrappers which invoke `bootc internals complete`
+//! with the words of the command line; the candidates are computed from
+//! the CLI definition, so they never get out of sync with it.  As the
+//! scripts themselves are static, they are also installed directly from
+//! the source tree.  Image references are completed from the images known
+//! locally, i.e. those of the deployments and the other host images in the
+//! ostree repository (see `bootc image list --type host`).
+
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use anyhow::Result;
+use clap::{Arg, Command, CommandFactory, ValueEnum};
+use ostree_ext::container as ostree_container;
+use ostree_ext::gio;
+use ostree_ext::ostree;
+
+/// Hidden subcommands (of `bootc` itself) which are completed nevertheless.
+const COMPLETE_HIDDEN: &[&str] = &["image"];
+/// The arguments which take an image reference, by subcommand path and argument id.
+const IMAGE_ARGS: &[(&[&str], &str)] = &[
+    (&["switch"], "target"),
+    (&["image", "copy-to-storage"], "source"),
+    (&["image", "pull-from-default-storage"], "image"),
+    (&["image", "inspect"], "image"),
+];
+
+const BASH_SCRIPT: &str = include_str!("completions/bootc.bash");
+const ZSH_SCRIPT: &str = include_str!("completions/_bootc");
+const FISH_SCRIPT: &str = include_str!("completions/bootc.fish");
+
+/// The shells for which a completion script can be generated.
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+pub(crate) enum Shell {
+    /// Bash; install the script as `/usr/share/bash-completion/completions/bootc`.
+    Bash,
+    /// Zsh; install the script as `_bootc` in a directory of `$fpath`.
+    Zsh,
+    /// Fish; install the script as `/usr/share/fish/vendor_completions.d/bootc.fish`.
+    Fish,
+}
+
+impl Shell {
+    fn script(self) -> &'static str {
+        match self {
+            Self::Bash => BASH_SCRIPT,
+            Self::Zsh => ZSH_SCRIPT,
+            Self::Fish => FISH_SCRIPT,
+        }
+    }
+}
+
+/// Whether the argument takes a value.
+fn takes_value(arg: &&Arg) -> bool {
+    arg.get_action().takes_values()
+}
+
+/// Find an option by its long name (or alias).
+fn find_long<'a>(cmd: &'a Command, name: &str) -> Option<&'a Arg> {
+    cmd.get_arguments().find(|arg| {
+        arg.get_long() == Some(name)
+            || arg
+                .get_all_aliases()
+                .is_some_and(|aliases| aliases.contains(&name))
+    })
+}
+
+/// Find an option by its short name.
+fn find_short(cmd: &Command, c: char) -> Option<&Arg> {
+    cmd.get_arguments().find(|arg| arg.get_short() == Some(c))
+}
+
+/// The positional argument receiving the value at the provided index.
+fn positional_at(cmd: &Command, index: usize) -> Option<&Arg> {
+    let positionals = cmd.get_positionals().collect::<Vec<_>>();
+    positionals.get(index).copied().or_else(|| {
+        positionals
+            .last()
+            .copied()
+            .filter(|arg| arg.get_num_args().is_some_and(|n| n.max_values() > 1))
+    })
+}
+
+/// The visible subcommands.
+fn subcommands(path: &[&str], cmd: &Command) -> Vec<String> {
+    cmd.get_subcommands()
+        .filter(|sub| {
+            !sub.is_hide_set() || (path.is_empty() && COMPLETE_HIDDEN.contains(&sub.get_name()))
+        })
+        .map(|sub| sub.get_name().to_owned())
+        .collect()
+}
+
+/// The visible options.
+fn flags(cmd: &Command) -> Vec<String> {
+    cmd.get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .filter_map(|arg| arg.get_long())
+        .map(|long| format!("--{long}"))
+        .collect()
+}
+
+/// The candidate values of an argument.
+fn values(path: &[&str], arg: &Arg, images: impl FnOnce() -> Vec<String>) -> Vec<String> {
+    let id = arg.get_id().as_str();
+    if IMAGE_ARGS.iter().any(|(p, a)| *p == path && *a == id) {
+        return images();
+    }
+    arg.get_possible_values()
+        .into_iter()
+        .filter(|v| !v.is_hide_set())
+        .map(|v| v.get_name().to_owned())
+        .collect()
+}
+
+/// Compute the candidates for the last of the provided words; the first word
+/// is the program name.  Image references are provided by `images`, which is
+/// only invoked when one is expected.
+fn complete(root: &Command, words: &[String], images: impl FnOnce() -> Vec<String>) -> Vec<String> {
+    let Some((current, words)) = words.split_last() else {
+        return Vec::new();
+    };
+    let Some(words) = words.get(1..) else {
+        return Vec::new();
+    };
+
+    let mut path = Vec::new();
+    let mut cmd = root;
+    // An option whose value is the next word
+    let mut pending = None;
+    let mut positionals = 0;
+    let mut only_positionals = false;
+    for word in words {
+        if pending.take().is_some() {
+            continue;
+        }
+        if only_positionals || !word.starts_with('-') || word == "-" {
+            if let Some(sub) = cmd
+                .find_subcommand(word)
+                .filter(|_| !only_positionals && positionals == 0)
+            {
+                path.push(sub.get_name());
+                cmd = sub;
+            } else {
+                positionals += 1;
+            }
+        } else if word == "--" {
+            only_positionals = true;
+        } else if let Some(long) = word.strip_prefix("--") {
+            if !long.contains('=') {
+                pending = find_long(cmd, long).filter(takes_value);
+            }
+        } else {
+            // A group of short options, of which only the last may take the next word
+            let mut shorts = word[1..].chars();
+            while let Some(c) = shorts.next() {
+                if let Some(arg) = find_short(cmd, c).filter(takes_value) {
+                    if shorts.as_str().is_empty() {
+                        pending = Some(arg);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut r = if let Some(arg) = pending {
+        values(&path, arg, images)
+    } else if let Some((name, _)) = current
+        .strip_prefix("--")
+        .and_then(|opt| opt.split_once('='))
+        .filter(|_| !only_positionals)
+    {
+        find_long(cmd, name)
+            .map(|arg| values(&path, arg, images))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|v| format!("--{name}={v}"))
+            .collect()
+    } else if current.starts_with('-') && !only_positionals {
+        flags(cmd)
+    } else {
+        let mut r = if positionals == 0 && !only_positionals {
+            subcommands(&path, cmd)
+        } else {
+            Vec::new()
+        };
+        if let Some(arg) = positional_at(cmd, positionals) {
+            r.extend(values(&path, arg, images));
+        } else if r.is_empty() && !only_positionals {
+            r = flags(cmd);
+        }
+        r
+    };
+    r.retain(|c| c.starts_with(current.as_str()));
+    r
+}
+
+/// The image references known locally: those of the deployments, and the other
+/// host images in the ostree repository.  Errors are ignored, as completion is best-effort and is
+/// usually invoked without privileges.
+fn known_images() -> Vec<String> {
+    let sysroot = ostree::Sysroot::new_default();
+    if let Err(e) = sysroot.load(gio::Cancellable::NONE) {
+        tracing::debug!("Loading sysroot: {e}");
+        return Vec::new();
+    }
+    let mut r = BTreeSet::new();
+    for deployment in sysroot.deployments() {
+        let imgref = deployment
+            .origin()
+            .and_then(|origin| crate::status::get_image_origin(&origin).ok().flatten());
+        if let Some(imgref) = imgref {
+            r.insert(imgref.imgref.name);
+        }
+    }
+    match ostree_container::store::list_images(&sysroot.repo()) {
+        Ok(images) => r.extend(
+            images
+                .iter()
+                .filter_map(|img| ostree_container::ImageReference::try_from(img.as_str()).ok())
+                .map(|img| img.name),
+        ),
+        Err(e) => tracing::debug!("Listing images: {e}"),
+    }
+    r.into_iter().collect()
+}
+
+/// Implementation of `bootc completions`.
+pub(crate) fn print_script(shell: Shell) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(shell.script().as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Implementation of `bootc internals complete`.
+pub(crate) fn print_candidates(words: &[String]) -> Result<()> {
+    let mut root = crate::cli::Opt::command();
+    root.build();
+    let mut stdout = std::io::stdout().lock();
+    for candidate in complete(&root, words, known_images) {
+        writeln!(stdout, "{candidate}")?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(words: &[&str]) -> Vec<String> {
+        let mut root = crate::cli::Opt::command();
+        root.build();
+        let words = words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        complete(&root, &words, || {
+            vec![
+                "quay.io/example/os:latest".into(),
+                "quay.io/other/os:42".into(),
+            ]
+        })
+    }
+
+    fn run_no_images(words: &[&str]) -> Vec<String> {
+        let mut root = crate::cli::Opt::command();
+        root.build();
+        let words = words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        complete(&root, &words, || panic!("Unexpected image completion"))
+    }
+
+    #[test]
+    fn test_complete_subcommands() {
+        assert!(run_no_images(&["bootc"]).is_empty());
+        let r = run_no_images(&["bootc", ""]);
+        for expected in ["upgrade", "switch", "status", "completions", "image"] {
+            assert!(r.iter().any(|c| c == expected), "{expected}");
+        }
+        assert!(!r.iter().any(|c| c == "internals"));
+        assert_eq!(run_no_images(&["bootc", "swi"]), ["switch"]);
+        assert_eq!(run_no_images(&["bootc", "image", "ins"]), ["inspect"]);
+        // Aliases are followed
+        assert_eq!(run_no_images(&["bootc", "usroverlay", "--h"]), ["--help"]);
+    }
+
+    #[test]
+    fn test_complete_options() {
+        assert_eq!(run_no_images(&["bootc", "upgrade", "--che"]), ["--check"]);
+        // Hidden options are not completed
+        assert!(!run_no_images(&["bootc", "switch", "--"])
+            .iter()
+            .any(|c| c == "--mutate-in-place"));
+        assert_eq!(
+            run_no_images(&["bootc", "status", "--format", ""]),
+            ["humanreadable", "yaml", "json"]
+        );
+        assert_eq!(
+            run_no_images(&["bootc", "status", "--format=j"]),
+            ["--format=json"]
+        );
+        assert_eq!(run_no_images(&["bootc", "completions", "z"]), ["zsh"]);
+    }
+
+    #[test]
+    fn test_complete_images() {
+        assert_eq!(
+            run(&["bootc", "switch", "quay.io/ex"]),
+            ["quay.io/example/os:latest"]
+        );
+        // Options (and their values) are skipped
+        assert_eq!(
+            run(&["bootc", "switch", "--transport", "registry", "--quiet", ""]).len(),
+            2
+        );
+        assert_eq!(run(&["bootc", "image", "inspect", "quay.io/o"]).len(), 1);
+        assert_eq!(
+            run(&["bootc", "image", "copy-to-storage", "--source", "q"]).len(),
+            2
+        );
+        // Only the first positional argument is an image
+        assert!(run_no_images(&["bootc", "switch", "quay.io/example/os:latest", "q"]).is_empty());
+    }
+
+    #[test]
+    fn test_scripts() {
+        for shell in Shell::value_variants() {
+            assert!(shell.script().contains("bootc internals complete --"));
+        }
+    }
+}
@@ -0,0 +1,16 @@
+# bash completion for bootc
+_bootc() {
+    local line="${COMP_LINE:0:COMP_POINT}"
+    local -a args
+    read -ra args <<< "$line"
+    if [[ "$line" =~ [[:space:]]$ ]]; then
+        args+=("")
+    fi
+    local cur="${args[-1]}"
+    local IFS=$'\n'
+    COMPREPLY=($(bootc internals complete -- "${args[@]}" 2>/dev/null))
+    # Bash splits words at these characters, and only replaces what follows them
+    local prefix="${cur%"${cur##*[=:]}"}"
+    COMPREPLY=("${COMPREPLY[@]#"$prefix"}")
+}
+complete -o default -F _bootc bootc
@@ -0,0 +1,10 @@
+# fish completion for bootc
+function __bootc_complete
+    set -l candidates (bootc internals complete -- (commandline -opc) (commandline -ct) 2>/dev/null)
+    if test (count $candidates) -eq 0
+        __fish_complete_path (commandline -ct)
+    else
+        printf '%s\n' $candidates
+    end
+end
+complete -c bootc -f -a '(__bootc_complete)'
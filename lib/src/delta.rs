@@ -0,0 +1,540 @@
+//! # Delta updates
+//!
+//! Upgrades between two known images can use a pre-generated delta artifact,
+//! published in the same repository as the image, instead of fetching each
+//! changed layer in full.  The artifact is tagged `delta-<from>-<to>` (using
+//! the first 32 hex characters of the manifest digests of the source and
+//! target images) and holds an [ostree static delta] per changed layer: from
+//! the ostree commit caching a layer of the source image, to the commit
+//! caching the corresponding layer of the target image.
+//!
+//! Deltas are best-effort: any layer for which no usable delta is found is
+//! fetched in full as usual.  Artifacts are generated by
+//! `bootc image generate-delta`.
+//!
+//! The content a delta produces cannot be checked against the layer digests
+//! of the (signed) image manifest, so the artifact itself must be trusted as
+//! much as the image: it is opened subject to the same `containers-policy.json`
+//! requirements for the repository, and hence must be signed in the same way.
+//! Images whose signatures are verified via an ostree remote instead never use
+//! deltas, as there is no equivalent verification of the artifact.
+//!
+//! [ostree static delta]: https://ostreedev.github.io/ostree/formats/#static-deltas
+
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8PathBuf;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::container::store::{ManifestLayerState, PreparedImport};
+use ostree_ext::containers_image_proxy::{ImageProxy, OpenedImage};
+use ostree_ext::oci_spec::image::{Descriptor, ImageManifest};
+use ostree_ext::ostree;
+use ostree_ext::{gio, glib};
+use serde_json::json;
+
+use crate::spec::ImageReference;
+
+/// The artifact type of a delta artifact.
+const ARTIFACT_TYPE: &str = "application/vnd.containers.bootc.delta.v1";
+/// The media type of a layer holding an ostree static delta.
+const DELTA_MEDIA_TYPE: &str = "application/vnd.containers.bootc.delta.ostree-static-delta.v1";
+/// The media type of the empty configuration of an artifact.
+const EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+/// The digest of the empty configuration (`{}`).
+const EMPTY_DIGEST: &str =
+    "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+/// Manifest annotation holding the manifest digest of the source image.
+const ANNOTATION_FROM: &str = "containers.bootc.delta.from";
+/// Manifest annotation holding the manifest digest of the target image.
+const ANNOTATION_TO: &str = "containers.bootc.delta.to";
+/// Layer annotation holding the digest of the target layer produced by the delta.
+const ANNOTATION_LAYER: &str = "containers.bootc.delta.layer";
+/// Layer annotation holding the ostree commit the delta applies to.
+const ANNOTATION_FROM_COMMIT: &str = "containers.bootc.delta.from-commit";
+/// Layer annotation holding the ostree commit produced by the delta.
+const ANNOTATION_TO_COMMIT: &str = "containers.bootc.delta.to-commit";
+/// The annotation naming a manifest in an OCI layout.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+/// The number of hex characters of each digest used in the tag.
+const TAG_DIGEST_LEN: usize = 32;
+
+/// Options for `bootc image generate-delta`.
+#[derive(Debug, clap::Parser, PartialEq, Eq)]
+pub(crate) struct GenerateDeltaOpts {
+    /// Path to an ostree repository holding both images, e.g. as pulled via
+    /// `bootc internals ostree-container image pull`.
+    #[clap(long)]
+    pub(crate) repo: Utf8PathBuf,
+
+    /// The transport of both images.
+    #[clap(long, default_value = "registry")]
+    pub(crate) transport: String,
+
+    /// The source image, i.e. the image the systems to update are running.
+    #[clap(long)]
+    pub(crate) from: String,
+
+    /// The target image.
+    #[clap(long)]
+    pub(crate) to: String,
+
+    /// Write the artifact to this OCI layout directory, which must not exist.
+    pub(crate) output: Utf8PathBuf,
+}
+
+/// The tag of the delta artifact between two images.
+fn delta_tag(from: &str, to: &str) -> Result<String> {
+    let abbrev = |digest: &str| -> Result<String> {
+        match digest.split_once(':') {
+            Some(("sha256", hex)) if hex.as_bytes().len() >= TAG_DIGEST_LEN => {
+                Ok(hex[..TAG_DIGEST_LEN].to_owned())
+            }
+            _ => Err(anyhow!("Invalid sha256 digest: {digest}")),
+        }
+    };
+    Ok(format!("delta-{}-{}", abbrev(from)?, abbrev(to)?))
+}
+
+/// The delta for a single layer.
+#[derive(Debug, PartialEq, Eq)]
+struct LayerDelta {
+    /// The blob holding the static delta.
+    blob: Descriptor,
+    /// The digest of the layer produced.
+    layer: String,
+    /// The ostree commit the delta applies to.
+    from_commit: String,
+    /// The ostree commit produced.
+    to_commit: String,
+}
+
+/// Parse the layer deltas of an artifact, verifying it is a delta between the
+/// provided images.
+fn parse_deltas(manifest: &ImageManifest, from: &str, to: &str) -> Result<Vec<LayerDelta>> {
+    let annotations = manifest.annotations().clone().unwrap_or_default();
+    let annotation = |k: &str| annotations.get(k).map(|v| v.as_str());
+    if annotation(ANNOTATION_FROM) != Some(from) || annotation(ANNOTATION_TO) != Some(to) {
+        anyhow::bail!("Artifact is not a delta from {from} to {to}");
+    }
+    let mut r = Vec::new();
+    for blob in manifest.layers() {
+        if blob.media_type().to_string() != DELTA_MEDIA_TYPE {
+            tracing::debug!("Skipping {} of type {}", blob.digest(), blob.media_type());
+            continue;
+        }
+        let annotations = blob.annotations().clone().unwrap_or_default();
+        let annotation = |k: &str| {
+            annotations
+                .get(k)
+                .cloned()
+                .ok_or_else(|| anyhow!("Missing annotation {k} on {}", blob.digest()))
+        };
+        r.push(LayerDelta {
+            layer: annotation(ANNOTATION_LAYER)?,
+            from_commit: annotation(ANNOTATION_FROM_COMMIT)?,
+            to_commit: annotation(ANNOTATION_TO_COMMIT)?,
+            blob: blob.clone(),
+        });
+    }
+    Ok(r)
+}
+
+/// All layers of an import, mutably.
+fn layers_mut(prep: &mut PreparedImport) -> impl Iterator<Item = &mut ManifestLayerState> {
+    prep.ostree_commit_layer
+        .iter_mut()
+        .chain(prep.ostree_layers.iter_mut())
+        .chain(prep.layers.iter_mut())
+}
+
+/// Fetch a blob of the artifact to a file.
+async fn fetch_blob(
+    proxy: &ImageProxy,
+    img: &OpenedImage,
+    blob: &Descriptor,
+    path: &Path,
+) -> Result<()> {
+    let (reader, driver) = proxy.get_blob(img, blob.digest(), blob.size()).await?;
+    let mut f = std::fs::File::create(path).with_context(|| format!("Creating {path:?}"))?;
+    let copy = tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut reader = tokio_util::io::SyncIoBridge::new(reader);
+        std::io::copy(&mut reader, &mut f)?;
+        f.sync_all()?;
+        Ok(())
+    });
+    let (copy, driver) = tokio::join!(copy, driver);
+    driver?;
+    copy?
+}
+
+/// Apply a static delta, and write the ref of the layer it produces.
+fn execute(repo: &ostree::Repo, path: &Path, delta: &LayerDelta, layer_ref: &str) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let txn = repo.auto_transaction(cancellable)?;
+    let repo = txn.repo();
+    repo.static_delta_execute_offline(&gio::File::for_path(path), false, cancellable)
+        .context("Executing delta")?;
+    // Verify that the delta produced the expected commit
+    repo.load_commit(&delta.to_commit)
+        .with_context(|| format!("Loading {}", delta.to_commit))?;
+    repo.transaction_set_ref(None, layer_ref, Some(&delta.to_commit));
+    txn.commit(cancellable)?;
+    Ok(())
+}
+
+async fn apply_impl(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    prep: &mut PreparedImport,
+    quiet: bool,
+) -> Result<()> {
+    if imgref.transport != "registry" {
+        return Ok(());
+    }
+    let sigverify = ostree_container::OstreeImageReference::from(imgref.clone()).sigverify;
+    if let ostree_container::SignatureSource::OstreeRemote(remote) = sigverify {
+        tracing::debug!("Not using deltas for an image verified via ostree remote {remote}");
+        return Ok(());
+    }
+    let Some(from) = prep
+        .previous_manifest_digest
+        .as_ref()
+        .map(|d| d.to_string())
+    else {
+        return Ok(());
+    };
+    if prep.all_layers().all(|l| l.commit.is_some()) {
+        return Ok(());
+    }
+    let to = prep.manifest_digest.to_string();
+    let tag = delta_tag(&from, &to)?;
+    let artifact = format!(
+        "docker://{}:{tag}",
+        crate::sbom::repository_of(&imgref.image)
+    );
+
+    // Unlike for images verified via an ostree remote, signature verification
+    // is never skipped here: the artifact must satisfy the policy for the
+    // repository, which is the same as for the image.
+    let mut config = Default::default();
+    ostree_container::merge_default_container_proxy_opts(&mut config)?;
+    let proxy = ImageProxy::new_with_config(config).await?;
+    let img = match proxy.open_image(&artifact).await {
+        Ok(img) => img,
+        Err(e) => {
+            // Most commonly, there simply is no delta
+            tracing::debug!("No delta at {artifact}: {e}");
+            return Ok(());
+        }
+    };
+    let (_, manifest) = proxy.fetch_manifest(&img).await?;
+    let deltas = parse_deltas(&manifest, &from, &to)?;
+
+    let tmpdir = tempfile::tempdir_in("/var/tmp")?;
+    let mut n_applied = 0;
+    let mut fetched = 0u64;
+    for delta in deltas {
+        let Some(layer) = layers_mut(prep)
+            .find(|l| l.commit.is_none() && l.layer().digest().to_string() == delta.layer)
+        else {
+            tracing::debug!("Layer {} is not needed", delta.layer);
+            continue;
+        };
+        if !repo.has_object(
+            ostree::ObjectType::Commit,
+            &delta.from_commit,
+            gio::Cancellable::NONE,
+        )? {
+            tracing::debug!("Missing source commit {}", delta.from_commit);
+            continue;
+        }
+        let path = tmpdir.path().join(delta.blob.digest().digest());
+        let r = match fetch_blob(&proxy, &img, &delta.blob, &path).await {
+            Ok(()) => execute(repo, &path, &delta, &layer.ostree_ref),
+            Err(e) => Err(e),
+        };
+        let _ = std::fs::remove_file(&path);
+        match r {
+            Ok(()) => {
+                tracing::debug!("Applied delta for {}", delta.layer);
+                layer.commit = Some(delta.to_commit);
+                n_applied += 1;
+                fetched += delta.blob.size();
+            }
+            Err(e) => tracing::warn!("Fetching layer {} in full: {e:#}", delta.layer),
+        }
+    }
+    proxy.close_image(&img).await?;
+    if !quiet && n_applied > 0 {
+        println!(
+            "Fetched deltas for {n_applied} layers ({})",
+            indicatif::HumanBytes(fetched)
+        );
+    }
+    Ok(())
+}
+
+/// Fetch and apply deltas from the previously stored version of the image,
+/// if published, marking the layers they produce as present in `prep`.
+/// Failures are only logged, as the layers are then fetched in full.
+pub(crate) async fn apply(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    prep: &mut PreparedImport,
+    quiet: bool,
+) {
+    if let Err(e) = apply_impl(repo, imgref, prep, quiet).await {
+        tracing::warn!("Not using delta: {e:#}");
+    }
+}
+
+/// Rename a file in the blobs directory of an OCI layout to its digest,
+/// returning its digest and size.
+fn commit_blob(blobs: &Dir, name: &str) -> Result<(String, u64)> {
+    let mut f = blobs
+        .open(name)
+        .with_context(|| format!("Opening {name}"))?;
+    let mut hasher = openssl::sha::Sha256::new();
+    let mut buf = vec![0u8; 128 * 1024];
+    let mut size = 0u64;
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    let hex = hex::encode(hasher.finish());
+    blobs.rename(name, blobs, &hex)?;
+    Ok((format!("sha256:{hex}"), size))
+}
+
+/// Write a blob to an OCI layout directory, returning its digest and size.
+fn write_blob(blobs: &Dir, contents: &[u8]) -> Result<(String, u64)> {
+    let hex = hex::encode(openssl::sha::sha256(contents));
+    blobs.write(&hex, contents)?;
+    Ok((format!("sha256:{hex}"), contents.len() as u64))
+}
+
+/// Generate a static delta between two commits into a file.
+fn generate_static_delta(repo: &ostree::Repo, from: &str, to: &str, path: &Path) -> Result<()> {
+    let params = glib::VariantDict::new(None);
+    // The file name is a NUL terminated bytestring
+    let mut filename = path.as_os_str().as_bytes().to_vec();
+    filename.push(0);
+    params.insert_value("filename", &glib::ToVariant::to_variant(&filename));
+    params.insert_value("inline-parts", &glib::ToVariant::to_variant(&true));
+    repo.static_delta_generate(
+        ostree::StaticDeltaGenerateOpt::Major,
+        Some(from),
+        to,
+        None,
+        Some(&params.end()),
+        gio::Cancellable::NONE,
+    )
+    .with_context(|| format!("Generating delta from {from} to {to}"))?;
+    Ok(())
+}
+
+/// Resolve the commit caching a layer.
+fn layer_commit(repo: &ostree::Repo, digest: &str) -> Result<String> {
+    let layer_ref = ostree_container::store::ref_for_blob_digest(digest)?;
+    let commit = repo
+        .resolve_rev(&layer_ref, false)?
+        .ok_or_else(|| anyhow!("Missing layer {digest}"))?;
+    Ok(commit.to_string())
+}
+
+/// Implementation of `bootc image generate-delta`.
+#[context("Generating delta")]
+pub(crate) fn generate(opts: GenerateDeltaOpts) -> Result<()> {
+    let repo = &ostree::Repo::new_for_path(opts.repo.as_std_path());
+    repo.open(gio::Cancellable::NONE)
+        .with_context(|| format!("Opening {}", opts.repo))?;
+    let transport = ostree_container::Transport::try_from(opts.transport.as_str())?;
+    let query = |name: &str| {
+        let imgref = ostree_container::ImageReference {
+            transport,
+            name: name.to_owned(),
+        };
+        ostree_container::store::query_image(repo, &imgref)?
+            .ok_or_else(|| anyhow!("Image {imgref} not found in {}", opts.repo))
+    };
+    let from = query(&opts.from)?;
+    let to = query(&opts.to)?;
+    let from_digest = from.manifest_digest.to_string();
+    let to_digest = to.manifest_digest.to_string();
+    let tag = delta_tag(&from_digest, &to_digest)?;
+
+    std::fs::create_dir(&opts.output).with_context(|| format!("Creating {}", opts.output))?;
+    let output = Dir::open_ambient_dir(&opts.output, cap_std::ambient_authority())?;
+    output.write("oci-layout", r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+    output.create_dir_all("blobs/sha256")?;
+    let blobs = &output.open_dir("blobs/sha256")?;
+    let blobs_path = opts.output.join("blobs/sha256");
+    write_blob(blobs, b"{}")?;
+
+    // Layers are paired by position, which matches the chunking of ostree
+    // images; a delta from any commit is valid, just larger.
+    let from_layers = from.manifest.layers();
+    let mut layers = Vec::new();
+    for (i, layer) in to.manifest.layers().iter().enumerate() {
+        let digest = layer.digest().to_string();
+        if from_layers.iter().any(|l| l.digest() == layer.digest()) {
+            continue;
+        }
+        let Some(from_layer) = from_layers.get(i) else {
+            println!("No source layer for {digest}; it will be fetched in full");
+            continue;
+        };
+        let from_commit = layer_commit(repo, &from_layer.digest().to_string())?;
+        let to_commit = layer_commit(repo, &digest)?;
+        let tmp_name = ".tmp-delta";
+        let tmp_path = blobs_path.join(tmp_name);
+        generate_static_delta(repo, &from_commit, &to_commit, tmp_path.as_std_path())?;
+        let (blob_digest, size) = commit_blob(blobs, tmp_name)?;
+        println!(
+            "Delta for {digest}: {} (layer: {})",
+            indicatif::HumanBytes(size),
+            indicatif::HumanBytes(layer.size())
+        );
+        layers.push(json!({
+            "mediaType": DELTA_MEDIA_TYPE,
+            "digest": blob_digest,
+            "size": size,
+            "annotations": {
+                ANNOTATION_LAYER: digest,
+                ANNOTATION_FROM_COMMIT: from_commit,
+                ANNOTATION_TO_COMMIT: to_commit,
+            },
+        }));
+    }
+
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": ARTIFACT_TYPE,
+        "config": {
+            "mediaType": EMPTY_MEDIA_TYPE,
+            "digest": EMPTY_DIGEST,
+            "size": 2,
+        },
+        "layers": layers,
+        "annotations": {
+            ANNOTATION_FROM: from_digest,
+            ANNOTATION_TO: to_digest,
+        },
+    });
+    let (manifest_digest, manifest_size) = write_blob(blobs, manifest.to_string().as_bytes())?;
+    let index = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [{
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": ARTIFACT_TYPE,
+            "digest": manifest_digest,
+            "size": manifest_size,
+            "annotations": { REF_NAME_ANNOTATION: tag },
+        }],
+    });
+    output.write("index.json", index.to_string())?;
+
+    let repository = crate::sbom::repository_of(&opts.to);
+    println!(
+        "Wrote deltas for {} layers to {}; publish them with:",
+        layers.len(),
+        opts.output
+    );
+    println!(
+        "  skopeo copy oci:{}:{tag} docker://{repository}:{tag}",
+        opts.output
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FROM: &str = "sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34";
+    const TO: &str = "sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566";
+
+    #[test]
+    fn test_delta_tag() {
+        assert_eq!(
+            delta_tag(FROM, TO).unwrap(),
+            "delta-736b359467c9437c1ac915acaae952aa-16dc2b6256b4ff0d2ec18d2dbfb06d11"
+        );
+        // Tags are at most 128 characters
+        assert!(delta_tag(FROM, TO).unwrap().len() <= 128);
+        assert!(delta_tag("sha256:abcd", TO).is_err());
+        assert!(delta_tag(FROM, "sha512:abcd").is_err());
+    }
+
+    #[test]
+    fn test_empty_digest() {
+        assert_eq!(
+            format!("sha256:{}", hex::encode(openssl::sha::sha256(b"{}"))),
+            EMPTY_DIGEST
+        );
+    }
+
+    #[test]
+    fn test_parse_deltas() -> Result<()> {
+        let layer = json!({
+            "mediaType": DELTA_MEDIA_TYPE,
+            "digest": FROM,
+            "size": 1234,
+            "annotations": {
+                ANNOTATION_LAYER: TO,
+                ANNOTATION_FROM_COMMIT: "a".repeat(64),
+                ANNOTATION_TO_COMMIT: "b".repeat(64),
+            },
+        });
+        let manifest = |layers: Vec<serde_json::Value>| -> Result<ImageManifest> {
+            let v = json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "artifactType": ARTIFACT_TYPE,
+                "config": {
+                    "mediaType": EMPTY_MEDIA_TYPE,
+                    "digest": EMPTY_DIGEST,
+                    "size": 2,
+                },
+                "layers": layers,
+                "annotations": { ANNOTATION_FROM: FROM, ANNOTATION_TO: TO },
+            });
+            Ok(serde_json::from_value(v)?)
+        };
+
+        let m = manifest(vec![layer.clone()])?;
+        let deltas = parse_deltas(&m, FROM, TO)?;
+        assert_eq!(deltas.len(), 1);
+        let delta = &deltas[0];
+        assert_eq!(delta.layer, TO);
+        assert_eq!(delta.from_commit, "a".repeat(64));
+        assert_eq!(delta.to_commit, "b".repeat(64));
+        assert_eq!(delta.blob.size(), 1234);
+
+        // The artifact must be for the images being updated
+        assert!(parse_deltas(&m, TO, FROM).is_err());
+
+        // Other layers are ignored, but deltas must be fully annotated
+        let mut other = layer.clone();
+        other["mediaType"] = "application/octet-stream".into();
+        assert!(parse_deltas(&manifest(vec![other])?, FROM, TO)?.is_empty());
+        let mut incomplete = layer;
+        incomplete["annotations"]
+            .as_object_mut()
+            .unwrap()
+            .remove(ANNOTATION_TO_COMMIT);
+        assert!(parse_deltas(&manifest(vec![incomplete])?, FROM, TO).is_err());
+        Ok(())
+    }
+}
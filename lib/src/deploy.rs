@@ -3,7 +3,8 @@
 //! Create a merged filesystem tree with the image and mounted configmaps.
 
 use std::collections::HashSet;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, IsTerminal, Write};
+use std::time::Duration;
 
 use anyhow::Ok;
 use anyhow::{anyhow, Context, Result};
@@ -21,8 +22,10 @@ use ostree_ext::ostree::{self, Sysroot};
 use ostree_ext::sysroot::SysrootLock;
 use ostree_ext::tokio_util::spawn_blocking_cancellable_flatten;
 
-use crate::spec::ImageReference;
+use crate::hooks::Hook;
+use crate::progress_jsonl::{Event, ProgressWriter};
 use crate::spec::{BootOrder, HostSpec};
+use crate::spec::{ImageReference, ImageSource};
 use crate::status::labels_of_config;
 use crate::store::Storage;
 use crate::utils::async_task_with_spinner;
@@ -33,6 +36,27 @@ const BASE_IMAGE_PREFIX: &str = "ostree/container/baseimage/bootc";
 /// Set on an ostree commit if this is a derived commit
 const BOOTC_DERIVED_KEY: &str = "bootc.derived";
 
+/// How often container fetch progress is logged when standard error is not a terminal
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Origin key (in the `bootc` group) holding the list of image mirrors
+pub(crate) const ORIGIN_MIRRORS: &str = "mirrors";
+/// Origin key (in the `bootc` group) holding the mirror which served the image
+pub(crate) const ORIGIN_FETCHED_FROM: &str = "fetched-from";
+/// The record of the image fetched by `bootc fetch`, relative to the sysroot.
+const FETCH_RECORD: &str = "ostree/bootc/fetched.json";
+
+/// Persisted by `bootc fetch`, as the mirror which served an image is not
+/// stored in the ostree repository.
+#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct FetchRecord {
+    /// The digest of the fetched image
+    image_digest: String,
+    /// The mirror the image was fetched from, if not the primary location
+    source: Option<ImageSource>,
+}
+
 /// Variant of HostSpec but required to be filled out
 pub(crate) struct RequiredHostSpec<'a> {
     pub(crate) image: &'a ImageReference,
@@ -43,6 +67,8 @@ pub(crate) struct ImageState {
     pub(crate) manifest_digest: Digest,
     pub(crate) version: Option<String>,
     pub(crate) ostree_commit: String,
+    /// The mirror the image was fetched from, if not the primary location
+    pub(crate) source: Option<ImageSource>,
 }
 
 impl<'a> RequiredHostSpec<'a> {
@@ -65,6 +91,7 @@ impl From<ostree_container::store::LayeredImageState> for ImageState {
             manifest_digest: value.manifest_digest,
             version,
             ostree_commit,
+            source: None,
         }
     }
 }
@@ -137,33 +164,80 @@ fn prefix_of_progress(p: &ImportProgress) -> &'static str {
     }
 }
 
-/// Write container fetch progress to standard output.
+/// Format the overall progress of a container fetch as a single line, for
+/// when progress bars can't be shown.
+fn format_fetch_progress(
+    layers_done: u64,
+    layers_total: u64,
+    fetched: u64,
+    bytes_total: u64,
+    elapsed: Duration,
+) -> String {
+    let mut r = format!(
+        "Fetching layers: {layers_done}/{layers_total}, {}/{}",
+        indicatif::HumanBytes(fetched),
+        indicatif::HumanBytes(bytes_total)
+    );
+    let persec = fetched as f64 / elapsed.as_secs_f64();
+    if fetched > 0 && persec.is_finite() {
+        let remaining = bytes_total.saturating_sub(fetched) as f64 / persec;
+        r.push_str(&format!(
+            " ({}/s, ETA {})",
+            indicatif::HumanBytes(persec as u64),
+            indicatif::HumanDuration(
+                Duration::try_from_secs_f64(remaining).unwrap_or(Duration::MAX)
+            )
+        ));
+    }
+    r
+}
+
+/// Write container fetch progress to standard output (unless `quiet` is set),
+/// and as events to the progress writer.  If standard error is a terminal,
+/// progress bars are shown; otherwise, the progress is logged periodically.
 async fn handle_layer_progress_print(
     mut layers: tokio::sync::mpsc::Receiver<ostree_container::store::ImportProgress>,
     mut layer_bytes: tokio::sync::watch::Receiver<Option<ostree_container::store::LayerProgress>>,
     n_layers_to_fetch: usize,
+    bytes_to_fetch: u64,
+    quiet: bool,
+    prog: ProgressWriter,
 ) {
     let start = std::time::Instant::now();
     let mut total_read = 0u64;
+    let mut layers_done = 0u64;
+    let steps_total = n_layers_to_fetch as u64;
+    // The digest, description and size of the layer currently being fetched
+    let mut current = (String::new(), String::new(), 0u64);
+    // The bytes fetched of the current layer
+    let mut current_read = 0u64;
+    let interactive = !quiet && std::io::stderr().is_terminal();
     let bar = indicatif::MultiProgress::new();
+    if !interactive {
+        bar.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+    }
+    let mut log_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + PROGRESS_LOG_INTERVAL,
+        PROGRESS_LOG_INTERVAL,
+    );
+    log_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let log_progress = !quiet && !interactive;
     let layers_bar = bar.add(indicatif::ProgressBar::new(
         n_layers_to_fetch.try_into().unwrap(),
     ));
     let byte_bar = bar.add(indicatif::ProgressBar::new(0));
-    // let byte_bar = indicatif::ProgressBar::new(0);
-    // byte_bar.set_draw_target(indicatif::ProgressDrawTarget::hidden());
     layers_bar.set_style(
         indicatif::ProgressStyle::default_bar()
             .template("{prefix} {bar} {pos}/{len} {wide_msg}")
             .unwrap(),
     );
     layers_bar.set_prefix("Fetching layers");
-    layers_bar.set_message("");
+    layers_bar.set_message(format!("0 B/{}", indicatif::HumanBytes(bytes_to_fetch)));
     byte_bar.set_prefix("Fetching");
     byte_bar.set_style(
         indicatif::ProgressStyle::default_bar()
                 .template(
-                    " └ {prefix} {bar} {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}) {wide_msg}",
+                    " └ {prefix} {bar} {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, {eta}) {wide_msg}",
                 )
                 .unwrap()
         );
@@ -181,11 +255,39 @@ async fn handle_layer_progress_print(
                         byte_bar.set_length(layer_size);
                         let layer_type = prefix_of_progress(&l);
                         let short_digest = &layer.digest().digest()[0..21];
-                        byte_bar.set_message(format!("{layer_type} {short_digest}"));
+                        let description = format!("{layer_type} {short_digest}");
+                        byte_bar.set_message(description.clone());
+                        current = (layer.digest().to_string(), description, layer_size);
+                        current_read = 0;
+                        prog.send(Event::ProgressBytes {
+                            task: "pulling",
+                            description: format!("Fetching {}", current.1).into(),
+                            id: current.0.as_str().into(),
+                            bytes: 0,
+                            bytes_total: layer_size,
+                            steps: layers_done,
+                            steps_total,
+                        });
                     } else {
                         byte_bar.set_position(layer_size);
                         layers_bar.inc(1);
                         total_read = total_read.saturating_add(layer_size);
+                        current_read = 0;
+                        layers_done += 1;
+                        layers_bar.set_message(format!(
+                            "{}/{}",
+                            indicatif::HumanBytes(total_read),
+                            indicatif::HumanBytes(bytes_to_fetch)
+                        ));
+                        prog.send(Event::ProgressBytes {
+                            task: "pulling",
+                            description: format!("Fetched {}", current.1).into(),
+                            id: current.0.as_str().into(),
+                            bytes: layer_size,
+                            bytes_total: layer_size,
+                            steps: layers_done,
+                            steps_total,
+                        });
                     }
                 } else {
                     // If the receiver is disconnected, then we're done
@@ -200,8 +302,28 @@ async fn handle_layer_progress_print(
                 let bytes = layer_bytes.borrow();
                 if let Some(bytes) = &*bytes {
                     byte_bar.set_position(bytes.fetched);
+                    current_read = bytes.fetched;
+                    prog.send(Event::ProgressBytes {
+                        task: "pulling",
+                        description: format!("Fetching {}", current.1).into(),
+                        id: current.0.as_str().into(),
+                        bytes: bytes.fetched,
+                        bytes_total: current.2,
+                        steps: layers_done,
+                        steps_total,
+                    });
                 }
             }
+            _ = log_interval.tick(), if log_progress => {
+                let line = format_fetch_progress(
+                    layers_done,
+                    steps_total,
+                    total_read.saturating_add(current_read),
+                    bytes_to_fetch,
+                    start.elapsed(),
+                );
+                println!("{line}");
+            }
         }
     }
     byte_bar.finish_and_clear();
@@ -209,53 +331,104 @@ async fn handle_layer_progress_print(
     if let Err(e) = bar.clear() {
         tracing::warn!("clearing bar: {e}");
     }
-    let end = std::time::Instant::now();
-    let elapsed = end.duration_since(start);
+    let elapsed = start.elapsed();
     let persec = total_read as f64 / elapsed.as_secs_f64();
     let persec = indicatif::HumanBytes(persec as u64);
-    if let Err(e) = bar.println(&format!(
-        "Fetched layers: {} in {} ({}/s)",
-        indicatif::HumanBytes(total_read),
-        indicatif::HumanDuration(elapsed),
-        persec,
-    )) {
-        tracing::warn!("writing to stdout: {e}");
+    if !quiet {
+        println!(
+            "Fetched layers: {} in {} ({}/s)",
+            indicatif::HumanBytes(total_read),
+            indicatif::HumanDuration(elapsed),
+            persec,
+        );
     }
 }
 
-/// Wrapper for pulling a container image, wiring up status output.
+/// Marks an error as the image failing verification, rather than failing to be
+/// fetched; fetching it from elsewhere does not help.
+#[derive(Debug)]
+struct VerificationFailed;
+
+impl std::fmt::Display for VerificationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Image verification failed")
+    }
+}
+
+/// Returns true if fetching failed because the image was rejected by a
+/// signature policy: either ours, or that of containers/image.
+fn is_verification_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<VerificationFailed>().is_some()
+        || e.chain()
+            .any(|e| e.to_string().contains("Source image rejected"))
+}
+
+/// Wrapper for pulling a container image, wiring up status output.  If a
+/// signature policy is provided, the image is verified before fetching any layers.
 #[context("Pulling")]
 pub(crate) async fn pull(
     repo: &ostree::Repo,
     imgref: &ImageReference,
     target_imgref: Option<&OstreeImageReference>,
+    policy: Option<&crate::sigpolicy::Policy>,
     quiet: bool,
+    prog: &ProgressWriter,
 ) -> Result<Box<ImageState>> {
     let ostree_imgref = &OstreeImageReference::from(imgref.clone());
     let mut imp = new_importer(repo, ostree_imgref).await?;
     if let Some(target) = target_imgref {
         imp.set_target(target);
     }
-    let prep = match imp.prepare().await? {
+    let verify = |digest: &str| -> Result<()> {
+        let Some(policy) = policy else {
+            return Ok(());
+        };
+        // When fetching from a mirror, the policy applies to the image identity
+        match target_imgref {
+            Some(target) => {
+                let target = ImageReference::from(target.clone());
+                policy.verify(&target, digest, quiet)
+            }
+            None => policy.verify(imgref, digest, quiet),
+        }
+        .context(VerificationFailed)
+    };
+    let mut prep = match imp.prepare().await? {
         PrepareResult::AlreadyPresent(c) => {
+            // The image may have been fetched under a different (or no) policy
+            verify(c.manifest_digest.as_ref())?;
             println!("No changes in {imgref:#} => {}", c.manifest_digest);
             return Ok(Box::new((*c).into()));
         }
         PrepareResult::Ready(p) => p,
     };
+    verify(prep.manifest_digest.as_ref())?;
     check_bootc_label(&prep.config);
     if let Some(warning) = prep.deprecated_warning() {
         ostree_ext::cli::print_deprecated_warning(warning).await;
     }
+    crate::delta::apply(repo, imgref, &mut prep, quiet).await;
     ostree_ext::cli::print_layer_status(&prep);
     let layers_to_fetch = prep.layers_to_fetch().collect::<Result<Vec<_>>>()?;
     let n_layers_to_fetch = layers_to_fetch.len();
-    let printer = (!quiet).then(|| {
+    let bytes_to_fetch = layers_to_fetch
+        .iter()
+        .map(|(l, _)| l.layer().size())
+        .sum::<u64>();
+    let printer = (!quiet || prog.is_enabled()).then(|| {
         let layer_progress = imp.request_progress();
         let layer_byte_progress = imp.request_layer_progress();
+        let prog = prog.clone();
         tokio::task::spawn(async move {
-            handle_layer_progress_print(layer_progress, layer_byte_progress, n_layers_to_fetch)
-                .await
+            handle_layer_progress_print(
+                layer_progress,
+                layer_byte_progress,
+                n_layers_to_fetch,
+                bytes_to_fetch,
+                quiet,
+                prog,
+            )
+            .await
         })
     });
     let import = imp.import(prep).await;
@@ -273,6 +446,88 @@ pub(crate) async fn pull(
     Ok(Box::new((*import).into()))
 }
 
+/// Wrapper for [`pull`] which falls back to the mirrors of the image, in order,
+/// if fetching from its primary location fails.  The image is always stored
+/// under its primary reference.  An image which fails verification is not
+/// fetched from the remaining sources.
+pub(crate) async fn pull_with_mirrors(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    policy: Option<&crate::sigpolicy::Policy>,
+    quiet: bool,
+    prog: &ProgressWriter,
+) -> Result<Box<ImageState>> {
+    let primary_err = match pull(repo, imgref, None, policy, quiet, prog).await {
+        Err(e) if !imgref.mirrors.is_empty() && !is_verification_error(&e) => e,
+        r => return r,
+    };
+    tracing::warn!("Failed to fetch {imgref:#}: {primary_err:#}");
+    let target = OstreeImageReference::from(imgref.clone());
+    let mut errs = vec![format!("{imgref:#}: {primary_err:#}")];
+    for mirror in imgref.mirrors.iter() {
+        let mirror_imgref = ImageReference {
+            image: mirror.image.clone(),
+            transport: mirror.transport.clone(),
+            signature: imgref.signature.clone(),
+            mirrors: Vec::new(),
+        };
+        println!("Trying mirror {mirror_imgref:#}");
+        match pull(repo, &mirror_imgref, Some(&target), policy, quiet, prog).await {
+            Err(e) if !is_verification_error(&e) => {
+                tracing::warn!("Failed to fetch {mirror_imgref:#}: {e:#}");
+                errs.push(format!("{mirror_imgref:#}: {e:#}"));
+            }
+            r => {
+                let mut r = r?;
+                r.source = Some(mirror.clone());
+                return Ok(r);
+            }
+        }
+    }
+    anyhow::bail!(
+        "Failed to fetch image from all sources:\n{}",
+        errs.join("\n")
+    )
+}
+
+/// Record the source of an image fetched by `bootc fetch`, for [`query_fetched`].
+#[context("Writing fetch record")]
+pub(crate) fn write_fetch_record(sysroot: &Dir, image: &ImageState) -> Result<()> {
+    let record = FetchRecord {
+        image_digest: image.manifest_digest.to_string(),
+        source: image.source.clone(),
+    };
+    // SAFETY: We know there's a parent
+    let parent = std::path::Path::new(FETCH_RECORD).parent().unwrap();
+    sysroot.create_dir_all(parent)?;
+    sysroot.atomic_replace_with(FETCH_RECORD, |w| {
+        serde_json::to_writer(w, &record).map_err(anyhow::Error::new)
+    })
+}
+
+/// Query the state of an image previously fetched via [`pull`], without accessing
+/// the network.  Returns `None` if the image has not been fetched.
+#[context("Querying fetched image")]
+pub(crate) fn query_fetched(
+    sysroot: &Dir,
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+) -> Result<Option<Box<ImageState>>> {
+    let ostree_imgref = OstreeImageReference::from(imgref.clone());
+    let Some(state) = ostree_container::store::query_image(repo, &ostree_imgref.imgref)? else {
+        return Ok(None);
+    };
+    let mut state: ImageState = (*state).into();
+    if let Some(f) = sysroot.open_optional(FETCH_RECORD)? {
+        let record: FetchRecord = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("Parsing {FETCH_RECORD}"))?;
+        if record.image_digest == state.manifest_digest.as_ref() {
+            state.source = record.source;
+        }
+    }
+    Ok(Some(Box::new(state)))
+}
+
 /// Gather all bound images in all deployments, then prune the image store,
 /// using the gathered images as the roots (that will not be GC'd).
 pub(crate) async fn prune_container_store(sysroot: &Storage) -> Result<()> {
@@ -304,59 +559,60 @@ pub(crate) async fn wipe_ostree(sysroot: Sysroot) -> Result<()> {
 }
 
 pub(crate) async fn cleanup(sysroot: &Storage) -> Result<()> {
-    let bound_prune = prune_container_store(sysroot);
+    // We run these in parallel mostly because we can.
+    tokio::try_join!(prune_repo(sysroot), prune_container_store(sysroot))?;
+    Ok(())
+}
 
+/// Regenerate the base references of derived commits, then prune the container
+/// images (and their layers) which are not used by any deployment.
+pub(crate) async fn prune_repo(sysroot: &Storage) -> Result<()> {
     // We create clones (just atomic reference bumps) here to move to the thread.
     let repo = sysroot.repo();
     let sysroot = sysroot.sysroot.clone();
-    let repo_prune =
-        ostree_ext::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
-            let locked_sysroot = &SysrootLock::from_assumed_locked(&sysroot);
-            let cancellable = Some(cancellable);
-            let repo = &repo;
-            let txn = repo.auto_transaction(cancellable)?;
-            let repo = txn.repo();
-
-            // Regenerate our base references.  First, we delete the ones that exist
-            for ref_entry in repo
-                .list_refs_ext(
-                    Some(BASE_IMAGE_PREFIX),
-                    ostree::RepoListRefsExtFlags::NONE,
-                    cancellable,
-                )
-                .context("Listing refs")?
-                .keys()
-            {
-                repo.transaction_set_refspec(ref_entry, None);
-            }
+    ostree_ext::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+        let locked_sysroot = &SysrootLock::from_assumed_locked(&sysroot);
+        let cancellable = Some(cancellable);
+        let repo = &repo;
+        let txn = repo.auto_transaction(cancellable)?;
+        let repo = txn.repo();
 
-            // Then, for each deployment which is derived (e.g. has configmaps) we synthesize
-            // a base ref to ensure that it's not GC'd.
-            for (i, deployment) in sysroot.deployments().into_iter().enumerate() {
-                let commit = deployment.csum();
-                if let Some(base) = get_base_commit(repo, &commit)? {
-                    repo.transaction_set_refspec(&format!("{BASE_IMAGE_PREFIX}/{i}"), Some(&base));
-                }
-            }
+        // Regenerate our base references.  First, we delete the ones that exist
+        for ref_entry in repo
+            .list_refs_ext(
+                Some(BASE_IMAGE_PREFIX),
+                ostree::RepoListRefsExtFlags::NONE,
+                cancellable,
+            )
+            .context("Listing refs")?
+            .keys()
+        {
+            repo.transaction_set_refspec(ref_entry, None);
+        }
 
-            let pruned =
-                ostree_container::deploy::prune(locked_sysroot).context("Pruning images")?;
-            if !pruned.is_empty() {
-                let size = glib::format_size(pruned.objsize);
-                println!(
-                    "Pruned images: {} (layers: {}, objsize: {})",
-                    pruned.n_images, pruned.n_layers, size
-                );
-            } else {
-                tracing::debug!("Nothing to prune");
+        // Then, for each deployment which is derived (e.g. has configmaps) we synthesize
+        // a base ref to ensure that it's not GC'd.
+        for (i, deployment) in sysroot.deployments().into_iter().enumerate() {
+            let commit = deployment.csum();
+            if let Some(base) = get_base_commit(repo, &commit)? {
+                repo.transaction_set_refspec(&format!("{BASE_IMAGE_PREFIX}/{i}"), Some(&base));
             }
+        }
 
-            Ok(())
-        });
+        let pruned = ostree_container::deploy::prune(locked_sysroot).context("Pruning images")?;
+        if !pruned.is_empty() {
+            let size = glib::format_size(pruned.objsize);
+            println!(
+                "Pruned images: {} (layers: {}, objsize: {})",
+                pruned.n_images, pruned.n_layers, size
+            );
+        } else {
+            tracing::debug!("Nothing to prune");
+        }
 
-    // We run these in parallel mostly because we can.
-    tokio::try_join!(repo_prune, bound_prune)?;
-    Ok(())
+        Ok(())
+    })
+    .await
 }
 
 /// If commit is a bootc-derived commit (e.g. has configmaps), return its base.
@@ -373,15 +629,16 @@ pub(crate) fn get_base_commit(repo: &ostree::Repo, commit: &str) -> Result<Optio
 async fn deploy(
     sysroot: &Storage,
     merge_deployment: Option<&Deployment>,
+    kargs_from: Option<&Deployment>,
     stateroot: &str,
     image: &ImageState,
     origin: &glib::KeyFile,
 ) -> Result<Deployment> {
     // Compute the kernel argument overrides. In practice today this API is always expecting
-    // a merge deployment. The kargs code also always looks at the booted root (which
-    // is a distinct minor issue, but not super important as right now the install path
-    // doesn't use this API).
-    let override_kargs = if let Some(deployment) = merge_deployment {
+    // a deployment to take them from (usually the merge deployment). The kargs code also
+    // always looks at the booted root (which is a distinct minor issue, but not super
+    // important as right now the install path doesn't use this API).
+    let override_kargs = if let Some(deployment) = kargs_from {
         Some(crate::kargs::get_kargs(sysroot, &deployment, image)?)
     } else {
         None
@@ -440,9 +697,27 @@ fn origin_from_imageref(imgref: &ImageReference) -> Result<glib::KeyFile> {
         ostree_container::deploy::ORIGIN_CONTAINER,
         imgref.to_string().as_str(),
     );
+    if !imgref.mirrors.is_empty() {
+        let mirrors = imgref
+            .mirrors
+            .iter()
+            .map(|m| ostree_container::ImageReference::try_from(m).map(|m| m.to_string()))
+            .collect::<Result<Vec<_>>>()?;
+        let mirrors = mirrors.iter().map(String::as_str).collect::<Vec<_>>();
+        origin.set_string_list("bootc", ORIGIN_MIRRORS, &mirrors);
+    }
     Ok(origin)
 }
 
+/// Record the mirror which served the image (if any) in the origin.
+fn set_origin_source(origin: &glib::KeyFile, image: &ImageState) -> Result<()> {
+    if let Some(source) = image.source.as_ref() {
+        let source = ostree_container::ImageReference::try_from(source)?;
+        origin.set_string("bootc", ORIGIN_FETCHED_FROM, &source.to_string());
+    }
+    Ok(())
+}
+
 /// Stage (queue deployment of) a fetched container image.
 #[context("Staging")]
 pub(crate) async fn stage(
@@ -450,30 +725,86 @@ pub(crate) async fn stage(
     stateroot: &str,
     image: &ImageState,
     spec: &RequiredHostSpec<'_>,
+    prog: &ProgressWriter,
 ) -> Result<()> {
+    const STEPS: &[&str] = &["Deploying", "Pulling bound images", "Cleaning up"];
+    let id = image.manifest_digest.to_string();
+    let send_step = |steps: usize, description: &str| {
+        prog.send(Event::ProgressSteps {
+            task: "staging",
+            description: description.into(),
+            id: id.as_str().into(),
+            steps: steps as u64,
+            steps_total: STEPS.len() as u64,
+        })
+    };
+    let (_, _, host) = crate::status::get_status_require_booted(sysroot)?;
+    let booted_image = host.status.booted.as_ref().and_then(|b| b.image.as_ref());
+    let hook_context = |hook| crate::hooks::HookContext {
+        new_image: Some(format!("{:#}", spec.image)),
+        new_version: image.version.clone(),
+        new_image_digest: Some(id.clone()),
+        ..crate::hooks::HookContext::new(hook, booted_image, None)
+    };
+    crate::hooks::run(&hook_context(Hook::PreUpgrade))?;
     let merge_deployment = sysroot.merge_deployment(Some(stateroot));
     let origin = origin_from_imageref(spec.image)?;
+    set_origin_source(&origin, image)?;
+    send_step(0, STEPS[0]);
     let deployment = crate::deploy::deploy(
         sysroot,
         merge_deployment.as_ref(),
+        merge_deployment.as_ref(),
         stateroot,
         image,
         &origin,
     )
     .await?;
 
+    send_step(1, STEPS[1]);
     crate::boundimage::pull_bound_images(sysroot, &deployment).await?;
 
+    send_step(2, STEPS[2]);
     crate::deploy::cleanup(sysroot).await?;
+    let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let deployment_root = &crate::utils::deployment_fd(sysroot, &deployment)?;
+    crate::bootcount::arm(rootfs, deployment_root)?;
+    crate::maintenance::schedule_reboot(rootfs, deployment_root)?;
+    send_step(STEPS.len(), "Staged");
     println!("Queued for next boot: {:#}", spec.image);
     if let Some(version) = image.version.as_deref() {
         println!("  Version: {version}");
     }
     println!("  Digest: {}", image.manifest_digest);
+    crate::hooks::run(&hook_context(Hook::PostStage))?;
 
     Ok(())
 }
 
+/// Stage a deployment of an image into a new stateroot, without merging
+/// configuration from any existing deployment; only the kernel arguments are
+/// taken from `kargs_from`.
+#[context("Staging into new stateroot {stateroot}")]
+pub(crate) async fn stage_pristine(
+    sysroot: &Storage,
+    stateroot: &str,
+    image: &ImageState,
+    imgref: &ImageReference,
+    kargs_from: &Deployment,
+) -> Result<Deployment> {
+    sysroot
+        .init_osname(stateroot, gio::Cancellable::NONE)
+        .context("Initializing stateroot")?;
+    let origin = origin_from_imageref(imgref)?;
+    set_origin_source(&origin, image)?;
+    let deployment = deploy(sysroot, None, Some(kargs_from), stateroot, image, &origin).await?;
+    crate::boundimage::pull_bound_images(sysroot, &deployment).await?;
+    let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let deployment_root = &crate::utils::deployment_fd(sysroot, &deployment)?;
+    crate::bootcount::arm(rootfs, deployment_root)?;
+    Ok(deployment)
+}
+
 /// Implementation of rollback functionality
 pub(crate) async fn rollback(sysroot: &Storage) -> Result<()> {
     const ROLLBACK_JOURNAL_ID: &str = "26f3b1eb24464d12aa5e7b544a6b5468";
@@ -513,6 +844,11 @@ pub(crate) async fn rollback(sysroot: &Storage) -> Result<()> {
         ]
         .into_iter(),
     )?;
+    // The staged deployment is discarded, and with it a reboot scheduled into it
+    if deployments.staged.is_some() {
+        let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        crate::maintenance::cancel_reboot(rootfs)?;
+    }
     // SAFETY: If there's a rollback status, then there's a deployment
     let rollback_deployment = deployments.rollback.expect("rollback deployment");
     let new_deployments = if reverting {
@@ -531,6 +867,17 @@ pub(crate) async fn rollback(sysroot: &Storage) -> Result<()> {
     } else {
         println!("Next boot: rollback deployment");
     }
+    let booted_image = host.status.booted.as_ref().and_then(|b| b.image.as_ref());
+    let next_image = if reverting {
+        booted_image
+    } else {
+        rollback_status.image.as_ref()
+    };
+    crate::hooks::run(&crate::hooks::HookContext::new(
+        Hook::PostRollback,
+        booted_image,
+        next_image,
+    ))?;
     Ok(())
 }
 
@@ -555,7 +902,7 @@ fn find_newest_deployment_name(deploysdir: &Dir) -> Result<String> {
     }
 }
 
-// Implementation of `bootc switch --in-place`
+// Implementation of `bootc switch --mutate-in-place`
 pub(crate) fn switch_origin_inplace(root: &Dir, imgref: &ImageReference) -> Result<String> {
     // First, just create the new origin file
     let origin = origin_from_imageref(imgref)?;
@@ -616,6 +963,7 @@ fn test_switch_inplace() -> Result<()> {
         image: "quay.io/exampleos/original:sometag".into(),
         transport: "registry".into(),
         signature: None,
+        mirrors: Vec::new(),
     };
     {
         let origin = origin_from_imageref(&orig_imgref)?;
@@ -629,6 +977,7 @@ fn test_switch_inplace() -> Result<()> {
         image: "quay.io/someother/otherimage:latest".into(),
         transport: "registry".into(),
         signature: None,
+        mirrors: Vec::new(),
     };
 
     let replaced = switch_origin_inplace(&td, &target_imgref).unwrap();
@@ -636,6 +985,58 @@ fn test_switch_inplace() -> Result<()> {
     Ok(())
 }
 
+/// Implementation of `bootc switch --in-place`: retarget the booted deployment
+/// to a different image reference which serves the identical image (e.g. a mirror
+/// or renamed repository), without fetching it or creating a new deployment.
+#[context("Switching in place")]
+pub(crate) async fn switch_inplace(
+    sysroot: &Storage,
+    booted: &Deployment,
+    booted_imgref: &ImageReference,
+    booted_digest: &str,
+    target: &ImageReference,
+    policy: Option<&crate::sigpolicy::Policy>,
+) -> Result<()> {
+    let repo = &sysroot.repo();
+    let target_imgref = &OstreeImageReference::from(target.clone());
+    // Fetch just the manifest to verify that the target serves the same image
+    let mut imp = new_importer(repo, target_imgref).await?;
+    let digest = match imp.prepare().await? {
+        PrepareResult::AlreadyPresent(c) => c.manifest_digest,
+        PrepareResult::Ready(p) => p.manifest_digest,
+    };
+    if digest.as_ref() != booted_digest {
+        anyhow::bail!(
+            "{target} resolves to {digest}, which differs from the booted image {booted_digest}"
+        );
+    }
+    if let Some(policy) = policy {
+        policy.verify(target, booted_digest, false)?;
+    }
+
+    // Reference the stored image under the new name too, so it is not garbage collected
+    let booted_imgref = &OstreeImageReference::from(booted_imgref.clone());
+    ostree_container::store::copy(repo, &booted_imgref.imgref, repo, &target_imgref.imgref).await?;
+
+    // Preserve any other state in the origin, only replacing the image reference
+    let origin = glib::KeyFile::new();
+    if let Some(booted_origin) = booted.origin() {
+        origin.load_from_data(&booted_origin.to_data(), glib::KeyFileFlags::KEEP_COMMENTS)?;
+    }
+    origin.set_string(
+        "origin",
+        ostree_container::deploy::ORIGIN_CONTAINER,
+        target_imgref.to_string().as_str(),
+    );
+    // Mirrors of the previous image don't apply to the new one
+    for key in [ORIGIN_MIRRORS, ORIGIN_FETCHED_FROM] {
+        let _ = origin.remove_key("bootc", key);
+    }
+    sysroot.write_origin_file(booted, Some(&origin), gio::Cancellable::NONE)?;
+    println!("Updated booted deployment to {target}");
+    Ok(())
+}
+
 /// A workaround for https://github.com/ostreedev/ostree/issues/3193
 /// as generated by anaconda.
 #[context("Updating /etc/fstab for anaconda+composefs")]
@@ -673,8 +1074,8 @@ pub(crate) fn fixup_etc_fstab(root: &Dir) -> Result<()> {
         if path != "/" {
             return Ok(false);
         }
-        // If options already contains `ro`, nothing to do
-        if options.split(',').any(|s| s == "ro") {
+        // If the mount is already read-only, nothing to do
+        if crate::mount::MountOptions::parse(options).is_readonly() {
             return Ok(false);
         }
 
@@ -769,3 +1170,26 @@ UUID=6907-17CA          /boot/efi               vfat    umask=0077,shortname=win
     assert_eq!(tempdir.read_to_string("etc/fstab")?, modified);
     Ok(())
 }
+
+#[test]
+fn test_format_fetch_progress() {
+    const MIB: u64 = 1 << 20;
+    similar_asserts::assert_eq!(
+        format_fetch_progress(0, 4, 0, 40 * MIB, Duration::ZERO),
+        "Fetching layers: 0/4, 0 B/40.00 MiB"
+    );
+    similar_asserts::assert_eq!(
+        format_fetch_progress(1, 4, 10 * MIB, 40 * MIB, Duration::from_secs(10)),
+        "Fetching layers: 1/4, 10.00 MiB/40.00 MiB (1.00 MiB/s, ETA 30 seconds)"
+    );
+}
+
+#[test]
+fn test_is_verification_error() {
+    let e = anyhow!("cosign failed").context(VerificationFailed);
+    assert!(is_verification_error(&e.context("Pulling")));
+    let e = anyhow!("Source image rejected: A signature was required, but no signature exists");
+    assert!(is_verification_error(&e.context("Pulling")));
+    let e = anyhow!("pinging container registry: connection refused");
+    assert!(!is_verification_error(&e.context("Pulling")));
+}
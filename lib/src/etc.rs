@@ -0,0 +1,502 @@
+//! # Inspecting and resetting `/etc`
+//!
+//! The `/etc` of a deployment starts out as a copy of the defaults shipped in
+//! its image (`/usr/etc`).  On upgrade, the local changes relative to the
+//! defaults of the booted image are applied onto the defaults of the new image
+//! (a 3-way merge).  `bootc etc diff` shows those changes, and `bootc etc reset`
+//! reverts selected ones; as the reset is done in the current `/etc`, it is
+//! also carried over to the next deployment.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::{Dir, Metadata, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::sysroot::LockMode;
+use serde::{Serialize, Serializer};
+
+use crate::cli::{EtcOpts, OutputFormat};
+use crate::task::Task;
+
+/// The defaults of `/etc` shipped in the image, relative to the root.
+const DEFAULTS: &str = "usr/etc";
+/// The current `/etc`, relative to the root.
+const ETC: &str = "etc";
+
+/// How a path in `/etc` differs from the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ChangeKind {
+    /// Not present in the defaults
+    Added,
+    /// Present in both, with different content, type, permissions, ownership
+    /// or extended attributes
+    Modified,
+    /// Only present in the defaults
+    Removed,
+}
+
+impl ChangeKind {
+    fn symbol(self) -> char {
+        match self {
+            Self::Added => 'A',
+            Self::Modified => 'M',
+            Self::Removed => 'D',
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Modified => "modified",
+            Self::Removed => "removed",
+        }
+    }
+}
+
+/// A path in `/etc` which differs from the defaults.  The contents of added
+/// or removed directories are not listed separately.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct EtcChange {
+    pub(crate) kind: ChangeKind,
+    /// The path, relative to `/etc`
+    #[serde(serialize_with = "serialize_etc_path")]
+    pub(crate) path: Utf8PathBuf,
+}
+
+fn serialize_etc_path<S: Serializer>(path: &Utf8Path, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(Utf8Path::new("/etc").join(path).as_str())
+}
+
+/// Validate a path below `/etc`, returning it relative to `/etc`.
+pub(crate) fn relative_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let mut components = path.components();
+    if components.next() != Some(Utf8Component::RootDir)
+        || components.next() != Some(Utf8Component::Normal("etc"))
+    {
+        anyhow::bail!("Path must be below /etc: {path}");
+    }
+    let rest: Utf8PathBuf = components
+        .map(|c| match c {
+            Utf8Component::Normal(c) => Ok(c),
+            _ => Err(anyhow::anyhow!("Invalid path: {path}")),
+        })
+        .collect::<Result<_>>()?;
+    if rest.as_str().is_empty() {
+        anyhow::bail!("Path must be below /etc: {path}");
+    }
+    Ok(rest)
+}
+
+/// Whether the type, permissions and ownership are the same.
+fn same_metadata(a: &Metadata, b: &Metadata) -> bool {
+    (a.mode(), a.uid(), a.gid()) == (b.mode(), b.uid(), b.gid())
+}
+
+/// The path of an entry of a directory via procfs, for the `l*xattr` calls.
+fn proc_path(d: &Dir, name: &str) -> String {
+    format!("/proc/self/fd/{}/{name}", d.as_raw_fd())
+}
+
+/// Read the extended attributes of an entry (without following symlinks).
+fn read_xattrs(d: &Dir, name: &str) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let path = proc_path(d, name);
+    let mut r = BTreeMap::new();
+    let size = match rustix::fs::llistxattr(&path, &mut []) {
+        Ok(n) => n,
+        Err(rustix::io::Errno::OPNOTSUPP) => return Ok(r),
+        Err(e) => return Err(e).with_context(|| format!("Listing xattrs of {name}")),
+    };
+    let mut names = vec![0; size];
+    let n = rustix::fs::llistxattr(&path, &mut names)
+        .with_context(|| format!("Listing xattrs of {name}"))?;
+    let names = names[..n].iter().map(|&c| c as u8).collect::<Vec<u8>>();
+    for xattr in names.split(|&c| c == 0).filter(|x| !x.is_empty()) {
+        let size = rustix::fs::lgetxattr(&path, xattr, &mut [])
+            .with_context(|| format!("Reading xattrs of {name}"))?;
+        let mut value = vec![0u8; size];
+        let n = rustix::fs::lgetxattr(&path, xattr, &mut value)
+            .with_context(|| format!("Reading xattrs of {name}"))?;
+        value.truncate(n);
+        r.insert(xattr.to_vec(), value);
+    }
+    Ok(r)
+}
+
+/// Whether the extended attributes of an entry are the same in both directories.
+fn same_xattrs(defaults: &Dir, current: &Dir, name: &str) -> Result<bool> {
+    Ok(read_xattrs(defaults, name)? == read_xattrs(current, name)?)
+}
+
+/// Make the extended attributes of `target` those of `source`.
+fn copy_xattrs(root: &Dir, source: &Utf8Path, target: &Utf8Path) -> Result<()> {
+    let wanted = read_xattrs(root, source.as_str())?;
+    let path = proc_path(root, target.as_str());
+    for xattr in read_xattrs(root, target.as_str())?.into_keys() {
+        if !wanted.contains_key(&xattr) {
+            rustix::fs::lremovexattr(&path, xattr.as_slice())
+                .with_context(|| format!("Removing xattrs of {target}"))?;
+        }
+    }
+    for (xattr, value) in wanted {
+        rustix::fs::lsetxattr(
+            &path,
+            xattr.as_slice(),
+            &value,
+            rustix::fs::XattrFlags::empty(),
+        )
+        .with_context(|| format!("Setting xattrs of {target}"))?;
+    }
+    Ok(())
+}
+
+/// Whether a (non-directory) entry is the same in both directories.
+fn same_entry(
+    defaults: &Dir,
+    current: &Dir,
+    name: &str,
+    a: &Metadata,
+    b: &Metadata,
+) -> Result<bool> {
+    if !same_metadata(a, b) || !same_xattrs(defaults, current, name)? {
+        return Ok(false);
+    }
+    if a.is_symlink() {
+        return Ok(defaults.read_link(name)? == current.read_link(name)?);
+    }
+    if a.is_file() {
+        return Ok(a.len() == b.len() && defaults.read(name)? == current.read(name)?);
+    }
+    Ok(true)
+}
+
+fn diff_dir(
+    defaults: &Dir,
+    current: &Dir,
+    path: &mut Utf8PathBuf,
+    out: &mut Vec<EtcChange>,
+) -> Result<()> {
+    let mut names = BTreeSet::new();
+    for d in [defaults, current] {
+        for ent in d.entries()? {
+            let name = ent?.file_name();
+            let name = name
+                .into_string()
+                .map_err(|name| anyhow::anyhow!("Invalid non-UTF-8 filename: {name:?}"))?;
+            names.insert(name);
+        }
+    }
+    for name in names {
+        path.push(&name);
+        let kind = match (
+            defaults.symlink_metadata_optional(&name)?,
+            current.symlink_metadata_optional(&name)?,
+        ) {
+            (None, None) => None,
+            (Some(_), None) => Some(ChangeKind::Removed),
+            (None, Some(_)) => Some(ChangeKind::Added),
+            (Some(a), Some(b)) if a.is_dir() && b.is_dir() => {
+                let changed = !same_metadata(&a, &b) || !same_xattrs(defaults, current, &name)?;
+                if changed {
+                    out.push(EtcChange {
+                        kind: ChangeKind::Modified,
+                        path: path.clone(),
+                    });
+                }
+                let (defaults, current) = (defaults.open_dir(&name)?, current.open_dir(&name)?);
+                diff_dir(&defaults, &current, path, out)?;
+                None
+            }
+            (Some(a), Some(b)) => {
+                (!same_entry(defaults, current, &name, &a, &b)?).then_some(ChangeKind::Modified)
+            }
+        };
+        if let Some(kind) = kind {
+            out.push(EtcChange {
+                kind,
+                path: path.clone(),
+            });
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Compute the changes of the `/etc` of a root relative to its defaults, sorted by path.
+#[context("Comparing /etc to {DEFAULTS}")]
+pub(crate) fn diff(root: &Dir) -> Result<Vec<EtcChange>> {
+    let defaults = root.open_dir(DEFAULTS).context("Opening defaults")?;
+    let current = root.open_dir(ETC)?;
+    let mut r = Vec::new();
+    diff_dir(&defaults, &current, &mut Utf8PathBuf::new(), &mut r)?;
+    r.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(r)
+}
+
+/// The changes to reset for a path; this fails if the path is below an added
+/// or removed directory, as only that directory as a whole can be reset.
+fn changes_below<'a>(changes: &'a [EtcChange], path: &Utf8Path) -> Result<Vec<&'a EtcChange>> {
+    if let Some(parent) = changes.iter().find(|c| {
+        c.kind != ChangeKind::Modified && path.starts_with(&c.path) && path != c.path.as_path()
+    }) {
+        anyhow::bail!(
+            "/etc/{path} is below /etc/{}, which was {}; reset that instead",
+            parent.path,
+            parent.kind.as_str()
+        );
+    }
+    Ok(changes
+        .iter()
+        .filter(|c| c.path.starts_with(path))
+        .collect())
+}
+
+/// Reset a change, in the `/etc` of the provided root.
+fn reset_change(root: &Dir, change: &EtcChange) -> Result<()> {
+    let defaults = Utf8Path::new(DEFAULTS).join(&change.path);
+    let target = Utf8Path::new(ETC).join(&change.path);
+    let is_dir = root
+        .symlink_metadata_optional(&target)?
+        .is_some_and(|m| m.is_dir())
+        && root
+            .symlink_metadata_optional(&defaults)?
+            .is_some_and(|m| m.is_dir());
+    if change.kind == ChangeKind::Modified && is_dir {
+        // Only the metadata of the directory itself differs
+        let reference = format!("--reference={defaults}");
+        for cmd in ["chmod", "chown"] {
+            Task::new_quiet(cmd)
+                .cwd(root)?
+                .args([reference.as_str(), target.as_str()])
+                .run()?;
+        }
+        return copy_xattrs(root, &defaults, &target);
+    }
+    root.remove_all_optional(&target)?;
+    if change.kind != ChangeKind::Added {
+        Task::new_quiet("cp")
+            .cwd(root)?
+            .args(["-a", "--reflink=auto", defaults.as_str(), target.as_str()])
+            .run()?;
+    }
+    Ok(())
+}
+
+/// Reset the provided paths below `/etc` to the defaults.
+#[context("Resetting /etc")]
+fn reset(root: &Dir, paths: &[Utf8PathBuf], dry_run: bool) -> Result<()> {
+    let changes = diff(root)?;
+    let paths = paths
+        .iter()
+        .map(|p| relative_path(p))
+        .collect::<Result<Vec<_>>>()?;
+    let verb = if dry_run { "Would reset" } else { "Resetting" };
+    for path in paths {
+        let selected = changes_below(&changes, &path)?;
+        if selected.is_empty() {
+            println!("/etc/{path}: no changes to reset");
+            continue;
+        }
+        for change in selected {
+            let action = match change.kind {
+                ChangeKind::Added => "removing",
+                ChangeKind::Modified => "restoring",
+                ChangeKind::Removed => "recreating",
+            };
+            println!("{verb} /etc/{} ({action})", change.path);
+            if !dry_run {
+                reset_change(root, change)
+                    .with_context(|| format!("Resetting /etc/{}", change.path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the changes.
+fn print_diff(changes: &[EtcChange], format: OutputFormat) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut out, changes)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Yaml => serde_yaml::to_writer(&mut out, changes)?,
+        OutputFormat::HumanReadable => {
+            for change in changes {
+                writeln!(out, "{} /etc/{}", change.kind.symbol(), change.path)?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Implementation of the `bootc etc` subcommands.
+pub(crate) async fn etc_entrypoint(opts: EtcOpts) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    match opts {
+        EtcOpts::Diff { format } => {
+            // Ensures that we're booted into a deployment; the lock is not otherwise needed
+            let _sysroot = crate::cli::get_storage(LockMode::Shared).await?;
+            let changes = diff(root)?;
+            print_diff(&changes, format.unwrap_or(OutputFormat::HumanReadable))
+        }
+        EtcOpts::Reset { paths, dry_run } => {
+            // Avoid racing with the creation of a new deployment, which copies /etc
+            let _sysroot = crate::cli::get_storage(LockMode::Exclusive).await?;
+            reset(root, &paths, dry_run)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std::fs::PermissionsExt;
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path(Utf8Path::new("/etc/chrony.conf")).unwrap(),
+            "chrony.conf"
+        );
+        assert_eq!(
+            relative_path(Utf8Path::new("/etc/NetworkManager/conf.d")).unwrap(),
+            "NetworkManager/conf.d"
+        );
+        for invalid in [
+            "/etc",
+            "/etc/",
+            "etc/hostname",
+            "/var/lib/foo",
+            "/etc/../usr",
+        ] {
+            assert!(relative_path(Utf8Path::new(invalid)).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_diff() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        for d in [DEFAULTS, ETC] {
+            td.create_dir_all(format!("{d}/ssh"))?;
+            td.create_dir_all(format!("{d}/removed.d"))?;
+            td.write(format!("{d}/hostname"), "default\n")?;
+            td.write(format!("{d}/ssh/sshd_config"), "PermitRootLogin no\n")?;
+            td.write(format!("{d}/removed.d/a.conf"), "a")?;
+            td.write(format!("{d}/shadow"), "root:!::0:99999:7:::\n")?;
+            td.symlink("../usr/share/zoneinfo/UTC", format!("{d}/localtime"))?;
+        }
+        // Unchanged trees
+        assert!(diff(td)?.is_empty());
+
+        td.write("etc/ssh/sshd_config", "PermitRootLogin yes\n")?;
+        td.remove_file("etc/localtime")?;
+        td.symlink("../usr/share/zoneinfo/Europe/Berlin", "etc/localtime")?;
+        td.set_permissions("etc/shadow", cap_std::fs::Permissions::from_mode(0o000))?;
+        td.remove_file("etc/hostname")?;
+        td.remove_all_optional("etc/removed.d")?;
+        td.create_dir_all("etc/added.d")?;
+        td.write("etc/added.d/b.conf", "b")?;
+        td.write("etc/motd", "hello")?;
+
+        let changes = diff(td)?;
+        let changes = changes
+            .iter()
+            .map(|c| (c.kind, c.path.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                (ChangeKind::Added, "added.d"),
+                (ChangeKind::Removed, "hostname"),
+                (ChangeKind::Modified, "localtime"),
+                (ChangeKind::Added, "motd"),
+                (ChangeKind::Removed, "removed.d"),
+                (ChangeKind::Modified, "shadow"),
+                (ChangeKind::Modified, "ssh/sshd_config"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_xattrs() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        for d in [DEFAULTS, ETC] {
+            td.create_dir_all(format!("{d}/conf.d"))?;
+            td.write(format!("{d}/conf.d/a.conf"), "a")?;
+        }
+        let set = |path: &str, value: &[u8]| {
+            rustix::fs::lsetxattr(
+                proc_path(td, path),
+                "user.bootc.test",
+                value,
+                rustix::fs::XattrFlags::empty(),
+            )
+        };
+        // User xattrs are not supported by all filesystems, e.g. tmpfs
+        if let Err(rustix::io::Errno::OPNOTSUPP) = set("usr/etc/conf.d", b"1") {
+            return Ok(());
+        }
+        set("usr/etc/conf.d/a.conf", b"1")?;
+        set("etc/conf.d/a.conf", b"1")?;
+        let changes = diff(td)?;
+        let changes = changes
+            .iter()
+            .map(|c| (c.kind, c.path.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, [(ChangeKind::Modified, "conf.d")]);
+
+        reset_change(
+            td,
+            &EtcChange {
+                kind: ChangeKind::Modified,
+                path: "conf.d".into(),
+            },
+        )?;
+        assert!(diff(td)?.is_empty());
+
+        set("etc/conf.d/a.conf", b"2")?;
+        let changes = diff(td)?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "conf.d/a.conf");
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes_below() {
+        let change = |kind, path: &str| EtcChange {
+            kind,
+            path: path.into(),
+        };
+        let changes = [
+            change(ChangeKind::Added, "added.d"),
+            change(ChangeKind::Modified, "ssh"),
+            change(ChangeKind::Modified, "ssh/sshd_config"),
+            change(ChangeKind::Removed, "sshd_config.d"),
+        ];
+        let paths = |p: &str| {
+            changes_below(&changes, Utf8Path::new(p))
+                .unwrap()
+                .into_iter()
+                .map(|c| c.path.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths("ssh"), ["ssh", "ssh/sshd_config"]);
+        assert_eq!(paths("ssh/sshd_config"), ["ssh/sshd_config"]);
+        // Not a prefix by path component
+        assert_eq!(paths("sshd_config.d"), ["sshd_config.d"]);
+        assert!(paths("hostname").is_empty());
+        assert!(changes_below(&changes, Utf8Path::new("added.d/a.conf")).is_err());
+
+        let json = serde_json::to_string(&changes[0]).unwrap();
+        assert_eq!(json, r#"{"kind":"added","path":"/etc/added.d"}"#);
+    }
+}
@@ -8,6 +8,7 @@ use ostree_ext::container_utils::is_ostree_booted_in;
 use rustix::{fd::AsFd, fs::StatVfsMountFlags};
 
 const EDIT_UNIT: &str = "bootc-fstab-edit.service";
+const BOOT_COMPLETE_UNIT: &str = "bootc-boot-complete.service";
 const FSTAB_ANACONDA_STAMP: &str = "Created by anaconda";
 pub(crate) const BOOTC_EDITED_STAMP: &str = "Updated by bootc-fstab-edit.service";
 
@@ -47,8 +48,31 @@ pub(crate) fn fstab_generator_impl(root: &Dir, unit_dir: &Dir) -> Result<bool> {
     Ok(false)
 }
 
+/// Enable the unit marking the boot as successful, which disarms the boot
+/// counter and refreshes the update notifications.
+#[context("Enabling {BOOT_COMPLETE_UNIT}")]
+pub(crate) fn boot_complete_generator(root: &Dir, unit_dir: &Dir) -> Result<bool> {
+    if !is_ostree_booted_in(root)? {
+        return Ok(false);
+    }
+    let target = "multi-user.target.wants";
+    unit_dir.create_dir_all(target)?;
+    unit_dir.symlink(
+        &format!("/usr/lib/systemd/system/{BOOT_COMPLETE_UNIT}"),
+        &format!("{target}/{BOOT_COMPLETE_UNIT}"),
+    )?;
+    Ok(true)
+}
+
 /// Main entrypoint for the generator
 pub(crate) fn generator(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    let enabled = boot_complete_generator(root, unit_dir)?;
+    tracing::trace!("Enabled {BOOT_COMPLETE_UNIT}: {enabled}");
+    // A broken bound-images.d shouldn't prevent the rest of the generator from running
+    match crate::imgstorage::generator(root, unit_dir) {
+        Ok(mounted) => tracing::trace!("Mounted image storage: {mounted}"),
+        Err(e) => tracing::error!("{e:#}"),
+    }
     // Right now we only do something if the root is a read-only overlayfs (a composefs really)
     let st = rustix::fs::fstatfs(root.as_fd())?;
     if st.f_type != libc::OVERLAYFS_SUPER_MAGIC {
@@ -134,6 +158,37 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_boot_complete_generator() -> Result<()> {
+        let tempdir = fixture()?;
+        let unit_dir = &tempdir.open_dir("run/systemd/system")?;
+        assert!(!boot_complete_generator(&tempdir, unit_dir)?);
+        assert_eq!(unit_dir.entries()?.count(), 0);
+
+        tempdir.atomic_write(OSTREE_BOOTED, "ostree booted")?;
+        assert!(boot_complete_generator(&tempdir, unit_dir)?);
+        let link = unit_dir.read_link(format!("multi-user.target.wants/{BOOT_COMPLETE_UNIT}"))?;
+        assert_eq!(
+            link.to_str().unwrap(),
+            "/usr/lib/systemd/system/bootc-boot-complete.service"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generator_invalid_bound_images() -> Result<()> {
+        let tempdir = fixture()?;
+        let unit_dir = &tempdir.open_dir("run/systemd/system")?;
+        tempdir.atomic_write(OSTREE_BOOTED, "ostree booted")?;
+        tempdir.create_dir_all("usr/lib/bootc/bound-images.d")?;
+        tempdir.atomic_write("usr/lib/bootc/bound-images.d/foo.image", "[Image]\n")?;
+        // The error is logged, and the other units are still generated
+        generator(&tempdir, unit_dir)?;
+        unit_dir.read_link(format!("multi-user.target.wants/{BOOT_COMPLETE_UNIT}"))?;
+        assert!(!unit_dir.try_exists("var-lib-bootc-storage.mount")?);
+        Ok(())
+    }
+
     #[test]
     fn test_generator_fstab_idempotent() -> Result<()> {
         let anaconda_fstab = indoc::indoc! { "
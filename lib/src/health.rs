@@ -0,0 +1,188 @@
+//! # Health checks
+//!
+//! After booting a new deployment with boot counting enabled, the checks in
+//! `/usr/lib/bootc/health.d` are run before the boot is marked as successful.
+//! Entries are run in lexicographic order of their names:
+//!
+//! - An entry named `*.service` (typically a symbolic link to the unit file)
+//!   names a systemd unit, which is started and waited for if it is
+//!   `Type=oneshot`; the check fails if the unit does.
+//! - Any other executable file is run directly; the check fails if it exits
+//!   with a non-zero status.
+//!
+//! Other entries are ignored with a warning.  A check which does not complete
+//! within [`CHECK_TIMEOUT`] is killed (or its unit stopped) and fails.
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+/// The directory containing health checks, relative to the root.
+const HEALTH_DIR: &str = "usr/lib/bootc/health.d";
+/// The time after which a check is considered to have failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// A health check.
+#[derive(Debug, PartialEq, Eq)]
+enum Check {
+    /// A systemd unit to start
+    Unit(String),
+    /// An executable to run
+    Executable(String),
+}
+
+impl Check {
+    fn name(&self) -> &str {
+        match self {
+            Check::Unit(name) | Check::Executable(name) => name,
+        }
+    }
+}
+
+/// Find the health checks in the provided root, sorted by name.
+fn list_checks(root: &Dir) -> Result<Vec<Check>> {
+    let Some(d) = root.open_dir_optional(HEALTH_DIR)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            tracing::warn!("Ignoring non-UTF-8 health check name: {name:?}");
+            continue;
+        };
+        if name.ends_with(".service") {
+            r.push(Check::Unit(name.to_owned()));
+            continue;
+        }
+        // Follow symbolic links, e.g. to an executable elsewhere in /usr
+        let metadata = d.metadata(name)?;
+        if metadata.is_file() && metadata.mode() & 0o111 != 0 {
+            r.push(Check::Executable(name.to_owned()));
+        } else {
+            tracing::warn!("Ignoring non-executable health check: /{HEALTH_DIR}/{name}");
+        }
+    }
+    r.sort_by(|a, b| a.name().cmp(b.name()));
+    Ok(r)
+}
+
+/// Run a command, killing it if it does not exit within the timeout.
+fn run_with_timeout(mut cmd: Command, timeout: Duration) -> Result<()> {
+    let mut child = cmd.spawn().with_context(|| format!("Spawning {cmd:?}"))?;
+    let deadline = Instant::now() + timeout;
+    let st = loop {
+        if let Some(st) = child.try_wait()? {
+            break st;
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            anyhow::bail!("Timed out after {}s", timeout.as_secs());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    if !st.success() {
+        anyhow::bail!("Failed: {st}");
+    }
+    Ok(())
+}
+
+/// Run a health check from the provided root.
+fn run_check(root: &Dir, check: &Check, timeout: Duration) -> Result<()> {
+    match check {
+        Check::Unit(name) => {
+            let mut cmd = Command::new("systemctl");
+            cmd.args(["start", name.as_str()]);
+            let r = run_with_timeout(cmd, timeout);
+            if r.is_err() {
+                // Don't leave a hung check running
+                let _ = Command::new("systemctl")
+                    .args(["stop", name.as_str()])
+                    .status();
+            }
+            r
+        }
+        Check::Executable(name) => {
+            // Relative to the root, which is the working directory
+            let mut cmd = Command::new(format!("./{HEALTH_DIR}/{name}"));
+            cmd.cwd_dir(root.try_clone()?);
+            run_with_timeout(cmd, timeout)
+        }
+    }
+}
+
+/// Run all health checks in the provided root, returning the names of those which failed.
+#[context("Running health checks")]
+pub(crate) fn run(root: &Dir) -> Result<Vec<String>> {
+    let checks = list_checks(root)?;
+    let mut failed = Vec::new();
+    for check in checks {
+        let name = check.name();
+        println!("Running health check: {name}");
+        if let Err(e) =
+            run_check(root, &check, CHECK_TIMEOUT).with_context(|| format!("Health check {name}"))
+        {
+            crate::journal::journal_print(libsystemd::logging::Priority::Error, &format!("{e:#}"));
+            failed.push(name.to_owned());
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std::fs::PermissionsExt;
+    use cap_std_ext::cap_std;
+
+    use super::*;
+
+    #[test]
+    fn test_list_checks() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(list_checks(td)?.is_empty());
+        td.create_dir_all(HEALTH_DIR)?;
+        let d = td.open_dir(HEALTH_DIR)?;
+        d.write("20-network", "#!/bin/sh\n")?;
+        d.set_permissions("20-network", cap_std::fs::Permissions::from_mode(0o755))?;
+        d.write("README", "not a check")?;
+        d.write("10-app.service", "")?;
+        d.symlink("20-network", "30-link")?;
+        assert_eq!(
+            list_checks(td)?,
+            [
+                Check::Unit("10-app.service".into()),
+                Check::Executable("20-network".into()),
+                Check::Executable("30-link".into()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_check() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all(HEALTH_DIR)?;
+        let d = td.open_dir(HEALTH_DIR)?;
+        for (name, script) in [
+            ("ok", "#!/bin/sh\nexit 0\n"),
+            ("fail", "#!/bin/sh\nexit 1\n"),
+            ("hang", "#!/bin/sh\nexec sleep 60\n"),
+        ] {
+            d.write(name, script)?;
+            d.set_permissions(name, cap_std::fs::Permissions::from_mode(0o755))?;
+        }
+        let timeout = Duration::from_secs(1);
+        run_check(td, &Check::Executable("ok".into()), timeout)?;
+        assert!(run_check(td, &Check::Executable("fail".into()), timeout).is_err());
+        let err = run_check(td, &Check::Executable("hang".into()), timeout).unwrap_err();
+        assert!(err.to_string().contains("Timed out"));
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+//! # Update hooks
+//!
+//! Executables in `/usr/lib/bootc/hooks/<hook>.d` (of the booted deployment)
+//! are run around updates, in lexicographic order of their names, with a JSON
+//! [`HookContext`] on stdin:
+//!
+//! - `pre-upgrade`: before a fetched image is deployed (by `bootc upgrade`,
+//!   `bootc switch` or `bootc edit`); a failure aborts the update.
+//! - `post-stage`: after the new deployment has been staged.
+//! - `post-rollback`: after the boot order was changed by a rollback, including
+//!   an automatic one.
+//!
+//! Failures of the `post-*` hooks are logged, but do not fail the operation,
+//! which has already happened.
+//!
+//! Hooks run while bootc holds the sysroot lock, in its private mount
+//! namespace; hence they cannot invoke bootc themselves (which fails
+//! immediately, rather than waiting for the lock forever).  Instead, the
+//! context includes the image references, versions and digests.
+
+use std::io::{Seek, Write};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Serialize;
+
+use crate::spec::ImageStatus;
+
+/// The directory containing hooks, relative to the root.
+const HOOKS_DIR: &str = "usr/lib/bootc/hooks";
+/// The environment variable set to the name of the hook being run.
+pub(crate) const HOOK_ENV: &str = "BOOTC_HOOK";
+
+/// The points at which hooks are run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(into = "&'static str")]
+pub(crate) enum Hook {
+    /// Before deploying an update
+    PreUpgrade,
+    /// After staging an update
+    PostStage,
+    /// After a rollback
+    PostRollback,
+}
+
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Hook::PreUpgrade => "pre-upgrade",
+            Hook::PostStage => "post-stage",
+            Hook::PostRollback => "post-rollback",
+        }
+    }
+}
+
+impl From<Hook> for &'static str {
+    fn from(hook: Hook) -> Self {
+        hook.name()
+    }
+}
+
+/// The context passed to hooks on stdin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HookContext {
+    /// The hook being run
+    pub(crate) hook: Hook,
+    /// The image reference of the booted deployment
+    pub(crate) old_image: Option<String>,
+    /// The version of the image of the booted deployment
+    pub(crate) old_version: Option<String>,
+    /// The digest of the image of the booted deployment
+    pub(crate) old_image_digest: Option<String>,
+    /// The image reference of the deployment which will be booted next
+    pub(crate) new_image: Option<String>,
+    /// The version of the image which will be booted next
+    pub(crate) new_version: Option<String>,
+    /// The digest of the image which will be booted next
+    pub(crate) new_image_digest: Option<String>,
+}
+
+impl HookContext {
+    /// Create the context from the status of the booted image and of the
+    /// image which will be booted next.
+    pub(crate) fn new(hook: Hook, old: Option<&ImageStatus>, new: Option<&ImageStatus>) -> Self {
+        Self {
+            hook,
+            old_image: old.map(|i| format!("{:#}", i.image)),
+            old_version: old.and_then(|i| i.version.clone()),
+            old_image_digest: old.map(|i| i.image_digest.clone()),
+            new_image: new.map(|i| format!("{:#}", i.image)),
+            new_version: new.and_then(|i| i.version.clone()),
+            new_image_digest: new.map(|i| i.image_digest.clone()),
+        }
+    }
+}
+
+/// Find the executables for a hook in the provided root, sorted by name.
+fn list_hooks(root: &Dir, hook: Hook) -> Result<Vec<String>> {
+    let dir = format!("{HOOKS_DIR}/{}.d", hook.name());
+    let Some(d) = root.open_dir_optional(&dir)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            tracing::warn!("Ignoring non-UTF-8 hook name: {name:?}");
+            continue;
+        };
+        // Follow symbolic links, e.g. to an executable elsewhere in /usr
+        let metadata = d.metadata(name)?;
+        if metadata.is_file() && metadata.mode() & 0o111 != 0 {
+            r.push(format!("/{dir}/{name}"));
+        } else {
+            tracing::warn!("Ignoring non-executable hook: /{dir}/{name}");
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Run an executable with the serialized context on stdin.
+fn run_one(path: &str, hook: Hook, context: &[u8]) -> Result<()> {
+    // Use a file rather than a pipe, so hooks don't need to read all of it
+    let mut stdin = tempfile::tempfile()?;
+    stdin.write_all(context)?;
+    stdin.rewind()?;
+    Command::new(path)
+        .env(HOOK_ENV, hook.name())
+        .stdin(Stdio::from(stdin))
+        .run()
+}
+
+/// Run the executables of a hook, stopping at the first failure.
+#[context("Running {} hooks", ctx.hook.name())]
+fn run_hooks(root: &Dir, ctx: &HookContext) -> Result<()> {
+    let hooks = list_hooks(root, ctx.hook)?;
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let buf = serde_json::to_vec(ctx)?;
+    for path in hooks {
+        println!("Running hook: {path}");
+        run_one(&path, ctx.hook, &buf).with_context(|| format!("Hook {path}"))?;
+    }
+    Ok(())
+}
+
+/// Run the hooks of the booted root; an error is returned only for
+/// [`Hook::PreUpgrade`], failures of the other hooks are logged.
+pub(crate) fn run(ctx: &HookContext) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    let r = run_hooks(root, ctx);
+    match (ctx.hook, r) {
+        (Hook::PreUpgrade, r) => r,
+        (_, Err(e)) => {
+            crate::journal::journal_print(
+                libsystemd::logging::Priority::Warning,
+                &format!("{e:#}"),
+            );
+            Ok(())
+        }
+        (_, Ok(())) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std::fs::PermissionsExt;
+    use cap_std_ext::cap_std;
+
+    use super::*;
+
+    #[test]
+    fn test_list_hooks() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(list_hooks(td, Hook::PreUpgrade)?.is_empty());
+        let dir = "usr/lib/bootc/hooks/pre-upgrade.d";
+        td.create_dir_all(dir)?;
+        let d = td.open_dir(dir)?;
+        for name in ["20-drain", "10-stop-app"] {
+            d.write(name, "#!/bin/sh\n")?;
+            d.set_permissions(name, cap_std::fs::Permissions::from_mode(0o755))?;
+        }
+        d.write("README", "not a hook")?;
+        assert_eq!(
+            list_hooks(td, Hook::PreUpgrade)?,
+            [
+                "/usr/lib/bootc/hooks/pre-upgrade.d/10-stop-app",
+                "/usr/lib/bootc/hooks/pre-upgrade.d/20-drain"
+            ]
+        );
+        assert!(list_hooks(td, Hook::PostStage)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_context() {
+        let old = ImageStatus {
+            image: crate::spec::ImageReference {
+                image: "quay.io/example/os:latest".into(),
+                transport: "registry".into(),
+                signature: None,
+                mirrors: Vec::new(),
+            },
+            version: Some("42.1".into()),
+            timestamp: None,
+            image_digest: "sha256:aaaa".into(),
+            kernel: None,
+            changelog: None,
+            fetched_from: None,
+        };
+        let context = HookContext::new(Hook::PostStage, Some(&old), None);
+        let v = serde_json::to_value(&context).unwrap();
+        assert_eq!(
+            v,
+            serde_json::json!({
+                "hook": "post-stage",
+                "oldImage": "quay.io/example/os:latest",
+                "oldVersion": "42.1",
+                "oldImageDigest": "sha256:aaaa",
+                "newImage": null,
+                "newVersion": null,
+                "newImageDigest": null,
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Hook::PreUpgrade).unwrap(),
+            "pre-upgrade"
+        );
+    }
+}
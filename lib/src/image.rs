@@ -9,11 +9,12 @@ use clap::ValueEnum;
 use comfy_table::{presets::NOTHING, Table};
 use fn_error_context::context;
 use ostree_ext::container::{ImageReference, Transport};
+use ostree_ext::sysroot::LockMode;
 use serde::Serialize;
 
 use crate::{
     boundimage::query_bound_images,
-    cli::{ImageListFormat, ImageListType},
+    cli::{ImageInspectFormat, ImageListFormat, ImageListType},
 };
 
 /// The name of the image we push to containers-storage if nothing is specified.
@@ -74,7 +75,7 @@ async fn list_images(list_type: ImageListType) -> Result<Vec<ImageOutput>> {
         if ostree_ext::container_utils::running_in_container() {
             None
         } else {
-            Some(crate::cli::get_storage().await?)
+            Some(crate::cli::get_storage(LockMode::Shared).await?)
         };
 
     Ok(match (list_type, sysroot) {
@@ -127,7 +128,7 @@ pub(crate) async fn list_entrypoint(
 #[context("Pushing image")]
 pub(crate) async fn push_entrypoint(source: Option<&str>, target: Option<&str>) -> Result<()> {
     let transport = Transport::ContainerStorage;
-    let sysroot = crate::cli::get_storage().await?;
+    let sysroot = crate::cli::get_storage(LockMode::Exclusive).await?;
 
     let repo = &sysroot.repo();
 
@@ -166,6 +167,23 @@ pub(crate) async fn push_entrypoint(source: Option<&str>, target: Option<&str>)
     Ok(())
 }
 
+/// Implementation of `bootc image inspect`.
+#[context("Inspecting image")]
+pub(crate) fn inspect_entrypoint(
+    storage: &crate::imgstorage::Storage,
+    image: &str,
+    format: ImageInspectFormat,
+) -> Result<()> {
+    let inspect = storage.inspect(image)?;
+    match format {
+        ImageInspectFormat::Json => {
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &inspect)?;
+        }
+    }
+    Ok(())
+}
+
 /// Thin wrapper for invoking `podman image <X>` but set up for our internal
 /// image store (as distinct from /var/lib/containers default).
 pub(crate) async fn imgcmd_entrypoint(
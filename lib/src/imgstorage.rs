@@ -4,25 +4,34 @@
 //! `/sysroot/ostree`.
 //!
 //! This containers-storage: which canonically lives in `/sysroot/ostree/bootc`.
+//!
+//! When invoked unprivileged (e.g. for development workflows), a per-user
+//! storage root following the XDG base directory specification is used instead.
 
 use std::collections::HashSet;
 use std::io::Seek;
-use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use bootc_utils::{AsyncCommandRunExt, CommandRunExt, ExitStatusExt};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std;
 use cap_std_ext::cap_std::fs::Dir;
 use cap_std_ext::cap_tempfile::TempDir;
 use cap_std_ext::cmdext::CapStdExtCommandExt;
 use cap_std_ext::dirext::CapStdExtDirExt;
 use fn_error_context::context;
+use ostree_ext::oci_spec::image::{ImageConfiguration, ImageManifest};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::os::fd::OwnedFd;
 use tokio::process::Command as AsyncCommand;
 
+use crate::mount::{BindMountUnit, MountNamespace};
+use crate::progress_jsonl::{Event, ProgressWriter};
+use crate::spec::StorageCheck;
+
 // Pass only 100 args at a time just to avoid potentially overflowing argument
 // vectors; not that this should happen in reality, but just in case.
 const SUBCMD_ARGV_CHUNKING: usize = 100;
@@ -32,14 +41,175 @@ const SUBCMD_ARGV_CHUNKING: usize = 100;
 /// /proc/self/fd/N trick because it currently breaks due
 /// to how the untar process is forked in the child.
 pub(crate) const STORAGE_ALIAS_DIR: &str = "/run/bootc/storage";
+/// Stable path at which the storage is exposed read-only on the booted host,
+/// when it is in use.
+pub(crate) const STORAGE_HOST_PATH: &str = "/var/lib/bootc/storage";
 /// We pass this via /proc/self/fd to the child process.
 const STORAGE_RUN_FD: i32 = 3;
 
 /// The path to the storage, relative to the physical system root.
 pub(crate) const SUBPATH: &str = "ostree/bootc/storage";
+/// The record of the most recent storage check, relative to the sysroot.
+const CHECK_RECORD: &str = "ostree/bootc/last-check.json";
 /// The path to the "runroot" with transient runtime state; this is
 /// relative to the /run directory
 const RUNROOT: &str = "bootc/storage";
+/// A file in the runroot recording the boot it was created in; state from
+/// previous boots is stale and is removed.
+const RUNROOT_BOOT_ID: &str = ".bootc-boot-id";
+/// The kernel interface for the current boot ID.
+const PROC_BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+/// A file which is created by containers-storage when initializing a root.
+const STORAGE_LOCK: &str = "storage.lock";
+/// The path to the per-user storage, relative to `$XDG_DATA_HOME`
+/// or `$XDG_RUNTIME_DIR` (for the runroot).
+const USER_SUBPATH: &str = "bootc/storage";
+/// A containers-storage configuration drop-in (relative to a root) which
+/// exposes our storage as a read-only additional image store for the
+/// default storage, so that e.g. bound images can be used by podman without copying.
+/// It is written at boot by [`STORAGE_CONFIG_UNIT`], so that nothing is persisted in `/etc`.
+const ADDITIONAL_STORE_DROPIN: &str = "run/containers/storage.conf.d/50-bootc.conf";
+/// The unit which writes [`ADDITIONAL_STORE_DROPIN`]; a generator may only
+/// write into its output directories.
+const STORAGE_CONFIG_UNIT: &str = "bootc-storage-config.service";
+/// The layer metadata for the overlay driver, relative to the storage root.
+const OVERLAY_LAYERS_JSON: &str = "overlay-layers/layers.json";
+
+/// The image metadata for the overlay driver, relative to the storage root.
+const OVERLAY_IMAGES_JSON: &str = "overlay-images/images.json";
+/// The directory holding per-image data, relative to the storage root.
+const OVERLAY_IMAGES: &str = "overlay-images";
+/// The key for the manifest in the image's "big data".
+const BIGDATA_MANIFEST: &str = "manifest";
+
+/// An entry in [`OVERLAY_IMAGES_JSON`].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ImageEntry {
+    id: String,
+    digest: Option<String>,
+    #[serde(default)]
+    names: Vec<String>,
+}
+
+impl ImageEntry {
+    /// Returns true if this entry is referenced by the provided name or (possibly
+    /// truncated) ID.
+    fn matches(&self, image: &str) -> bool {
+        if self.names.iter().any(|n| n == image) {
+            return true;
+        }
+        // Like podman, default to the latest tag
+        let latest = format!("{image}:latest");
+        if self.names.iter().any(|n| *n == latest) {
+            return true;
+        }
+        let id = image.strip_prefix("sha256:").unwrap_or(image);
+        id.as_bytes().len() >= 12 && self.id.starts_with(id)
+    }
+}
+
+/// Metadata for an image in the storage.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct InspectedImage {
+    /// The storage-local image ID
+    pub(crate) id: String,
+    /// The names referencing this image
+    pub(crate) names: Vec<String>,
+    /// The manifest digest
+    pub(crate) digest: Option<String>,
+    /// The image manifest
+    pub(crate) manifest: ImageManifest,
+    /// The image configuration
+    pub(crate) config: ImageConfiguration,
+}
+
+/// The filename containers-storage uses for a "big data" item: keys which
+/// aren't purely lowercase alphanumeric (and `.`) are encoded.
+fn bigdata_filename(key: &str) -> String {
+    if key
+        .bytes()
+        .all(|c| c == b'.' || c.is_ascii_digit() || c.is_ascii_lowercase())
+    {
+        key.to_owned()
+    } else {
+        format!("={}", ostree_ext::glib::base64_encode(key.as_bytes()))
+    }
+}
+
+/// An entry in [`OVERLAY_LAYERS_JSON`].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct LayerEntry {
+    /// The uncompressed digest of the layer, if known
+    diff_digest: Option<String>,
+}
+
+/// How podman finds our storage root.
+#[derive(Debug)]
+enum StorageMode {
+    /// The system storage, which lives in the physical root and is only
+    /// reachable via file descriptors. We bind mount it to [`STORAGE_ALIAS_DIR`]
+    /// in a new mount namespace for the child process.
+    System,
+    /// Per-user storage. This already lives at a stable absolute path
+    /// owned by the invoking user, so we can just pass it to podman
+    /// directly without needing any privileges (or a user namespace)
+    /// for the bind mount.
+    User {
+        /// Absolute path to the storage root
+        root: Utf8PathBuf,
+        /// Absolute path to the runroot
+        runroot: Utf8PathBuf,
+    },
+}
+
+impl StorageMode {
+    /// The absolute path to the storage root as seen by podman.
+    fn root(&self) -> &str {
+        match self {
+            StorageMode::System => STORAGE_ALIAS_DIR,
+            StorageMode::User { root, .. } => root.as_str(),
+        }
+    }
+
+    /// Configure the command to see our storage, returning the runroot path
+    /// as seen by the child.
+    fn prepare(&self, cmd: &mut Command, storage_root: &Dir, run_root: &Dir) -> Result<String> {
+        match self {
+            StorageMode::System => {
+                bind_storage_roots(cmd, storage_root, run_root)?;
+                Ok(format!("/proc/self/fd/{STORAGE_RUN_FD}"))
+            }
+            StorageMode::User { runroot, .. } => Ok(runroot.to_string()),
+        }
+    }
+}
+
+/// Compute the per-user storage root and runroot from the provided environment
+/// lookup function, following the XDG base directory specification.
+fn user_storage_paths(
+    getenv: impl Fn(&str) -> Option<String>,
+    uid: u32,
+) -> Result<(Utf8PathBuf, Utf8PathBuf)> {
+    // Per the spec, relative paths are invalid and should be ignored
+    let getenv_abs = |k: &str| getenv(k).filter(|v| v.starts_with('/'));
+    let data_home = match getenv_abs("XDG_DATA_HOME") {
+        Some(v) => Utf8PathBuf::from(v),
+        None => {
+            let home = getenv_abs("HOME").ok_or_else(|| {
+                anyhow::anyhow!("Neither XDG_DATA_HOME nor HOME are set to an absolute path")
+            })?;
+            Utf8Path::new(&home).join(".local/share")
+        }
+    };
+    let runtime_dir = getenv_abs("XDG_RUNTIME_DIR")
+        .map(Utf8PathBuf::from)
+        .unwrap_or_else(|| format!("/run/user/{uid}").into());
+    Ok((data_home.join(USER_SUBPATH), runtime_dir.join(USER_SUBPATH)))
+}
+
 pub(crate) struct Storage {
     /// The root directory
     sysroot: Dir,
@@ -48,6 +218,8 @@ pub(crate) struct Storage {
     #[allow(dead_code)]
     /// Our runtime state
     run: Dir,
+    /// How podman is pointed at the storage root
+    mode: StorageMode,
     /// Disallow using this across multiple threads concurrently; while we
     /// have internal locking in podman, in the future we may change how
     /// things work here. And we don't have a use case right now for
@@ -60,11 +232,9 @@ pub(crate) enum PullMode {
     /// Pull only if the image is not present
     IfNotExists,
     /// Always check for an update
-    #[allow(dead_code)]
     Always,
 }
 
-#[allow(unsafe_code)]
 #[context("Binding storage roots")]
 fn bind_storage_roots(cmd: &mut Command, storage_root: &Dir, run_root: &Dir) -> Result<()> {
     // podman requires an absolute path, for two reasons right now:
@@ -74,56 +244,184 @@ fn bind_storage_roots(cmd: &mut Command, storage_root: &Dir, run_root: &Dir) ->
     // We create a new mount namespace, which also has the helpful side effect
     // of automatically cleaning up the global bind mount that the storage stack
     // creates.
-
-    let storage_root = Arc::new(storage_root.try_clone().context("Cloning storage root")?);
+    MountNamespace::new()
+        .bind_dir(storage_root, STORAGE_ALIAS_DIR.into())?
+        .apply_to(cmd);
     let run_root: Arc<OwnedFd> = Arc::new(run_root.try_clone().context("Cloning runroot")?.into());
-    // SAFETY: All the APIs we call here are safe to invoke between fork and exec.
-    unsafe {
-        cmd.pre_exec(move || {
-            use rustix::fs::{Mode, OFlags};
-            // For reasons I don't understand, we can't just `mount("/proc/self/fd/N", "/path/to/target")`
-            // but it *does* work to fchdir(fd) + mount(".", "/path/to/target").
-            // I think it may be that mount doesn't like operating on the magic links?
-            // This trick only works if we set our working directory to the target *before*
-            // creating the new namespace too.
-            //
-            // I think we may be hitting this:
-            //
-            // "       EINVAL A bind operation (MS_BIND) was requested where source referred a mount namespace magic link (i.e., a /proc/pid/ns/mnt magic link or a bind mount to such a link) and the propagation type of the parent mount of target was
-            // MS_SHARED, but propagation of the requested bind mount could lead to a circular dependency that might prevent the mount namespace from ever being freed."
-            //
-            // But...how did we avoid that circular dependency by using the process cwd?
-            //
-            // I tried making the mounts recursively private, but that didn't help.
-            let oldwd = rustix::fs::open(
-                ".",
-                OFlags::DIRECTORY | OFlags::CLOEXEC | OFlags::RDONLY,
-                Mode::empty(),
-            )?;
-            rustix::process::fchdir(&storage_root)?;
-            rustix::thread::unshare(rustix::thread::UnshareFlags::NEWNS)?;
-            rustix::mount::mount_bind(".", STORAGE_ALIAS_DIR)?;
-            rustix::process::fchdir(&oldwd)?;
-            Ok(())
-        })
-    };
     cmd.take_fd_n(run_root, STORAGE_RUN_FD);
     Ok(())
 }
 
-fn new_podman_cmd_in(storage_root: &Dir, run_root: &Dir) -> Result<Command> {
+fn new_podman_cmd_in(mode: &StorageMode, storage_root: &Dir, run_root: &Dir) -> Result<Command> {
     let mut cmd = Command::new("podman");
-    bind_storage_roots(&mut cmd, storage_root, run_root)?;
-    let run_root = format!("/proc/self/fd/{}", STORAGE_RUN_FD);
-    cmd.args(["--root", STORAGE_ALIAS_DIR, "--runroot", run_root.as_str()]);
+    let run_root = mode.prepare(&mut cmd, storage_root, run_root)?;
+    cmd.args(["--root", mode.root(), "--runroot", run_root.as_str()]);
     Ok(cmd)
 }
 
+/// Generate the contents of [`ADDITIONAL_STORE_DROPIN`].
+fn additional_image_store_config() -> String {
+    format!(
+        "# Automatically generated by bootc; do not edit.\n\
+         # Exposes the bootc container storage read-only to the default storage.\n\
+         [storage.options]\n\
+         additionalimagestores = [\"{STORAGE_HOST_PATH}\"]\n"
+    )
+}
+
+/// Generate the unit writing [`ADDITIONAL_STORE_DROPIN`] once the storage is
+/// mounted at [`STORAGE_HOST_PATH`].
+fn storage_config_unit() -> String {
+    format!(
+        "[Unit]\n\
+         Description=Expose bootc storage as an additional image store\n\
+         Documentation=man:bootc(8)\n\
+         DefaultDependencies=no\n\
+         RequiresMountsFor={STORAGE_HOST_PATH}\n\
+         Before=local-fs.target shutdown.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         RemainAfterExit=yes\n\
+         ExecStart=bootc internals write-storage-config\n"
+    )
+}
+
+/// Write [`ADDITIONAL_STORE_DROPIN`] into the provided root; invoked from [`STORAGE_CONFIG_UNIT`].
+#[context("Writing {ADDITIONAL_STORE_DROPIN}")]
+pub(crate) fn write_additional_store_config(root: &Dir) -> Result<()> {
+    // SAFETY: We know there's a parent
+    let parent = Utf8Path::new(ADDITIONAL_STORE_DROPIN).parent().unwrap();
+    root.create_dir_all(parent)
+        .with_context(|| format!("Creating {parent}"))?;
+    root.atomic_write(ADDITIONAL_STORE_DROPIN, additional_image_store_config())?;
+    Ok(())
+}
+
+/// The bind mount exposing our storage read-only at [`STORAGE_HOST_PATH`].
+fn storage_bind_mount() -> BindMountUnit {
+    BindMountUnit {
+        what: Utf8Path::new("/sysroot").join(SUBPATH),
+        target: STORAGE_HOST_PATH.into(),
+        readonly: true,
+        automount: false,
+    }
+}
+
+/// Record the result of a storage check in the provided sysroot, so that it
+/// can be shown by `bootc status`.
+#[context("Writing check record")]
+pub(crate) fn write_check_record(sysroot: &Dir, record: &StorageCheck) -> Result<()> {
+    sysroot.atomic_replace_with(CHECK_RECORD, |w| {
+        serde_json::to_writer(w, record).map_err(anyhow::Error::new)
+    })
+}
+
+/// Read the result of the most recent storage check, if any.
+#[context("Reading check record")]
+pub(crate) fn read_check_record(sysroot: &Dir) -> Result<Option<StorageCheck>> {
+    let Some(f) = sysroot.open_optional(CHECK_RECORD)? else {
+        return Ok(None);
+    };
+    let r = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {CHECK_RECORD}"))?;
+    Ok(Some(r))
+}
+
+/// Called from the systemd generator: if the booted root has bound images,
+/// expose our storage read-only at [`STORAGE_HOST_PATH`], and enable the unit
+/// configuring it as an additional image store for the default storage.
+#[context("Generating image storage configuration")]
+pub(crate) fn generator(root: &Dir, unit_dir: &Dir) -> Result<bool> {
+    if !ostree_ext::container_utils::is_ostree_booted_in(root)? {
+        return Ok(false);
+    }
+    if crate::boundimage::query_bound_images(root)?.is_empty() {
+        return Ok(false);
+    }
+    crate::mount::generate_bind_mount_units(unit_dir, &[storage_bind_mount()])?;
+    unit_dir.atomic_write(STORAGE_CONFIG_UNIT, storage_config_unit())?;
+    let target = "local-fs.target.wants";
+    unit_dir.create_dir_all(target)?;
+    unit_dir.symlink(
+        format!("../{STORAGE_CONFIG_UNIT}"),
+        format!("{target}/{STORAGE_CONFIG_UNIT}"),
+    )?;
+    Ok(true)
+}
+
+/// Open the runroot in the provided run directory, creating it if missing. If
+/// it contains state from a previous boot (i.e. `run` is not actually a tmpfs),
+/// it is cleared.
+#[context("Reconciling runroot")]
+fn open_runroot(run: &Dir, boot_id: &str) -> Result<Dir> {
+    let boot_id = boot_id.trim();
+    if let Some(runroot) = run.open_dir_optional(RUNROOT)? {
+        let prev_boot_id = runroot
+            .read_to_string(RUNROOT_BOOT_ID)
+            .map(|s| s.trim().to_owned())
+            .ok();
+        if prev_boot_id.as_deref() == Some(boot_id) {
+            return Ok(runroot);
+        }
+        drop(runroot);
+        tracing::debug!("Removing stale runroot");
+        run.remove_all_optional(RUNROOT)
+            .with_context(|| format!("Removing {RUNROOT}"))?;
+    }
+    run.create_dir_all(RUNROOT)
+        .with_context(|| format!("Creating {RUNROOT}"))?;
+    let runroot = run.open_dir(RUNROOT)?;
+    runroot.atomic_write(RUNROOT_BOOT_ID, boot_id)?;
+    Ok(runroot)
+}
+
+/// Run the command, parsing its output as JSON.
+async fn run_and_parse_json<T: DeserializeOwned + Send + 'static>(mut cmd: Command) -> Result<T> {
+    cmd.stdin(Stdio::null());
+    // It's maximally convenient for us to just pipe the whole output to a tempfile
+    let mut stdout = tempfile::tempfile()?;
+    cmd.stdout(stdout.try_clone()?);
+    // Allocate stderr, which is passed to the status checker
+    let stderr = tempfile::tempfile()?;
+    cmd.stderr(stderr.try_clone()?);
+
+    // Spawn the child and wait
+    AsyncCommand::from(cmd)
+        .status()
+        .await?
+        .check_status(stderr)?;
+    // Spawn a helper thread to avoid blocking the main thread
+    // parsing JSON.
+    tokio::task::spawn_blocking(move || -> Result<_> {
+        stdout.seek(std::io::SeekFrom::Start(0))?;
+        let stdout = std::io::BufReader::new(stdout);
+        let r = serde_json::from_reader(stdout)?;
+        Ok(r)
+    })
+    .await?
+}
+
+/// Run `image inspect` via the provided `podman image` command.
+async fn inspect_image_with(mut cmd: Command, image: &str) -> Result<crate::podman::ImageInspect> {
+    cmd.args(["inspect", image]);
+    let r: Vec<crate::podman::ImageInspect> = run_and_parse_json(cmd).await?;
+    r.into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No images returned for inspect of {image}"))
+}
+
 impl Storage {
+    /// Create a `podman` Command instance prepared to operate on our alternative
+    /// root. Prefer [`Self::new_image_cmd`] where possible.
+    fn new_podman_cmd(&self) -> Result<Command> {
+        new_podman_cmd_in(&self.mode, &self.storage_root, &self.run)
+    }
+
     /// Create a `podman image` Command instance prepared to operate on our alternative
     /// root.
     pub(crate) fn new_image_cmd(&self) -> Result<Command> {
-        let mut r = new_podman_cmd_in(&self.storage_root, &self.run)?;
+        let mut r = self.new_podman_cmd()?;
         // We want to limit things to only manipulating images by default.
         r.arg("image");
         Ok(r)
@@ -136,31 +434,46 @@ impl Storage {
         Ok(())
     }
 
+    /// There's no explicit API to initialize a containers-storage:
+    /// root, simply passing a path will attempt to auto-create it.
+    /// We run "podman images" in the new root.
+    fn init_storage_root(storage_root: &Dir, run: &Dir) -> Result<()> {
+        new_podman_cmd_in(&StorageMode::System, storage_root, run)?
+            .stdout(Stdio::null())
+            .arg("images")
+            .run()
+            .context("Initializing images")
+    }
+
     #[context("Creating imgstorage")]
     pub(crate) fn create(sysroot: &Dir, run: &Dir) -> Result<Self> {
         Self::init_globals()?;
         let subpath = Utf8Path::new(SUBPATH);
         // SAFETY: We know there's a parent
         let parent = subpath.parent().unwrap();
-        if !sysroot
-            .try_exists(subpath)
+        // Clean up any leftovers from a previously interrupted creation
+        let tmp = format!("{SUBPATH}.tmp");
+        if sysroot.remove_all_optional(&tmp).context("Removing tmp")? {
+            tracing::debug!("Removed stale {tmp}");
+        }
+        if let Some(storage_root) = sysroot
+            .open_dir_optional(subpath)
             .with_context(|| format!("Querying {subpath}"))?
         {
-            let tmp = format!("{SUBPATH}.tmp");
-            sysroot.remove_all_optional(&tmp).context("Removing tmp")?;
+            // This shouldn't happen with the rename below, but could be the result of
+            // e.g. manual intervention. Because podman always sees the storage at the
+            // same (alias) path, it's safe to just re-run the initialization in place.
+            if !storage_root.try_exists(STORAGE_LOCK)? {
+                tracing::warn!("Found incompletely initialized image store; reinitializing");
+                Self::init_storage_root(&storage_root, run)?;
+            }
+        } else {
             sysroot
                 .create_dir_all(parent)
                 .with_context(|| format!("Creating {parent}"))?;
             sysroot.create_dir_all(&tmp).context("Creating tmpdir")?;
             let storage_root = sysroot.open_dir(&tmp).context("Open tmp")?;
-            // There's no explicit API to initialize a containers-storage:
-            // root, simply passing a path will attempt to auto-create it.
-            // We run "podman images" in the new root.
-            new_podman_cmd_in(&storage_root, &run)?
-                .stdout(Stdio::null())
-                .arg("images")
-                .run()
-                .context("Initializing images")?;
+            Self::init_storage_root(&storage_root, run)?;
             drop(storage_root);
             sysroot
                 .rename(&tmp, sysroot, subpath)
@@ -177,14 +490,45 @@ impl Storage {
         let storage_root = sysroot
             .open_dir(SUBPATH)
             .with_context(|| format!("Opening {SUBPATH}"))?;
-        // Always auto-create this if missing
-        run.create_dir_all(RUNROOT)
-            .with_context(|| format!("Creating {RUNROOT}"))?;
-        let run = run.open_dir(RUNROOT)?;
+        let boot_id = std::fs::read_to_string(PROC_BOOT_ID)
+            .with_context(|| format!("Reading {PROC_BOOT_ID}"))?;
+        let run = open_runroot(run, &boot_id)?;
         Ok(Self {
             sysroot: sysroot.try_clone()?,
             storage_root,
             run,
+            mode: StorageMode::System,
+            _unsync: Default::default(),
+        })
+    }
+
+    /// Open the per-user storage, creating it if necessary. This is intended
+    /// for use when invoked unprivileged, e.g. to test bound image handling
+    /// in development workflows. The storage lives in `$XDG_DATA_HOME/bootc/storage`,
+    /// with transient state in `$XDG_RUNTIME_DIR/bootc/storage`.
+    #[context("Opening user imgstorage")]
+    pub(crate) fn open_user() -> Result<Self> {
+        let uid = rustix::process::getuid().as_raw();
+        let (root, runroot) = user_storage_paths(|k| std::env::var(k).ok(), uid)?;
+        tracing::trace!("Opening user container image store at {root}");
+        let authority = cap_std::ambient_authority();
+        for d in [&root, &runroot] {
+            std::fs::create_dir_all(d).with_context(|| format!("Creating {d}"))?;
+        }
+        // Unlike the system storage, this path is stable and hence we don't
+        // need to initialize into a temporary directory; podman will
+        // auto-create the storage on first use.
+        let storage_root =
+            Dir::open_ambient_dir(&root, authority).with_context(|| format!("Opening {root}"))?;
+        let run = Dir::open_ambient_dir(&runroot, authority)
+            .with_context(|| format!("Opening {runroot}"))?;
+        // This is used for finding the global authfile
+        let sysroot = Dir::open_ambient_dir("/", authority)?;
+        Ok(Self {
+            sysroot,
+            storage_root,
+            run,
+            mode: StorageMode::User { root, runroot },
             _unsync: Default::default(),
         })
     }
@@ -193,29 +537,55 @@ impl Storage {
     pub(crate) async fn list_images(&self) -> Result<Vec<crate::podman::ImageListEntry>> {
         let mut cmd = self.new_image_cmd()?;
         cmd.args(["list", "--format=json"]);
-        cmd.stdin(Stdio::null());
-        // It's maximally convenient for us to just pipe the whole output to a tempfile
-        let mut stdout = tempfile::tempfile()?;
-        cmd.stdout(stdout.try_clone()?);
-        // Allocate stderr, which is passed to the status checker
-        let stderr = tempfile::tempfile()?;
-        cmd.stderr(stderr.try_clone()?);
-
-        // Spawn the child and wait
-        AsyncCommand::from(cmd)
-            .status()
-            .await?
-            .check_status(stderr)?;
-        // Spawn a helper thread to avoid blocking the main thread
-        // parsing JSON.
-        tokio::task::spawn_blocking(move || -> Result<_> {
-            stdout.seek(std::io::SeekFrom::Start(0))?;
-            let stdout = std::io::BufReader::new(stdout);
-            let r = serde_json::from_reader(stdout)?;
-            Ok(r)
+        run_and_parse_json(cmd).await
+    }
+
+    /// Find the image by name or ID, and return its manifest and config. This
+    /// reads the storage directly instead of invoking podman; note that we don't
+    /// take the storage locks.
+    #[context("Inspecting {image}")]
+    pub(crate) fn inspect(&self, image: &str) -> Result<InspectedImage> {
+        let f = self
+            .storage_root
+            .open_optional(OVERLAY_IMAGES_JSON)?
+            .ok_or_else(|| anyhow::anyhow!("Image not found: {image}"))?;
+        let images: Vec<ImageEntry> = serde_json::from_reader(std::io::BufReader::new(f))?;
+        let entry = images
+            .into_iter()
+            .find(|i| i.matches(image))
+            .ok_or_else(|| anyhow::anyhow!("Image not found: {image}"))?;
+        let imgdir = self
+            .storage_root
+            .open_dir(Utf8Path::new(OVERLAY_IMAGES).join(&entry.id))
+            .with_context(|| format!("Opening image {}", entry.id))?;
+        let manifest = imgdir
+            .open(bigdata_filename(BIGDATA_MANIFEST))
+            .context("Opening manifest")?;
+        let manifest = ImageManifest::from_reader(std::io::BufReader::new(manifest))
+            .context("Parsing manifest")?;
+        let config_digest = manifest.config().digest().to_string();
+        let config = imgdir
+            .open(bigdata_filename(&config_digest))
+            .with_context(|| format!("Opening config {config_digest}"))?;
+        let config = ImageConfiguration::from_reader(std::io::BufReader::new(config))
+            .context("Parsing config")?;
+        Ok(InspectedImage {
+            id: entry.id,
+            names: entry.names,
+            digest: entry.digest,
+            manifest,
+            config,
         })
-        .await?
-        .map_err(Into::into)
+    }
+
+    /// Return the uncompressed digests of all layers present in the storage.
+    #[context("Reading layers")]
+    fn layer_diff_digests(&self) -> Result<HashSet<String>> {
+        let Some(f) = self.storage_root.open_optional(OVERLAY_LAYERS_JSON)? else {
+            return Ok(Default::default());
+        };
+        let layers: Vec<LayerEntry> = serde_json::from_reader(std::io::BufReader::new(f))?;
+        Ok(layers.into_iter().filter_map(|l| l.diff_digest).collect())
     }
 
     #[context("Pruning")]
@@ -245,6 +615,44 @@ impl Storage {
         Ok(garbage)
     }
 
+    /// Verify the consistency of the storage, including the checksums of
+    /// all layers. If `repair` is set, images with corrupted layers are
+    /// removed and then re-pulled from the name they were stored with; only the
+    /// affected layers will be fetched again.
+    #[context("Checking storage")]
+    pub(crate) async fn check(&self, repair: bool) -> Result<()> {
+        let mut cmd = self.new_podman_cmd()?;
+        cmd.stdin(Stdio::null());
+        cmd.args(["system", "check"]);
+        if !repair {
+            return AsyncCommand::from(cmd).run().await;
+        }
+        let before = self.list_images().await?;
+        cmd.arg("--repair");
+        AsyncCommand::from(cmd).run().await?;
+        let after = self.list_images().await?;
+        let after = after.iter().map(|i| i.id.as_str()).collect::<HashSet<_>>();
+        let removed = before
+            .iter()
+            .filter(|i| !after.contains(i.id.as_str()))
+            .collect::<Vec<_>>();
+        if removed.is_empty() {
+            println!("No images needed to be repaired");
+            return Ok(());
+        }
+        for image in removed {
+            let Some(name) = image.names.iter().flatten().next() else {
+                // An untagged image has no source from which we could re-fetch it; it
+                // would be garbage collected anyways.
+                println!("Removed corrupted untagged image {}", image.id);
+                continue;
+            };
+            println!("Re-pulling corrupted image: {name}");
+            self.pull(name, PullMode::Always).await?;
+        }
+        Ok(())
+    }
+
     /// Return true if the image exists in the storage.
     pub(crate) async fn exists(&self, image: &str) -> Result<bool> {
         // Sadly https://docs.rs/containers-image-proxy/latest/containers_image_proxy/struct.ImageProxy.html#method.open_image_optional
@@ -282,26 +690,75 @@ impl Storage {
     }
 
     /// Copy an image from the default container storage (/var/lib/containers/)
-    /// to this storage.
+    /// to this storage; return whether or not the image was copied. If an image
+    /// with the same digest is already present, this does nothing.
     #[context("Pulling from host storage: {image}")]
-    pub(crate) async fn pull_from_host_storage(&self, image: &str) -> Result<()> {
+    pub(crate) async fn pull_from_host_storage(
+        &self,
+        image: &str,
+        quiet: bool,
+        prog: &ProgressWriter,
+    ) -> Result<bool> {
+        let mut host_cmd = Command::new("podman");
+        host_cmd.arg("image");
+        let src = inspect_image_with(host_cmd, image).await?;
+        if self.exists(image).await? {
+            let dest = inspect_image_with(self.new_image_cmd()?, image).await?;
+            if dest.digest == src.digest {
+                tracing::debug!("Image {image} is already present at {}", src.digest);
+                return Ok(false);
+            }
+        }
+        let present_layers = self.layer_diff_digests()?;
+        let copied_layers = src
+            .root_fs
+            .layers
+            .iter()
+            .filter(|l| !present_layers.contains(l.as_str()))
+            .collect::<Vec<_>>();
+        let send_step = |steps: u64, description: String| {
+            prog.send(Event::ProgressSteps {
+                task: "copying",
+                description: description.into(),
+                id: src.digest.as_str().into(),
+                steps,
+                steps_total: 1,
+            })
+        };
+        send_step(0, format!("Copying image {image}"));
+
         let mut cmd = Command::new("podman");
         cmd.stdin(Stdio::null());
         cmd.stdout(Stdio::null());
         // An ephemeral place for the transient state;
         let temp_runroot = TempDir::new(cap_std::ambient_authority())?;
-        bind_storage_roots(&mut cmd, &self.storage_root, &temp_runroot)?;
+        let runroot = self
+            .mode
+            .prepare(&mut cmd, &self.storage_root, &temp_runroot)?;
 
         // The destination (target stateroot) + container storage dest
         let storage_dest = &format!(
-            "containers-storage:[overlay@{STORAGE_ALIAS_DIR}+/proc/self/fd/{STORAGE_RUN_FD}]"
+            "containers-storage:[overlay@{}+{runroot}]",
+            self.mode.root()
         );
         cmd.args(["image", "push", "--remove-signatures", image])
             .arg(format!("{storage_dest}{image}"));
         let mut cmd = AsyncCommand::from(cmd);
         cmd.run().await?;
         temp_runroot.close()?;
-        Ok(())
+        for layer in copied_layers.iter() {
+            tracing::debug!("Copied layer: {layer}");
+        }
+        let msg = format!(
+            "Copied image {image}: {} of {} layers",
+            copied_layers.len(),
+            src.root_fs.layers.len()
+        );
+        if !quiet {
+            println!("{msg}");
+        }
+        send_step(1, msg);
+        Ok(true)
     }
 }
 
@@ -309,4 +766,100 @@ impl Storage {
 mod tests {
     use super::*;
     static_assertions::assert_not_impl_any!(Storage: Sync);
+
+    #[test]
+    fn test_bigdata_filename() {
+        assert_eq!(bigdata_filename("manifest"), "manifest");
+        assert_eq!(
+            bigdata_filename("sha256:abc"),
+            format!("={}", ostree_ext::glib::base64_encode(b"sha256:abc"))
+        );
+    }
+
+    #[test]
+    fn test_image_entry_matches() {
+        let e = ImageEntry {
+            id: "4f2c3bd8a14a8a6b54d2f3e5a1cbd5b8e46e8f6f1c4b0e8c1d27f6b9f5a2c3d4".into(),
+            digest: None,
+            names: vec!["quay.io/example/foo:latest".into()],
+        };
+        assert!(e.matches("quay.io/example/foo:latest"));
+        assert!(e.matches("quay.io/example/foo"));
+        assert!(e.matches("4f2c3bd8a14a"));
+        assert!(e.matches("sha256:4f2c3bd8a14a8a6b"));
+        assert!(!e.matches("4f2c"));
+        assert!(!e.matches("quay.io/example/bar"));
+    }
+
+    #[test]
+    fn test_open_runroot() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let runroot = open_runroot(td, "boot-a\n")?;
+        runroot.write("somestate", "foo")?;
+        // Same boot, state is retained
+        let runroot = open_runroot(td, "boot-a")?;
+        assert!(runroot.try_exists("somestate")?);
+        // New boot, state is discarded
+        let runroot = open_runroot(td, "boot-b")?;
+        assert!(!runroot.try_exists("somestate")?);
+        assert_eq!(runroot.read_to_string(RUNROOT_BOOT_ID)?, "boot-b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generator() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("run/systemd/generator")?;
+        let unit_dir = &td.open_dir("run/systemd/generator")?;
+        td.create_dir_all("usr/lib/bootc/bound-images.d")?;
+        td.write(
+            "usr/lib/bootc/bound-images.d/foo.image",
+            "[Image]\nImage=quay.io/example/foo:latest\n",
+        )?;
+        // Not booted via ostree
+        assert!(!generator(td, unit_dir)?);
+        assert!(!td.try_exists(ADDITIONAL_STORE_DROPIN)?);
+        td.write(ostree_ext::container_utils::OSTREE_BOOTED, "")?;
+        assert!(generator(td, unit_dir)?);
+        let contents = unit_dir.read_to_string("var-lib-bootc-storage.mount")?;
+        assert!(contents.contains("What=/sysroot/ostree/bootc/storage\n"));
+        assert!(unit_dir.try_exists("local-fs.target.wants/var-lib-bootc-storage.mount")?);
+        let contents = unit_dir.read_to_string(STORAGE_CONFIG_UNIT)?;
+        assert!(contents.contains("RequiresMountsFor=/var/lib/bootc/storage\n"));
+        assert!(unit_dir.try_exists(format!("local-fs.target.wants/{STORAGE_CONFIG_UNIT}"))?);
+        // The generator itself only writes into its output directory
+        assert!(!td.try_exists(ADDITIONAL_STORE_DROPIN)?);
+        write_additional_store_config(td)?;
+        let contents = td.read_to_string(ADDITIONAL_STORE_DROPIN)?;
+        assert!(contents.contains(r#"additionalimagestores = ["/var/lib/bootc/storage"]"#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_storage_paths() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |k: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == k)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        let (root, runroot) = user_storage_paths(
+            env(&[
+                ("XDG_DATA_HOME", "/home/user/.data"),
+                ("XDG_RUNTIME_DIR", "/run/user/1000"),
+            ]),
+            1000,
+        )
+        .unwrap();
+        assert_eq!(root, "/home/user/.data/bootc/storage");
+        assert_eq!(runroot, "/run/user/1000/bootc/storage");
+        // Fallbacks, and relative paths are ignored
+        let (root, runroot) =
+            user_storage_paths(env(&[("HOME", "/home/user"), ("XDG_DATA_HOME", "foo")]), 42)
+                .unwrap();
+        assert_eq!(root, "/home/user/.local/share/bootc/storage");
+        assert_eq!(runroot, "/run/user/42/bootc/storage");
+        assert!(user_storage_paths(env(&[]), 42).is_err());
+    }
 }
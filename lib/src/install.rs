@@ -9,11 +9,15 @@
 pub(crate) mod baseline;
 pub(crate) mod completion;
 pub(crate) mod config;
+mod diskimage;
+mod layout;
+pub(crate) mod migrate;
 mod osbuild;
 pub(crate) mod osconfig;
+pub(crate) mod preflight;
 
 use std::io::Write;
-use std::os::fd::{AsFd, AsRawFd};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::process::Command;
@@ -48,6 +52,7 @@ use crate::boundimage::{BoundImage, ResolvedBoundImage};
 use crate::containerenv::ContainerExecutionInfo;
 use crate::lsm;
 use crate::mount::Filesystem;
+use crate::progress_jsonl::{Event, ProgressWriter};
 use crate::spec::ImageReference;
 use crate::store::Storage;
 use crate::task::Task;
@@ -181,6 +186,21 @@ pub(crate) struct InstallConfigOpts {
     #[clap(long)]
     root_ssh_authorized_keys: Option<Utf8PathBuf>,
 
+    /// The path to an Ignition config that will be injected into the target for
+    /// first boot provisioning.
+    ///
+    /// It is written to `/boot/ignition/config.ign` (the same location used by
+    /// `coreos-installer`), and Ignition is armed to run on the next boot.
+    #[clap(long)]
+    ignition_config: Option<Utf8PathBuf>,
+
+    /// The path to cloud-init user-data that will be injected into the target for
+    /// first boot provisioning.
+    ///
+    /// It is written to the NoCloud seed directory `/var/lib/cloud/seed/nocloud`.
+    #[clap(long)]
+    cloud_init_user_data: Option<Utf8PathBuf>,
+
     /// Perform configuration changes suitable for a "generic" disk image.
     /// At the moment:
     ///
@@ -199,6 +219,11 @@ pub(crate) struct InstallConfigOpts {
     /// The stateroot name to use. Defaults to `default`.
     #[clap(long)]
     pub(crate) stateroot: Option<String>,
+
+    /// Write progress events in JSON lines format to this file descriptor.
+    #[clap(long)]
+    #[serde(skip)]
+    pub(crate) progress_fd: Option<RawFd>,
 }
 
 #[derive(Debug, Clone, clap::Parser, Serialize, Deserialize, PartialEq, Eq)]
@@ -223,6 +248,10 @@ pub(crate) struct InstallToDiskOpts {
     #[clap(long)]
     #[serde(default)]
     pub(crate) via_loopback: bool,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) image_opts: diskimage::DiskImageOpts,
 }
 
 #[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -315,6 +344,9 @@ pub(crate) struct InstallToExistingRootOpts {
     #[clap(flatten)]
     pub(crate) config_opts: InstallConfigOpts,
 
+    #[clap(flatten)]
+    pub(crate) migrate_opts: migrate::MigrateEtcOpts,
+
     /// Accept that this is a destructive action and skip a warning timer.
     #[clap(long)]
     pub(crate) acknowledge_destructive: bool,
@@ -350,11 +382,17 @@ pub(crate) struct State {
     pub(crate) install_config: Option<config::InstallConfiguration>,
     /// The parsed contents of the authorized_keys (not the file path)
     pub(crate) root_ssh_authorized_keys: Option<String>,
+    /// The validated contents of the Ignition config (not the file path)
+    pub(crate) ignition_config: Option<String>,
+    /// The validated contents of the cloud-init user-data (not the file path)
+    pub(crate) cloud_init_user_data: Option<String>,
     #[allow(dead_code)]
     pub(crate) host_is_container: bool,
     /// The root filesystem of the running container
     pub(crate) container_root: Dir,
     pub(crate) tempdir: TempDir,
+    /// Progress events
+    pub(crate) progress: ProgressWriter,
 }
 
 impl State {
@@ -733,7 +771,15 @@ async fn install_container(
         let spec_imgref = ImageReference::from(src_imageref.clone());
         let repo = &sysroot.repo();
         repo.set_disable_fsync(true);
-        let r = crate::deploy::pull(repo, &spec_imgref, Some(&state.target_imgref), false).await?;
+        let r = crate::deploy::pull(
+            repo,
+            &spec_imgref,
+            Some(&state.target_imgref),
+            None,
+            false,
+            &state.progress,
+        )
+        .await?;
         repo.set_disable_fsync(false);
         r
     };
@@ -821,18 +867,50 @@ async fn install_container(
         }
     }
 
-    // Write the entry for /boot to /etc/fstab.  TODO: Encourage OSes to use the karg?
-    // Or better bind this with the grub data.
-    if let Some(boot) = root_setup.boot.as_ref() {
+    // Write the entry for /boot (and any other filesystems) to /etc/fstab.
+    // TODO: Encourage OSes to use the karg? Or better bind this with the grub data.
+    if root_setup.boot.is_some() || !root_setup.mounts.is_empty() {
+        let mounts = root_setup.boot.iter().chain(root_setup.mounts.iter());
         crate::lsm::atomic_replace_labeled(&root, "etc/fstab", 0o644.into(), sepolicy, |w| {
-            writeln!(w, "{}", boot.to_fstab()).map_err(Into::into)
+            for mount in mounts {
+                writeln!(w, "{}", mount.to_fstab())?;
+            }
+            Ok(())
+        })?;
+    }
+    if let Some(var) = root_setup.encrypted_var.as_ref() {
+        crate::lsm::atomic_replace_labeled(&root, "etc/crypttab", 0o644.into(), sepolicy, |w| {
+            writeln!(w, "{}", var.crypttab_entry())?;
+            Ok(())
         })?;
     }
 
+    if let Some(migration) = root_setup.migrate_etc.as_ref() {
+        migrate::migrate(
+            &root_setup.physical_root,
+            path.as_str(),
+            sepolicy,
+            migration,
+        )?;
+    }
+
     if let Some(contents) = state.root_ssh_authorized_keys.as_deref() {
         osconfig::inject_root_ssh_authorized_keys(&root, sepolicy, contents)?;
     }
 
+    if let Some(contents) = state.ignition_config.as_deref() {
+        osconfig::inject_ignition_config(&root_setup.physical_root, sepolicy, contents)?;
+    }
+
+    if let Some(contents) = state.cloud_init_user_data.as_deref() {
+        let stateroot_dir = root_setup
+            .physical_root
+            .open_dir(format!("ostree/deploy/{stateroot}"))
+            .context("Opening stateroot")?;
+        let instance_id = format!("iid-bootc-{}", Utc::now().format("%Y%m%d%H%M%S"));
+        osconfig::inject_cloud_init_user_data(&stateroot_dir, sepolicy, contents, &instance_id)?;
+    }
+
     let aleph = InstallAleph::new(&src_imageref, &imgstate, &state.selinux_state)?;
     Ok((deployment, aleph))
 }
@@ -872,7 +950,11 @@ pub(crate) fn exec_in_host_mountns(args: &[std::ffi::OsString]) -> Result<()> {
 
 pub(crate) struct RootSetup {
     luks_device: Option<String>,
+    /// RAID arrays backing the root (and /boot) filesystems
+    raid_devices: Vec<Utf8PathBuf>,
     device_info: crate::blockdev::PartitionTable,
+    /// The second device, if the installation is mirrored
+    mirror_device_info: Option<crate::blockdev::PartitionTable>,
     /// Absolute path to the location where we've mounted the physical
     /// root filesystem for the system we're installing.
     physical_root_path: Utf8PathBuf,
@@ -882,6 +964,12 @@ pub(crate) struct RootSetup {
     /// True if we should skip finalizing
     skip_finalize: bool,
     boot: Option<MountSpec>,
+    /// Additional filesystems to mount in the installed system
+    mounts: Vec<MountSpec>,
+    /// The encrypted `/var` volume, if any; its mount is part of `mounts`
+    encrypted_var: Option<crate::varluks::EncryptedVar>,
+    /// Configuration to carry over from the `/etc` of the existing root
+    migrate_etc: Option<migrate::EtcMigration>,
     kargs: Vec<String>,
 }
 
@@ -897,9 +985,15 @@ impl RootSetup {
         self.boot.as_ref().map(require_boot_uuid).transpose()
     }
 
-    // Drop any open file descriptors and return just the mount path and backing luks device, if any
-    fn into_storage(self) -> (Utf8PathBuf, Option<String>) {
-        (self.physical_root_path, self.luks_device)
+    // Drop any open file descriptors and return just the mount path and backing luks and RAID devices, if any
+    fn into_storage(self) -> (Utf8PathBuf, Vec<String>, Vec<Utf8PathBuf>) {
+        let luks_devices = self
+            .encrypted_var
+            .map(|_| crate::varluks::LUKS_NAME.to_owned())
+            .into_iter()
+            .chain(self.luks_device)
+            .collect();
+        (self.physical_root_path, luks_devices, self.raid_devices)
     }
 }
 
@@ -1218,8 +1312,8 @@ async fn prepare_install(
     tracing::debug!("Target image reference: {target_imgref}");
 
     // A bit of basic global state setup
-    crate::mount::ensure_mirrored_host_mount("/dev")?;
-    crate::mount::ensure_mirrored_host_mount("/var/lib/containers")?;
+    crate::mount::ensure_mirrored_host_mount("/dev", true)?;
+    crate::mount::ensure_mirrored_host_mount("/var/lib/containers", true)?;
     ensure_var()?;
     setup_tmp_mounts()?;
     // Allocate a temporary directory we can use in various places to avoid
@@ -1244,11 +1338,24 @@ async fn prepare_install(
     let selinux_state = reexecute_self_for_selinux_if_needed(&source, config_opts.disable_selinux)?;
     tracing::debug!("SELinux state: {selinux_state:?}");
 
+    // Only take ownership of the progress fd after any re-execution above
+    let progress = ProgressWriter::from_opt_fd(config_opts.progress_fd)?;
+
     println!("Installing image: {:#}", &target_imgref);
     if let Some(digest) = source.digest.as_deref() {
         println!("Digest: {digest}");
     }
 
+    // If the image carries a signature policy, it must also be satisfied by the
+    // image we're installing, since it'll be enforced on the next upgrade.
+    if let Some(policy) = crate::sigpolicy::load_policy(&rootfs)? {
+        let digest = source
+            .digest
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Signature policy requires a known image digest"))?;
+        policy.verify(&ImageReference::from(target_imgref.clone()), digest, false)?;
+    }
+
     let install_config = config::load_config()?;
     if install_config.is_some() {
         tracing::debug!("Loaded install configuration");
@@ -1263,6 +1370,25 @@ async fn prepare_install(
         .as_ref()
         .map(|p| std::fs::read_to_string(p).with_context(|| format!("Reading {p}")))
         .transpose()?;
+    let ignition_config = config_opts
+        .ignition_config
+        .as_ref()
+        .map(|p| {
+            let buf = std::fs::read_to_string(p).with_context(|| format!("Reading {p}"))?;
+            osconfig::validate_ignition_config(&buf).with_context(|| format!("Reading {p}"))?;
+            anyhow::Ok(buf)
+        })
+        .transpose()?;
+    let cloud_init_user_data = config_opts
+        .cloud_init_user_data
+        .as_ref()
+        .map(|p| {
+            let buf = std::fs::read_to_string(p).with_context(|| format!("Reading {p}"))?;
+            osconfig::validate_cloud_init_user_data(&buf)
+                .with_context(|| format!("Reading {p}"))?;
+            anyhow::Ok(buf)
+        })
+        .transpose()?;
 
     // Create our global (read-only) state which gets wrapped in an Arc
     // so we can pass it to worker threads too. Right now this just
@@ -1274,9 +1400,12 @@ async fn prepare_install(
         target_imgref,
         install_config,
         root_ssh_authorized_keys,
+        ignition_config,
+        cloud_init_user_data,
         container_root: rootfs,
         tempdir,
         host_is_container,
+        progress,
     });
 
     Ok(state)
@@ -1294,8 +1423,20 @@ async fn install_with_sysroot(
     bound_images: BoundImages,
     has_ostree: bool,
 ) -> Result<()> {
+    const STEPS: &[&str] = &["Deploying", "Installing bootloader", "Pulling bound images"];
+    let id = state.target_imgref.to_string();
+    let send_step = |steps: usize, description: &str| {
+        state.progress.send(Event::ProgressSteps {
+            task: "installing",
+            description: description.into(),
+            id: id.as_str().into(),
+            steps: steps as u64,
+            steps_total: STEPS.len() as u64,
+        })
+    };
     // And actually set up the container in that root, returning a deployment and
     // the aleph state (see below).
+    send_step(0, STEPS[0]);
     let (_deployment, aleph) = install_container(state, rootfs, &sysroot, has_ostree).await?;
     // Write the aleph data that captures the system state at the time of provisioning for aid in future debugging.
     rootfs
@@ -1306,19 +1447,24 @@ async fn install_with_sysroot(
         })
         .context("Writing aleph version")?;
 
+    send_step(1, STEPS[1]);
     if cfg!(target_arch = "s390x") {
         // TODO: Integrate s390x support into install_via_bootupd
         crate::bootloader::install_via_zipl(&rootfs.device_info, boot_uuid)?;
     } else {
-        crate::bootloader::install_via_bootupd(
-            &rootfs.device_info,
-            &rootfs.physical_root_path,
-            &state.config_opts,
-        )?;
+        let devices = std::iter::once(&rootfs.device_info).chain(&rootfs.mirror_device_info);
+        for device in devices {
+            crate::bootloader::install_via_bootupd(
+                device,
+                &rootfs.physical_root_path,
+                &state.config_opts,
+            )?;
+        }
     }
     tracing::debug!("Installed bootloader");
 
     tracing::debug!("Perfoming post-deployment operations");
+    send_step(2, STEPS[2]);
 
     // Note that we *always* initialize this container storage, even if there are no bound images
     // today.
@@ -1330,7 +1476,9 @@ async fn install_with_sysroot(
             // Now copy each bound image from the host's container storage into the target.
             for image in resolved_bound_images {
                 let image = image.image.as_str();
-                imgstore.pull_from_host_storage(image).await?;
+                imgstore
+                    .pull_from_host_storage(image, false, &state.progress)
+                    .await?;
             }
         }
         BoundImages::Unresolved(bound_images) => {
@@ -1339,6 +1487,7 @@ async fn install_with_sysroot(
                 .context("pulling bound images")?;
         }
     }
+    send_step(STEPS.len(), "Installed");
 
     Ok(())
 }
@@ -1423,6 +1572,15 @@ async fn install_to_filesystem_impl(state: &State, rootfs: &mut RootSetup) -> Re
         // descriptors.
     }
 
+    // Copy the initial contents of /var into the encrypted volume
+    if let Some(var) = rootfs.encrypted_var.as_ref() {
+        let stateroot_var = rootfs
+            .physical_root_path
+            .join(format!("ostree/deploy/{}/var", state.stateroot()));
+        let sepolicy = state.load_policy()?;
+        var.populate(&stateroot_var, sepolicy.as_ref())?;
+    }
+
     // Finalize mounted filesystems
     if !rootfs.skip_finalize {
         let bootfs = rootfs.boot.as_ref().map(|_| ("boot", "boot"));
@@ -1442,6 +1600,16 @@ fn installation_complete() {
 #[context("Installing to disk")]
 pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
     let mut block_opts = opts.block_opts;
+    // When installing via loopback, this is the final disk image; the device
+    // is the raw file we attach.
+    let image_output = if opts.via_loopback {
+        let output = block_opts.device.clone();
+        let raw = diskimage::prepare(&opts.image_opts, &output)?;
+        block_opts.device = raw.path().to_owned();
+        Some((output, raw))
+    } else {
+        None
+    };
     let target_blockdev_meta = block_opts
         .device
         .metadata()
@@ -1462,15 +1630,25 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
     } else if !target_blockdev_meta.file_type().is_block_device() {
         anyhow::bail!("Not a block device: {}", block_opts.device);
     }
+    if let Some(mirror) = block_opts.mirror.as_deref() {
+        if opts.via_loopback {
+            anyhow::bail!("--mirror is not supported with --via-loopback");
+        }
+        let meta = mirror
+            .metadata()
+            .with_context(|| format!("Querying {mirror}"))?;
+        if !meta.file_type().is_block_device() {
+            anyhow::bail!("Not a block device: {mirror}");
+        }
+    }
     let state = prepare_install(opts.config_opts, opts.source_opts, opts.target_opts).await?;
 
     // This is all blocking stuff
     let (mut rootfs, loopback) = {
-        let loopback_dev = if opts.via_loopback {
-            let loopback_dev =
-                crate::blockdev::LoopbackDevice::new(block_opts.device.as_std_path())?;
+        let loopback_dev = if let Some((output, raw)) = image_output {
+            let loopback_dev = crate::blockdev::LoopbackDevice::new(raw.path().as_std_path())?;
             block_opts.device = loopback_dev.path().into();
-            Some(loopback_dev)
+            Some((loopback_dev, raw, output))
         } else {
             None
         };
@@ -1486,18 +1664,29 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
     install_to_filesystem_impl(&state, &mut rootfs).await?;
 
     // Drop all data about the root except the bits we need to ensure any file descriptors etc. are closed.
-    let (root_path, luksdev) = rootfs.into_storage();
-    Task::new_and_run(
-        "Unmounting filesystems",
-        "umount",
-        ["-R", root_path.as_str()],
+    let (root_path, luks_devices, raid_devices) = rootfs.into_storage();
+    println!("Unmounting filesystems");
+    crate::mount::unmount(
+        &root_path,
+        crate::mount::UnmountFlags {
+            recursive: true,
+            ..Default::default()
+        },
     )?;
-    if let Some(luksdev) = luksdev.as_deref() {
-        Task::new_and_run("Closing root LUKS device", "cryptsetup", ["close", luksdev])?;
+    for luksdev in luks_devices {
+        Task::new_and_run(
+            format!("Closing {luksdev} LUKS device"),
+            "cryptsetup",
+            ["close", luksdev.as_str()],
+        )?;
+    }
+    for dev in raid_devices {
+        crate::blockdev::stop_raid(&dev)?;
     }
 
-    if let Some(loopback_dev) = loopback {
+    if let Some((loopback_dev, raw, output)) = loopback {
         loopback_dev.close()?;
+        diskimage::finalize(&opts.image_opts, raw, &output)?;
     }
 
     // At this point, all other threads should be gone.
@@ -1539,6 +1728,22 @@ fn require_empty_rootdir(rootfs_fd: &Dir) -> Result<()> {
     Ok(())
 }
 
+/// Ensure that the only filesystems mounted at or below the target root are the
+/// root itself, `/boot` and `/boot/efi`; wiping the root recurses across mount
+/// points, and would otherwise also delete the contents of any other mount.
+#[context("Checking for mounts under {root}")]
+fn require_no_extra_mounts(root: &Utf8Path) -> Result<()> {
+    let root = root.canonicalize_utf8()?;
+    let boot = root.join(BOOT);
+    let allowed = [root.clone(), boot.join(crate::bootloader::EFI_DIR), boot];
+    for fs in crate::mount::mounts_under(&root)? {
+        if !allowed.iter().any(|p| p.as_str() == fs.target) {
+            anyhow::bail!("Found unexpected mount {} ({})", fs.target, fs.fstype);
+        }
+    }
+    Ok(())
+}
+
 /// Remove all entries in a directory, but do not traverse across distinct devices.
 #[context("Removing entries (noxdev)")]
 fn remove_all_in_dir_no_xdev(d: &Dir) -> Result<()> {
@@ -1598,7 +1803,15 @@ fn find_root_args_to_inherit(cmdline: &[&str], root_info: &Filesystem) -> Result
             .uuid
             .as_deref()
             .ok_or_else(|| anyhow!("No filesystem uuid found in target root"))?;
-        (format!("UUID={uuid}"), Vec::new())
+        // If the root is on LUKS, the initramfs also needs to know to unlock it
+        let kargs = root_info
+            .crypt
+            .as_ref()
+            .and_then(|c| c.luks_uuid.as_deref())
+            .map(|luks_uuid| format!("luks.uuid={luks_uuid}"))
+            .into_iter()
+            .collect();
+        (format!("UUID={uuid}"), kargs)
     };
 
     Ok(RootMountInfo { mount_spec, kargs })
@@ -1637,6 +1850,7 @@ fn warn_on_host_root(rootfs_fd: &Dir) -> Result<()> {
 pub(crate) async fn install_to_filesystem(
     opts: InstallToFilesystemOpts,
     targeting_host_root: bool,
+    migrate_etc: Option<migrate::EtcMigration>,
 ) -> Result<()> {
     // Gather global state, destructuring the provided options.
     // IMPORTANT: We might re-execute the current process in this function (for SELinux among other things)
@@ -1710,6 +1924,7 @@ pub(crate) async fn install_to_filesystem(
 
     match fsopts.replace {
         Some(ReplaceMode::Wipe) => {
+            require_no_extra_mounts(&fsopts.root_path)?;
             let rootfs_fd = rootfs_fd.try_clone()?;
             println!("Wiping contents of root");
             tokio::task::spawn_blocking(move || {
@@ -1749,8 +1964,9 @@ pub(crate) async fn install_to_filesystem(
             .ok_or_else(|| anyhow!("No filesystem uuid found in target root"))?;
         let kargs = match inspect.fstype.as_str() {
             "btrfs" => {
-                let subvol = crate::utils::find_mount_option(&inspect.options, "subvol");
-                subvol
+                let options = inspect.mount_options();
+                options
+                    .subvol()
                     .map(|vol| format!("rootflags=subvol={vol}"))
                     .into_iter()
                     .collect::<Vec<_>>()
@@ -1837,13 +2053,18 @@ pub(crate) async fn install_to_filesystem(
         matches!(fsopts.replace, Some(ReplaceMode::Alongside)) || fsopts.skip_finalize;
     let mut rootfs = RootSetup {
         luks_device: None,
+        raid_devices: Vec::new(),
         device_info,
+        mirror_device_info: None,
         physical_root_path: fsopts.root_path,
         physical_root: rootfs_fd,
         rootfs_uuid: inspect.uuid.clone(),
         boot,
         kargs,
         skip_finalize,
+        mounts: Vec::new(),
+        encrypted_var: None,
+        migrate_etc,
     };
 
     install_to_filesystem_impl(&state, &mut rootfs).await?;
@@ -1857,6 +2078,7 @@ pub(crate) async fn install_to_filesystem(
 }
 
 pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) -> Result<()> {
+    let migrate_etc = migrate::EtcMigration::new(&opts.migrate_opts)?;
     let opts = InstallToFilesystemOpts {
         filesystem_opts: InstallTargetFilesystemOpts {
             root_path: opts.root_path,
@@ -1871,7 +2093,7 @@ pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) ->
         config_opts: opts.config_opts,
     };
 
-    install_to_filesystem(opts, true).await
+    install_to_filesystem(opts, true, migrate_etc).await
 }
 
 #[test]
@@ -1903,10 +2125,29 @@ fn test_gather_root_args() {
         maj_min: "252:4".into(),
         options: "rw".into(),
         uuid: Some("965eb3c7-5a3f-470d-aaa2-1bcf04334bc6".into()),
+        label: None,
         children: None,
+        subvolid: None,
+        crypt: None,
     };
     let r = find_root_args_to_inherit(&[], &inspect).unwrap();
     assert_eq!(r.mount_spec, "UUID=965eb3c7-5a3f-470d-aaa2-1bcf04334bc6");
+    assert!(r.kargs.is_empty());
+
+    // The same, but on top of LUKS
+    let luks_inspect = Filesystem {
+        source: "/dev/mapper/root".into(),
+        maj_min: "253:0".into(),
+        crypt: Some(crate::mount::CryptInfo {
+            name: "root".into(),
+            luks_uuid: Some("6e1d5a2f-3f5d-4a3c-9d0e-1f2a3b4c5d6e".into()),
+            backing_device: Some("/dev/vda4".into()),
+        }),
+        ..inspect.clone()
+    };
+    let r = find_root_args_to_inherit(&[], &luks_inspect).unwrap();
+    assert_eq!(r.mount_spec, "UUID=965eb3c7-5a3f-470d-aaa2-1bcf04334bc6");
+    assert_eq!(r.kargs, ["luks.uuid=6e1d5a2f-3f5d-4a3c-9d0e-1f2a3b4c5d6e"]);
 
     // In this case we take the root= from the kernel cmdline
     let r = find_root_args_to_inherit(
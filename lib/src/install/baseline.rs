@@ -1,8 +1,8 @@
 //! # The baseline installer
 //!
 //! This module handles creation of simple root filesystem setups.  At the current time
-//! it's very simple - just a direct filesystem (e.g. xfs, ext4, btrfs etc.).  It is
-//! intended to add opinionated handling of TPM2-bound LUKS too.  But that's about it;
+//! it's very simple - just a direct filesystem (e.g. xfs, ext4, btrfs etc.), optionally
+//! on top of a TPM2-bound LUKS2 volume.  But that's about it;
 //! other more complex flows should set things up externally and use `bootc install to-filesystem`.
 
 use std::borrow::Cow;
@@ -35,6 +35,8 @@ pub(crate) const BOOTPN_SIZE_MB: u32 = 510;
 pub(crate) const EFIPN_SIZE_MB: u32 = 512;
 /// The GPT type for "linux"
 pub(crate) const LINUX_PARTTYPE: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+/// The GPT type for "linux RAID"
+pub(crate) const RAID_PARTTYPE: &str = "A19D880F-05FC-4D3B-A006-743F0F84911E";
 
 #[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -64,6 +66,9 @@ impl Display for BlockSetup {
     }
 }
 
+/// The highest valid TPM2 PCR index.
+const TPM2_PCR_MAX: u32 = 23;
+
 /// Options for installing to a block device
 #[derive(Debug, Clone, clap::Args, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
@@ -71,6 +76,12 @@ pub(crate) struct InstallBlockDeviceOpts {
     /// Target block device for installation.  The entire device will be wiped.
     pub(crate) device: Utf8PathBuf,
 
+    /// A second target block device; if specified, the root (and `/boot`, if any)
+    /// filesystems are mirrored across both devices via software RAID1, and the
+    /// bootloader is installed to both.  This device will also be wiped.
+    #[clap(long)]
+    pub(crate) mirror: Option<Utf8PathBuf>,
+
     /// Automatically wipe all existing data on device
     #[clap(long)]
     #[serde(default)]
@@ -92,6 +103,44 @@ pub(crate) struct InstallBlockDeviceOpts {
     /// By default, all remaining space on the disk will be used.
     #[clap(long)]
     pub(crate) root_size: Option<String>,
+
+    /// Bind the LUKS key to the provided TPM2 PCRs, separated by `+` (e.g. `0+7`).
+    ///
+    /// Only applicable with `--block-setup=tpm2-luks` or `--encrypt-var`.
+    #[clap(long, value_delimiter = '+')]
+    #[serde(default)]
+    pub(crate) tpm2_pcrs: Vec<u32>,
+
+    /// Enroll a generated recovery key in addition to the TPM2 binding; it is
+    /// printed once at installation time, and should be stored securely.
+    ///
+    /// Only applicable with `--block-setup=tpm2-luks` or `--encrypt-var`.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) luks_recovery_key: bool,
+
+    /// Enroll the passphrase read from this file in addition to the TPM2 binding.
+    /// A single trailing newline is ignored.
+    ///
+    /// Only applicable with `--block-setup=tpm2-luks` or `--encrypt-var`.
+    #[clap(long)]
+    pub(crate) luks_passphrase_file: Option<Utf8PathBuf>,
+
+    /// Place `/var` on a separate LUKS2 volume bound to the TPM2 device, using the
+    /// space remaining after the root partition; this requires `--root-size`.
+    ///
+    /// Unlike `--block-setup=tpm2-luks`, this leaves the root filesystem (i.e. the
+    /// operating system content) unencrypted.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) encrypt_var: bool,
+}
+
+impl InstallBlockDeviceOpts {
+    /// Returns true if any LUKS specific options were provided.
+    pub(super) fn has_luks_opts(&self) -> bool {
+        !self.tpm2_pcrs.is_empty() || self.luks_recovery_key || self.luks_passphrase_file.is_some()
+    }
 }
 
 impl BlockSetup {
@@ -104,7 +153,94 @@ impl BlockSetup {
     }
 }
 
-fn mkfs<'a>(
+/// Format PCR indices as expected by `systemd-cryptenroll --tpm2-pcrs`.
+pub(crate) fn tpm2_pcrs_arg(pcrs: &[u32]) -> Result<String> {
+    if let Some(pcr) = pcrs.iter().find(|&&pcr| pcr > TPM2_PCR_MAX) {
+        anyhow::bail!("Invalid TPM2 PCR: {pcr}");
+    }
+    let pcrs = pcrs.iter().map(u32::to_string).collect::<Vec<_>>();
+    Ok(format!("--tpm2-pcrs={}", pcrs.join("+")))
+}
+
+/// Initialize a LUKS2 volume on the device, with its key bound to the local TPM2
+/// device (and optionally a passphrase and/or a recovery key), returning the
+/// UUID of the volume.
+#[context("Initializing LUKS for {name}")]
+pub(crate) fn luks_format_tpm2(
+    name: &str,
+    devpath: &Utf8Path,
+    tpm2_pcrs: Option<&str>,
+    passphrase: Option<&str>,
+    recovery_key: bool,
+) -> Result<String> {
+    let uuid = uuid::Uuid::new_v4().to_string();
+    // This occupies the first keyslot, and will be removed via --wipe-slot=0
+    // when binding to the TPM below
+    let dummy_passphrase = uuid::Uuid::new_v4().to_string();
+    let mut tmp_keyfile = tempfile::NamedTempFile::new()?;
+    tmp_keyfile.write_all(dummy_passphrase.as_bytes())?;
+    tmp_keyfile.flush()?;
+    let tmp_keyfile = tmp_keyfile.path();
+    let dummy_passphrase_input = Some(dummy_passphrase.as_bytes());
+
+    Task::new(format!("Initializing LUKS for {name}"), "cryptsetup")
+        .args(["luksFormat", "--type", "luks2", "--uuid", uuid.as_str()])
+        .arg("--key-file")
+        .args([tmp_keyfile])
+        .args([devpath])
+        .run()?;
+    if let Some(passphrase) = passphrase {
+        let mut t = Task::new(
+            format!("Enrolling {name} device passphrase"),
+            "systemd-cryptenroll",
+        )
+        .args(["--password", "--unlock-key-file"])
+        .args([tmp_keyfile])
+        .args([devpath]);
+        t.cmd.env("NEWPASSWORD", passphrase);
+        t.run()?;
+    }
+    if recovery_key {
+        // Note that the recovery key is written to stdout, which we pass through.
+        Task::new(
+            format!("Enrolling {name} device recovery key"),
+            "systemd-cryptenroll",
+        )
+        .args(["--recovery-key", "--unlock-key-file"])
+        .args([tmp_keyfile])
+        .args([devpath])
+        .run()?;
+    }
+    // The --wipe-slot=0 removes our temporary passphrase, and binds to the local TPM device.
+    // We also use .verbose() here as the details are important/notable.
+    Task::new(
+        format!("Enrolling {name} device with TPM"),
+        "systemd-cryptenroll",
+    )
+    .args(["--wipe-slot=0", "--tpm2-device=auto"])
+    .args(tpm2_pcrs)
+    .arg("--unlock-key-file")
+    .args([tmp_keyfile])
+    .args([devpath])
+    .verbose()
+    .run_with_stdin_buf(dummy_passphrase_input)?;
+    Ok(uuid)
+}
+
+/// Open a LUKS volume as `/dev/mapper/<name>`, returning that path.
+pub(crate) fn luks_open(name: &str, devpath: &Utf8Path) -> Result<String> {
+    Task::new(format!("Opening {name} LUKS device"), "cryptsetup")
+        .args(["luksOpen", devpath.as_str(), name])
+        .run()?;
+    let dev = format!("/dev/mapper/{name}");
+    crate::blockdev::wait_for_device(
+        crate::blockdev::DeviceSpec::Path(dev.as_str().into()),
+        crate::blockdev::DEVICE_WAIT_TIMEOUT,
+    )?;
+    Ok(dev)
+}
+
+pub(crate) fn mkfs<'a>(
     dev: &str,
     fs: Filesystem,
     label: &str,
@@ -142,26 +278,10 @@ fn mkfs<'a>(
     Ok(u)
 }
 
-#[context("Creating rootfs")]
-pub(crate) fn install_create_rootfs(
-    state: &State,
-    opts: InstallBlockDeviceOpts,
-) -> Result<RootSetup> {
-    let luks_name = "root";
-    // Ensure we have a root filesystem upfront
-    let root_filesystem = opts
-        .filesystem
-        .or(state
-            .install_config
-            .as_ref()
-            .and_then(|c| c.filesystem_root())
-            .and_then(|r| r.fstype))
-        .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
-    // Verify that the target is empty (if not already wiped in particular, but it's
-    // also good to verify that the wipe worked)
-    let device = crate::blockdev::list_dev(&opts.device)?;
-    // Canonicalize devpath
-    let devpath: Utf8PathBuf = device.path().into();
+/// Verify that a target block device is not in use, wiping it if requested.
+#[context("Preparing {dev}")]
+pub(crate) fn prepare_device(dev: &Utf8Path, wipe: bool) -> Result<crate::blockdev::Device> {
+    let device = crate::blockdev::list_dev(dev)?;
 
     // Always disallow writing to mounted device
     if is_mounted_in_pid1_mountns(&device.path())? {
@@ -169,8 +289,7 @@ pub(crate) fn install_create_rootfs(
     }
 
     // Handle wiping any existing data
-    if opts.wipe {
-        let dev = &opts.device;
+    if wipe {
         for child in device.children.iter().flatten() {
             let child = child.path();
             println!("Wiping {child}");
@@ -180,16 +299,146 @@ pub(crate) fn install_create_rootfs(
         crate::blockdev::wipefs(dev)?;
     } else if device.has_children() {
         anyhow::bail!(
-            "Detected existing partitions on {}; use e.g. `wipefs` or --wipe if you intend to overwrite",
-            opts.device
+            "Detected existing partitions on {dev}; use e.g. `wipefs` or --wipe if you intend to overwrite"
         );
     }
+    Ok(device)
+}
 
+/// Create a fresh directory to use for mount points.  Note that we're
+/// in a mount namespace, so these should not be visible on the host.
+pub(crate) fn prepare_mntdir() -> Result<Utf8PathBuf> {
     let run_bootc = Utf8Path::new(RUN_BOOTC);
     let mntdir = run_bootc.join("mounts");
     if mntdir.exists() {
         std::fs::remove_dir_all(&mntdir)?;
     }
+    Ok(mntdir)
+}
+
+/// Write the sfdisk specification for the partitions required by the bootloader
+/// (if any) and the EFI system partition, returning the number of partitions
+/// written and the partition number of the ESP.
+pub(super) fn write_bootloader_partitions(buf: &mut String) -> Result<(u32, Option<u32>)> {
+    let mut partno = 0;
+    if cfg!(target_arch = "x86_64") {
+        partno += 1;
+        writeln!(
+            buf,
+            r#"size=1MiB, bootable, type=21686148-6449-6E6F-744E-656564454649, name="BIOS-BOOT""#
+        )?;
+    } else if cfg!(target_arch = "powerpc64") {
+        // PowerPC-PReP-boot
+        partno += 1;
+        let label = crate::bootloader::PREPBOOT_LABEL;
+        let uuid = crate::bootloader::PREPBOOT_GUID;
+        writeln!(buf, r#"size=4MiB, bootable, type={uuid}, name="{label}""#)?;
+    } else if cfg!(any(target_arch = "aarch64", target_arch = "s390x")) {
+        // No bootloader partition is necessary
+    } else {
+        anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
+    }
+
+    let esp_partno = if super::ARCH_USES_EFI {
+        let esp_guid = crate::bootloader::ESP_GUID;
+        partno += 1;
+        writeln!(
+            buf,
+            r#"size={EFIPN_SIZE_MB}MiB, type={esp_guid}, name="EFI-SYSTEM""#
+        )?;
+        Some(partno)
+    } else {
+        None
+    };
+    Ok((partno, esp_partno))
+}
+
+/// Write a new GPT partition table (with a random label) to the device from the
+/// provided sfdisk specification.
+pub(super) fn write_partition_table(dev: &Utf8Path, spec: &str) -> Result<()> {
+    let random_label = uuid::Uuid::new_v4();
+    let buf = format!("label: gpt\nlabel-id: {random_label}\n{spec}");
+    Task::new("Initializing partitions", "sfdisk")
+        .arg("--wipe=always")
+        .arg(dev)
+        .quiet()
+        .run_with_stdin_buf(Some(buf.as_bytes()))
+        .with_context(|| format!("Failed to run sfdisk on {dev}"))
+}
+
+/// Wait for the device nodes of all partitions to exist.
+pub(super) fn wait_for_partitions(partitions: &crate::blockdev::PartitionTable) -> Result<()> {
+    for partition in partitions.partitions.iter() {
+        crate::blockdev::wait_for_device(
+            crate::blockdev::DeviceSpec::Path(partition.path()),
+            crate::blockdev::DEVICE_WAIT_TIMEOUT,
+        )?;
+    }
+    Ok(())
+}
+
+/// Create the filesystem for the EFI system partition.
+pub(super) fn mkfs_esp(partitions: &crate::blockdev::PartitionTable, partno: u32) -> Result<()> {
+    let espdev = partitions.find_partno(partno)?;
+    Task::new("Creating ESP filesystem", "mkfs.fat")
+        .args([espdev.node.as_str(), "-n", "EFI-SYSTEM"])
+        .verbose()
+        .quiet_output()
+        .run()
+}
+
+#[context("Creating rootfs")]
+pub(crate) fn install_create_rootfs(
+    state: &State,
+    opts: InstallBlockDeviceOpts,
+) -> Result<RootSetup> {
+    // A partition layout in the install configuration replaces the built-in one
+    if let Some(layout) = state
+        .install_config
+        .as_ref()
+        .and_then(|c| c.partitions.as_deref())
+    {
+        return super::layout::install_create_rootfs(state, opts, layout);
+    }
+    let luks_name = "root";
+    // Ensure we have a root filesystem upfront
+    let root_filesystem = opts
+        .filesystem
+        .or(state
+            .install_config
+            .as_ref()
+            .and_then(|c| c.filesystem_root())
+            .and_then(|r| r.fstype))
+        .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
+    // Verify that the target is empty (if not already wiped in particular, but it's
+    // also good to verify that the wipe worked)
+    let device = prepare_device(&opts.device, opts.wipe)?;
+    // Canonicalize devpath
+    let devpath: Utf8PathBuf = device.path().into();
+    let mirror_devpath: Option<Utf8PathBuf> = if let Some(mirror) = opts.mirror.as_deref() {
+        if cfg!(target_arch = "s390x") {
+            anyhow::bail!("Mirroring is not supported on this architecture");
+        }
+        let mirror = prepare_device(mirror, opts.wipe)?;
+        let mirror_devpath = Utf8PathBuf::from(mirror.path());
+        if mirror_devpath == devpath {
+            anyhow::bail!("The mirror device must be distinct from {devpath}");
+        }
+        if mirror.size < device.size {
+            anyhow::bail!("The mirror device {mirror_devpath} is smaller than {devpath}");
+        }
+        Some(mirror_devpath)
+    } else {
+        None
+    };
+    // When mirroring, the root and /boot partitions are RAID members
+    let member_parttype = if mirror_devpath.is_some() {
+        RAID_PARTTYPE
+    } else {
+        LINUX_PARTTYPE
+    };
+
+    let mntdir = prepare_mntdir()?;
 
     // Use the install configuration to find the block setup, if we have one
     let block_setup = if let Some(config) = state.install_config.as_ref() {
@@ -203,12 +452,53 @@ pub(crate) fn install_create_rootfs(
         // and we need to error out.
         anyhow::bail!("No install configuration found, and no filesystem specified")
     };
+    if block_setup != BlockSetup::Tpm2Luks && !opts.encrypt_var && opts.has_luks_opts() {
+        anyhow::bail!(
+            "LUKS options require --block-setup={} or --encrypt-var",
+            BlockSetup::Tpm2Luks
+        );
+    }
+    if opts.encrypt_var {
+        if opts.root_size.is_none() {
+            anyhow::bail!("--encrypt-var requires --root-size");
+        }
+        if mirror_devpath.is_some() {
+            anyhow::bail!("--encrypt-var is not supported with --mirror");
+        }
+    }
+    // Command line options take precedence over the install configuration
+    let luks_config = state.install_config.as_ref().and_then(|c| c.luks.as_ref());
+    let tpm2_pcrs = if !opts.tpm2_pcrs.is_empty() {
+        Some(opts.tpm2_pcrs.as_slice())
+    } else {
+        luks_config.and_then(|c| c.tpm2_pcrs.as_deref())
+    };
+    let tpm2_pcrs = tpm2_pcrs.map(tpm2_pcrs_arg).transpose()?;
+    let luks_recovery_key =
+        opts.luks_recovery_key || luks_config.and_then(|c| c.recovery_key).unwrap_or_default();
+    let luks_passphrase = opts
+        .luks_passphrase_file
+        .as_deref()
+        .map(|p| {
+            let mut buf = std::fs::read_to_string(p).with_context(|| format!("Reading {p}"))?;
+            if buf.ends_with('\n') {
+                buf.pop();
+            }
+            if buf.is_empty() {
+                anyhow::bail!("Empty passphrase in {p}");
+            }
+            Ok(buf)
+        })
+        .transpose()?;
     let serial = device.serial.as_deref().unwrap_or("<unknown>");
     let model = device.model.as_deref().unwrap_or("<unknown>");
     println!("Block setup: {block_setup}");
     println!("       Size: {}", device.size);
     println!("     Serial: {serial}");
     println!("      Model: {model}");
+    if let Some(mirror) = mirror_devpath.as_deref() {
+        println!("     Mirror: {mirror}");
+    }
 
     let root_size = opts
         .root_size
@@ -229,43 +519,8 @@ pub(crate) fn install_create_rootfs(
     std::fs::create_dir_all(bootfs)?;
 
     // Generate partitioning spec as input to sfdisk
-    let mut partno = 0;
     let mut partitioning_buf = String::new();
-    writeln!(partitioning_buf, "label: gpt")?;
-    let random_label = uuid::Uuid::new_v4();
-    writeln!(&mut partitioning_buf, "label-id: {random_label}")?;
-    if cfg!(target_arch = "x86_64") {
-        partno += 1;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size=1MiB, bootable, type=21686148-6449-6E6F-744E-656564454649, name="BIOS-BOOT""#
-        )?;
-    } else if cfg!(target_arch = "powerpc64") {
-        // PowerPC-PReP-boot
-        partno += 1;
-        let label = crate::bootloader::PREPBOOT_LABEL;
-        let uuid = crate::bootloader::PREPBOOT_GUID;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size=4MiB, bootable, type={uuid}, name="{label}""#
-        )?;
-    } else if cfg!(any(target_arch = "aarch64", target_arch = "s390x")) {
-        // No bootloader partition is necessary
-    } else {
-        anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
-    }
-
-    let esp_partno = if super::ARCH_USES_EFI {
-        let esp_guid = crate::bootloader::ESP_GUID;
-        partno += 1;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size={EFIPN_SIZE_MB}MiB, type={esp_guid}, name="EFI-SYSTEM""#
-        )?;
-        Some(partno)
-    } else {
-        None
-    };
+    let (mut partno, esp_partno) = write_bootloader_partitions(&mut partitioning_buf)?;
 
     // Initialize the /boot filesystem.  Note that in the future, we may match
     // what systemd/uapi-group encourages and make /boot be FAT32 as well, as
@@ -274,7 +529,7 @@ pub(crate) fn install_create_rootfs(
         partno += 1;
         writeln!(
             &mut partitioning_buf,
-            r#"size={BOOTPN_SIZE_MB}MiB, name="boot""#
+            r#"size={BOOTPN_SIZE_MB}MiB, type={member_parttype}, name="boot""#
         )?;
         Some(partno)
     } else {
@@ -286,15 +541,21 @@ pub(crate) fn install_create_rootfs(
         .unwrap_or_else(|| Cow::Borrowed(""));
     writeln!(
         &mut partitioning_buf,
-        r#"{root_size}type={LINUX_PARTTYPE}, name="root""#
+        r#"{root_size}type={member_parttype}, name="root""#
     )?;
+    let var_partno = if opts.encrypt_var {
+        writeln!(
+            &mut partitioning_buf,
+            r#"type={LINUX_PARTTYPE}, name="var""#
+        )?;
+        Some(rootpn + 1)
+    } else {
+        None
+    };
     tracing::debug!("Partitioning: {partitioning_buf}");
-    Task::new("Initializing partitions", "sfdisk")
-        .arg("--wipe=always")
-        .arg(device.path())
-        .quiet()
-        .run_with_stdin_buf(Some(partitioning_buf.as_bytes()))
-        .context("Failed to run sfdisk")?;
+    for dev in std::iter::once(&devpath).chain(mirror_devpath.as_ref()) {
+        write_partition_table(dev, &partitioning_buf)?;
+    }
     tracing::debug!("Created partition table");
 
     // Full udev sync; it'd obviously be better to await just the devices
@@ -303,45 +564,46 @@ pub(crate) fn install_create_rootfs(
 
     // Re-read what we wrote into structured information
     let base_partitions = &crate::blockdev::partitions_of(&devpath)?;
+    let mirror_partitions = mirror_devpath
+        .as_deref()
+        .map(crate::blockdev::partitions_of)
+        .transpose()?;
+    // And ensure the partition device nodes actually exist before we use them
+    for partitions in std::iter::once(base_partitions).chain(mirror_partitions.as_ref()) {
+        wait_for_partitions(partitions)?;
+    }
 
     let root_partition = base_partitions.find_partno(rootpn)?;
-    if root_partition.parttype.as_str() != LINUX_PARTTYPE {
+    if root_partition.parttype.as_str() != member_parttype {
         anyhow::bail!(
-            "root partition {partno} has type {}; expected {LINUX_PARTTYPE}",
+            "root partition {partno} has type {}; expected {member_parttype}",
             root_partition.parttype.as_str()
         );
     }
+    // RAID arrays we created, which need to be stopped when done
+    let mut raid_devices = Vec::new();
+    let mut root_raid_uuid = None;
+    let root_base: Utf8PathBuf = if let Some(mirror_partitions) = mirror_partitions.as_ref() {
+        let mirror_root = mirror_partitions.find_partno(rootpn)?;
+        let members = [root_partition.path(), mirror_root.path()];
+        let (dev, uuid) = crate::blockdev::create_raid1("root", "1.2", &members)?;
+        raid_devices.push(dev.clone());
+        root_raid_uuid = Some(uuid);
+        dev
+    } else {
+        root_partition.path().to_owned()
+    };
     let (rootdev, root_blockdev_kargs) = match block_setup {
-        BlockSetup::Direct => (root_partition.node.to_owned(), None),
+        BlockSetup::Direct => (root_base.to_string(), None),
         BlockSetup::Tpm2Luks => {
-            let uuid = uuid::Uuid::new_v4().to_string();
-            // This will be replaced via --wipe-slot=all when binding to tpm below
-            let dummy_passphrase = uuid::Uuid::new_v4().to_string();
-            let mut tmp_keyfile = tempfile::NamedTempFile::new()?;
-            tmp_keyfile.write_all(dummy_passphrase.as_bytes())?;
-            tmp_keyfile.flush()?;
-            let tmp_keyfile = tmp_keyfile.path();
-            let dummy_passphrase_input = Some(dummy_passphrase.as_bytes());
-
-            let root_devpath = root_partition.path();
-
-            Task::new("Initializing LUKS for root", "cryptsetup")
-                .args(["luksFormat", "--uuid", uuid.as_str(), "--key-file"])
-                .args([tmp_keyfile])
-                .args([root_devpath])
-                .run()?;
-            // The --wipe-slot=all removes our temporary passphrase, and binds to the local TPM device.
-            // We also use .verbose() here as the details are important/notable.
-            Task::new("Enrolling root device with TPM", "systemd-cryptenroll")
-                .args(["--wipe-slot=all", "--tpm2-device=auto", "--unlock-key-file"])
-                .args([tmp_keyfile])
-                .args([root_devpath])
-                .verbose()
-                .run_with_stdin_buf(dummy_passphrase_input)?;
-            Task::new("Opening root LUKS device", "cryptsetup")
-                .args(["luksOpen", root_devpath.as_str(), luks_name])
-                .run()?;
-            let rootdev = format!("/dev/mapper/{luks_name}");
+            let uuid = luks_format_tpm2(
+                luks_name,
+                root_base.as_path(),
+                tpm2_pcrs.as_deref(),
+                luks_passphrase.as_deref(),
+                luks_recovery_key,
+            )?;
+            let rootdev = luks_open(luks_name, root_base.as_path())?;
             let kargs = vec![
                 format!("luks.uuid={uuid}"),
                 format!("luks.options=tpm2-device=auto,headless=true"),
@@ -352,27 +614,46 @@ pub(crate) fn install_create_rootfs(
 
     // Initialize the /boot filesystem
     let bootdev = if let Some(bootpn) = boot_partno {
-        Some(base_partitions.find_partno(bootpn)?)
+        let boot_partition = base_partitions.find_partno(bootpn)?;
+        if let Some(mirror_partitions) = mirror_partitions.as_ref() {
+            let mirror_boot = mirror_partitions.find_partno(bootpn)?;
+            let members = [boot_partition.path(), mirror_boot.path()];
+            // Use metadata at the end of the members, so the bootloader can read them
+            let (dev, _) = crate::blockdev::create_raid1("boot", "1.0", &members)?;
+            raid_devices.push(dev.clone());
+            Some(dev.into_string())
+        } else {
+            Some(boot_partition.node.clone())
+        }
     } else {
         None
     };
-    let boot_uuid = if let Some(bootdev) = bootdev {
-        Some(
-            mkfs(
-                bootdev.node.as_str(),
-                root_filesystem,
-                "boot",
-                opts.wipe,
-                [],
-            )
-            .context("Initializing /boot")?,
-        )
+    let boot_uuid = if let Some(bootdev) = bootdev.as_deref() {
+        Some(mkfs(bootdev, root_filesystem, "boot", opts.wipe, []).context("Initializing /boot")?)
     } else {
         None
     };
 
     // Initialize rootfs
     let root_uuid = mkfs(&rootdev, root_filesystem, "root", opts.wipe, [])?;
+
+    // And the encrypted /var, if requested
+    let mut mounts = Vec::new();
+    let encrypted_var = if let Some(varpn) = var_partno {
+        let var_partition = base_partitions.find_partno(varpn)?;
+        let var = crate::varluks::EncryptedVar::create(
+            var_partition.path(),
+            root_filesystem,
+            tpm2_pcrs.as_deref(),
+            luks_passphrase.as_deref(),
+            luks_recovery_key,
+            opts.wipe,
+        )?;
+        mounts.push(var.mount_spec());
+        Some(var)
+    } else {
+        None
+    };
     let rootarg = format!("root=UUID={root_uuid}");
     let bootsrc = boot_uuid.as_ref().map(|uuid| format!("UUID={uuid}"));
     let bootarg = bootsrc.as_deref().map(|bootsrc| format!("boot={bootsrc}"));
@@ -382,9 +663,16 @@ pub(crate) fn install_create_rootfs(
         fstype: MountSpec::AUTO.into(),
         options: Some("ro".into()),
     });
-    let kargs = root_blockdev_kargs
+    let root_raid_karg = root_raid_uuid.map(|uuid| format!("rd.md.uuid={uuid}"));
+    // When the root is unlocked via luks.uuid=, other volumes must be listed too
+    let var_karg = encrypted_var
+        .as_ref()
+        .filter(|_| block_setup == BlockSetup::Tpm2Luks)
+        .map(|var| var.luks_karg());
+    let kargs = root_raid_karg
         .into_iter()
-        .flatten()
+        .chain(root_blockdev_kargs.into_iter().flatten())
+        .chain(var_karg)
         .chain([rootarg, RW_KARG.to_string()].into_iter())
         .chain(bootarg)
         .collect::<Vec<_>>();
@@ -396,20 +684,18 @@ pub(crate) fn install_create_rootfs(
     let bootfs = physical_root_path.join("boot");
     // Create the underlying mount point directory, which should be labeled
     crate::lsm::ensure_dir_labeled(&target_rootfs, "boot", None, 0o755.into(), sepolicy)?;
-    if let Some(bootdev) = bootdev {
-        mount::mount(bootdev.node.as_str(), &bootfs)?;
+    if let Some(bootdev) = bootdev.as_deref() {
+        mount::mount(bootdev, &bootfs)?;
     }
     // And we want to label the root mount of /boot
     crate::lsm::ensure_dir_labeled(&target_rootfs, "boot", None, 0o755.into(), sepolicy)?;
 
-    // Create the EFI system partition, if applicable
+    // Create the EFI system partition, if applicable.  When mirroring, each device
+    // gets its own ESP, which are populated when installing the bootloader.
     if let Some(esp_partno) = esp_partno {
-        let espdev = base_partitions.find_partno(esp_partno)?;
-        Task::new("Creating ESP filesystem", "mkfs.fat")
-            .args([espdev.node.as_str(), "-n", "EFI-SYSTEM"])
-            .verbose()
-            .quiet_output()
-            .run()?;
+        for partitions in std::iter::once(base_partitions).chain(mirror_partitions.as_ref()) {
+            mkfs_esp(partitions, esp_partno)?;
+        }
         let efifs_path = bootfs.join(crate::bootloader::EFI_DIR);
         std::fs::create_dir(&efifs_path).context("Creating efi dir")?;
     }
@@ -419,14 +705,30 @@ pub(crate) fn install_create_rootfs(
         BlockSetup::Tpm2Luks => Some(luks_name.to_string()),
     };
     let device_info = crate::blockdev::partitions_of(&devpath)?;
+    let mirror_device_info = mirror_devpath
+        .as_deref()
+        .map(crate::blockdev::partitions_of)
+        .transpose()?;
     Ok(RootSetup {
         luks_device,
+        raid_devices,
         device_info,
+        mirror_device_info,
         physical_root_path,
         physical_root,
         rootfs_uuid: Some(root_uuid.to_string()),
         boot,
+        mounts,
+        encrypted_var,
+        migrate_etc: None,
         kargs,
         skip_finalize: false,
     })
 }
+
+#[test]
+fn test_tpm2_pcrs_arg() {
+    assert_eq!(tpm2_pcrs_arg(&[7]).unwrap(), "--tpm2-pcrs=7");
+    assert_eq!(tpm2_pcrs_arg(&[0, 7, 14]).unwrap(), "--tpm2-pcrs=0+7+14");
+    assert!(tpm2_pcrs_arg(&[7, 24]).is_err());
+}
@@ -41,6 +41,63 @@ pub(crate) struct BasicFilesystems {
     // pub(crate) esp: Option<FilesystemCustomization>,
 }
 
+/// Configuration for LUKS encryption of the root filesystem, used
+/// with the `tpm2-luks` block setup.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct LuksConfig {
+    /// The TPM2 PCRs to bind the key to; if unset, the `systemd-cryptenroll` default is used.
+    pub(crate) tpm2_pcrs: Option<Vec<u32>>,
+    /// Also enroll a generated recovery key, which is printed at installation time.
+    pub(crate) recovery_key: Option<bool>,
+}
+
+/// A GPT partition attribute.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PartitionFlag {
+    /// The partition is required for the platform to function
+    Required,
+    /// The partition is bootable by legacy BIOS firmware
+    LegacyBiosBootable,
+    /// The partition should be mounted read-only (per the Discoverable Partitions Specification)
+    ReadOnly,
+    /// The partition should not be automatically mounted (per the Discoverable Partitions Specification)
+    NoAuto,
+}
+
+/// A btrfs subvolume to create in a partition.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubvolumeConfig {
+    /// The path of the subvolume, relative to the top level
+    pub(crate) name: String,
+    /// Where to mount the subvolume in the installed system, if anywhere
+    pub(crate) mountpoint: Option<String>,
+}
+
+/// A partition in a custom layout; see `[[install.partitions]]`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PartitionConfig {
+    /// The partition label, which is also used as the filesystem label
+    pub(crate) label: String,
+    /// The partition size (e.g. `10G`); if unset, the partition uses all remaining
+    /// space, which is only valid for the last partition
+    pub(crate) size: Option<String>,
+    /// The filesystem type; if unset, the partition is left unformatted
+    #[serde(rename = "type")]
+    pub(crate) fstype: Option<super::baseline::Filesystem>,
+    /// Where to mount the filesystem in the installed system, if anywhere
+    pub(crate) mountpoint: Option<String>,
+    /// GPT partition attributes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) flags: Vec<PartitionFlag>,
+    /// Subvolumes to create, only valid for btrfs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) subvolumes: Vec<SubvolumeConfig>,
+}
+
 /// The serialized [install] section
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename = "install", rename_all = "kebab-case", deny_unknown_fields)]
@@ -50,6 +107,10 @@ pub(crate) struct InstallConfiguration {
     /// Enabled block storage configurations
     pub(crate) block: Option<Vec<BlockSetup>>,
     pub(crate) filesystem: Option<BasicFilesystems>,
+    /// LUKS configuration for the `tpm2-luks` block setup
+    pub(crate) luks: Option<LuksConfig>,
+    /// A custom partition layout for `install to-disk`, replacing the built-in one
+    pub(crate) partitions: Option<Vec<PartitionConfig>>,
     /// Kernel arguments, applied at installation time
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) kargs: Option<Vec<String>>,
@@ -101,6 +162,14 @@ impl Mergeable for BasicFilesystems {
     }
 }
 
+impl Mergeable for LuksConfig {
+    /// Apply any values in other, overriding any existing values in `self`.
+    fn merge(&mut self, other: Self, env: &EnvProperties) {
+        merge_basic(&mut self.tpm2_pcrs, other.tpm2_pcrs, env);
+        merge_basic(&mut self.recovery_key, other.recovery_key, env)
+    }
+}
+
 impl Mergeable for InstallConfiguration {
     /// Apply any values in other, overriding any existing values in `self`.
     fn merge(&mut self, other: Self, env: &EnvProperties) {
@@ -114,6 +183,8 @@ impl Mergeable for InstallConfiguration {
             merge_basic(&mut self.root_fs_type, other.root_fs_type, env);
             merge_basic(&mut self.block, other.block, env);
             self.filesystem.merge(other.filesystem, env);
+            self.luks.merge(other.luks, env);
+            merge_basic(&mut self.partitions, other.partitions, env);
             if let Some(other_kargs) = other.kargs {
                 self.kargs
                     .get_or_insert_with(Default::default)
@@ -550,3 +621,98 @@ root-fs-type = "xfs"
         )
     );
 }
+
+#[test]
+fn test_parse_luks() {
+    let env = EnvProperties {
+        sys_arch: "x86_64".to_string(),
+    };
+    let c: InstallConfigurationToplevel = toml::from_str(
+        r##"[install]
+block = ["tpm2-luks"]
+[install.luks]
+tpm2-pcrs = [7]
+"##,
+    )
+    .unwrap();
+    let mut install = c.install.unwrap();
+    assert_eq!(
+        install.luks,
+        Some(LuksConfig {
+            tpm2_pcrs: Some(vec![7]),
+            recovery_key: None
+        })
+    );
+    let other = InstallConfigurationToplevel {
+        install: Some(InstallConfiguration {
+            luks: Some(LuksConfig {
+                recovery_key: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+    };
+    install.merge(other.install.unwrap(), &env);
+    assert_eq!(
+        install.luks,
+        Some(LuksConfig {
+            tpm2_pcrs: Some(vec![7]),
+            recovery_key: Some(true)
+        })
+    );
+
+    let r: Result<InstallConfigurationToplevel, _> = toml::from_str(
+        r##"[install.luks]
+tpm2-pcr = [7]
+"##,
+    );
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_parse_partitions() {
+    use super::baseline::Filesystem;
+    let env = EnvProperties {
+        sys_arch: "x86_64".to_string(),
+    };
+    let c: InstallConfigurationToplevel = toml::from_str(indoc::indoc! { r#"
+        [[install.partitions]]
+        label = "boot"
+        size = "1G"
+        type = "xfs"
+        mountpoint = "/boot"
+        [[install.partitions]]
+        label = "root"
+        type = "btrfs"
+        flags = ["no-auto"]
+        subvolumes = [
+          { name = "root", mountpoint = "/" },
+          { name = "var", mountpoint = "/var" },
+        ]
+    "# })
+    .unwrap();
+    let mut install = c.install.unwrap();
+    let partitions = install.partitions.as_deref().unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[0].fstype, Some(Filesystem::Xfs));
+    assert_eq!(partitions[0].mountpoint.as_deref(), Some("/boot"));
+    assert!(partitions[0].subvolumes.is_empty());
+    assert_eq!(partitions[1].size, None);
+    assert_eq!(partitions[1].flags, [PartitionFlag::NoAuto]);
+    assert_eq!(partitions[1].subvolumes[1].name, "var");
+
+    // A later layout replaces the previous one entirely
+    let other = InstallConfiguration {
+        partitions: Some(vec![PartitionConfig {
+            label: "root".into(),
+            size: None,
+            fstype: Some(Filesystem::Xfs),
+            mountpoint: Some("/".into()),
+            flags: Vec::new(),
+            subvolumes: Vec::new(),
+        }]),
+        ..Default::default()
+    };
+    install.merge(other, &env);
+    assert_eq!(install.partitions.unwrap().len(), 1);
+}
@@ -0,0 +1,251 @@
+//! # Disk image generation for `bootc install to-disk --via-loopback`
+//!
+//! The installation itself always targets a raw file attached via a loopback
+//! device. When a different output format or compression is requested, we
+//! install to a temporary raw file alongside the output and convert it
+//! once the installation is complete.
+
+use std::fs::File;
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use crate::task::Task;
+
+/// The format of the generated disk image.
+#[derive(ValueEnum, Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DiskImageFormat {
+    /// A raw disk image
+    #[default]
+    Raw,
+    /// A qcow2 disk image, as used by e.g. qemu
+    Qcow2,
+}
+
+/// How to compress the generated disk image.
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DiskImageCompression {
+    /// Compress using zstd; for qcow2 images the compression is internal to the image
+    Zstd,
+    /// Compress using xz; only supported for raw images
+    Xz,
+}
+
+/// Options for generating a disk image when installing via loopback.
+#[derive(Debug, Default, Clone, clap::Args, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DiskImageOpts {
+    /// Create the target file with this size, replacing any existing file (default specifier: M).
+    /// Allowed specifiers: M (mebibytes), G (gibibytes), T (tebibytes).
+    ///
+    /// By default, the target file must already exist.
+    #[clap(long, requires = "via_loopback")]
+    pub(crate) image_size: Option<String>,
+
+    /// The format of the generated disk image.
+    #[clap(long, value_enum, requires = "via_loopback")]
+    pub(crate) image_format: Option<DiskImageFormat>,
+
+    /// Compress the generated disk image.
+    #[clap(long, value_enum, requires = "via_loopback")]
+    pub(crate) image_compression: Option<DiskImageCompression>,
+}
+
+impl DiskImageOpts {
+    /// Returns true if the raw image needs to be converted after installation.
+    fn needs_conversion(&self) -> bool {
+        self.image_format.unwrap_or_default() != DiskImageFormat::Raw
+            || self.image_compression.is_some()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.image_format == Some(DiskImageFormat::Qcow2)
+            && self.image_compression == Some(DiskImageCompression::Xz)
+        {
+            anyhow::bail!("xz compression is not supported for qcow2 images");
+        }
+        if self.needs_conversion() && self.image_size.is_none() {
+            anyhow::bail!("--image-size is required when converting or compressing the image");
+        }
+        Ok(())
+    }
+}
+
+/// The path of the temporary raw image used when the output needs conversion.
+fn raw_image_path(output: &Utf8Path) -> Result<Utf8PathBuf> {
+    let name = output
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid image path: {output}"))?;
+    Ok(output.with_file_name(format!(".{name}.raw.tmp")))
+}
+
+/// The raw image file attached via loopback.  If it is a temporary file to be
+/// converted, it is removed when dropped, e.g. if the installation fails.
+#[derive(Debug)]
+pub(crate) struct RawImage {
+    path: Utf8PathBuf,
+    temporary: bool,
+}
+
+impl RawImage {
+    pub(crate) fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+}
+
+impl Drop for RawImage {
+    fn drop(&mut self) {
+        if self.temporary {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Failed to remove {}: {e}", self.path);
+                }
+            }
+        }
+    }
+}
+
+/// Create the raw image file (if requested), returning the file which should be
+/// attached via loopback.
+#[context("Preparing disk image {output}")]
+pub(crate) fn prepare(opts: &DiskImageOpts, output: &Utf8Path) -> Result<RawImage> {
+    opts.validate()?;
+    let raw = if opts.needs_conversion() {
+        RawImage {
+            path: raw_image_path(output)?,
+            temporary: true,
+        }
+    } else {
+        RawImage {
+            path: output.to_owned(),
+            temporary: false,
+        }
+    };
+    if let Some(size) = opts.image_size.as_deref() {
+        let size = crate::blockdev::parse_size_mib(size)
+            .with_context(|| format!("Parsing image size {size}"))?;
+        if size == 0 {
+            anyhow::bail!("Image size must be non-zero");
+        }
+        let path = raw.path();
+        let f = File::create(path).with_context(|| format!("Creating {path}"))?;
+        f.set_len(size * 1024 * 1024)
+            .with_context(|| format!("Resizing {path}"))?;
+    }
+    Ok(raw)
+}
+
+/// Convert the raw image into the requested output format (if necessary),
+/// removing the raw image.  This must be called after the loopback device
+/// has been detached.
+#[context("Generating disk image {output}")]
+pub(crate) fn finalize(opts: &DiskImageOpts, mut raw: RawImage, output: &Utf8Path) -> Result<()> {
+    if !raw.temporary {
+        return Ok(());
+    }
+    let r = convert(opts, raw.path(), output);
+    raw.temporary = false;
+    std::fs::remove_file(raw.path()).with_context(|| format!("Removing {}", raw.path()))?;
+    r
+}
+
+fn convert(opts: &DiskImageOpts, raw: &Utf8Path, output: &Utf8Path) -> Result<()> {
+    match (
+        opts.image_format.unwrap_or_default(),
+        opts.image_compression,
+    ) {
+        (DiskImageFormat::Raw, None) => Ok(()),
+        (DiskImageFormat::Raw, Some(DiskImageCompression::Zstd)) => {
+            Task::new(format!("Compressing {output}"), "zstd")
+                .args(["-q", "-T0", "-f", "-o", output.as_str()])
+                .arg(raw)
+                .run()
+        }
+        (DiskImageFormat::Raw, Some(DiskImageCompression::Xz)) => {
+            let f = File::create(output).with_context(|| format!("Creating {output}"))?;
+            let mut task = Task::new(format!("Compressing {output}"), "xz")
+                .args(["-T0", "-c"])
+                .arg(raw);
+            task.cmd.stdout(Stdio::from(f));
+            task.run()
+        }
+        (DiskImageFormat::Qcow2, compression) => {
+            let mut task = Task::new(format!("Converting {output} to qcow2"), "qemu-img")
+                .args(["convert", "-f", "raw", "-O", "qcow2"]);
+            match compression {
+                None => {}
+                Some(DiskImageCompression::Zstd) => {
+                    task = task.args(["-c", "-o", "compression_type=zstd"]);
+                }
+                Some(DiskImageCompression::Xz) => unreachable!("validated"),
+            }
+            task.args([raw, output]).run()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let opts = DiskImageOpts::default();
+        opts.validate().unwrap();
+        assert!(!opts.needs_conversion());
+
+        let mut opts = DiskImageOpts {
+            image_format: Some(DiskImageFormat::Qcow2),
+            ..Default::default()
+        };
+        assert!(opts.needs_conversion());
+        assert!(opts.validate().is_err());
+        opts.image_size = Some("10G".into());
+        opts.validate().unwrap();
+        opts.image_compression = Some(DiskImageCompression::Xz);
+        assert!(opts.validate().is_err());
+        opts.image_compression = Some(DiskImageCompression::Zstd);
+        opts.validate().unwrap();
+    }
+
+    #[test]
+    fn test_prepare_cleanup() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let output = Utf8Path::from_path(td.path()).unwrap().join("disk.qcow2");
+        let opts = DiskImageOpts {
+            image_size: Some("1M".into()),
+            image_format: Some(DiskImageFormat::Qcow2),
+            ..Default::default()
+        };
+        let raw = prepare(&opts, &output)?;
+        let path = raw.path().to_owned();
+        assert_eq!(path.metadata()?.len(), 1024 * 1024);
+        // E.g. a failed installation
+        drop(raw);
+        assert!(!path.try_exists()?);
+
+        // The output itself is kept
+        let opts = DiskImageOpts {
+            image_size: Some("1M".into()),
+            ..Default::default()
+        };
+        drop(prepare(&opts, &output)?);
+        assert!(output.try_exists()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_image_path() {
+        assert_eq!(
+            raw_image_path("/output/disk.qcow2".into()).unwrap(),
+            "/output/.disk.qcow2.raw.tmp"
+        );
+        assert!(raw_image_path("/".into()).is_err());
+    }
+}
@@ -0,0 +1,498 @@
+//! # Custom partition layouts
+//!
+//! This module implements `bootc install to-disk` for a partition layout declared
+//! in the install configuration via `[[install.partitions]]`, which replaces the
+//! built-in layout from [`super::baseline`].  Partitions required by the bootloader
+//! (including the EFI system partition) are still created automatically, before
+//! the configured partitions.
+
+use std::collections::HashSet;
+use std::fmt::Write as _;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use fn_error_context::context;
+
+use super::baseline::{self, BlockSetup, Filesystem, InstallBlockDeviceOpts};
+use super::config::{PartitionConfig, PartitionFlag};
+use super::MountSpec;
+use super::RootSetup;
+use super::State;
+use super::RW_KARG;
+use crate::mount;
+use crate::task::Task;
+
+/// The maximum length of a GPT partition name.
+const GPT_NAME_MAX: usize = 36;
+/// Labels used for partitions we create ourselves.
+const RESERVED_LABELS: &[&str] = &["BIOS-BOOT", "EFI-SYSTEM", crate::bootloader::PREPBOOT_LABEL];
+/// Mount points which are owned by the OS image, and cannot be separate filesystems.
+const RESERVED_MOUNTPOINTS: &[&str] = &["/usr", "/etc", "/sysroot", "/ostree"];
+
+impl PartitionFlag {
+    /// The attribute as understood by sfdisk.
+    fn sfdisk_attr(&self) -> &'static str {
+        match self {
+            PartitionFlag::Required => "RequiredPartition",
+            PartitionFlag::LegacyBiosBootable => "LegacyBIOSBootable",
+            PartitionFlag::ReadOnly => "GUID:60",
+            PartitionFlag::NoAuto => "GUID:63",
+        }
+    }
+}
+
+/// Where the root filesystem is in a layout.
+#[derive(Debug, PartialEq, Eq)]
+struct RootLocation<'a> {
+    /// Index of the partition in the layout
+    index: usize,
+    /// The btrfs subvolume, if any
+    subvol: Option<&'a str>,
+}
+
+fn validate_mountpoint(mountpoint: &str) -> Result<()> {
+    if !mountpoint.starts_with('/') {
+        anyhow::bail!("Mount point must be absolute: {mountpoint}");
+    }
+    if mountpoint.contains(char::is_whitespace) {
+        anyhow::bail!("Invalid mount point: {mountpoint}");
+    }
+    let is_under = |p: &str| {
+        mountpoint
+            .strip_prefix(p)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    if RESERVED_MOUNTPOINTS.iter().any(|&p| is_under(p)) {
+        anyhow::bail!("Mount point is part of the OS image: {mountpoint}");
+    }
+    // The ESP is handled automatically
+    if mountpoint != "/boot" && is_under("/boot") {
+        anyhow::bail!("Unsupported mount point: {mountpoint}");
+    }
+    Ok(())
+}
+
+/// Verify the properties of a single partition, independent of the others.
+fn validate_partition(part: &PartitionConfig, is_last: bool) -> Result<()> {
+    let label = part.label.as_str();
+    if label.is_empty() || label.encode_utf16().count() > GPT_NAME_MAX {
+        anyhow::bail!("Label must be between 1 and {GPT_NAME_MAX} characters");
+    }
+    if !label
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        anyhow::bail!("Invalid character in label");
+    }
+    if RESERVED_LABELS.contains(&label) {
+        anyhow::bail!("Label is reserved");
+    }
+    match part.size.as_deref() {
+        Some(size) => {
+            if crate::blockdev::parse_size_mib(size).context("Parsing size")? == 0 {
+                anyhow::bail!("Size must not be zero");
+            }
+        }
+        None if !is_last => anyhow::bail!("Only the last partition may omit the size"),
+        None => {}
+    }
+    if let Some(mountpoint) = part.mountpoint.as_deref() {
+        if part.fstype.is_none() {
+            anyhow::bail!("A mount point requires a filesystem type");
+        }
+        if !part.subvolumes.is_empty() {
+            anyhow::bail!("A mount point cannot be combined with subvolumes");
+        }
+        validate_mountpoint(mountpoint)?;
+    }
+    if !part.subvolumes.is_empty() && part.fstype != Some(Filesystem::Btrfs) {
+        anyhow::bail!("Subvolumes require a btrfs filesystem");
+    }
+    let mut names = HashSet::new();
+    for subvol in part.subvolumes.iter() {
+        let name = subvol.name.as_str();
+        if name.is_empty()
+            || name
+                .split('/')
+                .any(|c| c.is_empty() || c == "." || c == "..")
+        {
+            anyhow::bail!("Invalid subvolume name: {name:?}");
+        }
+        if !names.insert(name) {
+            anyhow::bail!("Duplicate subvolume: {name}");
+        }
+        if let Some(mountpoint) = subvol.mountpoint.as_deref() {
+            if mountpoint == "/boot" {
+                anyhow::bail!("/boot cannot be a subvolume");
+            }
+            validate_mountpoint(mountpoint)?;
+        }
+    }
+    Ok(())
+}
+
+/// Verify that a partition layout is well-formed, returning the location of the root filesystem.
+fn validate<'a>(layout: &'a [PartitionConfig]) -> Result<RootLocation<'a>> {
+    if layout.is_empty() {
+        anyhow::bail!("Empty partition layout");
+    }
+    let mut labels = HashSet::new();
+    let mut mountpoints = HashSet::new();
+    let mut root = None;
+    for (index, part) in layout.iter().enumerate() {
+        let label = part.label.as_str();
+        validate_partition(part, index + 1 == layout.len())
+            .with_context(|| format!("Partition {label}"))?;
+        if !labels.insert(label) {
+            anyhow::bail!("Duplicate partition label: {label}");
+        }
+        let subvol_mounts = part
+            .subvolumes
+            .iter()
+            .filter_map(|v| v.mountpoint.as_deref().map(|m| (m, Some(v.name.as_str()))));
+        let part_mounts = part
+            .mountpoint
+            .as_deref()
+            .map(|m| (m, None))
+            .into_iter()
+            .chain(subvol_mounts);
+        for (mountpoint, subvol) in part_mounts {
+            if !mountpoints.insert(mountpoint) {
+                anyhow::bail!("Duplicate mount point: {mountpoint}");
+            }
+            if mountpoint == "/" {
+                root = Some(RootLocation { index, subvol });
+            }
+        }
+    }
+    root.ok_or_else(|| anyhow::anyhow!("No partition for /"))
+}
+
+/// Write the sfdisk specification for a partition.
+fn write_partition(buf: &mut String, part: &PartitionConfig) -> Result<()> {
+    let size = part
+        .size
+        .as_deref()
+        .map(crate::blockdev::parse_size_mib)
+        .transpose()?
+        .map(|v| format!("size={v}MiB, "))
+        .unwrap_or_default();
+    let parttype = baseline::LINUX_PARTTYPE;
+    let label = &part.label;
+    write!(buf, r#"{size}type={parttype}, name="{label}""#)?;
+    if !part.flags.is_empty() {
+        let attrs = part
+            .flags
+            .iter()
+            .map(PartitionFlag::sfdisk_attr)
+            .collect::<Vec<_>>();
+        write!(buf, r#", attrs="{}""#, attrs.join(" "))?;
+    }
+    writeln!(buf)?;
+    Ok(())
+}
+
+/// Create the provided subvolumes in the btrfs filesystem on the device.
+#[context("Creating subvolumes on {dev}")]
+fn create_subvolumes(dev: &str, mntdir: &Utf8Path, names: &[&str]) -> Result<()> {
+    let mnt = mntdir.join("btrfs-toplevel");
+    std::fs::create_dir_all(&mnt)?;
+    mount::mount(dev, &mnt)?;
+    let r = names.iter().try_for_each(|name| {
+        Task::new(format!("Creating subvolume {name}"), "btrfs")
+            .args(["subvolume", "create"])
+            .arg(mnt.join(name))
+            .quiet_output()
+            .run()
+    });
+    mount::unmount(&mnt, Default::default())?;
+    r
+}
+
+/// Create and mount the filesystems for a custom partition layout.
+#[context("Creating rootfs with custom partition layout")]
+pub(crate) fn install_create_rootfs(
+    state: &State,
+    opts: InstallBlockDeviceOpts,
+    layout: &[PartitionConfig],
+) -> Result<RootSetup> {
+    let root = validate(layout).context("Validating partition layout")?;
+    if opts.filesystem.is_some() || opts.root_size.is_some() {
+        anyhow::bail!("--filesystem and --root-size cannot be used with a custom partition layout");
+    }
+    if opts.mirror.is_some() {
+        anyhow::bail!("--mirror is not supported with a custom partition layout");
+    }
+    let block_setup = state
+        .install_config
+        .as_ref()
+        .map(|c| c.get_block_setup(opts.block_setup))
+        .transpose()?
+        .unwrap_or_default();
+    if block_setup != BlockSetup::Direct || opts.has_luks_opts() {
+        anyhow::bail!("Only direct block setup is supported with a custom partition layout");
+    }
+    if opts.encrypt_var {
+        anyhow::bail!("--encrypt-var is not supported with a custom partition layout");
+    }
+
+    let device = baseline::prepare_device(&opts.device, opts.wipe)?;
+    let devpath = Utf8PathBuf::from(device.path());
+    let mntdir = baseline::prepare_mntdir()?;
+    let serial = device.serial.as_deref().unwrap_or("<unknown>");
+    let model = device.model.as_deref().unwrap_or("<unknown>");
+    println!("Partitions: {}", layout.len());
+    println!("      Size: {}", device.size);
+    println!("    Serial: {serial}");
+    println!("     Model: {model}");
+
+    // Load the policy from the container root, which also must be our install root
+    let sepolicy = state.load_policy()?;
+    let sepolicy = sepolicy.as_ref();
+
+    let physical_root_path = mntdir.join("rootfs");
+    std::fs::create_dir_all(&physical_root_path)?;
+
+    let mut partitioning_buf = String::new();
+    let (base_partno, esp_partno) = baseline::write_bootloader_partitions(&mut partitioning_buf)?;
+    for part in layout {
+        write_partition(&mut partitioning_buf, part)?;
+    }
+    tracing::debug!("Partitioning: {partitioning_buf}");
+    baseline::write_partition_table(&devpath, &partitioning_buf)?;
+    crate::blockdev::udev_settle()?;
+    let partitions = &crate::blockdev::partitions_of(&devpath)?;
+    baseline::wait_for_partitions(partitions)?;
+
+    // Create all filesystems, gathering the UUIDs and device nodes
+    let mut filesystems = Vec::new();
+    for (i, part) in layout.iter().enumerate() {
+        let partno = base_partno + 1 + i as u32;
+        let node = partitions.find_partno(partno)?.node.as_str();
+        let Some(fstype) = part.fstype else {
+            filesystems.push(None);
+            continue;
+        };
+        let uuid = baseline::mkfs(node, fstype, &part.label, opts.wipe, [])?;
+        if !part.subvolumes.is_empty() {
+            let names = part
+                .subvolumes
+                .iter()
+                .map(|v| v.name.as_str())
+                .collect::<Vec<_>>();
+            create_subvolumes(node, &mntdir, &names)?;
+        }
+        filesystems.push(Some((node, fstype, uuid)));
+    }
+
+    let (rootdev, _, root_uuid) = filesystems[root.index].expect("root filesystem");
+    let mut kargs = vec![format!("root=UUID={root_uuid}")];
+    if let Some(subvol) = root.subvol {
+        kargs.push(format!("rootflags=subvol={subvol}"));
+        mount::mount_btrfs_subvolume(rootdev, subvol, &physical_root_path)?;
+    } else {
+        mount::mount(rootdev, &physical_root_path)?;
+    }
+    kargs.push(RW_KARG.to_string());
+    let target_rootfs = Dir::open_ambient_dir(&physical_root_path, cap_std::ambient_authority())?;
+    crate::lsm::ensure_dir_labeled(&target_rootfs, "", Some("/".into()), 0o755.into(), sepolicy)?;
+    let physical_root = Dir::open_ambient_dir(&physical_root_path, cap_std::ambient_authority())?;
+    let bootfs = physical_root_path.join("boot");
+    crate::lsm::ensure_dir_labeled(&target_rootfs, "boot", None, 0o755.into(), sepolicy)?;
+
+    // Gather the remaining mounts; only /boot needs to be mounted for installation
+    let mut boot = None;
+    let mut mounts = Vec::new();
+    for (part, fs) in layout.iter().zip(filesystems.iter()) {
+        let Some((node, fstype, uuid)) = fs else {
+            continue;
+        };
+        let source = format!("UUID={uuid}");
+        if let Some(mountpoint) = part.mountpoint.as_deref() {
+            match mountpoint {
+                "/" => {}
+                "/boot" => {
+                    mount::mount(node, &bootfs)?;
+                    crate::lsm::ensure_dir_labeled(
+                        &target_rootfs,
+                        "boot",
+                        None,
+                        0o755.into(),
+                        sepolicy,
+                    )?;
+                    kargs.push(format!("boot={source}"));
+                    boot = Some(MountSpec {
+                        source: source.clone(),
+                        target: "/boot".into(),
+                        fstype: MountSpec::AUTO.into(),
+                        options: Some("ro".into()),
+                    });
+                }
+                mountpoint => mounts.push(MountSpec {
+                    source: source.clone(),
+                    target: mountpoint.into(),
+                    fstype: fstype.to_string(),
+                    options: None,
+                }),
+            }
+        }
+        for subvol in part.subvolumes.iter() {
+            match subvol.mountpoint.as_deref() {
+                None | Some("/") => {}
+                Some(mountpoint) => mounts.push(MountSpec {
+                    source: source.clone(),
+                    target: mountpoint.into(),
+                    fstype: fstype.to_string(),
+                    options: Some(format!("subvol={}", subvol.name)),
+                }),
+            }
+        }
+    }
+
+    if let Some(esp_partno) = esp_partno {
+        baseline::mkfs_esp(partitions, esp_partno)?;
+        let efifs_path = bootfs.join(crate::bootloader::EFI_DIR);
+        std::fs::create_dir(&efifs_path).context("Creating efi dir")?;
+    }
+
+    let device_info = crate::blockdev::partitions_of(&devpath)?;
+    Ok(RootSetup {
+        luks_device: None,
+        raid_devices: Vec::new(),
+        device_info,
+        mirror_device_info: None,
+        physical_root_path,
+        physical_root,
+        rootfs_uuid: Some(root_uuid.to_string()),
+        boot,
+        mounts,
+        encrypted_var: None,
+        migrate_etc: None,
+        kargs,
+        skip_finalize: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::config::SubvolumeConfig;
+
+    fn part(label: &str, size: Option<&str>, mountpoint: Option<&str>) -> PartitionConfig {
+        PartitionConfig {
+            label: label.into(),
+            size: size.map(Into::into),
+            fstype: Some(Filesystem::Xfs),
+            mountpoint: mountpoint.map(Into::into),
+            flags: Vec::new(),
+            subvolumes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let layout = [
+            part("boot", Some("1G"), Some("/boot")),
+            part("root", Some("20G"), Some("/")),
+            part("var", None, Some("/var")),
+        ];
+        assert_eq!(
+            validate(&layout).unwrap(),
+            RootLocation {
+                index: 1,
+                subvol: None
+            }
+        );
+
+        let mut btrfs = part("system", None, None);
+        btrfs.fstype = Some(Filesystem::Btrfs);
+        btrfs.subvolumes = ["root", "home"]
+            .into_iter()
+            .map(|name| SubvolumeConfig {
+                name: name.into(),
+                mountpoint: Some(if name == "root" {
+                    "/".into()
+                } else {
+                    format!("/var/{name}")
+                }),
+            })
+            .collect();
+        let layout = [part("boot", Some("1G"), Some("/boot")), btrfs.clone()];
+        assert_eq!(
+            validate(&layout).unwrap(),
+            RootLocation {
+                index: 1,
+                subvol: Some("root")
+            }
+        );
+
+        let invalid: &[&[PartitionConfig]] = &[
+            &[],
+            // No root
+            &[part("boot", Some("1G"), Some("/boot"))],
+            // Size omitted before the last partition
+            &[
+                part("root", None, Some("/")),
+                part("var", None, Some("/var")),
+            ],
+            // Duplicate label and mountpoint
+            &[
+                part("root", Some("1G"), Some("/")),
+                part("root", None, Some("/var")),
+            ],
+            &[
+                part("root", Some("1G"), Some("/")),
+                part("var", None, Some("/")),
+            ],
+            // Reserved mount points and labels
+            &[
+                part("root", Some("1G"), Some("/")),
+                part("usr", None, Some("/usr")),
+            ],
+            &[
+                part("root", Some("1G"), Some("/")),
+                part("efi", None, Some("/boot/efi")),
+            ],
+            &[
+                part("root", Some("1G"), Some("/")),
+                part("EFI-SYSTEM", None, None),
+            ],
+            &[part("root", Some("1G"), Some("relative"))],
+            &[part("has space", None, Some("/"))],
+            &[part("root", Some("0M"), Some("/"))],
+        ];
+        for layout in invalid {
+            assert!(validate(layout).is_err(), "{layout:?}");
+        }
+
+        // Subvolumes are only valid on btrfs, and not combined with a mount point
+        let mut xfs = btrfs.clone();
+        xfs.fstype = Some(Filesystem::Xfs);
+        assert!(validate(&[xfs]).is_err());
+        let mut both = btrfs.clone();
+        both.mountpoint = Some("/mnt".into());
+        assert!(validate(&[both]).is_err());
+        let mut bad_subvol = btrfs;
+        bad_subvol.subvolumes[1].name = "../home".into();
+        assert!(validate(&[bad_subvol]).is_err());
+    }
+
+    #[test]
+    fn test_write_partition() {
+        let mut buf = String::new();
+        let mut p = part("var", Some("10G"), Some("/var"));
+        write_partition(&mut buf, &p).unwrap();
+        p.size = None;
+        p.flags = vec![PartitionFlag::NoAuto, PartitionFlag::ReadOnly];
+        write_partition(&mut buf, &p).unwrap();
+        similar_asserts::assert_eq!(
+            buf,
+            indoc::indoc! { r#"
+                size=10240MiB, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name="var"
+                type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name="var", attrs="GUID:63 GUID:60"
+            "# }
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! # Migrating `/etc` from the existing root
+//!
+//! When converting a package-based system in place via
+//! `bootc install to-existing-root --migrate-etc`, the identity and
+//! configuration of the host is carried over from its `/etc` into the
+//! `/etc` of the new deployment: the machine ID, hostname, locale, time zone,
+//! network configuration, SSH host keys and local (non-system) user accounts.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+
+use crate::task::Task;
+
+/// The paths (relative to `/etc`) which are migrated if present.
+const DEFAULT_PATHS: &[&str] = &[
+    "machine-id",
+    "hostname",
+    "locale.conf",
+    "vconsole.conf",
+    "localtime",
+    "NetworkManager/system-connections",
+    "systemd/network",
+    "sysconfig/network-scripts",
+];
+/// The directory holding the SSH host keys.
+const SSH_DIR: &str = "ssh";
+/// The prefix of the SSH host keys.
+const SSH_HOST_KEY_PREFIX: &str = "ssh_host_";
+/// The account databases, along with their shadow counterpart and its mode.
+const ACCOUNT_DATABASES: &[(&str, &str, u32)] =
+    &[("passwd", "shadow", 0o000), ("group", "gshadow", 0o000)];
+/// The first UID/GID of regular (non-system) accounts.
+const FIRST_REGULAR_ID: u32 = 1000;
+/// The UID/GID of `nobody`, which is not a regular account.
+const NOBODY_ID: u32 = 65534;
+
+/// Options for migrating `/etc` from the existing root.
+#[derive(clap::Args, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct MigrateEtcOpts {
+    /// Carry over the host configuration from the existing `/etc` into the new
+    /// deployment: the machine ID, hostname, locale, time zone, network configuration,
+    /// SSH host keys and local (non-system) user accounts.
+    #[clap(long)]
+    pub(crate) migrate_etc: bool,
+
+    /// Also carry over this path, which must be below `/etc`.  This option can be
+    /// provided multiple times.
+    ///
+    /// Example: --migrate-etc-path=/etc/chrony.conf
+    #[clap(long = "migrate-etc-path", requires = "migrate_etc")]
+    pub(crate) migrate_etc_paths: Vec<Utf8PathBuf>,
+}
+
+/// The validated set of paths to migrate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EtcMigration {
+    /// Paths relative to `/etc`; the boolean is true if the path was
+    /// explicitly requested (and hence must exist).
+    paths: Vec<(Utf8PathBuf, bool)>,
+}
+
+impl EtcMigration {
+    /// Validate the options, returning `None` if nothing should be migrated.
+    pub(crate) fn new(opts: &MigrateEtcOpts) -> Result<Option<Self>> {
+        if !opts.migrate_etc {
+            return Ok(None);
+        }
+        let explicit = opts
+            .migrate_etc_paths
+            .iter()
+            .map(|p| crate::etc::relative_path(p).map(|p| (p, true)));
+        let defaults = DEFAULT_PATHS
+            .iter()
+            .map(|p| Ok((Utf8PathBuf::from(*p), false)));
+        let paths = explicit.chain(defaults).collect::<Result<_>>()?;
+        Ok(Some(Self { paths }))
+    }
+}
+
+/// The UID (or GID) of an entry in a passwd(5) or group(5) database.
+fn entry_id(line: &str) -> Option<u32> {
+    line.split(':').nth(2)?.parse().ok()
+}
+
+/// The name of an entry in a passwd(5), group(5) or shadow(5) database.
+fn entry_name(line: &str) -> Option<&str> {
+    line.split_once(':')
+        .map(|(name, _)| name)
+        .filter(|name| !name.is_empty() && !name.starts_with('#'))
+}
+
+/// The names of the regular accounts (or groups) of the host database
+/// which are missing from the target database.  It is an error if the UID
+/// (or GID) of such an entry is used by a different one in the target, as
+/// the files owned by the host account would then belong to the latter.
+fn missing_regular_entries<'a>(host: &'a str, target: &str) -> Result<Vec<&'a str>> {
+    let present = target
+        .lines()
+        .filter_map(|line| Some((entry_name(line)?, entry_id(line))))
+        .collect::<Vec<_>>();
+    let mut r = Vec::new();
+    for line in host.lines() {
+        let Some(id) = entry_id(line).filter(|id| (FIRST_REGULAR_ID..NOBODY_ID).contains(id))
+        else {
+            continue;
+        };
+        let Some(name) = entry_name(line) else {
+            continue;
+        };
+        if present.iter().any(|&(n, _)| n == name) {
+            continue;
+        }
+        if let Some((other, _)) = present.iter().find(|&&(_, i)| i == Some(id)) {
+            anyhow::bail!("Cannot migrate {name}: ID {id} is already used by {other}");
+        }
+        r.push(name);
+    }
+    Ok(r)
+}
+
+/// Append the host entries with the provided names to the target database.
+fn append_entries(host: &str, target: &str, names: &[&str]) -> String {
+    let mut r = target.to_owned();
+    if !r.is_empty() && !r.ends_with('\n') {
+        r.push('\n');
+    }
+    for line in host.lines() {
+        if entry_name(line).is_some_and(|name| names.contains(&name)) {
+            r.push_str(line);
+            r.push('\n');
+        }
+    }
+    r
+}
+
+/// Add the local accounts and groups of the host to the new deployment.
+#[context("Migrating accounts")]
+fn migrate_accounts(host_etc: &Dir, root: &Dir, sepolicy: Option<&ostree::SePolicy>) -> Result<()> {
+    for &(db, shadow, shadow_mode) in ACCOUNT_DATABASES {
+        let db_path = Utf8Path::new("etc").join(db);
+        let shadow_path = Utf8Path::new("etc").join(shadow);
+        let (Some(host_db), Some(target_db)) = (
+            host_etc.read_to_string_optional(db)?,
+            root.read_to_string_optional(&db_path)?,
+        ) else {
+            continue;
+        };
+        let names = missing_regular_entries(&host_db, &target_db)
+            .with_context(|| format!("Migrating /etc/{db}"))?;
+        if names.is_empty() {
+            continue;
+        }
+        tracing::debug!("Migrating /etc/{db} entries: {names:?}");
+        let mut updates = vec![(db_path, 0o644, append_entries(&host_db, &target_db, &names))];
+        if let (Some(host_shadow), Some(target_shadow)) = (
+            host_etc.read_to_string_optional(shadow)?,
+            root.read_to_string_optional(&shadow_path)?,
+        ) {
+            updates.push((
+                shadow_path,
+                shadow_mode,
+                append_entries(&host_shadow, &target_shadow, &names),
+            ));
+        }
+        for (path, mode, contents) in updates {
+            crate::lsm::atomic_replace_labeled(root, &path, mode.into(), sepolicy, |w| {
+                w.write_all(contents.as_bytes())?;
+                Ok(())
+            })
+            .with_context(|| format!("Writing /{path}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the host configuration from the existing `/etc` of the physical root
+/// into the `/etc` of the new deployment.
+#[context("Migrating /etc")]
+pub(crate) fn migrate(
+    physical_root: &Dir,
+    deployment_path: &str,
+    sepolicy: Option<&ostree::SePolicy>,
+    migration: &EtcMigration,
+) -> Result<()> {
+    let host_etc = &physical_root
+        .open_dir("etc")
+        .context("Opening existing /etc")?;
+    let root = &physical_root
+        .open_dir(deployment_path)
+        .context("Opening deployment dir")?;
+    let etc = &root.open_dir("etc").context("Opening deployment /etc")?;
+    let deployment_etc = Utf8Path::new(deployment_path).join("etc");
+
+    let host_keys = host_etc
+        .open_dir_optional(SSH_DIR)?
+        .map(|d| -> Result<Vec<_>> {
+            let mut r = Vec::new();
+            for ent in d.entries()? {
+                let name = ent?.file_name();
+                if let Some(name) = name.to_str().filter(|n| n.starts_with(SSH_HOST_KEY_PREFIX)) {
+                    r.push((Utf8Path::new(SSH_DIR).join(name), false));
+                }
+            }
+            Ok(r)
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    for (path, required) in migration.paths.iter().chain(host_keys.iter()) {
+        if host_etc.symlink_metadata_optional(path)?.is_none() {
+            if *required {
+                anyhow::bail!("Path to migrate does not exist: /etc/{path}");
+            }
+            tracing::debug!("Skipping nonexistent /etc/{path}");
+            continue;
+        }
+        if let Some(parent) = path.parent().filter(|p| !p.as_str().is_empty()) {
+            etc.create_dir_all(parent)
+                .with_context(|| format!("Creating /etc/{parent}"))?;
+        }
+        // Replace what the image provides with the host contents
+        etc.remove_all_optional(path)?;
+        let src = Utf8Path::new("etc").join(path);
+        let dest = deployment_etc.join(path);
+        Task::new(format!("Migrating /etc/{path}"), "cp")
+            .cwd(physical_root)?
+            .args(["-a", "--reflink=auto", src.as_str(), dest.as_str()])
+            .run()?;
+    }
+
+    migrate_accounts(host_etc, root, sepolicy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migration_opts() {
+        assert_eq!(EtcMigration::new(&Default::default()).unwrap(), None);
+        let opts = MigrateEtcOpts {
+            migrate_etc: true,
+            migrate_etc_paths: vec!["/etc/chrony.conf".into()],
+        };
+        let m = EtcMigration::new(&opts).unwrap().unwrap();
+        assert_eq!(m.paths[0], ("chrony.conf".into(), true));
+        assert!(m.paths.contains(&("machine-id".into(), false)));
+        let opts = MigrateEtcOpts {
+            migrate_etc: true,
+            migrate_etc_paths: vec!["/var/lib/foo".into()],
+        };
+        assert!(EtcMigration::new(&opts).is_err());
+    }
+
+    #[test]
+    fn test_accounts() {
+        let host = indoc::indoc! { "
+            root:x:0:0:root:/root:/bin/bash
+            sshd:x:74:74:Privilege-separated SSH:/usr/share/empty.sshd:/usr/sbin/nologin
+            alice:x:1000:1000:Alice:/home/alice:/bin/bash
+            bob:x:1001:1001::/home/bob:/bin/zsh
+            nobody:x:65534:65534:Kernel Overflow User:/:/usr/sbin/nologin
+        " };
+        let target = indoc::indoc! { "
+            root:x:0:0:root:/root:/bin/bash
+            bob:x:1001:1001::/var/home/bob:/bin/bash"
+        };
+        let names = missing_regular_entries(host, target).unwrap();
+        assert_eq!(names, ["alice"]);
+        similar_asserts::assert_eq!(
+            append_entries(host, target, &names),
+            indoc::indoc! { "
+                root:x:0:0:root:/root:/bin/bash
+                bob:x:1001:1001::/var/home/bob:/bin/bash
+                alice:x:1000:1000:Alice:/home/alice:/bin/bash
+            " }
+        );
+        assert!(missing_regular_entries(target, host).unwrap().is_empty());
+
+        // The image already has a different account with the same UID
+        let target =
+            "root:x:0:0:root:/root:/bin/bash\ncore:x:1000:1000::/var/home/core:/bin/bash\n";
+        let e = missing_regular_entries(host, target).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Cannot migrate alice: ID 1000 is already used by core"
+        );
+
+        let host_shadow = "root:!locked::0:99999:7:::\nalice:$6$abc:19000:0:99999:7:::\n";
+        assert_eq!(
+            append_entries(host_shadow, "root:!locked::0:99999:7:::\n", &names),
+            host_shadow
+        );
+    }
+}
@@ -10,6 +10,22 @@ use ostree_ext::ostree;
 
 const ETC_TMPFILES: &str = "etc/tmpfiles.d";
 const ROOT_SSH_TMPFILE: &str = "bootc-root-ssh.conf";
+/// The location of the Ignition config, relative to the physical root; this is
+/// the same location used by `coreos-installer`.
+const IGNITION_DIR: &str = "boot/ignition";
+const IGNITION_CONFIG: &str = "config.ign";
+/// The presence of this file causes Ignition to run on the next boot.
+const IGNITION_FIRSTBOOT: &str = "boot/ignition.firstboot";
+/// The cloud-init NoCloud seed directory, relative to the stateroot.
+const CLOUD_INIT_SEED: &str = "var/lib/cloud/seed/nocloud";
+/// The headers identifying the formats of cloud-init user-data we accept.
+const CLOUD_INIT_HEADERS: &[&str] = &[
+    "#cloud-config",
+    "#!",
+    "#include",
+    "#cloud-boothook",
+    "Content-Type: multipart/",
+];
 
 #[context("Injecting root authorized_keys")]
 pub(crate) fn inject_root_ssh_authorized_keys(
@@ -50,6 +66,90 @@ pub(crate) fn inject_root_ssh_authorized_keys(
     Ok(())
 }
 
+/// Verify that the provided contents are a plausible Ignition config; full
+/// validation is left to Ignition itself.
+#[context("Validating Ignition config")]
+pub(crate) fn validate_ignition_config(contents: &str) -> Result<()> {
+    let config: serde_json::Value = serde_json::from_str(contents).context("Parsing JSON")?;
+    config
+        .get("ignition")
+        .and_then(|v| v.get("version"))
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Missing ignition.version"))?;
+    Ok(())
+}
+
+/// Verify that the provided contents are a cloud-init user-data format we understand.
+#[context("Validating cloud-init user-data")]
+pub(crate) fn validate_cloud_init_user_data(contents: &str) -> Result<()> {
+    if contents.starts_with("#cloud-config") {
+        let v: serde_yaml::Value = serde_yaml::from_str(contents).context("Parsing YAML")?;
+        if !(v.is_mapping() || v.is_null()) {
+            anyhow::bail!("Expected a mapping in #cloud-config");
+        }
+        return Ok(());
+    }
+    if !CLOUD_INIT_HEADERS.iter().any(|h| contents.starts_with(h)) {
+        anyhow::bail!("Unrecognized user-data format; expected one of: {CLOUD_INIT_HEADERS:?}");
+    }
+    Ok(())
+}
+
+/// Write the Ignition config into /boot, and arm Ignition to run on first boot.
+#[context("Injecting Ignition config")]
+pub(crate) fn inject_ignition_config(
+    physical_root: &Dir,
+    sepolicy: Option<&ostree::SePolicy>,
+    contents: &str,
+) -> Result<()> {
+    crate::lsm::ensure_dir_labeled(physical_root, IGNITION_DIR, None, 0o700.into(), sepolicy)?;
+    // The config may contain secrets
+    let path = Utf8Path::new(IGNITION_DIR).join(IGNITION_CONFIG);
+    crate::lsm::atomic_replace_labeled(physical_root, &path, 0o600.into(), sepolicy, |w| {
+        w.write_all(contents.as_bytes()).map_err(Into::into)
+    })?;
+    crate::lsm::atomic_replace_labeled(
+        physical_root,
+        IGNITION_FIRSTBOOT,
+        0o644.into(),
+        sepolicy,
+        |_| Ok(()),
+    )?;
+    println!("Injected: {path}");
+    Ok(())
+}
+
+/// Write the cloud-init user-data into the NoCloud seed directory in /var of
+/// the provided stateroot.
+#[context("Injecting cloud-init user-data")]
+pub(crate) fn inject_cloud_init_user_data(
+    stateroot: &Dir,
+    sepolicy: Option<&ostree::SePolicy>,
+    contents: &str,
+    instance_id: &str,
+) -> Result<()> {
+    let mut path = Utf8PathBuf::new();
+    for component in Utf8Path::new(CLOUD_INIT_SEED).components() {
+        path.push(component);
+        crate::lsm::ensure_dir_labeled(stateroot, &path, None, 0o755.into(), sepolicy)?;
+    }
+    let meta_data = format!("instance-id: {instance_id}\n");
+    for (name, buf, mode) in [
+        ("user-data", contents, 0o600),
+        ("meta-data", meta_data.as_str(), 0o644),
+    ] {
+        crate::lsm::atomic_replace_labeled(
+            stateroot,
+            path.join(name),
+            mode.into(),
+            sepolicy,
+            |w| w.write_all(buf.as_bytes()).map_err(Into::into),
+        )?;
+    }
+    println!("Injected: /{CLOUD_INIT_SEED}/user-data");
+    Ok(())
+}
+
 #[test]
 fn test_inject_root_ssh_symlinked() -> Result<()> {
     let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
@@ -84,3 +184,57 @@ fn test_inject_root_ssh_dir() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn test_validate_ignition_config() {
+    validate_ignition_config(r#"{"ignition": {"version": "3.4.0"}}"#).unwrap();
+    for invalid in [
+        "",
+        "{}",
+        r#"{"ignition": {}}"#,
+        r#"{"ignition": {"version": 3}}"#,
+    ] {
+        assert!(validate_ignition_config(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_validate_cloud_init_user_data() {
+    for valid in [
+        "#cloud-config\n",
+        "#cloud-config\nusers:\n  - name: admin\n",
+        "#!/bin/bash\necho hello\n",
+        "Content-Type: multipart/mixed; boundary=\"foo\"\n",
+    ] {
+        validate_cloud_init_user_data(valid).unwrap();
+    }
+    for invalid in [
+        "",
+        "users: []\n",
+        "#cloud-config\n- foo\n",
+        "#cloud-config\nfoo: [\n",
+    ] {
+        assert!(validate_cloud_init_user_data(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_inject_firstboot() -> Result<()> {
+    let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+
+    root.create_dir("boot")?;
+    inject_ignition_config(root, None, "{}")?;
+    assert_eq!(root.read_to_string("boot/ignition/config.ign")?, "{}");
+    assert!(root.try_exists(IGNITION_FIRSTBOOT)?);
+
+    inject_cloud_init_user_data(root, None, "#cloud-config\n", "iid-test")?;
+    assert_eq!(
+        root.read_to_string("var/lib/cloud/seed/nocloud/user-data")?,
+        "#cloud-config\n"
+    );
+    assert_eq!(
+        root.read_to_string("var/lib/cloud/seed/nocloud/meta-data")?,
+        "instance-id: iid-test\n"
+    );
+    Ok(())
+}
@@ -0,0 +1,429 @@
+//! # Pre-flight validation for `bootc install`
+//!
+//! `bootc install preflight` runs the checks which `bootc install to-disk`
+//! would otherwise only hit partway through an installation, without making
+//! any changes: the execution environment, the target device, the SELinux
+//! policy of the image, the boot firmware, and the binaries required for
+//! installing.  The result is a report suitable for provisioning pipelines
+//! to fail fast on.
+
+use std::os::fd::AsRawFd;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::{self, fs::Dir};
+use fn_error_context::context;
+use ostree_ext::ostree;
+use ostree_ext::ostree::gio;
+use rustix::fs::FileTypeExt;
+use serde::Serialize;
+
+use super::baseline::{Filesystem, BOOTPN_SIZE_MB, EFIPN_SIZE_MB};
+use super::ARCH_USES_EFI;
+use crate::cli::OutputFormat;
+use crate::task::Task;
+
+/// The EFI variable holding the Secure Boot state.
+const SECURE_BOOT_VAR: &str =
+    "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+/// Space reserved for partitions other than the root, in MiB.
+const OVERHEAD_MB: u64 = (BOOTPN_SIZE_MB + EFIPN_SIZE_MB) as u64 + 16;
+
+/// Options for `bootc install preflight`.
+#[derive(Debug, Clone, clap::Parser, PartialEq, Eq)]
+pub(crate) struct InstallPreflightOpts {
+    /// The target block device, as would be passed to `bootc install to-disk`;
+    /// if unset, the device checks are skipped.
+    pub(crate) device: Option<Utf8PathBuf>,
+
+    /// The target root filesystem type; defaults to the one from the install
+    /// configuration of the image.
+    #[clap(long, value_enum)]
+    pub(crate) filesystem: Option<Filesystem>,
+
+    /// The output format.
+    #[clap(long, value_enum, default_value = "humanreadable")]
+    pub(crate) format: OutputFormat,
+}
+
+/// The outcome of a single check.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CheckStatus {
+    /// The installation can proceed
+    Pass,
+    /// The installation can proceed, but may not do what is expected
+    Warning,
+    /// The installation would fail
+    Fail,
+}
+
+impl CheckStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warning => "WARN",
+            CheckStatus::Fail => "FAIL",
+        }
+    }
+}
+
+/// The result of a single check.
+#[derive(Debug, Serialize)]
+struct CheckResult {
+    name: &'static str,
+    status: CheckStatus,
+    message: String,
+}
+
+/// The results of all checks, as output in JSON.
+#[derive(Debug, Serialize)]
+struct PreflightReport {
+    /// True if no check failed.
+    passed: bool,
+    checks: Vec<CheckResult>,
+}
+
+type CheckOutcome = Result<(CheckStatus, String)>;
+
+fn check_privileges() -> CheckOutcome {
+    crate::cli::require_root()?;
+    Ok((
+        CheckStatus::Pass,
+        "Running as root with CAP_SYS_ADMIN".into(),
+    ))
+}
+
+fn check_container(rootfs: &Dir) -> CheckOutcome {
+    if !crate::containerenv::is_container(rootfs) {
+        return Ok((
+            CheckStatus::Warning,
+            "Not running in a container; --source-imgref is required".into(),
+        ));
+    }
+    super::require_host_pidns()?;
+    super::require_host_userns()?;
+    let info = crate::containerenv::get_container_execution_info(rootfs)?;
+    if info.rootless.as_deref() == Some("1") {
+        anyhow::bail!("Cannot install from rootless podman; this command must be run as root");
+    }
+    Ok((
+        CheckStatus::Pass,
+        format!("Running in a privileged container from {}", info.image),
+    ))
+}
+
+/// Determine the root filesystem type.
+fn root_filesystem(opts: &InstallPreflightOpts) -> Result<Option<Filesystem>> {
+    if let Some(fs) = opts.filesystem {
+        return Ok(Some(fs));
+    }
+    let config = super::config::load_config()?;
+    Ok(config
+        .as_ref()
+        .and_then(|c| c.filesystem_root())
+        .and_then(|r| r.fstype))
+}
+
+fn check_filesystem(fs: Option<Filesystem>) -> CheckOutcome {
+    match fs {
+        Some(fs) => Ok((CheckStatus::Pass, format!("Root filesystem type: {fs}"))),
+        None => anyhow::bail!(
+            "No root filesystem type in the install configuration; --filesystem is required"
+        ),
+    }
+}
+
+/// The binaries invoked by `bootc install to-disk`.
+fn required_binaries(fs: Option<Filesystem>) -> Vec<String> {
+    let mut r: Vec<String> = ["lsblk", "sfdisk", "wipefs", "udevadm"]
+        .into_iter()
+        .map(ToOwned::to_owned)
+        .collect();
+    r.extend(fs.map(|fs| format!("mkfs.{fs}")));
+    if ARCH_USES_EFI {
+        r.push("mkfs.fat".into());
+    }
+    if cfg!(target_arch = "s390x") {
+        r.push("zipl".into());
+    } else {
+        r.push("bootupctl".into());
+    }
+    r
+}
+
+fn check_binaries(fs: Option<Filesystem>) -> CheckOutcome {
+    let required = required_binaries(fs);
+    let missing = required
+        .iter()
+        .filter(|b| !crate::utils::find_in_path(b))
+        .map(|b| b.as_str())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        anyhow::bail!("Missing required binaries: {}", missing.join(" "));
+    }
+    Ok((
+        CheckStatus::Pass,
+        format!("Found required binaries: {}", required.join(" ")),
+    ))
+}
+
+fn check_selinux(rootfs: &Dir) -> CheckOutcome {
+    if !crate::lsm::have_selinux_policy(rootfs)? {
+        return Ok((
+            CheckStatus::Pass,
+            "No SELinux policy in the image; the target will not be labeled".into(),
+        ));
+    }
+    let policy = ostree::SePolicy::new_at(rootfs.as_raw_fd(), gio::Cancellable::NONE)
+        .context("Loading SELinux policy of the image")?;
+    let name = policy.name().filter(|n| !n.is_empty()).ok_or_else(|| {
+        anyhow::anyhow!("The image has /etc/selinux/config, but no loadable SELinux policy")
+    })?;
+    let host = if crate::lsm::selinux_enabled()? {
+        "enabled"
+    } else {
+        "disabled"
+    };
+    Ok((
+        CheckStatus::Pass,
+        format!("SELinux policy {name} found in the image; SELinux is {host} on the host"),
+    ))
+}
+
+/// Parse the value of the `SecureBoot` EFI variable, which is prefixed by
+/// four bytes of attributes.
+fn parse_secure_boot(buf: &[u8]) -> Option<bool> {
+    buf.get(4).map(|&v| v == 1)
+}
+
+fn check_secure_boot() -> CheckOutcome {
+    if !Utf8Path::new("/sys/firmware/efi").try_exists()? {
+        let msg = if ARCH_USES_EFI {
+            "Not booted via EFI; installing for BIOS boot"
+        } else {
+            "Not booted via EFI"
+        };
+        return Ok((CheckStatus::Pass, msg.into()));
+    }
+    let state = match std::fs::read(SECURE_BOOT_VAR) {
+        Ok(buf) => parse_secure_boot(&buf),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("Reading SecureBoot EFI variable"),
+    };
+    let msg = match state {
+        Some(true) => "Booted via EFI; Secure Boot is enabled",
+        Some(false) => "Booted via EFI; Secure Boot is disabled",
+        None => "Booted via EFI; Secure Boot state is unknown",
+    };
+    Ok((CheckStatus::Pass, msg.into()))
+}
+
+/// Compute the disk usage of the operating system content in the image.
+#[context("Computing size of /usr")]
+fn usr_size() -> Result<u64> {
+    let out = Task::new_quiet("du")
+        .args(["-s", "-x", "--bytes", "/usr"])
+        .read()?;
+    parse_du(&out)
+}
+
+fn parse_du(out: &str) -> Result<u64> {
+    out.split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Empty output from du"))?
+        .parse()
+        .context("Parsing du output")
+}
+
+/// Check the size of the device against what the image needs; twice the
+/// content of the image leaves room for one update.
+fn check_size(device: u64, usr: u64) -> (CheckStatus, String) {
+    let mib = |v: u64| v / (1024 * 1024);
+    let overhead = OVERHEAD_MB * 1024 * 1024;
+    let minimum = overhead + usr;
+    let recommended = overhead + 2 * usr;
+    if device < minimum {
+        (
+            CheckStatus::Fail,
+            format!(
+                "Device size {} MiB is less than the minimum of {} MiB",
+                mib(device),
+                mib(minimum)
+            ),
+        )
+    } else if device < recommended {
+        (
+            CheckStatus::Warning,
+            format!(
+                "Device size {} MiB may not leave space for updates; recommended is at least {} MiB",
+                mib(device),
+                mib(recommended)
+            ),
+        )
+    } else {
+        (
+            CheckStatus::Pass,
+            format!("Device size {} MiB", mib(device)),
+        )
+    }
+}
+
+fn check_device(device: &Utf8Path) -> Vec<(&'static str, CheckOutcome)> {
+    let meta = match device
+        .metadata()
+        .with_context(|| format!("Querying {device}"))
+    {
+        Ok(meta) => meta,
+        Err(e) => return vec![("device", Err(e))],
+    };
+    if !meta.file_type().is_block_device() {
+        return vec![(
+            "device",
+            Err(anyhow::anyhow!("Not a block device: {device}")),
+        )];
+    }
+    let dev = match crate::blockdev::list_dev(device) {
+        Ok(dev) => dev,
+        Err(e) => return vec![("device", Err(e))],
+    };
+    let contents = {
+        let mut found = dev
+            .children
+            .iter()
+            .flatten()
+            .map(|c| match c.fstype.as_deref() {
+                Some(fstype) => format!("{} ({fstype})", c.path()),
+                None => c.path(),
+            })
+            .collect::<Vec<_>>();
+        if let Some(fstype) = dev.fstype.as_deref() {
+            found.push(format!("{fstype} filesystem"));
+        }
+        if found.is_empty() {
+            (CheckStatus::Pass, format!("{device} is empty"))
+        } else {
+            (
+                CheckStatus::Warning,
+                format!(
+                    "{device} is not empty, and must be wiped (--wipe): {}",
+                    found.join(", ")
+                ),
+            )
+        }
+    };
+    vec![
+        ("device", Ok(contents)),
+        (
+            "device-size",
+            usr_size().map(|usr| check_size(dev.size, usr)),
+        ),
+    ]
+}
+
+/// Run all checks.
+fn run_checks(opts: &InstallPreflightOpts, rootfs: &Dir) -> Vec<CheckResult> {
+    let fs = root_filesystem(opts);
+    let fs_opt = fs.as_ref().ok().copied().flatten();
+    let mut checks: Vec<(&'static str, CheckOutcome)> = vec![
+        ("privileges", check_privileges()),
+        ("container", check_container(rootfs)),
+        ("filesystem", fs.and_then(check_filesystem)),
+        ("binaries", check_binaries(fs_opt)),
+        ("selinux", check_selinux(rootfs)),
+        ("secure-boot", check_secure_boot()),
+    ];
+    if let Some(device) = opts.device.as_deref() {
+        checks.extend(check_device(device));
+    }
+    checks
+        .into_iter()
+        .map(|(name, r)| match r {
+            Ok((status, message)) => CheckResult {
+                name,
+                status,
+                message,
+            },
+            Err(e) => CheckResult {
+                name,
+                status: CheckStatus::Fail,
+                message: format!("{e:#}"),
+            },
+        })
+        .collect()
+}
+
+/// Implementation of `bootc install preflight`.
+#[context("Running pre-flight checks")]
+pub(crate) fn preflight(opts: InstallPreflightOpts) -> Result<()> {
+    let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let checks = run_checks(&opts, rootfs);
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == CheckStatus::Fail)
+        .count();
+    match opts.format {
+        OutputFormat::HumanReadable => {
+            for c in checks.iter() {
+                println!("{} {}: {}", c.status.as_str(), c.name, c.message);
+            }
+        }
+        OutputFormat::Json => {
+            let report = PreflightReport {
+                passed: failed == 0,
+                checks,
+            };
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &report)?;
+            println!();
+        }
+        OutputFormat::Yaml => {
+            let report = PreflightReport {
+                passed: failed == 0,
+                checks,
+            };
+            serde_yaml::to_writer(std::io::stdout().lock(), &report)?;
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("Pre-flight checks failed: {failed}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_size() {
+        const MIB: u64 = 1024 * 1024;
+        let usr = 2048 * MIB;
+        let (status, msg) = check_size(1024 * MIB, usr);
+        assert_eq!(status, CheckStatus::Fail);
+        assert!(msg.contains("minimum of 3086 MiB"), "{msg}");
+        let (status, _) = check_size(4096 * MIB, usr);
+        assert_eq!(status, CheckStatus::Warning);
+        let (status, msg) = check_size(16384 * MIB, usr);
+        assert_eq!(status, CheckStatus::Pass);
+        assert_eq!(msg, "Device size 16384 MiB");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_du("123456\t/usr\n").unwrap(), 123456);
+        assert!(parse_du("").is_err());
+        assert_eq!(parse_secure_boot(&[0x6, 0, 0, 0, 1]), Some(true));
+        assert_eq!(parse_secure_boot(&[0x6, 0, 0, 0, 0]), Some(false));
+        assert_eq!(parse_secure_boot(&[0x6, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_required_binaries() {
+        let r = required_binaries(Some(Filesystem::Xfs));
+        assert!(r.iter().any(|b| b == "mkfs.xfs"));
+        assert!(r.iter().any(|b| b == "sfdisk"));
+        let r = required_binaries(None);
+        assert!(!r.iter().any(|b| b.starts_with("mkfs.x")));
+    }
+}
@@ -3,6 +3,8 @@
 
 use std::collections::BTreeMap;
 
+use anyhow::Context;
+use ostree_ext::glib;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -26,4 +28,718 @@ pub struct ObjectMeta {
     pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_references: Option<Vec<OwnerReference>>,
+}
+
+/// A reference to an object which owns another object.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OwnerReference {
+    pub api_version: String,
+    pub kind: String,
+    pub name: String,
+    pub uid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub controller: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_owner_deletion: Option<bool>,
+}
+
+/// Arbitrary binary data, serialized as a base64 encoded string.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ByteString(pub Vec<u8>);
+
+impl Serialize for ByteString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&glib::base64_encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteString {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        // glib's decoder silently skips invalid input, so validate first.
+        let unpadded = s.trim_end_matches('=');
+        let (len, unpadded_len) = (s.as_bytes().len(), unpadded.as_bytes().len());
+        let valid = len % 4 == 0
+            && len - unpadded_len <= 2
+            && unpadded
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'+' || c == b'/');
+        if !valid {
+            return Err(serde::de::Error::custom("invalid base64 data"));
+        }
+        Ok(Self(glib::base64_decode(&s)))
+    }
+}
+
+/// A Kubernetes `v1/ConfigMap`, holding non-confidential key-value data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+#[allow(dead_code)]
+pub struct ConfigMap {
+    /// Metadata
+    #[serde(flatten)]
+    pub resource: Resource,
+    /// UTF-8 data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<BTreeMap<String, String>>,
+    /// Binary data, base64 encoded when serialized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary_data: Option<BTreeMap<String, ByteString>>,
+    /// If true, the data cannot be updated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub immutable: Option<bool>,
+}
+
+impl TypedResource for ConfigMap {
+    const API_VERSION: &'static str = "v1";
+    const KIND: &'static str = "ConfigMap";
+
+    fn resource(&self) -> &Resource {
+        &self.resource
+    }
+}
+
+/// A Kubernetes `v1/Secret`, holding (small amounts of) sensitive data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+#[allow(dead_code)]
+pub struct Secret {
+    /// Metadata
+    #[serde(flatten)]
+    pub resource: Resource,
+    /// The secret data, base64 encoded when serialized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<BTreeMap<String, ByteString>>,
+    /// Plain string data; entries here take precedence over `data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub string_data: Option<BTreeMap<String, String>>,
+    /// Used to facilitate programmatic handling of secret data.
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub ty: Option<String>,
+    /// If true, the data cannot be updated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub immutable: Option<bool>,
+}
+
+impl TypedResource for Secret {
+    const API_VERSION: &'static str = "v1";
+    const KIND: &'static str = "Secret";
+
+    fn resource(&self) -> &Resource {
+        &self.resource
+    }
+}
+
+#[allow(dead_code)]
+impl Secret {
+    /// Return the merged contents of `data` and `stringData`, with the latter
+    /// taking precedence as it does in Kubernetes.
+    pub(crate) fn merged_data(&self) -> BTreeMap<&str, &[u8]> {
+        let data = self
+            .data
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.as_str(), v.0.as_slice()));
+        let string_data = self
+            .string_data
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.as_str(), v.as_bytes()));
+        data.chain(string_data).collect()
+    }
+}
+
+/// The maximum length of a DNS-1123 subdomain.
+const DNS1123_SUBDOMAIN_MAX_LEN: usize = 253;
+
+/// Validate that the provided value is usable as an object name, i.e. that
+/// it is a DNS-1123 subdomain: at most 253 characters, consisting of lowercase
+/// alphanumeric characters, `-` or `.`, and starting and ending with an
+/// alphanumeric character.
+pub(crate) fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        anyhow::bail!("Invalid name: must not be empty");
+    }
+    let len = name.as_bytes().len();
+    if len > DNS1123_SUBDOMAIN_MAX_LEN {
+        anyhow::bail!("Invalid name: length {len} exceeds maximum of {DNS1123_SUBDOMAIN_MAX_LEN}");
+    }
+    if let Some((i, c)) = name
+        .char_indices()
+        .find(|&(_, c)| !matches!(c, 'a'..='z' | '0'..='9' | '-' | '.'))
+    {
+        anyhow::bail!(
+            "Invalid name {name:?}: invalid character {c:?} at offset {i}; \
+             only lowercase alphanumerics, '-' and '.' are allowed"
+        );
+    }
+    for (which, c) in [("start", name.chars().next()), ("end", name.chars().last())] {
+        if !c.is_some_and(|c| c.is_ascii_alphanumeric()) {
+            anyhow::bail!("Invalid name {name:?}: must {which} with an alphanumeric character");
+        }
+    }
+    Ok(())
+}
+
+/// A resource with a fixed `apiVersion` and `kind`.
+pub(crate) trait TypedResource {
+    /// The expected `apiVersion`.
+    const API_VERSION: &'static str;
+    /// The expected `kind`.
+    const KIND: &'static str;
+
+    /// The object header.
+    fn resource(&self) -> &Resource;
+}
+
+/// A list of objects; this is both the generic `v1/List`, and the
+/// basis for typed lists such as `ConfigMapList`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct List<T> {
+    /// Metadata
+    #[serde(flatten)]
+    pub resource: Resource,
+    /// The objects in the list.
+    #[serde(default)]
+    pub items: Vec<T>,
+}
+
+/// A list of config maps.
+#[allow(dead_code)]
+pub type ConfigMapList = List<ConfigMap>;
+
+/// Verify that an object has the expected `apiVersion` and `kind`.
+fn check_type<T: TypedResource>(resource: &Resource) -> anyhow::Result<()> {
+    if resource.api_version != T::API_VERSION || resource.kind != T::KIND {
+        anyhow::bail!(
+            "Expected {}/{}, found {}/{}",
+            T::API_VERSION,
+            T::KIND,
+            resource.api_version,
+            resource.kind
+        );
+    }
+    Ok(())
+}
+
+/// Parse a stream of one or more YAML (or JSON) documents, each of which is
+/// either a single object, a `List`, or a typed list such as `ConfigMapList`.
+/// Lists are expanded in place, so objects are returned in the order they
+/// appear in the input.  Empty documents are ignored, and duplicate object
+/// names are an error.
+#[allow(dead_code)]
+pub(crate) fn from_str_multi<T>(s: &str) -> anyhow::Result<Vec<T>>
+where
+    T: TypedResource + serde::de::DeserializeOwned,
+{
+    let typed_list_kind = format!("{}List", T::KIND);
+    let mut r = Vec::new();
+    for (i, doc) in serde_yaml::Deserializer::from_str(s).enumerate() {
+        let doc = serde_yaml::Value::deserialize(doc)
+            .and_then(|v| {
+                if v.is_null() {
+                    return Ok(Vec::new());
+                }
+                let kind = v.get("kind").and_then(|k| k.as_str());
+                if kind == Some("List") || kind == Some(typed_list_kind.as_str()) {
+                    let list: List<T> = serde_yaml::from_value(v)?;
+                    Ok(list.items)
+                } else {
+                    serde_yaml::from_value(v).map(|o| vec![o])
+                }
+            })
+            .with_context(|| format!("Parsing document {i}"))?;
+        for obj in doc {
+            check_type::<T>(obj.resource()).with_context(|| format!("Parsing document {i}"))?;
+            r.push(obj);
+        }
+    }
+    let mut names = std::collections::BTreeSet::new();
+    for obj in r.iter() {
+        if let Some(name) = obj.resource().metadata.name.as_deref() {
+            if !names.insert(name) {
+                anyhow::bail!("Duplicate {} name: {name}", T::KIND);
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// Walk the originally parsed document alongside the re-serialized value, and
+/// record every field present in the former but not the latter.  This works
+/// regardless of `#[serde(flatten)]`, which defeats `deny_unknown_fields`.
+fn find_unknown_fields(
+    input: &serde_yaml::Value,
+    parsed: &serde_yaml::Value,
+    path: &str,
+    unknown: &mut Vec<String>,
+) {
+    use serde_yaml::Value;
+    match (input, parsed) {
+        (Value::Mapping(input), Value::Mapping(parsed)) => {
+            for (k, v) in input {
+                // Explicit nulls are equivalent to an unset optional field
+                if v.is_null() {
+                    continue;
+                }
+                let name = match k.as_str() {
+                    Some(k) => k.to_owned(),
+                    None => serde_yaml::to_string(k)
+                        .unwrap_or_default()
+                        .trim()
+                        .to_owned(),
+                };
+                let subpath = if path.is_empty() {
+                    name
+                } else {
+                    format!("{path}.{name}")
+                };
+                match parsed.get(k) {
+                    Some(parsed_v) => find_unknown_fields(v, parsed_v, &subpath, unknown),
+                    None => unknown.push(subpath),
+                }
+            }
+        }
+        (Value::Sequence(input), Value::Sequence(parsed)) => {
+            for (i, (v, parsed_v)) in input.iter().zip(parsed).enumerate() {
+                find_unknown_fields(v, parsed_v, &format!("{path}[{i}]"), unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// An object parsed from a document of a priori unknown type.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)]
+pub(crate) enum KnownObject {
+    /// A `v1/ConfigMap`
+    ConfigMap(ConfigMap),
+    /// A `v1/Secret`
+    Secret(Secret),
+    /// Any other well-formed object.
+    Unknown {
+        /// The object header.
+        resource: Resource,
+        /// The full document.
+        raw: serde_yaml::Value,
+    },
+}
+
+impl KnownObject {
+    /// The object header.
+    #[allow(dead_code)]
+    pub(crate) fn resource(&self) -> &Resource {
+        match self {
+            KnownObject::ConfigMap(o) => o.resource(),
+            KnownObject::Secret(o) => o.resource(),
+            KnownObject::Unknown { resource, .. } => resource,
+        }
+    }
+}
+
+/// Parse a single YAML (or JSON) document into an object, dispatching
+/// on its `apiVersion` and `kind`.
+#[allow(dead_code)]
+pub(crate) fn parse_object(buf: &[u8]) -> anyhow::Result<KnownObject> {
+    let raw: serde_yaml::Value = serde_yaml::from_slice(buf)?;
+    let resource: Resource = serde_yaml::from_value(raw.clone())?;
+    let kind = (resource.api_version.as_str(), resource.kind.as_str());
+    let r = if kind == (ConfigMap::API_VERSION, ConfigMap::KIND) {
+        KnownObject::ConfigMap(serde_yaml::from_value(raw)?)
+    } else if kind == (Secret::API_VERSION, Secret::KIND) {
+        KnownObject::Secret(serde_yaml::from_value(raw)?)
+    } else {
+        KnownObject::Unknown { resource, raw }
+    };
+    Ok(r)
+}
+
+/// Parse a YAML (or JSON) document in strict mode: the `apiVersion` and `kind`
+/// must match the target type, and unknown fields are rejected.  Errors are
+/// qualified with the path to the offending field.
+///
+/// Unknown fields below any of the `ignored` top-level fields are accepted;
+/// this is intended for read-only fields such as `status`.
+pub(crate) fn from_str_strict<T>(s: &str, ignored: &[&str]) -> anyhow::Result<T>
+where
+    T: TypedResource + serde::de::DeserializeOwned + Serialize,
+{
+    let input: serde_yaml::Value = serde_yaml::from_str(s)?;
+    for (field, expected) in [("apiVersion", T::API_VERSION), ("kind", T::KIND)] {
+        match input.get(field).map(|v| v.as_str()) {
+            Some(Some(v)) if v == expected => {}
+            Some(Some(v)) => anyhow::bail!("{field}: expected {expected}, found {v}"),
+            Some(None) => anyhow::bail!("{field}: expected a string"),
+            None => anyhow::bail!("{field}: missing"),
+        }
+    }
+    // Parse from the original text, which gives us path-qualified errors
+    let r: T = serde_yaml::from_str(s)?;
+    if let Some(name) = r.resource().metadata.name.as_deref() {
+        validate_name(name).context("metadata.name")?;
+    }
+    let parsed = serde_yaml::to_value(&r)?;
+    let mut unknown = Vec::new();
+    find_unknown_fields(&input, &parsed, "", &mut unknown);
+    unknown.retain(|path| {
+        !ignored.iter().any(|i| {
+            path.strip_prefix(i)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+        })
+    });
+    if !unknown.is_empty() {
+        anyhow::bail!("Unknown fields: {}", unknown.join(", "));
+    }
+    Ok(r)
+}
+
+/// Apply a JSON merge patch ([RFC 7386](https://www.rfc-editor.org/rfc/rfc7386))
+/// to a document: objects are merged recursively, `null` removes a field, and
+/// any other value replaces the target.
+pub(crate) fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    use serde_json::Value;
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let Value::Object(target) = target else {
+        unreachable!()
+    };
+    for (k, v) in patch {
+        if v.is_null() {
+            target.remove(k);
+        } else {
+            merge_patch(target.entry(k.as_str()).or_insert(Value::Null), v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret() {
+        let secret: Secret = serde_yaml::from_str(indoc::indoc! { r#"
+            apiVersion: v1
+            kind: Secret
+            metadata:
+              name: pull-secret
+            type: Opaque
+            data:
+              username: YWRtaW4=
+              password: aHVudGVyMg==
+            stringData:
+              password: swordfish
+        "# })
+        .unwrap();
+        assert_eq!(secret.resource.kind, Secret::KIND);
+        assert_eq!(secret.ty.as_deref(), Some("Opaque"));
+        let merged = secret.merged_data();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged["username"], b"admin");
+        assert_eq!(merged["password"], b"swordfish");
+
+        // Round trip through serialization
+        let serialized = serde_json::to_string(&secret).unwrap();
+        assert!(serialized.contains(r#""username":"YWRtaW4=""#));
+        let reparsed: Secret = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(secret, reparsed);
+
+        for invalid in ["YWRtaW4", "YW=taW4=", "YWRt*W4=", "Y==="] {
+            let r = serde_json::from_value::<ByteString>(serde_json::Value::from(invalid));
+            assert!(r.is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_from_str_strict() {
+        let valid = indoc::indoc! { r#"
+            apiVersion: v1
+            kind: ConfigMap
+            metadata:
+              name: foo
+              annotations:
+                example.com/bar: baz
+              labels: null
+            data:
+              username: admin
+        "# };
+        let map: ConfigMap = from_str_strict(valid, &[]).unwrap();
+        assert_eq!(map.resource.metadata.name.as_deref(), Some("foo"));
+
+        let unknown = indoc::indoc! { r#"
+            apiVersion: v1
+            kind: ConfigMap
+            metadata:
+              name: foo
+              nmespace: bar
+            strngData:
+              foo: bar
+        "# };
+        let e = from_str_strict::<ConfigMap>(unknown, &[])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "Unknown fields: metadata.nmespace, strngData");
+
+        let e = from_str_strict::<ConfigMap>("apiVersion: v1\nkind: Secret\n", &[])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "kind: expected ConfigMap, found Secret");
+        let e = from_str_strict::<ConfigMap>("kind: ConfigMap\n", &[])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "apiVersion: missing");
+
+        let invalid_value = indoc::indoc! { r#"
+            apiVersion: v1
+            kind: ConfigMap
+            immutable: 42
+        "# };
+        assert!(from_str_strict::<ConfigMap>(invalid_value, &[]).is_err());
+
+        let ignored = indoc::indoc! { r#"
+            apiVersion: v1
+            kind: ConfigMap
+            data:
+              username: admin
+            dataa:
+              foo: bar
+            strngData:
+              foo: bar
+        "# };
+        let e = from_str_strict::<ConfigMap>(ignored, &["data"])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "Unknown fields: dataa, strngData");
+        from_str_strict::<ConfigMap>(ignored, &["dataa", "strngData"]).unwrap();
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        use serde_json::json;
+        // Examples from RFC 7386 appendix A
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (mut target, patch, expected) in cases {
+            merge_patch(&mut target, &patch);
+            assert_eq!(target, expected, "{patch}");
+        }
+    }
+
+    #[test]
+    fn test_from_str_multi() {
+        let input = indoc::indoc! { r#"
+            apiVersion: v1
+            kind: ConfigMap
+            metadata:
+              name: b
+            data:
+              foo: bar
+            ---
+            ---
+            apiVersion: v1
+            kind: ConfigMapList
+            items:
+            - apiVersion: v1
+              kind: ConfigMap
+              metadata:
+                name: a
+              binaryData:
+                blob: AAEC
+            - apiVersion: v1
+              kind: ConfigMap
+              metadata:
+                name: c
+            ---
+            {"apiVersion": "v1", "kind": "List", "items": [{"apiVersion": "v1", "kind": "ConfigMap", "metadata": {"name": "d"}}]}
+        "# };
+        let maps: Vec<ConfigMap> = from_str_multi(input).unwrap();
+        let names = maps
+            .iter()
+            .map(|m| m.resource.metadata.name.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["b", "a", "c", "d"]);
+        assert_eq!(maps[0].data.as_ref().unwrap()["foo"], "bar");
+        assert_eq!(
+            maps[1].binary_data.as_ref().unwrap()["blob"],
+            ByteString(vec![0, 1, 2])
+        );
+
+        let duplicate = "{apiVersion: v1, kind: ConfigMap, metadata: {name: a}}\n---\n{apiVersion: v1, kind: ConfigMap, metadata: {name: a}}\n";
+        let e = from_str_multi::<ConfigMap>(duplicate).unwrap_err();
+        assert_eq!(e.to_string(), "Duplicate ConfigMap name: a");
+
+        let wrong_kind = "{apiVersion: v1, kind: ConfigMap}\n---\n{apiVersion: v1, kind: Secret}\n";
+        let e = from_str_multi::<ConfigMap>(wrong_kind).unwrap_err();
+        assert_eq!(
+            format!("{e:#}"),
+            "Parsing document 1: Expected v1/ConfigMap, found v1/Secret"
+        );
+    }
+
+    #[test]
+    fn test_object_meta_roundtrip() {
+        let input = indoc::indoc! { r#"
+            apiVersion: v1
+            kind: ConfigMap
+            metadata:
+              name: foo
+              namespace: default
+              uid: 6b0b4f4c-2b9f-4a3e-9d59-2d0d3c1e5f7a
+              resourceVersion: '12345'
+              creationTimestamp: 2024-05-01T12:00:00Z
+              ownerReferences:
+              - apiVersion: apps/v1
+                kind: Deployment
+                name: bar
+                uid: 0f6c5e3a-1d2b-4c3d-8e9f-a0b1c2d3e4f5
+                controller: true
+        "# };
+        let map: ConfigMap = from_str_strict(input, &[]).unwrap();
+        let meta = &map.resource.metadata;
+        assert_eq!(meta.resource_version.as_deref(), Some("12345"));
+        assert_eq!(
+            meta.creation_timestamp.unwrap().to_rfc3339(),
+            "2024-05-01T12:00:00+00:00"
+        );
+        let owner = &meta.owner_references.as_ref().unwrap()[0];
+        assert_eq!(owner.kind, "Deployment");
+        assert_eq!(owner.block_owner_deletion, None);
+
+        let serialized = serde_json::to_value(&map).unwrap();
+        assert_eq!(
+            serialized["metadata"]["creationTimestamp"],
+            "2024-05-01T12:00:00Z"
+        );
+        let reparsed: ConfigMap = serde_json::from_value(serialized).unwrap();
+        assert_eq!(map, reparsed);
+    }
+
+    #[test]
+    fn test_validate_name() {
+        let long = "a".repeat(253);
+        let valid = ["a", "foo", "foo-bar.baz", "0abc", long.as_str()];
+        for name in valid {
+            validate_name(name).unwrap();
+        }
+        let invalid = [
+            ("", "Invalid name: must not be empty"),
+            (
+                "Foo",
+                r#"Invalid name "Foo": invalid character 'F' at offset 0; only lowercase alphanumerics, '-' and '.' are allowed"#,
+            ),
+            (
+                "foo_bar",
+                r#"Invalid name "foo_bar": invalid character '_' at offset 3; only lowercase alphanumerics, '-' and '.' are allowed"#,
+            ),
+            (
+                "-foo",
+                r#"Invalid name "-foo": must start with an alphanumeric character"#,
+            ),
+            (
+                "foo.",
+                r#"Invalid name "foo.": must end with an alphanumeric character"#,
+            ),
+        ];
+        for (name, expected) in invalid {
+            assert_eq!(validate_name(name).unwrap_err().to_string(), expected);
+        }
+        let e = validate_name(&"a".repeat(254)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid name: length 254 exceeds maximum of 253"
+        );
+
+        let e = from_str_strict::<ConfigMap>(
+            "{apiVersion: v1, kind: ConfigMap, metadata: {name: Foo}}",
+            &[],
+        )
+        .unwrap_err();
+        assert!(format!("{e:#}").starts_with("metadata.name: Invalid name"));
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let o = parse_object(b"{apiVersion: v1, kind: ConfigMap, data: {foo: bar}}").unwrap();
+        assert!(matches!(o, KnownObject::ConfigMap(_)));
+        let o =
+            parse_object(br#"{"apiVersion": "v1", "kind": "Secret", "type": "Opaque"}"#).unwrap();
+        let KnownObject::Secret(secret) = &o else {
+            panic!("Expected secret, found {o:?}");
+        };
+        assert_eq!(secret.ty.as_deref(), Some("Opaque"));
+        let o = parse_object(
+            b"{apiVersion: apps/v1, kind: Deployment, metadata: {name: foo}, spec: {}}",
+        )
+        .unwrap();
+        assert_eq!(o.resource().kind, "Deployment");
+        assert_eq!(o.resource().metadata.name.as_deref(), Some("foo"));
+        let KnownObject::Unknown { raw, .. } = &o else {
+            panic!("Expected unknown object, found {o:?}");
+        };
+        assert!(raw.get("spec").unwrap().is_mapping());
+        // A known kind with a different API version is not treated as known
+        let o = parse_object(b"{apiVersion: example.com/v1, kind: Secret}").unwrap();
+        assert!(matches!(o, KnownObject::Unknown { .. }));
+        assert!(parse_object(b"{kind: Secret}").is_err());
+        assert!(parse_object(b"{apiVersion: v1, kind: Secret, data: {foo: '!'}}").is_err());
+    }
 }
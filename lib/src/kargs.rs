@@ -10,13 +10,28 @@ use ostree_ext::ostree::Deployment;
 use ostree_ext::prelude::Cast;
 use ostree_ext::prelude::FileEnumeratorExt;
 use ostree_ext::prelude::FileExt;
+use ostree_ext::sysroot::LockMode;
 use serde::Deserialize;
 
+use crate::cli::{KargsEditOpts, KargsOpts, KargsTarget};
 use crate::deploy::ImageState;
 use crate::store::Storage;
 
 const KARGS_PATH: &str = "usr/lib/bootc/kargs.d";
 
+/// Kernel arguments which are required to boot the system, and hence
+/// cannot be changed via `bootc kargs` without `--force`.
+const PROTECTED_KARGS: &[&str] = &[
+    "root",
+    "rootflags",
+    "rootfstype",
+    "ostree",
+    "boot",
+    "init",
+    "ro",
+    "rw",
+];
+
 /// The kargs.d configuration file.
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
@@ -120,12 +135,7 @@ pub(crate) fn get_kargs(
     let sys_arch = std::env::consts::ARCH;
 
     // Get the kargs used for the merge in the bootloader config
-    if let Some(bootconfig) = ostree::Deployment::bootconfig(merge_deployment) {
-        if let Some(options) = ostree::BootconfigParser::get(&bootconfig, "options") {
-            let options = options.split_whitespace().map(|s| s.to_owned());
-            kargs.extend(options);
-        }
-    };
+    kargs.extend(deployment_kargs(merge_deployment));
 
     // Get the kargs in kargs.d of the merge
     let merge_root = &crate::utils::deployment_fd(sysroot, merge_deployment)?;
@@ -172,6 +182,160 @@ pub(crate) fn get_kargs(
     Ok(kargs)
 }
 
+/// Return the kernel arguments from the bootloader entry of a deployment.
+pub(crate) fn deployment_kargs(deployment: &Deployment) -> Vec<String> {
+    deployment
+        .bootconfig()
+        .and_then(|bootconfig| bootconfig.get("options"))
+        .map(|options| options.split_whitespace().map(ToOwned::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// Return the key of a kernel argument, i.e. the part before any `=`.
+fn karg_key(karg: &str) -> &str {
+    karg.split_once('=').map(|(k, _)| k).unwrap_or(karg)
+}
+
+/// A modification to a set of kernel arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KargsEdit {
+    Set,
+    Append,
+    Delete,
+}
+
+/// Apply a modification to the provided kernel arguments, returning the new arguments.
+/// Unless `force` is set, modifying any of [`PROTECTED_KARGS`] is an error.
+fn edit_kargs(
+    current: &[String],
+    edit: KargsEdit,
+    args: &[String],
+    force: bool,
+) -> Result<Vec<String>> {
+    for arg in args {
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            anyhow::bail!("Invalid kernel argument: {arg:?}");
+        }
+        let key = karg_key(arg);
+        if !force && PROTECTED_KARGS.contains(&key) {
+            anyhow::bail!(
+                "Refusing to modify kernel argument required for boot: {key} (use --force to override)"
+            );
+        }
+    }
+    let mut r = current.to_vec();
+    for arg in args {
+        let key = karg_key(arg);
+        match edit {
+            KargsEdit::Set => {
+                // Replace the first instance of the key in place, dropping any others
+                let mut found = false;
+                r.retain_mut(|existing| {
+                    if karg_key(existing) != key {
+                        return true;
+                    }
+                    if found {
+                        return false;
+                    }
+                    found = true;
+                    existing.clone_from(arg);
+                    true
+                });
+                if !found {
+                    r.push(arg.clone());
+                }
+            }
+            KargsEdit::Append => {
+                if !r.contains(arg) {
+                    r.push(arg.clone());
+                }
+            }
+            KargsEdit::Delete => {
+                let n = r.len();
+                if arg.contains('=') {
+                    r.retain(|existing| existing != arg);
+                } else {
+                    r.retain(|existing| karg_key(existing) != key);
+                }
+                if r.len() == n {
+                    anyhow::bail!("Kernel argument not found: {arg}");
+                }
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// Resolve the deployments targeted by `--apply-to`, along with a name for each.
+pub(crate) fn target_deployments(
+    sysroot: &Storage,
+    target: Option<KargsTarget>,
+) -> Result<Vec<(&'static str, Deployment)>> {
+    let staged = sysroot.staged_deployment();
+    let booted = sysroot
+        .booted_deployment()
+        .ok_or_else(|| anyhow::anyhow!("Not booted into an ostree deployment"))?;
+    let r = match (target, staged) {
+        (None, Some(staged)) | (Some(KargsTarget::Staged), Some(staged)) => {
+            vec![("staged", staged)]
+        }
+        (Some(KargsTarget::Staged), None) => anyhow::bail!("No staged deployment"),
+        (None, None) | (Some(KargsTarget::Booted), _) => vec![("booted", booted)],
+        (Some(KargsTarget::All), staged) => staged
+            .map(|d| ("staged", d))
+            .into_iter()
+            .chain(std::iter::once(("booted", booted)))
+            .collect(),
+    };
+    Ok(r)
+}
+
+/// Implementation of the `bootc kargs` CLI.
+pub(crate) async fn kargs_entrypoint(opts: KargsOpts) -> Result<()> {
+    let (edit, opts) = match opts {
+        KargsOpts::Get { apply_to } => {
+            let sysroot = &crate::cli::get_storage(LockMode::Shared).await?;
+            let targets = target_deployments(sysroot, apply_to)?;
+            let labeled = targets.len() > 1;
+            for (name, deployment) in targets {
+                let kargs = deployment_kargs(&deployment).join(" ");
+                if labeled {
+                    println!("{name}: {kargs}");
+                } else {
+                    println!("{kargs}");
+                }
+            }
+            return Ok(());
+        }
+        KargsOpts::Set(opts) => (KargsEdit::Set, opts),
+        KargsOpts::Append(opts) => (KargsEdit::Append, opts),
+        KargsOpts::Delete(opts) => (KargsEdit::Delete, opts),
+    };
+    let KargsEditOpts {
+        apply_to,
+        force,
+        kargs,
+    } = opts;
+    let sysroot = &crate::cli::get_storage(LockMode::Exclusive).await?;
+    let cancellable = gio::Cancellable::NONE;
+    for (name, deployment) in target_deployments(sysroot, apply_to)? {
+        let current = deployment_kargs(&deployment);
+        let new = edit_kargs(&current, edit, &kargs, force)
+            .with_context(|| format!("Updating {name} deployment"))?;
+        if new == current {
+            println!("No changes to {name} deployment");
+            continue;
+        }
+        let new = new.join(" ");
+        tracing::debug!("{name}: new kargs={new}");
+        sysroot
+            .deployment_set_kargs_in_place(&deployment, Some(&new), cancellable)
+            .with_context(|| format!("Updating {name} deployment"))?;
+        println!("Updated kernel arguments for {name} deployment");
+    }
+    Ok(())
+}
+
 /// This parses a bootc kargs.d toml file, returning the resulting
 /// vector of kernel arguments. Architecture matching is performed using
 /// `sys_arch`.
@@ -245,6 +409,55 @@ match-architectures = ["x86_64", "aarch64"]
         assert!(parse_kargs_toml(test_missing, "x86_64").is_err());
     }
 
+    #[test]
+    fn test_edit_kargs() {
+        let current = [
+            "root=UUID=abc",
+            "rw",
+            "console=tty0",
+            "quiet",
+            "console=ttyS0",
+        ]
+        .map(String::from);
+        let edit = |edit, args: &[&str], force| {
+            let args = args.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
+            edit_kargs(&current, edit, &args, force)
+        };
+
+        let r = edit(KargsEdit::Set, &["console=ttyS1", "nosmt"], false).unwrap();
+        assert_eq!(
+            r,
+            ["root=UUID=abc", "rw", "console=ttyS1", "quiet", "nosmt"]
+        );
+
+        let r = edit(KargsEdit::Append, &["quiet", "console=ttyS1"], false).unwrap();
+        assert_eq!(
+            r,
+            [
+                "root=UUID=abc",
+                "rw",
+                "console=tty0",
+                "quiet",
+                "console=ttyS0",
+                "console=ttyS1"
+            ]
+        );
+
+        let r = edit(KargsEdit::Delete, &["console=tty0"], false).unwrap();
+        assert_eq!(r, ["root=UUID=abc", "rw", "quiet", "console=ttyS0"]);
+        let r = edit(KargsEdit::Delete, &["console", "quiet"], false).unwrap();
+        assert_eq!(r, ["root=UUID=abc", "rw"]);
+        assert!(edit(KargsEdit::Delete, &["nosmt"], false).is_err());
+
+        // Protected and malformed arguments
+        assert!(edit(KargsEdit::Set, &["root=/dev/vda"], false).is_err());
+        assert!(edit(KargsEdit::Delete, &["rw"], false).is_err());
+        let r = edit(KargsEdit::Delete, &["rw"], true).unwrap();
+        assert_eq!(r.len(), current.len() - 1);
+        assert!(edit(KargsEdit::Append, &[""], false).is_err());
+        assert!(edit(KargsEdit::Append, &["foo bar"], false).is_err());
+    }
+
     #[context("writing test kargs")]
     fn write_test_kargs(td: &Dir) -> Result<()> {
         td.write(
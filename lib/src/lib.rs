@@ -4,21 +4,39 @@
 //! to provide a fully "container native" tool for using
 //! bootable container images.
 
+mod autoupdate;
+mod bootcount;
 mod boundimage;
+mod clean;
 pub mod cli;
+mod completions;
+mod delta;
 pub(crate) mod deploy;
+mod etc;
 pub(crate) mod generator;
+mod health;
+mod hooks;
 mod image;
 pub(crate) mod journal;
 pub(crate) mod kargs;
 mod lints;
 mod lsm;
+mod maintenance;
 pub(crate) mod metadata;
+mod metrics;
+mod notify;
+mod progress_jsonl;
 mod reboot;
 mod reexec;
+mod relabel;
+mod reset;
+mod sbom;
+mod service;
+mod sigpolicy;
 mod status;
 mod store;
 mod task;
+mod usroverlay;
 mod utils;
 
 #[cfg(feature = "install")]
@@ -32,10 +50,11 @@ mod install;
 mod k8sapitypes;
 #[cfg(feature = "install")]
 mod kernel;
-#[cfg(feature = "install")]
 pub(crate) mod mount;
 mod podman;
 pub mod spec;
+#[cfg(feature = "install")]
+mod varluks;
 
 #[cfg(feature = "docgen")]
 mod docgen;
@@ -9,17 +9,156 @@ use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
 use cap_std_ext::dirext::CapStdExtDirExt as _;
 use fn_error_context::context;
+use serde::Serialize;
 
-/// check for the existence of the /var/run directory
-/// if it exists we need to check that it links to /run if not error
-/// if it does not exist error.
+use crate::cli::OutputFormat;
+
+/// The maximum number of paths to show in a finding.
+const MAX_PATHS: usize = 10;
+
+/// Whether a failed lint makes the image unusable.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum LintType {
+    /// The image will not work correctly
+    Fatal,
+    /// The image likely has a problem, but may work
+    Warning,
+}
+
+/// A check run against the root filesystem of the image.
+struct Lint {
+    name: &'static str,
+    ty: LintType,
+    f: fn(&Dir) -> Result<()>,
+}
+
+const LINTS: &[Lint] = &[
+    Lint {
+        name: "var-run",
+        ty: LintType::Fatal,
+        f: check_var_run,
+    },
+    Lint {
+        name: "kernel",
+        ty: LintType::Fatal,
+        f: check_kernel,
+    },
+    Lint {
+        name: "kernel-present",
+        ty: LintType::Fatal,
+        f: check_kernel_present,
+    },
+    Lint {
+        name: "kargs",
+        ty: LintType::Fatal,
+        f: check_parse_kargs,
+    },
+    Lint {
+        name: "usr-etc",
+        ty: LintType::Fatal,
+        f: check_usr_etc,
+    },
+    Lint {
+        name: "machine-id",
+        ty: LintType::Fatal,
+        f: check_machine_id,
+    },
+    Lint {
+        name: "boot-kernel",
+        ty: LintType::Warning,
+        f: check_boot_kernel,
+    },
+    Lint {
+        name: "var-content",
+        ty: LintType::Warning,
+        f: check_var_content,
+    },
+    Lint {
+        name: "bootupd",
+        ty: LintType::Warning,
+        f: check_bootupd,
+    },
+];
+
+/// The result of a single lint.
+#[derive(Debug, Serialize)]
+struct LintResult {
+    name: &'static str,
+    #[serde(rename = "type")]
+    ty: LintType,
+    passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// The results of all lints, as output in JSON.
+#[derive(Debug, Serialize)]
+struct LintReport {
+    /// True if no fatal lints (or, with `--fatal-warnings`, no lints at all) failed.
+    passed: bool,
+    results: Vec<LintResult>,
+}
+
+/// Run all lints against the provided root.
+fn run_lints(root: &Dir) -> Vec<LintResult> {
+    LINTS
+        .iter()
+        .map(|lint| {
+            let r = (lint.f)(root);
+            LintResult {
+                name: lint.name,
+                ty: lint.ty,
+                passed: r.is_ok(),
+                message: r.err().map(|e| format!("{e:#}")),
+            }
+        })
+        .collect()
+}
+
+/// Run all lints, output the results, and return an error if any fatal lint
+/// failed; warnings are fatal too if `fatal_warnings` is set.
 #[context("Linting")]
-pub(crate) fn lint(root: &Dir) -> Result<()> {
-    let lints = [check_var_run, check_kernel, check_parse_kargs];
-    for lint in lints {
-        lint(&root)?;
+pub(crate) fn lint(root: &Dir, format: &OutputFormat, fatal_warnings: bool) -> Result<()> {
+    let results = run_lints(root);
+    let failed = |ty| results.iter().filter(|r| !r.passed && r.ty == ty).count();
+    let (fatal, warnings) = (failed(LintType::Fatal), failed(LintType::Warning));
+    let passed = fatal == 0 && !(fatal_warnings && warnings > 0);
+    match format {
+        OutputFormat::HumanReadable => {
+            for r in results.iter().filter(|r| !r.passed) {
+                let prefix = match r.ty {
+                    LintType::Fatal => "error",
+                    LintType::Warning => "warning",
+                };
+                // SAFETY: Failed lints always have a message
+                println!("{prefix}: {}: {}", r.name, r.message.as_deref().unwrap());
+            }
+            println!(
+                "Checks passed: {}",
+                results.iter().filter(|r| r.passed).count()
+            );
+            if warnings > 0 {
+                println!("Warnings: {warnings}");
+            }
+        }
+        OutputFormat::Json => {
+            let report = LintReport { passed, results };
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &report)?;
+            println!();
+        }
+        OutputFormat::Yaml => {
+            let report = LintReport { passed, results };
+            serde_yaml::to_writer(std::io::stdout().lock(), &report)?;
+        }
+    }
+    if !passed {
+        if fatal > 0 {
+            anyhow::bail!("Fatal lints failed: {fatal}");
+        }
+        anyhow::bail!("Lints with warnings failed: {warnings}");
     }
-    println!("Checks passed: {}", lints.len());
     Ok(())
 }
 
@@ -44,6 +183,111 @@ fn check_kernel(root: &Dir) -> Result<()> {
     Ok(())
 }
 
+/// A bootable image must have a kernel in `/usr/lib/modules/$kver/vmlinuz`.
+fn check_kernel_present(root: &Dir) -> Result<()> {
+    if ostree_ext::bootabletree::find_kernel_dir_fs(root)?.is_none() {
+        anyhow::bail!("No kernel found in usr/lib/modules/$kver/vmlinuz");
+    }
+    Ok(())
+}
+
+/// ostree uses `/usr/etc` for the default `/etc` of the image, so the image
+/// itself must not have it.
+fn check_usr_etc(root: &Dir) -> Result<()> {
+    if root.symlink_metadata_optional("usr/etc")?.is_some() {
+        anyhow::bail!("Found usr/etc; content for /etc must be in etc instead");
+    }
+    Ok(())
+}
+
+/// The machine ID is generated on first boot; if it is baked into the image,
+/// all systems installed from it share the same identity.
+fn check_machine_id(root: &Dir) -> Result<()> {
+    let Some(contents) = root.read_to_string_optional("etc/machine-id")? else {
+        return Ok(());
+    };
+    match contents.trim() {
+        "" | "uninitialized" => Ok(()),
+        _ => anyhow::bail!("etc/machine-id must be empty or absent; remove it in the build"),
+    }
+}
+
+/// Kernels must be in `/usr/lib/modules`; `/boot` in the image is not used.
+fn check_boot_kernel(root: &Dir) -> Result<()> {
+    let Some(d) = root.open_dir_optional("boot")? else {
+        return Ok(());
+    };
+    let mut found = Vec::new();
+    for entry in d.entries()? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("vmlinuz") || name.starts_with("initramfs") {
+            found.push(format!("boot/{name}"));
+        }
+    }
+    if !found.is_empty() {
+        found.sort();
+        anyhow::bail!(
+            "Found kernel or initramfs in boot, which is ignored (use usr/lib/modules/$kver instead): {}",
+            found.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// Collect the paths of non-directories below `path`, up to `limit`.
+fn collect_files(d: &Dir, path: &str, found: &mut Vec<String>, limit: usize) -> Result<()> {
+    for entry in d.entries()? {
+        if found.len() >= limit {
+            break;
+        }
+        let entry = entry?;
+        let name = entry.file_name();
+        let child = format!("{path}/{}", name.to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            let d = d.open_dir(&name)?;
+            collect_files(&d, &child, found, limit)?;
+        } else if child != "var/run" {
+            found.push(child);
+        }
+    }
+    Ok(())
+}
+
+/// Content in `/var` is only unpacked at installation time, and not updated
+/// afterwards; it should be created at runtime (e.g. via `tmpfiles.d`) instead.
+fn check_var_content(root: &Dir) -> Result<()> {
+    let Some(d) = root.open_dir_optional("var")? else {
+        return Ok(());
+    };
+    let mut found = Vec::new();
+    // Find one more than we show, to know whether the list is truncated
+    collect_files(&d, "var", &mut found, MAX_PATHS + 1)?;
+    if found.is_empty() {
+        return Ok(());
+    }
+    found.sort();
+    let truncated = found.len() > MAX_PATHS;
+    found.truncate(MAX_PATHS);
+    let suffix = if truncated { " ..." } else { "" };
+    anyhow::bail!(
+        "Found content in var, which will not be updated after installation: {}{suffix}",
+        found.join(" ")
+    )
+}
+
+/// The bootloader is installed and updated via bootupd, which must be in the
+/// image (except on s390x, which uses zipl).
+fn check_bootupd(root: &Dir) -> Result<()> {
+    if cfg!(target_arch = "s390x") {
+        return Ok(());
+    }
+    if !root.try_exists("usr/bin/bootupctl")? {
+        anyhow::bail!("Missing usr/bin/bootupctl; the bootloader cannot be installed or updated");
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 fn fixture() -> Result<cap_std_ext::cap_tempfile::TempDir> {
     let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
@@ -88,3 +332,82 @@ fn test_kargs() -> Result<()> {
     assert!(check_parse_kargs(root).is_err());
     Ok(())
 }
+
+#[test]
+fn test_kernel_present() -> Result<()> {
+    let root = &fixture()?;
+    assert!(check_kernel_present(root).is_err());
+    root.create_dir_all("usr/lib/modules/6.3.1")?;
+    root.write("usr/lib/modules/6.3.1/vmlinuz", "vmlinuz")?;
+    check_kernel_present(root).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_usr_etc() -> Result<()> {
+    let root = &fixture()?;
+    check_usr_etc(root).unwrap();
+    root.create_dir_all("usr/etc")?;
+    assert!(check_usr_etc(root).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_machine_id() -> Result<()> {
+    let root = &fixture()?;
+    check_machine_id(root).unwrap();
+    root.create_dir_all("etc")?;
+    root.write("etc/machine-id", "")?;
+    check_machine_id(root).unwrap();
+    root.write("etc/machine-id", "uninitialized\n")?;
+    check_machine_id(root).unwrap();
+    root.write("etc/machine-id", "0123456789abcdef0123456789abcdef\n")?;
+    assert!(check_machine_id(root).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_boot_kernel() -> Result<()> {
+    let root = &fixture()?;
+    check_boot_kernel(root).unwrap();
+    root.create_dir_all("boot/efi")?;
+    check_boot_kernel(root).unwrap();
+    root.write("boot/vmlinuz-6.3.1", "vmlinuz")?;
+    let e = check_boot_kernel(root).unwrap_err();
+    assert!(e.to_string().contains("boot/vmlinuz-6.3.1"));
+    Ok(())
+}
+
+#[test]
+fn test_var_content() -> Result<()> {
+    let root = &fixture()?;
+    check_var_content(root).unwrap();
+    root.create_dir_all("var/lib/foo")?;
+    root.symlink("../run", "var/run")?;
+    check_var_content(root).unwrap();
+    root.write("var/lib/foo/data", "data")?;
+    let e = check_var_content(root).unwrap_err().to_string();
+    assert!(e.ends_with(": var/lib/foo/data"), "{e}");
+    for i in 0..MAX_PATHS {
+        root.write(format!("var/lib/foo/data{i}"), "data")?;
+    }
+    let e = check_var_content(root).unwrap_err().to_string();
+    assert!(e.ends_with(" ..."), "{e}");
+    Ok(())
+}
+
+#[test]
+fn test_run_lints() -> Result<()> {
+    let root = &fixture()?;
+    let results = run_lints(root);
+    assert_eq!(results.len(), LINTS.len());
+    let kernel = results.iter().find(|r| r.name == "kernel-present").unwrap();
+    assert!(!kernel.passed);
+    assert_eq!(kernel.ty, LintType::Fatal);
+    assert!(kernel.message.is_some());
+    let var_run = results.iter().find(|r| r.name == "var-run").unwrap();
+    assert!(var_run.passed);
+    assert!(var_run.message.is_none());
+    assert!(lint(root, &OutputFormat::Json, false).is_err());
+    Ok(())
+}
@@ -99,7 +99,6 @@ pub(crate) fn selinux_ensure_install() -> Result<bool> {
 }
 
 /// Query whether SELinux is apparently enabled in the target root
-#[cfg(feature = "install")]
 pub(crate) fn have_selinux_policy(root: &Dir) -> Result<bool> {
     // TODO use ostree::SePolicy and query policy name
     root.try_exists("etc/selinux/config").map_err(Into::into)
@@ -244,7 +243,19 @@ pub(crate) fn has_security_selinux(root: &Dir, path: &Utf8Path) -> Result<SELinu
     }
 }
 
-#[cfg(feature = "install")]
+/// Query the SELinux label of a path (without following symlinks), if any.
+pub(crate) fn get_security_selinux_path(root: &Dir, path: &Utf8Path) -> Result<Option<Vec<u8>>> {
+    // TODO: avoid hardcoding a max size here
+    let mut buf = [0u8; 2048];
+    let fdpath = format!("/proc/self/fd/{}/", root.as_raw_fd());
+    let fdpath = &Path::new(&fdpath).join(path);
+    match rustix::fs::lgetxattr(fdpath, "security.selinux", &mut buf) {
+        Ok(n) => Ok(Some(buf[..n].to_vec())),
+        Err(rustix::io::Errno::NODATA) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to look up context for {path:?}")),
+    }
+}
+
 pub(crate) fn set_security_selinux_path(root: &Dir, path: &Utf8Path, label: &[u8]) -> Result<()> {
     // TODO: avoid hardcoding a max size here
     let fdpath = format!("/proc/self/fd/{}/", root.as_raw_fd());
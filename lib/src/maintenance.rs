@@ -0,0 +1,421 @@
+//! # Maintenance windows
+//!
+//! When `bootc/maintenance-windows.toml` (in `/etc` or `/usr/lib`) exists in
+//! a newly staged deployment, a reboot into it is scheduled at the next time
+//! inside one of the configured windows, using the systemd scheduled shutdown
+//! mechanism (`shutdown -r`).  The reboot is cancelled if the staged
+//! deployment is discarded.
+//!
+//! Windows are specified as (5 field) cron expressions, matching every minute
+//! inside the window; e.g. `* 2-3 * * sat,sun` is 02:00 to 03:59 on weekends.
+//! These are converted to systemd calendar events, which are evaluated
+//! in the configured timezone via `systemd-analyze calendar`.
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use serde::Deserialize;
+
+use crate::task::Task;
+
+/// Configuration files, in order of precedence.
+const CONFIG_PATHS: &[&str] = &[
+    "etc/bootc/maintenance-windows.toml",
+    "usr/lib/bootc/maintenance-windows.toml",
+];
+/// The state of a scheduled shutdown, as written by systemd-logind.
+const SCHEDULED_SHUTDOWN: &str = "run/systemd/shutdown/scheduled";
+/// Present if the scheduled reboot was scheduled by us.
+const SCHEDULED_STAMP: &str = "run/bootc/maintenance-reboot";
+const DAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// The maintenance window configuration.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// The timezone the windows are in, e.g. `Europe/Berlin`; by default the
+    /// system timezone is used.
+    pub(crate) timezone: Option<String>,
+    /// The windows, as cron expressions.
+    pub(crate) windows: Vec<String>,
+}
+
+/// Parse a single cron field into the sorted list of values it matches, or
+/// `None` if it matches any value.
+fn parse_cron_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<Option<Vec<u32>>> {
+    if field == "*" {
+        return Ok(None);
+    }
+    let parse_value = |v: &str| -> Result<u32> {
+        let lower = v.to_ascii_lowercase();
+        if let Some(i) = names.iter().position(|n| *n == lower) {
+            // Month names are 1-indexed, days of the week 0-indexed
+            return Ok(i as u32 + min);
+        }
+        let v: u32 = v.parse().with_context(|| format!("Invalid value: {v}"))?;
+        if v < min || v > max {
+            anyhow::bail!("Value {v} out of range {min}-{max}");
+        }
+        Ok(v)
+    };
+    let mut r = Vec::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .with_context(|| format!("Invalid step: {step}"))?;
+                if step == 0 {
+                    anyhow::bail!("Invalid step: 0");
+                }
+                (range, step)
+            }
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+            // As an extension, `a/n` means every n starting from a
+            None if step > 1 => (parse_value(range)?, max),
+            None => {
+                let v = parse_value(range)?;
+                (v, v)
+            }
+        };
+        if start > end {
+            anyhow::bail!("Invalid range: {range}");
+        }
+        r.extend((start..=end).step_by(step as usize));
+    }
+    r.sort_unstable();
+    r.dedup();
+    Ok(Some(r))
+}
+
+fn join(values: &[u32], f: impl Fn(u32) -> String) -> String {
+    values.iter().map(|&v| f(v)).collect::<Vec<_>>().join(",")
+}
+
+/// Convert a cron expression into a systemd calendar event.
+fn cron_to_calendar(expr: &str) -> Result<String> {
+    let fields = expr.split_whitespace().collect::<Vec<_>>();
+    let [minute, hour, dom, month, dow] = fields.as_slice() else {
+        anyhow::bail!("Expected 5 fields, found {}", fields.len());
+    };
+    let minute = parse_cron_field(minute, 0, 59, &[]).context("minute")?;
+    let hour = parse_cron_field(hour, 0, 23, &[]).context("hour")?;
+    let dom = parse_cron_field(dom, 1, 31, &[]).context("day of month")?;
+    let month = parse_cron_field(month, 1, 12, MONTHS).context("month")?;
+    let dow = parse_cron_field(dow, 0, 7, DAYS).context("day of week")?;
+    if dom.is_some() && dow.is_some() {
+        // cron matches either of these, but systemd requires both to match
+        anyhow::bail!("Restricting both the day of month and day of week is not supported");
+    }
+    let any = || "*".to_owned();
+    let number = |v| format!("{v:02}");
+    let mut r = String::new();
+    if let Some(mut dow) = dow {
+        // 7 is an alias for Sunday
+        dow = dow.into_iter().map(|d| d % 7).collect();
+        dow.sort_unstable();
+        dow.dedup();
+        r.push_str(&join(&dow, |d| {
+            let d = DAYS[d as usize];
+            d[..1].to_ascii_uppercase() + &d[1..]
+        }));
+        r.push(' ');
+    }
+    r.push_str(&format!(
+        "*-{}-{} {}:{}:00",
+        month.map_or_else(any, |v| join(&v, number)),
+        dom.map_or_else(any, |v| join(&v, number)),
+        hour.map_or_else(any, |v| join(&v, number)),
+        minute.map_or_else(any, |v| join(&v, number)),
+    ));
+    Ok(r)
+}
+
+impl Config {
+    /// The systemd calendar events for the windows.
+    fn calendar_events(&self) -> Result<Vec<String>> {
+        self.windows
+            .iter()
+            .map(|w| {
+                let mut event =
+                    cron_to_calendar(w).with_context(|| format!("Invalid window: {w}"))?;
+                if let Some(tz) = self.timezone.as_deref() {
+                    event.push(' ');
+                    event.push_str(tz);
+                }
+                Ok(event)
+            })
+            .collect()
+    }
+
+    /// Compute the next time inside a maintenance window, if any.
+    #[context("Computing next maintenance window")]
+    pub(crate) fn next_window(&self) -> Result<Option<DateTime<Utc>>> {
+        let mut r = None;
+        for event in self.calendar_events()? {
+            let out = Task::new_quiet("systemd-analyze")
+                .args(["calendar", event.as_str()])
+                .read()?;
+            let next = parse_next_elapse(&out).with_context(|| format!("Evaluating {event}"))?;
+            r = match (r, next) {
+                (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+                (a, b) => a.or(b),
+            };
+        }
+        Ok(r)
+    }
+}
+
+/// Parse the output of `systemd-analyze calendar`, returning the next elapse time.
+fn parse_next_elapse(out: &str) -> Result<Option<DateTime<Utc>>> {
+    let mut next = None;
+    for line in out.lines() {
+        let Some((k, v)) = line.split_once(": ") else {
+            continue;
+        };
+        match k.trim() {
+            // This is only printed if the system timezone is not UTC
+            "(in UTC)" => next = Some(v.trim()),
+            "Next elapse" if next.is_none() => next = Some(v.trim()),
+            _ => {}
+        }
+    }
+    let next = next.ok_or_else(|| anyhow::anyhow!("Missing next elapse"))?;
+    if next == "never" {
+        return Ok(None);
+    }
+    let v = next
+        .strip_suffix(" UTC")
+        .ok_or_else(|| anyhow::anyhow!("Expected UTC time: {next}"))?;
+    let t = chrono::NaiveDateTime::parse_from_str(v, "%a %Y-%m-%d %H:%M:%S")
+        .with_context(|| format!("Parsing {next}"))?;
+    Ok(Some(t.and_utc()))
+}
+
+/// Load the maintenance window configuration from the provided root; returns
+/// `None` if maintenance windows are not configured.
+#[context("Loading maintenance windows")]
+pub(crate) fn load_config(root: &Dir) -> Result<Option<Config>> {
+    for path in CONFIG_PATHS {
+        let Some(f) = root.open_optional(path)? else {
+            continue;
+        };
+        let buf = std::io::read_to_string(f)?;
+        let config: Config = toml::from_str(&buf).with_context(|| format!("Parsing {path}"))?;
+        if config.windows.is_empty() {
+            anyhow::bail!("{path}: At least one window is required");
+        }
+        config
+            .calendar_events()
+            .with_context(|| format!("Parsing {path}"))?;
+        return Ok(Some(config));
+    }
+    Ok(None)
+}
+
+/// If maintenance windows are configured in the staged deployment, schedule
+/// a reboot into it at the next time inside a window.
+#[context("Scheduling reboot")]
+pub(crate) fn schedule_reboot(root: &Dir, deployment_root: &Dir) -> Result<()> {
+    let Some(config) = load_config(deployment_root)? else {
+        return Ok(());
+    };
+    let Some(next) = config.next_window()? else {
+        crate::journal::journal_print(
+            libsystemd::logging::Priority::Warning,
+            "No upcoming maintenance window; not scheduling a reboot",
+        );
+        return Ok(());
+    };
+    let secs = (next - Utc::now()).num_seconds().max(0);
+    let minutes = (secs + 59) / 60;
+    Task::new_quiet("shutdown")
+        .args([
+            "-r",
+            &format!("+{minutes}"),
+            "Rebooting into the staged update in the maintenance window",
+        ])
+        .run()?;
+    root.create_dir_all(SCHEDULED_STAMP.rsplit_once('/').unwrap().0)?;
+    root.atomic_write(SCHEDULED_STAMP, "")?;
+    println!(
+        "Reboot scheduled for {}",
+        next.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    );
+    Ok(())
+}
+
+/// Cancel a reboot scheduled by [`schedule_reboot`], as the staged deployment
+/// it was scheduled for is being discarded.
+#[context("Cancelling scheduled reboot")]
+pub(crate) fn cancel_reboot(root: &Dir) -> Result<()> {
+    if !root.try_exists(SCHEDULED_STAMP)? {
+        return Ok(());
+    }
+    // The reboot may have been cancelled (or replaced) by the administrator since
+    if planned_reboot(root)?.is_some() {
+        Task::new_quiet("shutdown").arg("-c").run()?;
+        println!("Cancelled the reboot scheduled for the maintenance window");
+    }
+    root.remove_file(SCHEDULED_STAMP)?;
+    Ok(())
+}
+
+/// Parse the logind scheduled shutdown state, returning the time of a scheduled reboot.
+fn parse_scheduled_shutdown(buf: &str) -> Result<Option<DateTime<Utc>>> {
+    let mut usec = None;
+    let mut mode = None;
+    for (k, v) in buf.lines().filter_map(|l| l.split_once('=')) {
+        match k {
+            "USEC" => usec = Some(v.parse::<i64>().with_context(|| format!("Parsing {v}"))?),
+            "MODE" => mode = Some(v),
+            _ => {}
+        }
+    }
+    if !matches!(mode, Some("reboot") | Some("kexec") | Some("soft-reboot")) {
+        return Ok(None);
+    }
+    Ok(usec.and_then(|usec| {
+        DateTime::from_timestamp(
+            usec.div_euclid(1_000_000),
+            (usec.rem_euclid(1_000_000) * 1000) as u32,
+        )
+    }))
+}
+
+/// Query the time of a scheduled reboot, if any.
+pub(crate) fn planned_reboot(root: &Dir) -> Result<Option<DateTime<Utc>>> {
+    let Some(f) = root.open_optional(SCHEDULED_SHUTDOWN)? else {
+        return Ok(None);
+    };
+    parse_scheduled_shutdown(&std::io::read_to_string(f)?)
+        .with_context(|| format!("Parsing {SCHEDULED_SHUTDOWN}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std;
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_cron_to_calendar() {
+        let cases = [
+            ("* * * * *", "*-*-* *:*:00"),
+            ("* 2-3 * * sat,sun", "Sun,Sat *-*-* 02,03:*:00"),
+            ("0-29 2 * * 1-5", "Mon,Tue,Wed,Thu,Fri *-*-* 02:00,01,02,03,04,05,06,07,08,09,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29:00"),
+            ("*/15 22 * * 7", "Sun *-*-* 22:00,15,30,45:00"),
+            ("0 4 1,15 Jan-Mar *", "*-01,02,03-01,15 04:00:00"),
+            ("30/10 0 * * 0,7", "Sun *-*-* 00:30,40,50:00"),
+        ];
+        for (cron, expected) in cases {
+            assert_eq!(cron_to_calendar(cron).unwrap(), expected, "{cron}");
+        }
+        for invalid in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "* * * * foo",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * 1 * 1",
+        ] {
+            assert!(cron_to_calendar(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse_next_elapse() {
+        let utc = indoc::indoc! { "
+              Original form: Sat 02:00
+            Normalized form: Sat *-*-* 02:00:00
+                Next elapse: Sat 2026-10-17 02:00:00 UTC
+                   From now: 14h left
+        " };
+        let expected = Utc.with_ymd_and_hms(2026, 10, 17, 2, 0, 0).unwrap();
+        assert_eq!(parse_next_elapse(utc).unwrap(), Some(expected));
+        let local = indoc::indoc! { "
+              Original form: Sat 02:00
+            Normalized form: Sat *-*-* 02:00:00
+                Next elapse: Sat 2026-10-17 02:00:00 EDT
+                   (in UTC): Sat 2026-10-17 06:00:00 UTC
+                   From now: 18h left
+        " };
+        let expected = Utc.with_ymd_and_hms(2026, 10, 17, 6, 0, 0).unwrap();
+        assert_eq!(parse_next_elapse(local).unwrap(), Some(expected));
+        let never = indoc::indoc! { "
+              Original form: 2020-01-01
+            Normalized form: 2020-01-01 00:00:00
+                Next elapse: never
+        " };
+        assert_eq!(parse_next_elapse(never).unwrap(), None);
+        assert!(parse_next_elapse("").is_err());
+    }
+
+    #[test]
+    fn test_parse_scheduled_shutdown() {
+        let buf = "USEC=1792202400000000\nWARN_WALL=1\nMODE=reboot\n";
+        let expected = Utc.with_ymd_and_hms(2026, 10, 17, 2, 0, 0).unwrap();
+        assert_eq!(parse_scheduled_shutdown(buf).unwrap(), Some(expected));
+        let buf = "USEC=1792202400000000\nWARN_WALL=1\nMODE=poweroff\n";
+        assert_eq!(parse_scheduled_shutdown(buf).unwrap(), None);
+        assert!(parse_scheduled_shutdown("USEC=foo\nMODE=reboot\n").is_err());
+    }
+
+    #[test]
+    fn test_cancel_reboot() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        // Nothing was scheduled by us
+        cancel_reboot(td)?;
+        // Scheduled by us, but since cancelled by the administrator
+        td.create_dir_all("run/bootc")?;
+        td.write(SCHEDULED_STAMP, "")?;
+        cancel_reboot(td)?;
+        assert!(!td.try_exists(SCHEDULED_STAMP)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_config() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(load_config(td)?, None);
+        td.create_dir_all("usr/lib/bootc")?;
+        td.write(
+            "usr/lib/bootc/maintenance-windows.toml",
+            indoc::indoc! { r#"
+                timezone = "Europe/Berlin"
+                windows = ["* 2-3 * * sat,sun", "0-29 1 * * *"]
+            "# },
+        )?;
+        let config = load_config(td)?.unwrap();
+        assert_eq!(
+            config.calendar_events()?[0],
+            "Sun,Sat *-*-* 02,03:*:00 Europe/Berlin"
+        );
+        td.create_dir_all("etc/bootc")?;
+        for invalid in [
+            "windows = []\n",
+            "windows = [\"* * *\"]\n",
+            "window = [\"* * * * *\"]\n",
+        ] {
+            td.write("etc/bootc/maintenance-windows.toml", invalid)?;
+            assert!(load_config(td).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+}
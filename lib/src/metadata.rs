@@ -1,4 +1,35 @@
+use ostree_ext::oci_spec::image::{ImageConfiguration, ImageManifest};
+
+use crate::status::labels_of_config;
+
 /// This label is expected to be present on compatible base images.
 pub(crate) const BOOTC_COMPAT_LABEL: &str = "containers.bootc";
 /// The current single well-known value for the label.
 pub(crate) const COMPAT_LABEL_V1: &str = "1";
+/// The kernel version of a bootable image, copied from the ostree commit metadata.
+pub(crate) const LINUX_LABEL: &str = "ostree.linux";
+/// A human-readable changelog for the image; this can be set as an annotation on
+/// the manifest (e.g. after the image was built), or as a label.
+pub(crate) const CHANGELOG_ANNOTATION: &str = "containers.bootc.changelog";
+
+/// Return the kernel version of an image, if any.
+pub(crate) fn image_kernel(config: &ImageConfiguration) -> Option<&str> {
+    labels_of_config(config)
+        .and_then(|l| l.get(LINUX_LABEL))
+        .map(|s| s.as_str())
+}
+
+/// Return the changelog of an image, if any; a manifest annotation takes
+/// precedence over a label.
+pub(crate) fn image_changelog<'a>(
+    manifest: &'a ImageManifest,
+    config: &'a ImageConfiguration,
+) -> Option<&'a str> {
+    manifest
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(CHANGELOG_ANNOTATION))
+        .or_else(|| labels_of_config(config).and_then(|l| l.get(CHANGELOG_ANNOTATION)))
+        .map(|s| s.as_str())
+        .filter(|s| !s.trim().is_empty())
+}
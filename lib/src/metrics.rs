@@ -0,0 +1,407 @@
+//! # Metrics
+//!
+//! `bootc metrics` exposes the state of the system in the Prometheus text
+//! exposition format, for fleet monitoring.  The metrics are either written
+//! to standard output, to a file for the node_exporter textfile collector,
+//! or served over HTTP.
+
+use std::cell::RefCell;
+use std::fmt::{Display, Write as _};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::autoupdate::UpdateResult;
+use crate::spec::{Host, ImageStatus};
+
+/// The content type of the text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// The maximum size of an HTTP request we accept.
+const MAX_REQUEST_SIZE: usize = 8192;
+/// How long to wait for a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long gathered metrics are served before gathering them again, so
+/// frequent scrapes don't contend for the sysroot lock.
+const CACHE_DURATION: Duration = Duration::from_secs(5);
+
+/// Options for `bootc metrics`.
+#[derive(Debug, clap::Parser, PartialEq, Eq)]
+pub(crate) struct MetricsOpts {
+    /// Atomically write the metrics to this file, e.g. in the directory of the
+    /// node_exporter textfile collector; the file name must end in `.prom`.
+    #[clap(long, conflicts_with = "listen")]
+    pub(crate) textfile: Option<Utf8PathBuf>,
+
+    /// Serve the metrics over HTTP on this address (e.g. `0.0.0.0:9470`), at `/metrics`.
+    #[clap(long)]
+    pub(crate) listen: Option<SocketAddr>,
+}
+
+/// Accumulates metrics in the text exposition format.
+#[derive(Debug, Default)]
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    /// Add a gauge with a single sample.
+    fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: impl Display) {
+        // SAFETY: Writing to a String cannot fail
+        writeln!(self.out, "# HELP {name} {help}").unwrap();
+        writeln!(self.out, "# TYPE {name} gauge").unwrap();
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{}\"", escape_label_value(v)))
+                .collect::<Vec<_>>();
+            write!(self.out, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.out, " {value}").unwrap();
+    }
+
+    /// Add a gauge which is 1 if the condition is true, and 0 otherwise.
+    fn flag(&mut self, name: &str, help: &str, value: bool) {
+        self.gauge(name, help, &[], u8::from(value))
+    }
+
+    /// Add an info-style gauge describing an image, along with its build time.
+    fn image(&mut self, slot: &str, image: &ImageStatus, now: chrono::DateTime<chrono::Utc>) {
+        let transport = image.image.transport.as_str();
+        let version = image.version.as_deref().unwrap_or_default();
+        self.gauge(
+            &format!("bootc_{slot}_image_info"),
+            &format!("The {slot} image; the value is always 1"),
+            &[
+                ("image", image.image.image.as_str()),
+                ("transport", transport),
+                ("digest", image.image_digest.as_str()),
+                ("version", version),
+            ],
+            1,
+        );
+        if let Some(timestamp) = image.timestamp {
+            self.gauge(
+                &format!("bootc_{slot}_image_timestamp_seconds"),
+                &format!("The build time of the {slot} image"),
+                &[],
+                timestamp.timestamp(),
+            );
+            self.gauge(
+                &format!("bootc_{slot}_image_age_seconds"),
+                &format!("The time since the {slot} image was built"),
+                &[],
+                (now - timestamp).num_seconds().max(0),
+            );
+        }
+    }
+}
+
+/// Escape a label value per the text exposition format.
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Render the metrics for the provided state of the system.
+fn render(
+    host: &Host,
+    last_update: Option<&UpdateResult>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let mut w = MetricsWriter::default();
+    let status = &host.status;
+    let booted = status.booted.as_ref().and_then(|b| b.image.as_ref());
+    let staged = status.staged.as_ref().and_then(|s| s.image.as_ref());
+    w.flag(
+        "bootc_booted",
+        "Whether the system is booted from a bootc compatible image",
+        booted.is_some(),
+    );
+    if let Some(booted) = booted {
+        w.image("booted", booted, now);
+    }
+    w.flag(
+        "bootc_update_staged",
+        "Whether an update is staged for the next boot",
+        status.staged.is_some(),
+    );
+    if let Some(staged) = staged {
+        w.image("staged", staged, now);
+    }
+    // The update found by the most recent `bootc upgrade --check`, if it
+    // has not already been staged or booted
+    let update_available = status
+        .booted
+        .as_ref()
+        .and_then(|b| b.cached_update.as_ref())
+        .is_some_and(|cached| {
+            [booted, staged]
+                .iter()
+                .flatten()
+                .all(|i| i.image_digest != cached.image_digest)
+        });
+    w.flag(
+        "bootc_update_available",
+        "Whether an update was found by the most recent check, and is not yet staged",
+        update_available,
+    );
+    w.flag(
+        "bootc_rollback_queued",
+        "Whether the rollback deployment is queued for the next boot",
+        status.rollback_queued,
+    );
+    if let Some(rollback) = status.automatic_rollback.as_ref() {
+        w.gauge(
+            "bootc_automatic_rollback_timestamp_seconds",
+            "When the system automatically rolled back from a deployment which failed to boot",
+            &[],
+            rollback.timestamp.timestamp(),
+        );
+    }
+    if let Some(r) = last_update {
+        w.gauge(
+            "bootc_last_update_timestamp_seconds",
+            "When the most recent bootc upgrade finished",
+            &[],
+            r.timestamp.timestamp(),
+        );
+        w.flag(
+            "bootc_last_update_success",
+            "Whether the most recent bootc upgrade succeeded",
+            r.success,
+        );
+    }
+    if let Some(storage) = status.storage.as_ref() {
+        w.gauge(
+            "bootc_storage_total_bytes",
+            "The total size of the filesystem holding deployments and images",
+            &[],
+            storage.total_bytes,
+        );
+        w.gauge(
+            "bootc_storage_used_bytes",
+            "The used space of the filesystem holding deployments and images",
+            &[],
+            storage.used_bytes,
+        );
+        w.gauge(
+            "bootc_storage_available_bytes",
+            "The space available to unprivileged users on the filesystem holding deployments and images",
+            &[],
+            storage.available_bytes,
+        );
+        if let Some(check) = storage.last_check.as_ref() {
+            w.gauge(
+                "bootc_storage_check_timestamp_seconds",
+                "When the most recent bootc image check was performed",
+                &[],
+                check.timestamp.timestamp(),
+            );
+            w.flag(
+                "bootc_storage_check_success",
+                "Whether the most recent bootc image check found no errors (or repaired them)",
+                check.success,
+            );
+        }
+    }
+    w.out
+}
+
+/// Gather the current state of the system, and render it as metrics.
+#[context("Gathering metrics")]
+async fn gather() -> Result<String> {
+    let host = crate::status::get_host(2).await?;
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let last_update = crate::autoupdate::read_update_result(root)?;
+    Ok(render(&host, last_update.as_ref(), chrono::Utc::now()))
+}
+
+/// Read an HTTP request up to the end of its headers, and return its method and path.
+async fn read_request(stream: &mut tokio::net::TcpStream) -> Result<(String, String)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            anyhow::bail!("Request too large");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("Invalid request");
+    };
+    let path = target.split_once('?').map_or(target, |(p, _)| p);
+    Ok((method.to_owned(), path.to_owned()))
+}
+
+/// The most recently gathered metrics, and when they were gathered.
+type Cache = Rc<RefCell<Option<(Instant, String)>>>;
+
+/// Return the cached metrics if they are recent enough, or gather them anew.
+async fn gather_cached(cache: &Cache) -> Result<String> {
+    if let Some((when, metrics)) = cache.borrow().as_ref() {
+        if when.elapsed() < CACHE_DURATION {
+            return Ok(metrics.clone());
+        }
+    }
+    let metrics = gather().await?;
+    *cache.borrow_mut() = Some((Instant::now(), metrics.clone()));
+    Ok(metrics)
+}
+
+/// Handle a single HTTP connection.
+async fn handle_connection(mut stream: tokio::net::TcpStream, cache: Cache) -> Result<()> {
+    let (method, path) = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .context("Timed out reading request")??;
+    let (status, body) = match (method.as_str(), path.as_str()) {
+        ("GET", "/metrics") => match gather_cached(&cache).await {
+            Ok(metrics) => ("200 OK", metrics),
+            Err(e) => {
+                tracing::warn!("{e:#}");
+                ("500 Internal Server Error", format!("{e:#}\n"))
+            }
+        },
+        ("GET", _) => ("404 Not Found", "Not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Serve the metrics over HTTP, handling connections concurrently.
+async fn serve(addr: SocketAddr) -> Result<()> {
+    // This may re-execute the process, which must happen before we accept
+    // connections rather than when gathering metrics for the first one.
+    if ostree_ext::container_utils::ostree_booted()? {
+        crate::cli::prepare_for_write()?;
+    }
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Binding to {addr}"))?;
+    println!("Serving metrics on http://{addr}/metrics");
+    // The ostree types used to gather the metrics are not Send
+    tokio::task::LocalSet::new()
+        .run_until(accept_connections(listener))
+        .await
+}
+
+/// Accept connections, handling each in its own task.
+async fn accept_connections(listener: tokio::net::TcpListener) -> Result<()> {
+    let cache = Cache::default();
+    loop {
+        let (stream, peer) = listener.accept().await.context("Accepting connection")?;
+        let cache = cache.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = handle_connection(stream, cache).await {
+                tracing::debug!("Handling request from {peer}: {e:#}");
+            }
+        });
+    }
+}
+
+/// Implementation of `bootc metrics`.
+#[context("Metrics")]
+pub(crate) async fn metrics(opts: MetricsOpts) -> Result<()> {
+    if let Some(addr) = opts.listen {
+        return serve(addr).await;
+    }
+    let metrics = gather().await?;
+    let Some(path) = opts.textfile.as_ref() else {
+        print!("{metrics}");
+        return Ok(());
+    };
+    // The textfile collector only reads files with this extension
+    if path.extension() != Some("prom") {
+        anyhow::bail!("Invalid file name {path}; must end in .prom");
+    }
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        anyhow::bail!("Invalid path {path}");
+    };
+    let dir = if dir.as_str().is_empty() {
+        Dir::open_ambient_dir(".", cap_std::ambient_authority())?
+    } else {
+        Dir::open_ambient_dir(dir, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {dir}"))?
+    };
+    dir.atomic_write(name, metrics.as_bytes())
+        .with_context(|| format!("Writing {path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("quay.io/os:1"), "quay.io/os:1");
+        assert_eq!(escape_label_value("a\"b\\c\nd"), r#"a\"b\\c\nd"#);
+    }
+
+    #[test]
+    fn test_render() {
+        let now: chrono::DateTime<chrono::Utc> =
+            chrono::DateTime::parse_from_rfc3339("2023-10-15T19:22:16Z")
+                .unwrap()
+                .into();
+        let host: Host = Default::default();
+        let out = render(&host, None, now);
+        assert!(out.contains("\nbootc_booted 0\n"));
+        assert!(!out.contains("bootc_booted_image_info"));
+
+        let host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        let last_update = UpdateResult {
+            timestamp: chrono::DateTime::from_timestamp(1700000000, 0).unwrap(),
+            success: false,
+            error: Some("oops".into()),
+        };
+        let out = render(&host, Some(&last_update), now);
+        let expected = indoc::indoc! { r#"
+            # HELP bootc_booted Whether the system is booted from a bootc compatible image
+            # TYPE bootc_booted gauge
+            bootc_booted 1
+            # HELP bootc_booted_image_info The booted image; the value is always 1
+            # TYPE bootc_booted_image_info gauge
+            bootc_booted_image_info{image="quay.io/example/someimage:latest",transport="registry",digest="sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34",version="nightly"} 1
+            # HELP bootc_booted_image_timestamp_seconds The build time of the booted image
+            # TYPE bootc_booted_image_timestamp_seconds gauge
+            bootc_booted_image_timestamp_seconds 1696101736
+            # HELP bootc_booted_image_age_seconds The time since the booted image was built
+            # TYPE bootc_booted_image_age_seconds gauge
+            bootc_booted_image_age_seconds 1296000
+            # HELP bootc_update_staged Whether an update is staged for the next boot
+            # TYPE bootc_update_staged gauge
+            bootc_update_staged 1
+        "# };
+        assert!(out.starts_with(expected), "{out}");
+        assert!(out.contains("\nbootc_update_available 0\n"));
+        assert!(out.contains("\nbootc_last_update_timestamp_seconds 1700000000\n"));
+        assert!(out.contains("\nbootc_last_update_success 0\n"));
+        assert!(!out.contains("bootc_storage_"));
+    }
+}
@@ -1,17 +1,35 @@
 //! Helpers for interacting with mountpoints
 
 use std::{
+    ffi::CString,
     fs,
-    os::fd::{AsFd, OwnedFd},
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    os::unix::process::CommandExt,
     process::Command,
 };
 
-use anyhow::{anyhow, Context, Result};
-use bootc_utils::CommandRunExt;
-use camino::Utf8Path;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
 use fn_error_context::context;
+use rustix::mount::{
+    FsMountFlags, FsOpenFlags, MountAttrFlags, MountFlags, MountPropagationFlags, MoveMountFlags,
+};
+
+// The rest is only used by install
+#[cfg(feature = "install")]
+use std::sync::Arc;
+
+#[cfg(feature = "install")]
+use anyhow::anyhow;
+#[cfg(feature = "install")]
+use bootc_utils::CommandRunExt;
+#[cfg(feature = "install")]
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+#[cfg(feature = "install")]
 use rustix::{
-    mount::{MoveMountFlags, OpenTreeFlags},
+    mount::OpenTreeFlags,
     net::{
         AddressFamily, RecvFlags, SendAncillaryBuffer, SendAncillaryMessage, SendFlags,
         SocketFlags, SocketType,
@@ -19,10 +37,13 @@ use rustix::{
     process::WaitOptions,
     thread::Pid,
 };
+#[cfg(feature = "install")]
 use serde::Deserialize;
 
+#[cfg(feature = "install")]
 use crate::task::Task;
 
+#[cfg(feature = "install")]
 /// Well known identifier for pid 1
 pub(crate) const PID1: Pid = const {
     match Pid::from_raw(1) {
@@ -31,7 +52,8 @@ pub(crate) const PID1: Pid = const {
     }
 };
 
-#[derive(Deserialize, Debug)]
+#[cfg(feature = "install")]
+#[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 #[allow(dead_code)]
 pub(crate) struct Filesystem {
@@ -43,21 +65,501 @@ pub(crate) struct Filesystem {
     pub(crate) fstype: String,
     pub(crate) options: String,
     pub(crate) uuid: Option<String>,
+    pub(crate) label: Option<String>,
     pub(crate) children: Option<Vec<Filesystem>>,
+    /// For btrfs, the ID of the mounted subvolume; this is derived from the options.
+    #[serde(default)]
+    pub(crate) subvolid: Option<u64>,
+    /// If the source is a dm-crypt mapping, information about it; this is derived from sysfs.
+    #[serde(skip)]
+    pub(crate) crypt: Option<CryptInfo>,
+}
+
+#[cfg(feature = "install")]
+/// Information about a dm-crypt mapping backing a filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CryptInfo {
+    /// The device mapper name, as in `/dev/mapper/<name>`
+    #[allow(dead_code)]
+    pub(crate) name: String,
+    /// The LUKS UUID; this is `None` for plain dm-crypt
+    pub(crate) luks_uuid: Option<String>,
+    /// The underlying device, e.g. `/dev/vda3`
+    #[allow(dead_code)]
+    pub(crate) backing_device: Option<String>,
+}
+
+#[cfg(feature = "install")]
+/// Extract the LUKS UUID from a device mapper UUID as set by cryptsetup,
+/// e.g. `CRYPT-LUKS2-<uuid without dashes>-<name>`.
+fn luks_uuid_of_dm_uuid(dm_uuid: &str) -> Option<String> {
+    let rest = dm_uuid
+        .strip_prefix("CRYPT-LUKS1-")
+        .or_else(|| dm_uuid.strip_prefix("CRYPT-LUKS2-"))?;
+    let hex = rest.get(..32)?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+#[cfg(feature = "install")]
+/// Look up dm-crypt information for the block device with the given `major:minor`,
+/// using the provided sysfs root. Returns `None` if the device is not a dm-crypt mapping.
+fn crypt_info_in(sysfs: &Dir, maj_min: &str) -> Result<Option<CryptInfo>> {
+    let Some(devdir) = sysfs.open_dir_optional(format!("dev/block/{maj_min}"))? else {
+        return Ok(None);
+    };
+    let Some(dm_uuid) = devdir.open_optional("dm/uuid")? else {
+        return Ok(None);
+    };
+    let dm_uuid = std::io::read_to_string(dm_uuid)?;
+    let dm_uuid = dm_uuid.trim();
+    if !dm_uuid.starts_with("CRYPT-") {
+        return Ok(None);
+    }
+    let name = devdir.read_to_string("dm/name")?.trim().to_owned();
+    // A dm-crypt mapping has exactly one underlying device, listed in
+    // the equivalent of `/sys/block/dm-N/slaves`.
+    let backing_device = devdir
+        .read_dir("slaves")?
+        .filter_map(Result::ok)
+        .find_map(|e| e.file_name().into_string().ok())
+        .map(|v| format!("/dev/{v}"));
+    Ok(Some(CryptInfo {
+        name,
+        luks_uuid: luks_uuid_of_dm_uuid(dm_uuid),
+        backing_device,
+    }))
+}
+
+#[cfg(feature = "install")]
+/// Look up dm-crypt information for the block device with the given `major:minor`.
+fn crypt_info(maj_min: &str) -> Option<CryptInfo> {
+    let sysfs = Dir::open_ambient_dir("/sys", cap_std_ext::cap_std::ambient_authority());
+    match sysfs
+        .map_err(Into::into)
+        .and_then(|d| crypt_info_in(&d, maj_min))
+    {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::debug!("Failed to query dm-crypt information for {maj_min}: {e:#}");
+            None
+        }
+    }
+}
+
+#[cfg(feature = "install")]
+impl Filesystem {
+    /// Parse the mount options.
+    pub(crate) fn mount_options(&self) -> MountOptions {
+        MountOptions::parse(&self.options)
+    }
+}
+
+/// A parsed mount option string like `rw,relatime,compress=zstd:1,subvol=/root`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct MountOptions {
+    options: Vec<(String, Option<String>)>,
+}
+
+#[allow(dead_code)]
+impl MountOptions {
+    /// Parse a comma-separated mount option string.
+    pub(crate) fn parse(s: &str) -> Self {
+        let options = s
+            .split(',')
+            .filter(|o| !o.is_empty())
+            .map(|o| match o.split_once('=') {
+                Some((k, v)) => (k.to_owned(), Some(v.to_owned())),
+                None => (o.to_owned(), None),
+            })
+            .collect();
+        Self { options }
+    }
+
+    /// Returns true if the flag (i.e. an option without a value) is present.
+    pub(crate) fn has_flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(k, v)| k == name && v.is_none())
+    }
+
+    /// Find the value of the first option like `name=value`. This will not match
+    /// a bare `name` without an equals.
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(k, _)| k == name)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    /// Returns true if the mount is read-only; the last of `ro` or `rw` wins.
+    pub(crate) fn is_readonly(&self) -> bool {
+        self.options
+            .iter()
+            .rev()
+            .find_map(|(k, v)| match (k.as_str(), v) {
+                ("ro", None) => Some(true),
+                ("rw", None) => Some(false),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns true if the filesystem supports SELinux labeling.
+    pub(crate) fn has_seclabel(&self) -> bool {
+        self.has_flag("seclabel")
+    }
+
+    /// The compression algorithm (e.g. for btrfs).
+    pub(crate) fn compress(&self) -> Option<&str> {
+        self.get("compress").or_else(|| self.get("compress-force"))
+    }
+
+    /// The btrfs subvolume path.
+    pub(crate) fn subvol(&self) -> Option<&str> {
+        self.get("subvol")
+    }
+
+    /// The btrfs subvolume ID.
+    pub(crate) fn subvolid(&self) -> Option<u64> {
+        self.get("subvolid").and_then(|v| v.parse().ok())
+    }
+}
+
+impl std::fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (k, v)) in self.options.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(k)?;
+            if let Some(v) = v {
+                write!(f, "={v}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "install")]
+/// The ID of the top level subvolume of a btrfs filesystem.
+const BTRFS_TOPLEVEL_SUBVOLID: u64 = 5;
+
+#[cfg(feature = "install")]
+/// A btrfs subvolume
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BtrfsSubvolume {
+    /// The subvolume ID
+    pub(crate) id: u64,
+    /// The path relative to the top level subvolume
+    pub(crate) path: String,
+}
+
+#[cfg(feature = "install")]
+/// Parse the output of `btrfs subvolume list`, which looks like:
+/// `ID 256 gen 35 top level 5 path root`
+fn parse_btrfs_subvolume_list(buf: &str) -> Result<Vec<BtrfsSubvolume>> {
+    buf.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let (fields, path) = l
+                .split_once(" path ")
+                .ok_or_else(|| anyhow!("Missing path in: {l}"))?;
+            let id = fields
+                .strip_prefix("ID ")
+                .and_then(|v| v.split(' ').next())
+                .ok_or_else(|| anyhow!("Missing ID in: {l}"))?;
+            let id = id.parse().with_context(|| format!("Parsing ID in: {l}"))?;
+            Ok(BtrfsSubvolume {
+                id,
+                path: path.to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(feature = "install")]
+/// Enumerate the subvolumes of the btrfs filesystem on the given device. The top
+/// level subvolume is temporarily mounted (read-only) to do this.
+#[allow(dead_code)]
+#[context("Listing btrfs subvolumes of {dev}")]
+pub(crate) fn list_btrfs_subvolumes(dev: &str) -> Result<Vec<BtrfsSubvolume>> {
+    let tempdir = tempfile::tempdir()?;
+    let mnt = Utf8Path::from_path(tempdir.path()).ok_or_else(|| anyhow!("Non-UTF8 tempdir"))?;
+    Task::new("Mounting btrfs top level", "mount")
+        .quiet()
+        .args([
+            "-o",
+            &format!("ro,subvolid={BTRFS_TOPLEVEL_SUBVOLID}"),
+            dev,
+            mnt.as_str(),
+        ])
+        .run()?;
+    let r = Task::new_quiet("btrfs")
+        .args(["subvolume", "list", mnt.as_str()])
+        .read()
+        .and_then(|o| parse_btrfs_subvolume_list(&o));
+    unmount(mnt, Default::default())?;
+    r
+}
+
+#[cfg(feature = "install")]
+/// Mount the btrfs subvolume (specified by path relative to the top level) on the device
+/// to the target path.
+pub(crate) fn mount_btrfs_subvolume(dev: &str, subvol: &str, target: &Utf8Path) -> Result<()> {
+    Task::new(format!("Mounting subvolume {subvol} at {target}"), "mount")
+        .args(["-o", &format!("subvol={subvol}"), dev, target.as_str()])
+        .run()
+}
+
+#[cfg(feature = "install")]
+/// A way to identify a filesystem or partition, as understood by e.g. `mount`
+/// and `findmnt --source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SourceTag<'a> {
+    /// The filesystem UUID
+    Uuid(&'a str),
+    /// The filesystem label
+    Label(&'a str),
+    /// The GPT partition label
+    PartLabel(&'a str),
+}
+
+#[cfg(feature = "install")]
+impl SourceTag<'_> {
+    /// The directory of symbolic links maintained by udev for this tag type
+    fn udev_dir(&self) -> &'static str {
+        match self {
+            SourceTag::Uuid(_) => "/dev/disk/by-uuid",
+            SourceTag::Label(_) => "/dev/disk/by-label",
+            SourceTag::PartLabel(_) => "/dev/disk/by-partlabel",
+        }
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            SourceTag::Uuid(v) | SourceTag::Label(v) | SourceTag::PartLabel(v) => v,
+        }
+    }
+
+    /// The path to the udev-maintained symbolic link to the device
+    pub(crate) fn device_link(&self) -> String {
+        format!("{}/{}", self.udev_dir(), udev_encode(self.value()))
+    }
+}
+
+#[cfg(feature = "install")]
+impl std::fmt::Display for SourceTag<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SourceTag::Uuid(_) => "UUID",
+            SourceTag::Label(_) => "LABEL",
+            SourceTag::PartLabel(_) => "PARTLABEL",
+        };
+        write!(f, "{name}={}", self.value())
+    }
+}
+
+#[cfg(feature = "install")]
+/// Encode a string the same way as udev (and blkid) do for the names of
+/// the symbolic links in e.g. `/dev/disk/by-label`.
+fn udev_encode(s: &str) -> String {
+    let mut r = String::with_capacity(s.as_bytes().len());
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+            r.push(c);
+        } else {
+            r.push_str(&format!("\\x{:02x}", c as u8));
+        }
+    }
+    r
+}
+
+#[cfg(feature = "install")]
+/// The inverse of [`udev_encode`].
+fn udev_decode(s: &str) -> String {
+    let mut r = Vec::with_capacity(s.as_bytes().len());
+    let mut bytes = s.as_bytes();
+    while let Some((&c, rest)) = bytes.split_first() {
+        if c == b'\\' {
+            if let Some(v) = rest
+                .strip_prefix(b"x")
+                .and_then(|rest| rest.get(..2))
+                .and_then(|o| std::str::from_utf8(o).ok())
+                .and_then(|o| u8::from_str_radix(o, 16).ok())
+            {
+                r.push(v);
+                bytes = &rest[3..];
+                continue;
+            }
+        }
+        r.push(c);
+        bytes = rest;
+    }
+    String::from_utf8_lossy(&r).into_owned()
 }
 
+#[cfg(feature = "install")]
 #[derive(Deserialize, Debug)]
 pub(crate) struct Findmnt {
     pub(crate) filesystems: Vec<Filesystem>,
 }
 
+#[cfg(feature = "install")]
+/// Unescape a field from `mountinfo`, which uses octal escapes
+/// for whitespace and backslashes.
+fn unescape_mountinfo(s: &str) -> String {
+    if !s.contains('\\') {
+        return s.to_owned();
+    }
+    let mut r = Vec::with_capacity(s.as_bytes().len());
+    let mut bytes = s.as_bytes();
+    while let Some((&c, rest)) = bytes.split_first() {
+        if c == b'\\' {
+            if let Some(v) = rest
+                .get(..3)
+                .and_then(|o| std::str::from_utf8(o).ok())
+                .and_then(|o| u8::from_str_radix(o, 8).ok())
+            {
+                r.push(v);
+                bytes = &rest[3..];
+                continue;
+            }
+        }
+        r.push(c);
+        bytes = rest;
+    }
+    String::from_utf8_lossy(&r).into_owned()
+}
+
+#[cfg(feature = "install")]
+/// Combine the per-mount (VFS) options with the superblock options, like
+/// the `OPTIONS` column of findmnt.
+fn merge_mount_options(vfs: &str, sb: &str) -> String {
+    let mut r = vfs.split(',').filter(|o| !o.is_empty()).collect::<Vec<_>>();
+    for opt in sb.split(',') {
+        // The superblock has its own ro/rw state, but the mount's is what matters
+        if opt.is_empty() || opt == "ro" || opt == "rw" || r.contains(&opt) {
+            continue;
+        }
+        r.push(opt);
+    }
+    r.join(",")
+}
+
+#[cfg(feature = "install")]
+/// Parse a single line of `/proc/<pid>/mountinfo`; see `proc(5)`.
+fn parse_mountinfo_line(line: &str) -> Result<Filesystem> {
+    let (mount, sb) = line
+        .split_once(" - ")
+        .ok_or_else(|| anyhow!("Missing separator"))?;
+    let mut mount = mount.split(' ');
+    let mut next = |name: &str| mount.next().ok_or_else(|| anyhow!("Missing field: {name}"));
+    let _mount_id = next("mount ID")?;
+    let _parent_id = next("parent ID")?;
+    let maj_min = next("major:minor")?.to_owned();
+    let _root = next("root")?;
+    let target = unescape_mountinfo(next("mount point")?);
+    let vfs_options = next("mount options")?;
+    let mut sb = sb.splitn(3, ' ');
+    let fstype = unescape_mountinfo(sb.next().ok_or_else(|| anyhow!("Missing fstype"))?);
+    let source = unescape_mountinfo(sb.next().ok_or_else(|| anyhow!("Missing source"))?);
+    let sb_options = sb.next().unwrap_or_default();
+    Ok(Filesystem {
+        source,
+        target,
+        maj_min,
+        fstype,
+        options: merge_mount_options(vfs_options, sb_options),
+        subvolid: MountOptions::parse(sb_options).subvolid(),
+        uuid: None,
+        label: None,
+        children: None,
+        crypt: None,
+    })
+}
+
+#[cfg(feature = "install")]
+/// Parse the contents of a `mountinfo` file into a flat list of filesystems,
+/// in mount order.
+fn parse_mountinfo(buf: &str) -> Result<Vec<Filesystem>> {
+    buf.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| parse_mountinfo_line(l).with_context(|| format!("Parsing mountinfo line: {l}")))
+        .collect()
+}
+
+#[cfg(feature = "install")]
+/// Read the mount table of the target process (or ourself).
+#[context("Reading mountinfo")]
+fn read_mountinfo(pid: Option<Pid>) -> Result<Vec<Filesystem>> {
+    let path = if let Some(pid) = pid {
+        format!("/proc/{}/mountinfo", pid.as_raw_nonzero())
+    } else {
+        "/proc/self/mountinfo".to_owned()
+    };
+    let buf = fs::read_to_string(&path).with_context(|| format!("Reading {path}"))?;
+    parse_mountinfo(&buf)
+}
+
+#[cfg(feature = "install")]
+/// Find a tag (e.g. the UUID) for a device via the symbolic links maintained by udev
+/// in the provided directory.
+fn tag_of_device(udev_dir: &str, dev: &str) -> Option<String> {
+    if !dev.starts_with("/dev/") {
+        return None;
+    }
+    let dev = fs::canonicalize(dev).ok()?;
+    fs::read_dir(udev_dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|e| fs::canonicalize(e.path()).ok().as_ref() == Some(&dev))
+        .and_then(|e| e.file_name().into_string().ok())
+        .map(|v| udev_decode(&v))
+}
+
+#[cfg(feature = "install")]
+/// Find the most recently mounted filesystem matching the predicate in our mount namespace,
+/// by parsing the mount table directly. If that fails (e.g. `/proc` is not mounted), fall back
+/// to querying `findmnt` with the provided arguments.
+fn find_filesystem(
+    findmnt_args: &[&str],
+    path: &str,
+    pred: impl Fn(&Filesystem) -> bool,
+) -> Result<Filesystem> {
+    let filesystems = match read_mountinfo(None) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::debug!("Falling back to findmnt: {e:#}");
+            return findmnt_filesystem(findmnt_args, path);
+        }
+    };
+    let mut fs = filesystems
+        .into_iter()
+        .rev()
+        .find(pred)
+        .ok_or_else(|| anyhow!("No mounted filesystem found for {path}"))?;
+    fs.uuid = tag_of_device(SourceTag::Uuid("").udev_dir(), &fs.source);
+    fs.label = tag_of_device(SourceTag::Label("").udev_dir(), &fs.source);
+    fs.crypt = crypt_info(&fs.maj_min);
+    Ok(fs)
+}
+
+#[cfg(feature = "install")]
 fn run_findmnt(args: &[&str], path: &str) -> Result<Findmnt> {
     let o: Findmnt = Command::new("findmnt")
         .args([
             "-J",
             "-v",
             // If you change this you probably also want to change the Filesystem struct above
-            "--output=SOURCE,TARGET,MAJ:MIN,FSTYPE,OPTIONS,UUID",
+            "--output=SOURCE,TARGET,MAJ:MIN,FSTYPE,OPTIONS,UUID,LABEL",
         ])
         .args(args)
         .arg(path)
@@ -66,38 +568,99 @@ fn run_findmnt(args: &[&str], path: &str) -> Result<Findmnt> {
     Ok(o)
 }
 
+#[cfg(feature = "install")]
 // Retrieve a mounted filesystem from a device given a matching path
 fn findmnt_filesystem(args: &[&str], path: &str) -> Result<Filesystem> {
     let o = run_findmnt(args, path)?;
-    o.filesystems
+    let mut fs = o
+        .filesystems
         .into_iter()
         .next()
-        .ok_or_else(|| anyhow!("findmnt returned no data for {path}"))
+        .ok_or_else(|| anyhow!("findmnt returned no data for {path}"))?;
+    fs.subvolid = fs.mount_options().subvolid();
+    fs.crypt = crypt_info(&fs.maj_min);
+    Ok(fs)
 }
 
+#[cfg(feature = "install")]
 #[context("Inspecting filesystem {path}")]
 /// Inspect a target which must be a mountpoint root - it is an error
 /// if the target is not the mount root.
 pub(crate) fn inspect_filesystem(path: &Utf8Path) -> Result<Filesystem> {
-    findmnt_filesystem(&["--mountpoint"], path.as_str())
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.as_std_path().to_owned());
+    find_filesystem(&["--mountpoint"], path.as_str(), |fs| {
+        std::path::Path::new(&fs.target) == target
+    })
 }
 
-#[context("Inspecting filesystem by UUID {uuid}")]
+#[cfg(feature = "install")]
+#[context("Inspecting filesystem {tag}")]
+/// Inspect a mounted filesystem by a tag such as its UUID or label
+pub(crate) fn inspect_filesystem_by_tag(tag: SourceTag) -> Result<Filesystem> {
+    let source = tag.to_string();
+    let dev = match fs::canonicalize(tag.device_link()) {
+        Ok(dev) => dev,
+        // Let findmnt sort it out
+        Err(_) => return findmnt_filesystem(&["--source"], &source),
+    };
+    let maj_min = {
+        use std::os::unix::fs::MetadataExt;
+        let rdev = fs::metadata(&dev)?.rdev();
+        format!("{}:{}", rustix::fs::major(rdev), rustix::fs::minor(rdev))
+    };
+    let mut r = find_filesystem(&["--source"], &source, |fs| {
+        fs.maj_min == maj_min || std::path::Path::new(&fs.source) == dev
+    })?;
+    match tag {
+        SourceTag::Uuid(uuid) => {
+            r.uuid.get_or_insert_with(|| uuid.to_owned());
+        }
+        SourceTag::Label(label) => {
+            r.label.get_or_insert_with(|| label.to_owned());
+        }
+        SourceTag::PartLabel(_) => {}
+    }
+    Ok(r)
+}
+
+#[cfg(feature = "install")]
 /// Inspect a filesystem by partition UUID
 pub(crate) fn inspect_filesystem_by_uuid(uuid: &str) -> Result<Filesystem> {
-    findmnt_filesystem(&["--source"], &(format!("UUID={uuid}")))
+    inspect_filesystem_by_tag(SourceTag::Uuid(uuid))
+}
+
+#[cfg(feature = "install")]
+/// Inspect a filesystem by its label
+#[allow(dead_code)]
+pub(crate) fn inspect_filesystem_by_label(label: &str) -> Result<Filesystem> {
+    inspect_filesystem_by_tag(SourceTag::Label(label))
+}
+
+#[cfg(feature = "install")]
+/// Inspect a filesystem by its GPT partition label
+#[allow(dead_code)]
+pub(crate) fn inspect_filesystem_by_partlabel(partlabel: &str) -> Result<Filesystem> {
+    inspect_filesystem_by_tag(SourceTag::PartLabel(partlabel))
 }
 
+#[cfg(feature = "install")]
 // Check if a specified device contains an already mounted filesystem
 // in the root mount namespace
 pub(crate) fn is_mounted_in_pid1_mountns(path: &str) -> Result<bool> {
-    let o = run_findmnt(&["-N"], "1")?;
+    let filesystems = match read_mountinfo(Some(PID1)) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::debug!("Falling back to findmnt: {e:#}");
+            run_findmnt(&["-N"], "1")?.filesystems
+        }
+    };
 
-    let mounted = o.filesystems.iter().any(|fs| is_source_mounted(path, fs));
+    let mounted = filesystems.iter().any(|fs| is_source_mounted(path, fs));
 
     Ok(mounted)
 }
 
+#[cfg(feature = "install")]
 // Recursively check a given filesystem to see if it contains an already mounted source
 pub(crate) fn is_source_mounted(path: &str, mounted_fs: &Filesystem) -> bool {
     if mounted_fs.source.contains(path) {
@@ -115,6 +678,7 @@ pub(crate) fn is_source_mounted(path: &str, mounted_fs: &Filesystem) -> bool {
     false
 }
 
+#[cfg(feature = "install")]
 /// Mount a device to the target path.
 pub(crate) fn mount(dev: &str, target: &Utf8Path) -> Result<()> {
     Task::new_and_run(
@@ -124,9 +688,148 @@ pub(crate) fn mount(dev: &str, target: &Utf8Path) -> Result<()> {
     )
 }
 
+#[cfg(feature = "install")]
+/// Options for [`unmount`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UnmountFlags {
+    /// Lazily detach the mount (`MNT_DETACH`)
+    pub(crate) lazy: bool,
+    /// Force the unmount (`MNT_FORCE`); this is only useful for network filesystems
+    pub(crate) force: bool,
+    /// Also unmount all mounts below the path (like `umount -R`)
+    pub(crate) recursive: bool,
+}
+
+#[cfg(feature = "install")]
+/// How many times we try unmounting a busy filesystem
+const UNMOUNT_ATTEMPTS: u32 = 5;
+
+#[cfg(feature = "install")]
+/// Find processes which have a working directory, root, or open file below the path.
+fn processes_using(path: &Utf8Path) -> Vec<(u32, String)> {
+    let Ok(procs) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let uses = |p: &std::path::Path| {
+        fs::read_link(p)
+            .map(|l| l.starts_with(path))
+            .unwrap_or_default()
+    };
+    procs
+        .filter_map(Result::ok)
+        .filter_map(|e| {
+            let pid: u32 = e.file_name().to_str()?.parse().ok()?;
+            let procdir = e.path();
+            let found = ["cwd", "root", "exe"]
+                .iter()
+                .any(|n| uses(&procdir.join(n)))
+                || fs::read_dir(procdir.join("fd"))
+                    .map(|fds| fds.filter_map(Result::ok).any(|fd| uses(&fd.path())))
+                    .unwrap_or_default();
+            if !found {
+                return None;
+            }
+            let comm = fs::read_to_string(procdir.join("comm")).unwrap_or_default();
+            Some((pid, comm.trim().to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(feature = "install")]
+fn unmount_one(target: &Utf8Path, flags: rustix::mount::UnmountFlags) -> Result<()> {
+    let mut delay = std::time::Duration::from_millis(100);
+    let mut attempt = 1;
+    loop {
+        match rustix::mount::unmount(target.as_std_path(), flags) {
+            Ok(()) => return Ok(()),
+            Err(rustix::io::Errno::BUSY) if attempt < UNMOUNT_ATTEMPTS => {
+                tracing::debug!("{target} is busy (attempt {attempt}), retrying");
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(rustix::io::Errno::BUSY) => {
+                let holders = processes_using(target)
+                    .into_iter()
+                    .map(|(pid, comm)| format!("{comm} ({pid})"))
+                    .collect::<Vec<_>>();
+                if holders.is_empty() {
+                    anyhow::bail!("{target} is busy");
+                }
+                anyhow::bail!("{target} is busy; in use by: {}", holders.join(", "));
+            }
+            Err(e) => return Err(e).with_context(|| format!("Unmounting {target}")),
+        }
+    }
+}
+
+#[cfg(feature = "install")]
+/// Filter the filesystems to those mounted at or below `path`.
+fn filter_mounts_under(filesystems: Vec<Filesystem>, path: &Utf8Path) -> Vec<Filesystem> {
+    filesystems
+        .into_iter()
+        .filter(|fs| Utf8Path::new(&fs.target).starts_with(path))
+        .collect()
+}
+
+#[cfg(feature = "install")]
+/// Find all filesystems mounted at or below `path` in our mount namespace, in
+/// mount order; to unmount them, iterate in reverse.
+#[context("Finding mounts under {path}")]
+pub(crate) fn mounts_under(path: &Utf8Path) -> Result<Vec<Filesystem>> {
+    Ok(filter_mounts_under(read_mountinfo(None)?, path))
+}
+
+#[cfg(feature = "install")]
+/// Unmount the filesystem at the target path, retrying a few times if it is busy. If
+/// it is still busy, the error includes the processes using it.
+#[context("Unmounting {path}")]
+pub(crate) fn unmount(path: &Utf8Path, flags: UnmountFlags) -> Result<()> {
+    let mut rflags = rustix::mount::UnmountFlags::empty();
+    if flags.lazy {
+        rflags |= rustix::mount::UnmountFlags::DETACH;
+    }
+    if flags.force {
+        rflags |= rustix::mount::UnmountFlags::FORCE;
+    }
+    if !flags.recursive {
+        return unmount_one(path, rflags);
+    }
+    // Unmount in the reverse order of mounting, which ensures we handle
+    // submounts first.
+    let targets = mounts_under(path)?
+        .into_iter()
+        .rev()
+        .map(|fs| Utf8PathBuf::from(fs.target))
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        anyhow::bail!("Not mounted: {path}");
+    }
+    for target in targets {
+        unmount_one(&target, rflags)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "install")]
+/// Mount a filesystem identified by a tag (e.g. `LABEL=boot`) to the target path,
+/// waiting for the device to show up first.
+#[allow(dead_code)]
+pub(crate) fn mount_by_tag(tag: SourceTag, target: &Utf8Path) -> Result<()> {
+    let link = tag.device_link();
+    let dev = crate::blockdev::wait_for_device(
+        crate::blockdev::DeviceSpec::Path(Utf8Path::new(&link)),
+        crate::blockdev::DEVICE_WAIT_TIMEOUT,
+    )?;
+    mount(dev.as_str(), target)
+}
+
+#[cfg(feature = "install")]
 /// If the fsid of the passed path matches the fsid of the same path rooted
-/// at /proc/1/root, it is assumed that these are indeed the same mounted
-/// filesystem between container and host.
+/// at /proc/1/root, and the path also refers to the same inode, it is assumed
+/// that these are indeed the same mounted filesystem between container and host.
+/// (Checking just the fsid is insufficient, as e.g. a distinct directory on the
+/// same filesystem could be mounted in the container.)
 /// Path should be absolute.
 #[context("Comparing filesystems at {path} and /proc/1/root/{path}")]
 pub(crate) fn is_same_as_host(path: &Utf8Path) -> Result<bool> {
@@ -143,9 +846,15 @@ pub(crate) fn is_same_as_host(path: &Utf8Path) -> Result<bool> {
         devstat.f_fsid,
         hostdevstat.f_fsid
     );
-    Ok(devstat.f_fsid == hostdevstat.f_fsid)
+    if devstat.f_fsid != hostdevstat.f_fsid {
+        return Ok(false);
+    }
+    let st = rustix::fs::stat(path.as_std_path())?;
+    let hoststat = rustix::fs::stat(hostpath.as_std_path())?;
+    Ok((st.st_dev, st.st_ino) == (hoststat.st_dev, hoststat.st_ino))
 }
 
+#[cfg(feature = "install")]
 /// Given a pid, enter its mount namespace and acquire a file descriptor
 /// for a mount from that namespace.
 #[allow(unsafe_code)]
@@ -242,6 +951,20 @@ pub(crate) fn open_tree_from_pidns(
     }
 }
 
+/// Attach a detached mount (e.g. from `open_tree` or `fsmount`) at the target path.
+#[context("Moving mount to {target}")]
+pub(crate) fn move_mount_to(mnt: BorrowedFd, target: &Utf8Path) -> Result<()> {
+    rustix::mount::move_mount(
+        mnt,
+        "",
+        rustix::fs::CWD,
+        target.as_std_path(),
+        MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH,
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "install")]
 /// Create a bind mount from the mount namespace of the target pid
 /// into our mount namespace.
 pub(crate) fn bind_mount_from_pidns(
@@ -251,28 +974,819 @@ pub(crate) fn bind_mount_from_pidns(
     recursive: bool,
 ) -> Result<()> {
     let src = open_tree_from_pidns(pid, src, recursive)?;
-    rustix::mount::move_mount(
-        src.as_fd(),
-        "",
-        rustix::fs::CWD,
-        target.as_std_path(),
-        MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH,
-    )
-    .context("Moving mount")?;
+    move_mount_to(src.as_fd(), target)
+}
+
+/// Create a new detached tmpfs mount with the given root directory mode.
+#[context("Creating tmpfs")]
+pub(crate) fn tmpfs_detached(mode: u32) -> Result<OwnedFd> {
+    let fsfd = rustix::mount::fsopen("tmpfs", FsOpenFlags::FSOPEN_CLOEXEC)?;
+    rustix::mount::fsconfig_set_string(fsfd.as_fd(), "mode", format!("{mode:o}"))?;
+    rustix::mount::fsconfig_create(fsfd.as_fd())?;
+    let mnt = rustix::mount::fsmount(
+        fsfd.as_fd(),
+        FsMountFlags::FSMOUNT_CLOEXEC,
+        MountAttrFlags::empty(),
+    )?;
+    Ok(mnt)
+}
+
+/// Create a new detached overlayfs mount. The lower directories are ordered
+/// from top to bottom. If the upper and work directories are not provided,
+/// the overlay is read-only.
+#[context("Creating overlay")]
+pub(crate) fn overlay_detached(
+    lower: &[&Utf8Path],
+    upper_work: Option<(&Utf8Path, &Utf8Path)>,
+) -> Result<OwnedFd> {
+    anyhow::ensure!(
+        !lower.is_empty(),
+        "At least one lower directory is required"
+    );
+    // The legacy option string format uses these as separators
+    for p in lower
+        .iter()
+        .chain(upper_work.iter().flat_map(|(u, w)| [u, w]))
+    {
+        if p.as_str().contains([':', ',']) {
+            anyhow::bail!("Unsupported overlay path: {p}");
+        }
+    }
+    let fsfd = rustix::mount::fsopen("overlay", FsOpenFlags::FSOPEN_CLOEXEC)?;
+    let fsfd = fsfd.as_fd();
+    let lowerdir = lower
+        .iter()
+        .map(|p| p.as_str())
+        .collect::<Vec<_>>()
+        .join(":");
+    rustix::mount::fsconfig_set_string(fsfd, "lowerdir", lowerdir.as_str())
+        .context("Setting lowerdir")?;
+    if let Some((upper, work)) = upper_work {
+        rustix::mount::fsconfig_set_string(fsfd, "upperdir", upper.as_str())
+            .context("Setting upperdir")?;
+        rustix::mount::fsconfig_set_string(fsfd, "workdir", work.as_str())
+            .context("Setting workdir")?;
+    }
+    rustix::mount::fsconfig_create(fsfd)?;
+    let attrs = if upper_work.is_some() {
+        MountAttrFlags::empty()
+    } else {
+        MountAttrFlags::MOUNT_ATTR_RDONLY
+    };
+    let mnt = rustix::mount::fsmount(fsfd, FsMountFlags::FSMOUNT_CLOEXEC, attrs)?;
+    Ok(mnt)
+}
+
+/// Copy the SELinux label (if any) from one path to another.
+fn copy_selinux_label(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    const SELINUX_XATTR: &str = "security.selinux";
+    let mut buf = [0u8; 1024];
+    match rustix::fs::getxattr(src.as_std_path(), SELINUX_XATTR, &mut buf) {
+        Ok(n) => rustix::fs::setxattr(
+            dest.as_std_path(),
+            SELINUX_XATTR,
+            &buf[..n],
+            rustix::fs::XattrFlags::empty(),
+        )?,
+        Err(rustix::io::Errno::NODATA) | Err(rustix::io::Errno::NOTSUP) => {}
+        Err(e) => return Err(e.into()),
+    }
     Ok(())
 }
 
+/// Mount a writable overlay on top of `target`, with the upper directory stored on
+/// a new tmpfs mounted at `state_dir`. Because all of the state is in memory,
+/// changes are discarded on reboot.
+#[context("Mounting transient overlay on {target}")]
+pub(crate) fn mount_transient_overlay(target: &Utf8Path, state_dir: &Utf8Path) -> Result<()> {
+    fs::create_dir_all(state_dir)?;
+    let tmpfs = tmpfs_detached(0o700)?;
+    move_mount_to(tmpfs.as_fd(), state_dir)?;
+    let upper = &state_dir.join("upper");
+    let work = &state_dir.join("work");
+    fs::create_dir(upper)?;
+    fs::create_dir(work)?;
+    // The root of the overlay takes its attributes from the upper directory
+    let st = rustix::fs::stat(target.as_std_path())?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(upper, fs::Permissions::from_mode(st.st_mode & 0o7777))?;
+    }
+    std::os::unix::fs::chown(upper, Some(st.st_uid), Some(st.st_gid))?;
+    copy_selinux_label(target, upper)?;
+    let overlay = overlay_detached(&[target], Some((upper, work)))?;
+    move_mount_to(overlay.as_fd(), target)
+}
+
+#[cfg(feature = "install")]
 // If the target path is not already mirrored from the host (e.g. via -v /dev:/dev)
-// then recursively mount it.
-pub(crate) fn ensure_mirrored_host_mount(path: impl AsRef<Utf8Path>) -> Result<()> {
+// then mount it. If `recursive` is set, mounts below the target path in the host
+// are also propagated; this also applies if the target path itself was already
+// mirrored but not all of its submounts were (e.g. -v /dev:/dev without /dev/pts).
+#[context("Mirroring host mount")]
+pub(crate) fn ensure_mirrored_host_mount(
+    path: impl AsRef<Utf8Path>,
+    recursive: bool,
+) -> Result<()> {
     let path = path.as_ref();
     // If we didn't have this in our filesystem already (e.g. for /var/lib/containers)
     // then create it now.
     std::fs::create_dir_all(path)?;
-    if is_same_as_host(path)? {
-        tracing::debug!("Already mounted from host: {path}");
+    if !is_same_as_host(path)? {
+        tracing::debug!("Propagating host mount: {path}");
+        return bind_mount_from_pidns(PID1, path, path, recursive);
+    }
+    tracing::debug!("Already mounted from host: {path}");
+    if !recursive {
         return Ok(());
     }
-    tracing::debug!("Propagating host mount: {path}");
-    bind_mount_from_pidns(PID1, path, path, true)
+    // Submounts which we have recursively propagated; anything below these is handled.
+    let mut propagated: Vec<Utf8PathBuf> = Vec::new();
+    for fs in read_mountinfo(Some(PID1))? {
+        let target = Utf8Path::new(&fs.target);
+        if target == path
+            || !target.starts_with(path)
+            || propagated.iter().any(|p| target.starts_with(p))
+        {
+            continue;
+        }
+        match is_same_as_host(target) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                // e.g. the mount point may be shadowed by another mount
+                tracing::debug!("Skipping host submount {target}: {e:#}");
+                continue;
+            }
+        }
+        tracing::debug!("Propagating host submount: {target}");
+        bind_mount_from_pidns(PID1, target, target, true)?;
+        propagated.push(target.to_owned());
+    }
+    Ok(())
+}
+
+/// Marker at the start of the systemd units we generate.
+const GENERATED_UNIT_STAMP: &str = "# Automatically generated by bootc-systemd-generator";
+/// The target our mount units are enabled for.
+const MOUNT_UNITS_WANTS: &str = "local-fs.target.wants";
+
+/// Escape a path for use as a systemd unit name, like `systemd-escape --path`.
+fn systemd_escape_path(path: &Utf8Path) -> String {
+    let components = path
+        .as_str()
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".");
+    let path = components.collect::<Vec<_>>().join("/");
+    if path.is_empty() {
+        return "-".to_owned();
+    }
+    let mut r = String::with_capacity(path.len());
+    for (i, b) in path.bytes().enumerate() {
+        match b {
+            b'/' => r.push('-'),
+            b'.' if i == 0 => r.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || matches!(b, b':' | b'_' | b'.') => r.push(b as char),
+            b => r.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+    r
+}
+
+/// A persistent bind mount managed by bootc, realized as a systemd mount unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BindMountUnit {
+    /// The source path
+    pub(crate) what: Utf8PathBuf,
+    /// The mount point
+    pub(crate) target: Utf8PathBuf,
+    /// Whether the bind mount is read-only
+    pub(crate) readonly: bool,
+    /// Mount on first access via an automount unit instead of at boot
+    pub(crate) automount: bool,
+}
+
+impl BindMountUnit {
+    fn unit_name(&self, suffix: &str) -> String {
+        format!("{}.{suffix}", systemd_escape_path(&self.target))
+    }
+
+    /// The name of the unit which should be enabled.
+    fn enabled_unit(&self) -> String {
+        self.unit_name(if self.automount { "automount" } else { "mount" })
+    }
+
+    /// Render the unit files for this mount, as pairs of (name, contents).
+    fn render(&self) -> Vec<(String, String)> {
+        let Self {
+            what,
+            target,
+            readonly,
+            automount,
+        } = self;
+        let options = if *readonly { "bind,ro" } else { "bind" };
+        let mut r = vec![(
+            self.unit_name("mount"),
+            format!(
+                "{GENERATED_UNIT_STAMP}\n\
+                 [Unit]\n\
+                 Documentation=man:bootc(8)\n\
+                 \n\
+                 [Mount]\n\
+                 What={what}\n\
+                 Where={target}\n\
+                 Type=none\n\
+                 Options={options}\n"
+            ),
+        )];
+        if *automount {
+            r.push((
+                self.unit_name("automount"),
+                format!(
+                    "{GENERATED_UNIT_STAMP}\n\
+                     [Unit]\n\
+                     Documentation=man:bootc(8)\n\
+                     \n\
+                     [Automount]\n\
+                     Where={target}\n"
+                ),
+            ));
+        }
+        r
+    }
+}
+
+/// Write systemd units for the provided bind mounts into the unit directory of
+/// a systemd generator, and enable them.  As generators run on every boot, the
+/// units always match the booted deployment, and nothing is persisted in `/etc`.
+#[context("Generating bind mount units")]
+pub(crate) fn generate_bind_mount_units(unit_dir: &Dir, mounts: &[BindMountUnit]) -> Result<()> {
+    unit_dir.create_dir_all(MOUNT_UNITS_WANTS)?;
+    for m in mounts {
+        for (name, contents) in m.render() {
+            unit_dir
+                .atomic_write(&name, contents)
+                .with_context(|| format!("Writing {name}"))?;
+        }
+        let enabled = m.enabled_unit();
+        unit_dir.symlink(
+            format!("../{enabled}"),
+            format!("{MOUNT_UNITS_WANTS}/{enabled}"),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "install")]
+/// `FS_IOC_MEASURE_VERITY`, from `linux/fsverity.h`
+const FS_IOC_MEASURE_VERITY: u32 = 0xc0046686;
+#[cfg(feature = "install")]
+/// The largest digest size supported by fsverity (sha512)
+const FS_VERITY_MAX_DIGEST_SIZE: usize = 64;
+
+#[cfg(feature = "install")]
+/// `struct fsverity_digest`, with space for the largest digest.
+#[repr(C)]
+struct FsVerityDigest {
+    // Filled in by the kernel, but we only care about the digest itself
+    #[allow(dead_code)]
+    algorithm: u16,
+    size: u16,
+    digest: [u8; FS_VERITY_MAX_DIGEST_SIZE],
+}
+
+#[cfg(feature = "install")]
+/// Return the hex-encoded fsverity digest of the file, or `None` if fsverity
+/// is not enabled on it (or is unsupported by the filesystem).
+#[allow(unsafe_code)]
+pub(crate) fn measure_verity(fd: BorrowedFd) -> Result<Option<String>> {
+    use std::os::fd::AsRawFd;
+    let mut d = FsVerityDigest {
+        algorithm: 0,
+        size: FS_VERITY_MAX_DIGEST_SIZE as u16,
+        digest: [0; FS_VERITY_MAX_DIGEST_SIZE],
+    };
+    // SAFETY: We pass a valid fd, and the kernel writes at most `size` bytes
+    // into the digest buffer.
+    let r = unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_MEASURE_VERITY as _, &mut d) };
+    if r < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENODATA | libc::ENOTTY | libc::EOPNOTSUPP) => Ok(None),
+            _ => Err(anyhow::Error::new(e).context("Measuring fsverity digest")),
+        };
+    }
+    let size = usize::from(d.size).min(FS_VERITY_MAX_DIGEST_SIZE);
+    Ok(Some(hex::encode(&d.digest[..size])))
+}
+
+#[cfg(feature = "install")]
+/// Why a file failed fsverity verification; this can be recovered from the
+/// returned error via [`anyhow::Error::downcast_ref`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum VerityError {
+    /// The file does not have fsverity enabled
+    NotEnabled,
+    /// The file has fsverity enabled, but with an unexpected digest
+    DigestMismatch {
+        /// The digest we expected
+        expected: String,
+        /// The digest of the file
+        found: String,
+    },
+}
+
+#[cfg(feature = "install")]
+impl std::fmt::Display for VerityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerityError::NotEnabled => f.write_str("fsverity is not enabled"),
+            VerityError::DigestMismatch { expected, found } => {
+                write!(
+                    f,
+                    "fsverity digest mismatch: expected {expected}, found {found}"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "install")]
+impl std::error::Error for VerityError {}
+
+#[cfg(feature = "install")]
+/// Check a measured digest (as returned by [`measure_verity`]) against the
+/// expected hex-encoded digest.
+fn check_verity_digest(found: Option<String>, expected: &str) -> Result<(), VerityError> {
+    let found = found.ok_or(VerityError::NotEnabled)?;
+    if !found.eq_ignore_ascii_case(expected) {
+        return Err(VerityError::DigestMismatch {
+            expected: expected.to_owned(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(feature = "install")]
+/// The kind of image passed to [`mount_verity_image`].
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub(crate) enum VerityImage<'a> {
+    /// A plain EROFS image, mounted read-only via a loopback device
+    Erofs,
+    /// A composefs image; the objects it references are found in `basedir`,
+    /// and are also required to have fsverity enabled.
+    Composefs {
+        /// The directory holding the content objects
+        basedir: &'a Utf8Path,
+    },
+}
+
+#[cfg(feature = "install")]
+/// The file descriptor number of the verified image in the `mount` process.
+const VERITY_IMAGE_FD: i32 = 3;
+
+#[cfg(feature = "install")]
+/// Verify that `image` has fsverity enabled with the expected digest, and then
+/// mount it read-only at `target`, requiring fsverity for the backing objects
+/// (if applicable). On verification failure, the error contains a [`VerityError`].
+#[allow(dead_code)]
+#[context("Mounting {image} with fsverity")]
+pub(crate) fn mount_verity_image(
+    image: &Utf8Path,
+    kind: VerityImage,
+    expected_digest: &str,
+    target: &Utf8Path,
+) -> Result<()> {
+    let f = fs::File::open(image)?;
+    check_verity_digest(measure_verity(f.as_fd())?, expected_digest)?;
+    // Mount the file we verified, not whatever is at the path by now
+    let source = format!("/proc/self/fd/{VERITY_IMAGE_FD}");
+    let mut task = match kind {
+        VerityImage::Erofs => {
+            Task::new(format!("Mounting {target}"), "mount").args(["-t", "erofs", "-o", "ro,loop"])
+        }
+        VerityImage::Composefs { basedir } => {
+            // The digest option also has the kernel verify the image itself on open
+            let options = format!("basedir={basedir},digest={expected_digest},verity");
+            Task::new(format!("Mounting {target}"), "mount").args([
+                "-t",
+                "composefs",
+                "-o",
+                options.as_str(),
+            ])
+        }
+    }
+    .args([source.as_str(), target.as_str()]);
+    task.cmd
+        .take_fd_n(Arc::new(OwnedFd::from(f)), VERITY_IMAGE_FD);
+    task.run()
+}
+
+/// A mount to set up in a new mount namespace; see [`MountNamespace`].
+#[derive(Debug)]
+enum NamespaceMount {
+    /// Bind mount the directory referenced by the file descriptor
+    BindDir { source: OwnedFd, target: CString },
+    /// Bind mount an absolute path
+    Bind { source: CString, target: CString },
+    /// Mount a fresh tmpfs
+    Tmpfs { target: CString },
+}
+
+fn path_to_cstring(p: &Utf8Path) -> Result<CString> {
+    CString::new(p.as_str()).with_context(|| format!("Invalid path {p}"))
+}
+
+/// A declarative set of mounts to apply in a fresh mount namespace, giving
+/// a child process (or a closure running on a helper thread) a private view
+/// of the filesystem. The mounts go away along with the namespace.
+#[derive(Debug, Default)]
+pub(crate) struct MountNamespace {
+    mounts: Vec<NamespaceMount>,
+}
+
+impl MountNamespace {
+    /// Create an empty set of mounts.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind mount the directory `source` at `target`. This works even if
+    /// `source` is not reachable by path (e.g. it was opened in a different
+    /// mount namespace), but as it relies on the working directory (see
+    /// `enter`), only one such directory is supported; use [`Self::bind`]
+    /// for any others.
+    pub(crate) fn bind_dir(mut self, source: &Dir, target: &Utf8Path) -> Result<Self> {
+        if self
+            .mounts
+            .iter()
+            .any(|m| matches!(m, NamespaceMount::BindDir { .. }))
+        {
+            anyhow::bail!("Only one directory bind mount is supported");
+        }
+        let source = source.try_clone().context("Cloning directory")?.into();
+        let target = path_to_cstring(target)?;
+        self.mounts.push(NamespaceMount::BindDir { source, target });
+        Ok(self)
+    }
+
+    /// Bind mount the absolute path `source` at `target`.
+    #[allow(dead_code)]
+    pub(crate) fn bind(mut self, source: &Utf8Path, target: &Utf8Path) -> Result<Self> {
+        let source = path_to_cstring(source)?;
+        let target = path_to_cstring(target)?;
+        self.mounts.push(NamespaceMount::Bind { source, target });
+        Ok(self)
+    }
+
+    /// Mount a new tmpfs at `target`.
+    #[allow(dead_code)]
+    pub(crate) fn tmpfs(mut self, target: &Utf8Path) -> Result<Self> {
+        let target = path_to_cstring(target)?;
+        self.mounts.push(NamespaceMount::Tmpfs { target });
+        Ok(self)
+    }
+
+    /// Create a new mount namespace for the calling thread and set up our mounts
+    /// in it, in the order they were added. This must be safe to invoke between
+    /// fork and exec, so it must not allocate; all paths are converted up front.
+    fn enter(&self) -> rustix::io::Result<()> {
+        use rustix::fs::{Mode, OFlags};
+        // For reasons I don't understand, we can't just `mount("/proc/self/fd/N", "/path/to/target")`
+        // but it *does* work to fchdir(fd) + mount(".", "/path/to/target").
+        // I think it may be that mount doesn't like operating on the magic links?
+        // This trick only works if we set our working directory to the target *before*
+        // creating the new namespace too.
+        //
+        // I think we may be hitting this:
+        //
+        // "       EINVAL A bind operation (MS_BIND) was requested where source referred a mount namespace magic link (i.e., a /proc/pid/ns/mnt magic link or a bind mount to such a link) and the propagation type of the parent mount of target was
+        // MS_SHARED, but propagation of the requested bind mount could lead to a circular dependency that might prevent the mount namespace from ever being freed."
+        //
+        // But...how did we avoid that circular dependency by using the process cwd?
+        //
+        // I tried making the mounts recursively private, but that didn't help.
+        let oldwd = rustix::fs::open(
+            ".",
+            OFlags::DIRECTORY | OFlags::CLOEXEC | OFlags::RDONLY,
+            Mode::empty(),
+        )?;
+        let bind_dir = self.mounts.iter().find_map(|m| match m {
+            NamespaceMount::BindDir { source, .. } => Some(source),
+            _ => None,
+        });
+        if let Some(source) = bind_dir {
+            rustix::process::fchdir(source)?;
+        }
+        rustix::thread::unshare(rustix::thread::UnshareFlags::NEWNS)?;
+        // The new namespace starts out with copies of our mounts, which are
+        // still in the same (shared) peer groups; any mount made below them,
+        // by us or by the child (e.g. the overlay mounts of podman) would then
+        // also appear in the host namespace.  Making them slaves stops that,
+        // while still receiving mounts from the host.
+        rustix::mount::mount_change(
+            "/",
+            MountPropagationFlags::SLAVE | MountPropagationFlags::REC,
+        )?;
+        for m in self.mounts.iter() {
+            match m {
+                NamespaceMount::BindDir { target, .. } => {
+                    rustix::mount::mount_bind(".", target.as_c_str())?
+                }
+                NamespaceMount::Bind { source, target } => {
+                    rustix::mount::mount_bind(source.as_c_str(), target.as_c_str())?
+                }
+                NamespaceMount::Tmpfs { target } => rustix::mount::mount2(
+                    Some("tmpfs"),
+                    target.as_c_str(),
+                    Some("tmpfs"),
+                    MountFlags::empty(),
+                    None,
+                )?,
+            }
+        }
+        rustix::process::fchdir(&oldwd)?;
+        Ok(())
+    }
+
+    /// Arrange for the command to run in a new mount namespace with our mounts.
+    #[allow(unsafe_code)]
+    pub(crate) fn apply_to(self, cmd: &mut Command) {
+        // SAFETY: All the APIs we call in enter() are safe to invoke between fork and exec.
+        unsafe {
+            cmd.pre_exec(move || Ok(self.enter()?));
+        }
+    }
+
+    /// Run the closure on a helper thread which has its own mount namespace with
+    /// our mounts; the namespace is torn down when the thread exits.
+    #[allow(dead_code)]
+    pub(crate) fn run<T: Send>(self, f: impl FnOnce() -> Result<T> + Send) -> Result<T> {
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                // Ensure we don't change the working directory of the whole process
+                rustix::thread::unshare(rustix::thread::UnshareFlags::FS)
+                    .context("unshare(CLONE_FS)")?;
+                self.enter().context("Entering mount namespace")?;
+                f()
+            })
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "install")]
+    fn test_parse_mountinfo() -> Result<()> {
+        let buf = indoc::indoc! { r#"
+            22 1 253:0 / / rw,relatime shared:1 - xfs /dev/mapper/root rw,seclabel,attr2,inode64,noquota
+            23 22 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+            98 22 0:45 /root /sysroot ro,relatime shared:2 - btrfs /dev/vda3 rw,seclabel,compress=zstd:1,subvol=/root
+            99 22 8:1 / /mnt/with\040space rw master:3 - vfat /dev/sda1 rw
+        "# };
+        let fss = parse_mountinfo(buf)?;
+        assert_eq!(fss.len(), 4);
+        let root = &fss[0];
+        assert_eq!(root.target, "/");
+        assert_eq!(root.source, "/dev/mapper/root");
+        assert_eq!(root.maj_min, "253:0");
+        assert_eq!(root.fstype, "xfs");
+        assert_eq!(root.options, "rw,relatime,seclabel,attr2,inode64,noquota");
+        let sysroot = &fss[2];
+        assert_eq!(
+            sysroot.options,
+            "ro,relatime,seclabel,compress=zstd:1,subvol=/root"
+        );
+        assert_eq!(fss[3].target, "/mnt/with space");
+        assert!(parse_mountinfo("22 1 253:0 / / rw").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "install")]
+    fn test_mounts_under() -> Result<()> {
+        let buf = indoc::indoc! { r#"
+            22 1 253:0 / / rw,relatime shared:1 - xfs /dev/mapper/root rw
+            40 22 252:3 / /target rw,relatime - xfs /dev/vdb3 rw
+            41 40 252:2 / /target/boot rw,relatime - ext4 /dev/vdb2 rw
+            42 22 0:50 / /target2 rw,relatime - tmpfs tmpfs rw
+            43 41 252:1 / /target/boot/efi rw,relatime - vfat /dev/vdb1 rw
+        "# };
+        let fss = parse_mountinfo(buf)?;
+        let under = filter_mounts_under(fss.clone(), "/target".into());
+        let targets = under
+            .iter()
+            .map(|fs| fs.target.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(targets, ["/target", "/target/boot", "/target/boot/efi"]);
+        let under = filter_mounts_under(fss.clone(), "/target/boot/efi".into());
+        assert_eq!(under.len(), 1);
+        assert!(filter_mounts_under(fss, "/nonexistent".into()).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "install")]
+    fn test_btrfs() -> Result<()> {
+        let buf = indoc::indoc! { r#"
+            ID 256 gen 35 top level 5 path home
+            ID 257 gen 40 top level 5 path root
+            ID 258 gen 12 top level 257 path root/var/lib/with space
+        "# };
+        let subvols = parse_btrfs_subvolume_list(buf)?;
+        assert_eq!(subvols.len(), 3);
+        assert_eq!(
+            subvols[0],
+            BtrfsSubvolume {
+                id: 256,
+                path: "home".into()
+            }
+        );
+        assert_eq!(subvols[2].path, "root/var/lib/with space");
+        assert!(parse_btrfs_subvolume_list("ID x gen 1 top level 5 path foo").is_err());
+        assert!(parse_btrfs_subvolume_list("ID 256 gen 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_options() {
+        const V1: &str = "rw,relatime,compress=foo,subvol=blah,fast";
+        let opts = MountOptions::parse(V1);
+        assert_eq!(opts.get("subvol").unwrap(), "blah");
+        assert_eq!(opts.get("rw"), None);
+        assert_eq!(opts.get("somethingelse"), None);
+        assert!(opts.has_flag("fast"));
+        assert!(!opts.has_flag("subvol"));
+        assert!(!opts.is_readonly());
+        assert_eq!(opts.compress(), Some("foo"));
+        assert_eq!(opts.to_string(), V1);
+
+        let opts = MountOptions::parse(
+            "ro,relatime,seclabel,compress-force=zstd:1,subvolid=257,subvol=/root",
+        );
+        assert!(opts.is_readonly());
+        assert!(opts.has_seclabel());
+        assert_eq!(opts.compress(), Some("zstd:1"));
+        assert_eq!(opts.subvol(), Some("/root"));
+        assert_eq!(opts.subvolid(), Some(257));
+
+        let opts = MountOptions::parse("");
+        assert_eq!(opts, MountOptions::default());
+        assert!(!opts.is_readonly());
+        assert_eq!(opts.subvolid(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "install")]
+    fn test_source_tag() {
+        assert_eq!(SourceTag::Uuid("abc").to_string(), "UUID=abc");
+        assert_eq!(SourceTag::Label("boot").to_string(), "LABEL=boot");
+        let tag = SourceTag::Label("EFI System");
+        assert_eq!(tag.to_string(), "LABEL=EFI System");
+        assert_eq!(tag.device_link(), r"/dev/disk/by-label/EFI\x20System");
+        let tag = SourceTag::PartLabel("EFI System Partition");
+        assert_eq!(tag.to_string(), "PARTLABEL=EFI System Partition");
+        assert_eq!(
+            tag.device_link(),
+            r"/dev/disk/by-partlabel/EFI\x20System\x20Partition"
+        );
+        for v in ["EFI-SYSTEM", "a/b c\\d", "ünïcode", r"\x"] {
+            assert_eq!(udev_decode(&udev_encode(v)), v);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "install")]
+    fn test_crypt_info() -> Result<()> {
+        assert_eq!(
+            luks_uuid_of_dm_uuid("CRYPT-LUKS2-965eb3c75a3f470daaa21bcf04334bc6-root").as_deref(),
+            Some("965eb3c7-5a3f-470d-aaa2-1bcf04334bc6")
+        );
+        assert_eq!(luks_uuid_of_dm_uuid("CRYPT-PLAIN-root"), None);
+        assert_eq!(luks_uuid_of_dm_uuid("LVM-abcd"), None);
+
+        let td =
+            cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+        // Not a device mapper device
+        td.create_dir_all("dev/block/252:4")?;
+        assert_eq!(crypt_info_in(&td, "252:4")?, None);
+        assert_eq!(crypt_info_in(&td, "252:5")?, None);
+        // An LVM volume
+        td.create_dir_all("dev/block/253:1/dm")?;
+        td.write("dev/block/253:1/dm/uuid", "LVM-abcd\n")?;
+        assert_eq!(crypt_info_in(&td, "253:1")?, None);
+        // A LUKS device
+        td.create_dir_all("dev/block/253:0/dm")?;
+        td.create_dir_all("dev/block/253:0/slaves/vda3")?;
+        td.write(
+            "dev/block/253:0/dm/uuid",
+            "CRYPT-LUKS2-965eb3c75a3f470daaa21bcf04334bc6-root\n",
+        )?;
+        td.write("dev/block/253:0/dm/name", "root\n")?;
+        assert_eq!(
+            crypt_info_in(&td, "253:0")?.unwrap(),
+            CryptInfo {
+                name: "root".into(),
+                luks_uuid: Some("965eb3c7-5a3f-470d-aaa2-1bcf04334bc6".into()),
+                backing_device: Some("/dev/vda3".into()),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_systemd_escape_path() {
+        let cases = [
+            ("/", "-"),
+            ("/var/lib/bootc/storage", "var-lib-bootc-storage"),
+            ("//var/./lib/", "var-lib"),
+            ("/var/lib/foo-bar", "var-lib-foo\\x2dbar"),
+            ("/.hidden/a b", "\\x2ehidden-a\\x20b"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(systemd_escape_path(path.into()), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_generate_bind_mount_units() -> Result<()> {
+        let unit_dir =
+            &cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+        let a = BindMountUnit {
+            what: "/sysroot/a".into(),
+            target: "/var/lib/a".into(),
+            readonly: true,
+            automount: false,
+        };
+        let b = BindMountUnit {
+            what: "/sysroot/b".into(),
+            target: "/var/lib/b".into(),
+            readonly: false,
+            automount: true,
+        };
+        generate_bind_mount_units(unit_dir, &[a, b])?;
+        let contents = unit_dir.read_to_string("var-lib-a.mount")?;
+        assert!(contents.starts_with(GENERATED_UNIT_STAMP));
+        assert!(contents.contains("What=/sysroot/a\n"));
+        assert!(contents.contains("Options=bind,ro\n"));
+        assert!(unit_dir.try_exists("local-fs.target.wants/var-lib-a.mount")?);
+        assert!(unit_dir.try_exists("var-lib-b.mount")?);
+        assert!(unit_dir.try_exists("var-lib-b.automount")?);
+        assert!(unit_dir.try_exists("local-fs.target.wants/var-lib-b.automount")?);
+        assert!(!unit_dir.try_exists("local-fs.target.wants/var-lib-b.mount")?);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "install")]
+    fn test_verity() -> Result<()> {
+        let expected = "ab".repeat(32);
+        assert_eq!(
+            check_verity_digest(None, &expected),
+            Err(VerityError::NotEnabled)
+        );
+        assert_eq!(
+            check_verity_digest(Some(expected.to_uppercase()), &expected),
+            Ok(())
+        );
+        let found = "cd".repeat(32);
+        assert_eq!(
+            check_verity_digest(Some(found.clone()), &expected),
+            Err(VerityError::DigestMismatch { expected, found })
+        );
+        // A freshly created file can't have fsverity enabled
+        let f = tempfile::tempfile()?;
+        assert_eq!(measure_verity(f.as_fd())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_namespace_single_bind_dir() -> Result<()> {
+        let td =
+            cap_std_ext::cap_tempfile::TempDir::new(cap_std_ext::cap_std::ambient_authority())?;
+        let ns = MountNamespace::new()
+            .bind_dir(&td, "/a".into())?
+            .tmpfs("/b".into())?
+            .bind("/c".into(), "/d".into())?;
+        assert_eq!(ns.mounts.len(), 3);
+        assert!(ns.bind_dir(&td, "/e".into()).is_err());
+        assert!(MountNamespace::new().tmpfs("/x\0y".into()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "install")]
+    fn test_unescape_mountinfo() {
+        assert_eq!(unescape_mountinfo("/foo"), "/foo");
+        assert_eq!(unescape_mountinfo(r"/a\040b\011c\134d"), "/a b\tc\\d");
+        // Invalid escapes are passed through
+        assert_eq!(unescape_mountinfo(r"/a\9"), r"/a\9");
+    }
 }
@@ -0,0 +1,218 @@
+//! # Update notifications
+//!
+//! When an update is staged, or an update check found one which is not yet
+//! staged, a snippet describing it is written to `/run/motd.d` so it is shown
+//! on login, and a structured journal message is logged.  The snippet is
+//! refreshed after each `bootc upgrade`, `switch`, `edit`, `rollback` and
+//! `reset`, and on boot by `bootc-boot-complete.service`; as it is in `/run`,
+//! it is cleared once the update is activated.
+
+use std::fmt::Write as _;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::spec::{Host, ImageStatus};
+
+/// The message of the day snippet.
+const MOTD_PATH: &str = "run/motd.d/bootc-update.motd";
+/// Journal message ID for a staged update.
+const UPDATE_STAGED_JOURNAL_ID: &str = "edf4e27893ad4904865ab5ae5d0a3439";
+/// Journal message ID for an available (not yet staged) update.
+const UPDATE_AVAILABLE_JOURNAL_ID: &str = "be580ede1fa5462fb81591dcca4a59b3";
+
+/// An update which has not yet been activated.
+#[derive(Debug, PartialEq, Eq)]
+enum PendingUpdate<'a> {
+    /// The update is staged, and will be applied on the next reboot.
+    Staged(&'a ImageStatus),
+    /// An update check found this update, but it is not yet staged.
+    Available(&'a ImageStatus),
+}
+
+impl PendingUpdate<'_> {
+    fn image(&self) -> &ImageStatus {
+        match self {
+            Self::Staged(i) | Self::Available(i) => i,
+        }
+    }
+
+    fn journal_id(&self) -> &'static str {
+        match self {
+            Self::Staged(_) => UPDATE_STAGED_JOURNAL_ID,
+            Self::Available(_) => UPDATE_AVAILABLE_JOURNAL_ID,
+        }
+    }
+
+    /// A one-line description of the update.
+    fn summary(&self) -> String {
+        let image = self.image();
+        let version = image
+            .version
+            .as_deref()
+            .map(|v| format!(" version {v}"))
+            .unwrap_or_default();
+        match self {
+            Self::Staged(_) => format!(
+                "Update to {}{version} is staged; reboot to apply it",
+                image.image.image
+            ),
+            Self::Available(_) => format!(
+                "Update to {}{version} is available; run `bootc upgrade` to stage it",
+                image.image.image
+            ),
+        }
+    }
+}
+
+/// Find the pending update of the host, if any.
+fn pending_update(host: &Host) -> Option<PendingUpdate<'_>> {
+    let status = &host.status;
+    let booted = status.booted.as_ref()?;
+    let booted_digest = booted.image.as_ref().map(|i| i.image_digest.as_str());
+    if let Some(staged) = status.staged.as_ref().and_then(|s| s.image.as_ref()) {
+        return Some(PendingUpdate::Staged(staged));
+    }
+    booted
+        .cached_update
+        .as_ref()
+        .filter(|u| Some(u.image_digest.as_str()) != booted_digest)
+        .map(PendingUpdate::Available)
+}
+
+/// Render the message of the day snippet for a pending update.
+fn render_motd(update: &PendingUpdate, host: &Host) -> String {
+    let image = update.image();
+    let mut out = String::new();
+    // SAFETY: Writing to a String cannot fail
+    writeln!(out, "{}", update.summary()).unwrap();
+    if let Some(timestamp) = image.timestamp {
+        writeln!(
+            out,
+            "  Built: {}",
+            timestamp.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+        .unwrap();
+    }
+    writeln!(out, "  Digest: {}", image.image_digest).unwrap();
+    if let Some(t) = host.status.planned_reboot {
+        writeln!(
+            out,
+            "  A reboot is scheduled at {}",
+            t.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Write (or remove) the message of the day snippet; returns the pending
+/// update if the snippet changed.
+fn update_motd<'a>(root: &Dir, host: &'a Host) -> Result<Option<PendingUpdate<'a>>> {
+    let Some(update) = pending_update(host) else {
+        root.remove_file_optional(MOTD_PATH)?;
+        return Ok(None);
+    };
+    let motd = render_motd(&update, host);
+    if root.read_to_string_optional(MOTD_PATH)?.as_deref() == Some(motd.as_str()) {
+        return Ok(None);
+    }
+    root.create_dir_all(MOTD_PATH.rsplit_once('/').unwrap().0)?;
+    root.atomic_write(MOTD_PATH, motd.as_bytes())
+        .with_context(|| format!("Writing {MOTD_PATH}"))?;
+    Ok(Some(update))
+}
+
+/// Refresh the notifications for the pending update of the host, logging a
+/// journal message if it changed.
+#[context("Updating notifications")]
+pub(crate) fn refresh(root: &Dir, host: &Host) -> Result<()> {
+    let Some(update) = update_motd(root, host)? else {
+        return Ok(());
+    };
+    let image = update.image();
+    let mut vars = vec![
+        ("MESSAGE_ID", update.journal_id().to_owned()),
+        ("BOOTC_IMAGE", image.image.image.clone()),
+        ("BOOTC_IMAGE_DIGEST", image.image_digest.clone()),
+        (
+            "BOOTC_REBOOT_REQUIRED",
+            matches!(update, PendingUpdate::Staged(_)).to_string(),
+        ),
+    ];
+    if let Some(version) = image.version.as_deref() {
+        vars.push(("BOOTC_IMAGE_VERSION", version.to_owned()));
+    }
+    crate::journal::journal_send(
+        libsystemd::logging::Priority::Notice,
+        &update.summary(),
+        vars.into_iter(),
+    );
+    Ok(())
+}
+
+/// Refresh the notifications after an update; failures are only logged, as
+/// they should not affect the update itself.
+pub(crate) async fn refresh_after_update() {
+    let r = async {
+        let host = crate::status::get_host(1).await?;
+        let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        refresh(root, &host)
+    };
+    if let Err(e) = r.await {
+        tracing::warn!("{e:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_update() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+
+        let host: Host = Default::default();
+        assert_eq!(pending_update(&host), None);
+
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml"))?;
+        let staged = host.status.staged.as_ref().unwrap().image.as_ref().unwrap();
+        let update = pending_update(&host).unwrap();
+        assert_eq!(update, PendingUpdate::Staged(staged));
+        let expected = indoc::indoc! { "
+            Update to quay.io/example/someimage:latest version nightly is staged; reboot to apply it
+              Built: 2023-10-14 19:22:15 UTC
+              Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566
+        " };
+        similar_asserts::assert_eq!(render_motd(&update, &host), expected);
+
+        // The journal message is only sent when the snippet changes
+        assert!(update_motd(td, &host)?.is_some());
+        assert_eq!(td.read_to_string(MOTD_PATH)?, expected);
+        assert!(update_motd(td, &host)?.is_none());
+
+        // Once the update is activated, the snippet is removed
+        let staged = host.status.staged.take().unwrap();
+        host.status.booted = Some(staged);
+        assert_eq!(pending_update(&host), None);
+        assert!(update_motd(td, &host)?.is_none());
+        assert!(!td.try_exists(MOTD_PATH)?);
+
+        // An update found by a check, but not yet staged
+        let mut available = host.status.booted.as_ref().unwrap().image.clone().unwrap();
+        available.image_digest = "sha256:abc".into();
+        available.version = None;
+        host.status.booted.as_mut().unwrap().cached_update = Some(available);
+        let update = pending_update(&host).unwrap();
+        assert!(matches!(update, PendingUpdate::Available(_)));
+        assert_eq!(
+            update.summary(),
+            "Update to quay.io/example/someimage:latest is available; run `bootc upgrade` to stage it"
+        );
+        Ok(())
+    }
+}
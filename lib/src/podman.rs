@@ -26,6 +26,23 @@ pub(crate) struct ImageListEntry {
     pub(crate) names: Option<Vec<String>>,
 }
 
+/// This is (a subset of) the output from `podman image inspect`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ImageInspect {
+    pub(crate) digest: String,
+    #[serde(rename = "RootFS")]
+    pub(crate) root_fs: ImageRootFs,
+}
+
+/// The layers of an image, identified by their uncompressed digests (diffids).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ImageRootFs {
+    #[serde(default)]
+    pub(crate) layers: Vec<String>,
+}
+
 /// Given an image ID, return its manifest digest
 #[cfg(feature = "install")]
 pub(crate) fn imageid_to_digest(imgid: &str) -> Result<String> {
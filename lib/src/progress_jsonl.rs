@@ -0,0 +1,174 @@
+//! Output progress data using the json-lines format. For more information
+//! see <https://jsonlines.org/>.
+//!
+//! This is intended to be consumed by graphical frontends (e.g. installers),
+//! which would otherwise need to scrape the human readable output.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use serde::Serialize;
+
+/// The version of the progress protocol; emitted in the initial `Start` event.
+pub(crate) const API_VERSION: &str = "org.containers.bootc.progress/v1";
+
+/// An event emitted as JSON.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub(crate) enum Event<'t> {
+    /// The first event, which declares the protocol version.
+    #[serde(rename_all = "camelCase")]
+    Start {
+        /// The protocol version.
+        version: &'t str,
+    },
+    /// Progress of a task measured in bytes, such as fetching a layer.
+    #[serde(rename_all = "camelCase")]
+    ProgressBytes {
+        /// A machine readable name for the task, e.g. `pulling`.
+        task: &'t str,
+        /// A human readable description of the task.
+        description: Cow<'t, str>,
+        /// A unique identifier for the current subject of the task, e.g. a layer digest.
+        id: Cow<'t, str>,
+        /// The number of bytes processed for the current subject.
+        bytes: u64,
+        /// The total number of bytes for the current subject.
+        bytes_total: u64,
+        /// The number of completed steps (e.g. layers).
+        steps: u64,
+        /// The total number of steps.
+        steps_total: u64,
+    },
+    /// Progress of a task measured in discrete steps, such as deployment.
+    #[serde(rename_all = "camelCase")]
+    ProgressSteps {
+        /// A machine readable name for the task, e.g. `staging`.
+        task: &'t str,
+        /// A human readable description of the current step.
+        description: Cow<'t, str>,
+        /// A unique identifier for the subject of the task.
+        id: Cow<'t, str>,
+        /// The number of completed steps.
+        steps: u64,
+        /// The total number of steps.
+        steps_total: u64,
+    },
+}
+
+/// A handle for writing progress events; cloning it shares the same output.
+/// By default, it is disabled and events are discarded.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProgressWriter {
+    target: Option<Arc<Mutex<BufWriter<File>>>>,
+}
+
+impl ProgressWriter {
+    /// Take ownership of the provided file descriptor, and emit the initial
+    /// `Start` event to it.
+    #[context("Initializing progress fd {fd}")]
+    #[allow(unsafe_code)]
+    pub(crate) fn from_raw_fd(fd: RawFd) -> Result<Self> {
+        // Reject the standard streams, and negative values which are not valid
+        // for BorrowedFd.
+        if fd <= libc::STDERR_FILENO {
+            anyhow::bail!("Invalid file descriptor");
+        }
+        // Verify that the file descriptor is open before taking ownership of it.
+        // SAFETY: We checked above that the value is not negative, and we only
+        // use the borrowed value for the duration of this call.
+        rustix::io::fcntl_getfd(unsafe { BorrowedFd::borrow_raw(fd) })?;
+        // SAFETY: The descriptor is open, and the caller passed it to us to own.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        rustix::io::fcntl_setfd(&fd, rustix::io::FdFlags::CLOEXEC)?;
+        let r = Self {
+            target: Some(Arc::new(Mutex::new(BufWriter::new(File::from(fd))))),
+        };
+        r.try_send(&Event::Start {
+            version: API_VERSION,
+        })?;
+        Ok(r)
+    }
+
+    /// Create a writer from an optional file descriptor, as passed via e.g. `--progress-fd`.
+    pub(crate) fn from_opt_fd(fd: Option<RawFd>) -> Result<Self> {
+        fd.map(Self::from_raw_fd)
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Returns true if progress events will be written.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.target.is_some()
+    }
+
+    fn try_send(&self, event: &Event) -> Result<()> {
+        let Some(target) = self.target.as_ref() else {
+            return Ok(());
+        };
+        // A poisoned lock can only occur if another thread panicked while writing,
+        // in which case the stream is likely corrupted anyways.
+        let mut target = target
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Progress writer poisoned"))?;
+        serde_json::to_writer(&mut *target, event)?;
+        target.write_all(b"\n")?;
+        target.flush().context("Writing progress")?;
+        Ok(())
+    }
+
+    /// Emit an event.  Progress is best-effort; write errors are logged,
+    /// but do not abort the operation in progress.
+    pub(crate) fn send(&self, event: Event) {
+        if let Err(e) = self.try_send(&event) {
+            tracing::warn!("Failed to write progress: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn test_progress_writer() -> Result<()> {
+        let (r, w) = UnixStream::pair()?;
+        let writer = ProgressWriter::from_raw_fd(w.into_raw_fd())?;
+        assert!(writer.is_enabled());
+        writer.send(Event::ProgressSteps {
+            task: "staging",
+            description: "Deploying".into(),
+            id: "quay.io/example/os:latest".into(),
+            steps: 0,
+            steps_total: 3,
+        });
+        drop(writer);
+        let lines = BufReader::new(r)
+            .lines()
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(
+            lines,
+            [
+                r#"{"type":"Start","version":"org.containers.bootc.progress/v1"}"#,
+                r#"{"type":"ProgressSteps","task":"staging","description":"Deploying","id":"quay.io/example/os:latest","steps":0,"stepsTotal":3}"#
+            ]
+        );
+
+        assert!(ProgressWriter::from_raw_fd(1).is_err());
+        let disabled = ProgressWriter::from_opt_fd(None)?;
+        assert!(!disabled.is_enabled());
+        disabled.send(Event::Start {
+            version: API_VERSION,
+        });
+        Ok(())
+    }
+}
@@ -2,10 +2,25 @@
 
 use std::io::Write;
 
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use fn_error_context::context;
+use ostree_ext::ostree;
+use ostree_ext::ostree::gio;
 
+use crate::store::Storage;
 use crate::task::Task;
 
+/// How to restart into a staged deployment without going through the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FastRestart {
+    /// The kernel, initramfs and kernel arguments are unchanged, so only
+    /// userspace needs to be restarted.
+    SoftReboot,
+    /// Directly load the new kernel.
+    Kexec,
+}
+
 /// Initiate a system reboot.
 /// This function will only return in case of error.
 #[context("Initiating reboot")]
@@ -19,3 +34,135 @@ pub(crate) fn reboot() -> anyhow::Result<()> {
         std::thread::park();
     }
 }
+
+/// Restart into the staged deployment, via [`soft_reboot`] if requested
+/// and otherwise via a regular [`reboot`].
+/// This function will only return in case of error.
+pub(crate) fn apply(sysroot: &Storage, soft: bool) -> Result<()> {
+    if soft {
+        soft_reboot(sysroot)
+    } else {
+        reboot()
+    }
+}
+
+/// The kernel arguments of a deployment, except for `ostree=` which always
+/// differs between deployments.
+fn comparable_kargs(deployment: &ostree::Deployment) -> Vec<String> {
+    crate::kargs::deployment_kargs(deployment)
+        .into_iter()
+        .filter(|karg| !karg.starts_with("ostree="))
+        .collect()
+}
+
+fn fast_restart_for(booted: &ostree::Deployment, staged: &ostree::Deployment) -> FastRestart {
+    if booted.bootcsum() == staged.bootcsum()
+        && comparable_kargs(booted) == comparable_kargs(staged)
+    {
+        FastRestart::SoftReboot
+    } else {
+        FastRestart::Kexec
+    }
+}
+
+/// Resolve a kernel or initramfs path from a bootloader entry; these are
+/// relative to the boot filesystem, optionally with a `/boot` prefix.
+fn boot_path(path: &str) -> Utf8PathBuf {
+    let path = path.trim_start_matches('/');
+    let path = path.strip_prefix("boot/").unwrap_or(path);
+    Utf8Path::new("/boot").join(path)
+}
+
+/// Restart into the staged deployment without a firmware reboot: if the
+/// kernel, initramfs and kernel arguments are unchanged, only userspace is
+/// restarted via `systemctl soft-reboot`; otherwise the new kernel is
+/// booted directly via kexec.  The sysroot lock is released.
+/// This function will only return in case of error.
+#[context("Activating staged deployment")]
+pub(crate) fn soft_reboot(sysroot: &Storage) -> Result<()> {
+    let booted = sysroot.require_booted_deployment()?;
+    let staged = sysroot
+        .staged_deployment()
+        .ok_or_else(|| anyhow!("No staged deployment"))?;
+    let mode = fast_restart_for(&booted, &staged);
+    tracing::debug!("Activating staged deployment via {mode:?}");
+
+    // This is normally done by ostree-finalize-staged.service on shutdown; we
+    // need the deployment (and its bootloader entry) to be written out first.
+    // It takes the sysroot lock itself, so release ours; we only read the
+    // sysroot from here on, and never return on success.
+    sysroot.unlock();
+    Task::new("Finalizing staged deployment", "ostree")
+        .args(["admin", "finalize-staged"])
+        .run()?;
+    sysroot.load(gio::Cancellable::NONE)?;
+    // The finalized deployment is now the default one.
+    let target = sysroot
+        .deployments()
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No deployments found after finalization"))?;
+
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+    match mode {
+        FastRestart::SoftReboot => {
+            Task::new("Preparing soft reboot", "ostree")
+                .args(["admin", "prepare-soft-reboot", "0"])
+                .run()?;
+            Task::new("Soft rebooting system", "systemctl")
+                .arg("soft-reboot")
+                .run()?;
+        }
+        FastRestart::Kexec => {
+            let bootconfig = target
+                .bootconfig()
+                .ok_or_else(|| anyhow!("Missing bootloader entry for new deployment"))?;
+            let get = |k: &str| {
+                bootconfig
+                    .get(k)
+                    .ok_or_else(|| anyhow!("Missing {k} in bootloader entry"))
+            };
+            let linux = boot_path(&get("linux")?);
+            let initrd = boot_path(&get("initrd")?);
+            let options = get("options")?;
+            Task::new("Loading new kernel", "kexec")
+                .args([
+                    "--load".to_owned(),
+                    linux.into_string(),
+                    format!("--initrd={initrd}"),
+                    format!("--command-line={options}"),
+                ])
+                .run()?;
+            Task::new("Rebooting system via kexec", "systemctl")
+                .arg("kexec")
+                .run()?;
+        }
+    }
+    tracing::debug!("Initiated restart, sleeping forever...");
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_path() {
+        let cases = [
+            (
+                "/ostree/default-abc/vmlinuz-6.11.0",
+                "/boot/ostree/default-abc/vmlinuz-6.11.0",
+            ),
+            (
+                "/boot/ostree/default-abc/initramfs-6.11.0.img",
+                "/boot/ostree/default-abc/initramfs-6.11.0.img",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(boot_path(input), expected);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! # SELinux relabeling
+//!
+//! `bootc internals relabel` resets the SELinux labels of the mutable state
+//! of a deployment, i.e. its `/etc` and the `/var` of its stateroot, to the
+//! labels defined by the policy shipped in the deployment's image.  This
+//! repairs label drift, e.g. after content was restored from a backup.
+//!
+//! Like `restorecon` without `-F`, files whose current type is listed in the
+//! policy's `contexts/customizable_types` (e.g. `container_file_t`) keep their
+//! label, as it was set on purpose rather than by the default rules.
+//!
+//! `/usr` is not touched: it is part of the image, and its files are
+//! hardlinks to objects in the ostree repository, whose labels are part of
+//! their checksums.
+
+use std::os::fd::AsRawFd;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::gio;
+use ostree_ext::ostree;
+use ostree_ext::sysroot::LockMode;
+
+/// Options for `bootc internals relabel`.
+#[derive(Debug, clap::Parser, PartialEq, Eq)]
+pub(crate) struct RelabelOpts {
+    /// The deployment to relabel, as `<checksum>.<serial>` (or just the checksum, if
+    /// unique); defaults to the booted deployment.
+    #[clap(long)]
+    pub(crate) deployment: Option<String>,
+
+    /// Print the files whose label would change, without changing them.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+}
+
+/// The policy and the types whose labels are kept.
+struct Policy {
+    /// The SELinux policy of the deployment
+    sepolicy: ostree::SePolicy,
+    /// The customizable types of the policy
+    customizable: Vec<String>,
+}
+
+/// Statistics of a relabeling pass.
+#[derive(Debug, Default)]
+struct Stats {
+    /// The number of files checked
+    checked: u64,
+    /// The number of files whose label was (or would be) changed
+    relabeled: u64,
+}
+
+/// The identifier of a deployment, as used for its directory name.
+fn deployment_id(deployment: &ostree::Deployment) -> String {
+    format!("{}.{}", deployment.csum(), deployment.deployserial())
+}
+
+/// Find the index of the requested deployment, given the deployment identifiers.
+fn select_deployment(ids: &[String], requested: &str) -> Result<usize> {
+    let mut matches = ids.iter().enumerate().filter(|(_, id)| {
+        id.as_str() == requested
+            || id
+                .rsplit_once('.')
+                .is_some_and(|(csum, _)| csum == requested)
+    });
+    let Some((i, _)) = matches.next() else {
+        anyhow::bail!("No deployment found matching {requested}");
+    };
+    if matches.next().is_some() {
+        anyhow::bail!("Multiple deployments match {requested}; specify <checksum>.<serial>");
+    }
+    Ok(i)
+}
+
+/// Whether the current label (an xattr value, which may be NUL terminated) is the expected one.
+fn label_matches(current: Option<&[u8]>, expected: &str) -> bool {
+    current.is_some_and(|l| l.strip_suffix(b"\0").unwrap_or(l) == expected.as_bytes())
+}
+
+/// Parse the contents of a `customizable_types` file.
+fn parse_customizable_types(buf: &str) -> Vec<String> {
+    buf.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Read the customizable types of the named policy in the provided root.
+#[context("Reading customizable types")]
+fn read_customizable_types(root: &Dir, name: &str) -> Result<Vec<String>> {
+    let path = format!("etc/selinux/{name}/contexts/customizable_types");
+    let Some(buf) = root.read_to_string_optional(&path)? else {
+        return Ok(Vec::new());
+    };
+    Ok(parse_customizable_types(&buf))
+}
+
+/// The type of a label, i.e. its third field.
+fn label_type(label: &[u8]) -> Option<&[u8]> {
+    label
+        .strip_suffix(b"\0")
+        .unwrap_or(label)
+        .split(|&c| c == b':')
+        .nth(2)
+}
+
+/// Reset the label of a file to the one defined by the policy.
+fn relabel_one(
+    dir: &Dir,
+    name: &Utf8Path,
+    abspath: &Utf8Path,
+    mode: u32,
+    policy: &Policy,
+    dry_run: bool,
+    stats: &mut Stats,
+) -> Result<()> {
+    stats.checked += 1;
+    let Some(expected) = policy
+        .sepolicy
+        .label(abspath.as_str(), mode, gio::Cancellable::NONE)?
+    else {
+        tracing::trace!("No label for {abspath}");
+        return Ok(());
+    };
+    let current = crate::lsm::get_security_selinux_path(dir, name)?;
+    if label_matches(current.as_deref(), &expected) {
+        return Ok(());
+    }
+    if let Some(ty) = current.as_deref().and_then(label_type) {
+        if policy.customizable.iter().any(|c| c.as_bytes() == ty) {
+            tracing::debug!("Keeping customizable label of {abspath}");
+            return Ok(());
+        }
+    }
+    stats.relabeled += 1;
+    let current = current
+        .as_deref()
+        .map(|l| String::from_utf8_lossy(l.strip_suffix(b"\0").unwrap_or(l)).into_owned())
+        .unwrap_or_else(|| "(unlabeled)".into());
+    if dry_run {
+        println!("Would relabel {abspath} from {current} to {expected}");
+    } else {
+        println!("Relabeling {abspath} from {current} to {expected}");
+        crate::lsm::set_security_selinux_path(dir, name, expected.as_bytes())
+            .with_context(|| format!("Relabeling {abspath}"))?;
+    }
+    Ok(())
+}
+
+/// Relabel the contents of a directory, not crossing into other filesystems.
+fn relabel_dir(
+    dir: &Dir,
+    abspath: &mut Utf8PathBuf,
+    policy: &Policy,
+    dry_run: bool,
+    stats: &mut Stats,
+) -> Result<()> {
+    let dev = dir.dir_metadata()?.dev();
+    for ent in dir.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF-8 filename: {name:?} in {abspath}"))?;
+        let metadata = ent.metadata()?;
+        abspath.push(name);
+        if metadata.dev() != dev {
+            tracing::debug!("Skipping mount point {abspath}");
+        } else {
+            relabel_one(
+                dir,
+                Utf8Path::new(name),
+                abspath,
+                metadata.mode(),
+                policy,
+                dry_run,
+                stats,
+            )?;
+            if metadata.is_dir() {
+                let child = dir.open_dir(name)?;
+                relabel_dir(&child, abspath, policy, dry_run, stats)?;
+            }
+        }
+        abspath.pop();
+    }
+    Ok(())
+}
+
+/// Relabel a directory tree, which is mounted at `abspath` in the deployment.
+#[context("Relabeling {abspath}")]
+fn relabel_tree(
+    parent: &Dir,
+    name: &str,
+    abspath: &str,
+    policy: &Policy,
+    dry_run: bool,
+    stats: &mut Stats,
+) -> Result<()> {
+    let metadata = parent.symlink_metadata(name)?;
+    relabel_one(
+        parent,
+        Utf8Path::new(name),
+        Utf8Path::new(abspath),
+        metadata.mode(),
+        policy,
+        dry_run,
+        stats,
+    )?;
+    let dir = parent.open_dir(name)?;
+    relabel_dir(
+        &dir,
+        &mut Utf8PathBuf::from(abspath),
+        policy,
+        dry_run,
+        stats,
+    )
+}
+
+/// Implementation of `bootc internals relabel`.
+#[context("Relabeling deployment")]
+pub(crate) async fn relabel(opts: RelabelOpts) -> Result<()> {
+    let lock = if opts.dry_run {
+        LockMode::Shared
+    } else {
+        LockMode::Exclusive
+    };
+    let sysroot = &crate::cli::get_storage(lock).await?;
+    let booted = sysroot.booted_deployment();
+    let deployments = sysroot.deployments();
+    let deployment = if let Some(requested) = opts.deployment.as_deref() {
+        let ids = deployments.iter().map(deployment_id).collect::<Vec<_>>();
+        &deployments[select_deployment(&ids, requested)?]
+    } else {
+        booted
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not booted into an ostree deployment"))?
+    };
+    let is_booted = booted.as_ref().is_some_and(|b| b.equal(deployment));
+
+    let deployment_root = &crate::utils::deployment_fd(sysroot, deployment)?;
+    if !crate::lsm::have_selinux_policy(deployment_root)? {
+        anyhow::bail!(
+            "No SELinux policy in deployment {}",
+            deployment_id(deployment)
+        );
+    }
+    let policy = ostree::SePolicy::new_at(deployment_root.as_raw_fd(), gio::Cancellable::NONE)
+        .context("Loading SELinux policy of the deployment")?;
+    let name = policy
+        .name()
+        .filter(|n| !n.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("No loadable SELinux policy in the deployment"))?;
+    tracing::debug!("Using SELinux policy {name}");
+    let customizable = read_customizable_types(deployment_root, &name)?;
+    let policy = Policy {
+        sepolicy: policy,
+        customizable,
+    };
+
+    let mut stats = Stats::default();
+    if is_booted {
+        // The live /var may be a distinct filesystem from the stateroot's
+        let root = &Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+        for d in ["etc", "var"] {
+            relabel_tree(root, d, &format!("/{d}"), &policy, opts.dry_run, &mut stats)?;
+        }
+    } else {
+        relabel_tree(
+            deployment_root,
+            "etc",
+            "/etc",
+            &policy,
+            opts.dry_run,
+            &mut stats,
+        )?;
+        let sysroot_dir = &Dir::reopen_dir(&crate::utils::sysroot_fd(sysroot))?;
+        let stateroot = &sysroot_dir
+            .open_dir(format!("ostree/deploy/{}", deployment.osname()))
+            .context("Opening stateroot")?;
+        relabel_tree(stateroot, "var", "/var", &policy, opts.dry_run, &mut stats)?;
+    }
+
+    let verb = if opts.dry_run {
+        "would be relabeled"
+    } else {
+        "relabeled"
+    };
+    println!(
+        "Checked {} files in deployment {}; {} {verb}",
+        stats.checked,
+        deployment_id(deployment),
+        stats.relabeled
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_deployment() {
+        let ids = ["aaaa.0", "bbbb.0", "aaaa.1"].map(String::from).to_vec();
+        assert_eq!(select_deployment(&ids, "bbbb.0").unwrap(), 1);
+        assert_eq!(select_deployment(&ids, "bbbb").unwrap(), 1);
+        assert_eq!(select_deployment(&ids, "aaaa.1").unwrap(), 2);
+        // Ambiguous
+        assert!(select_deployment(&ids, "aaaa").is_err());
+        assert!(select_deployment(&ids, "cccc").is_err());
+        assert!(select_deployment(&ids, "bbb").is_err());
+    }
+
+    #[test]
+    fn test_label_matches() {
+        let expected = "system_u:object_r:etc_t:s0";
+        assert!(label_matches(
+            Some(b"system_u:object_r:etc_t:s0\0"),
+            expected
+        ));
+        assert!(label_matches(Some(b"system_u:object_r:etc_t:s0"), expected));
+        assert!(!label_matches(
+            Some(b"system_u:object_r:var_t:s0\0"),
+            expected
+        ));
+        assert!(!label_matches(None, expected));
+    }
+
+    #[test]
+    fn test_customizable_types() {
+        let buf = "# comment\ncontainer_file_t\n\n  svirt_image_t \n";
+        assert_eq!(
+            parse_customizable_types(buf),
+            ["container_file_t", "svirt_image_t"]
+        );
+        assert_eq!(
+            label_type(b"system_u:object_r:container_file_t:s0:c1,c2\0"),
+            Some(&b"container_file_t"[..])
+        );
+        assert_eq!(label_type(b"invalid"), None);
+    }
+}
@@ -0,0 +1,266 @@
+//! # Factory reset
+//!
+//! `bootc reset` re-deploys the booted image into a fresh stateroot, which
+//! means the new deployment starts with the default `/etc` from the image and
+//! an empty `/var`.  Selected paths can be carried over from the current
+//! system.  The previous stateroot is left untouched, so the system can still
+//! be rolled back to it.
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::sysroot::LockMode;
+
+use crate::cli::ResetOpts;
+use crate::store::Storage;
+use crate::task::Task;
+
+/// Network configuration preserved by `--preserve-network`.
+const NETWORK_PATHS: &[&str] = &[
+    "/etc/NetworkManager/system-connections",
+    "/etc/systemd/network",
+    "/etc/sysconfig/network-scripts",
+];
+/// The machine ID, preserved by `--preserve-machine-id`.
+const MACHINE_ID: &str = "/etc/machine-id";
+
+/// Where a preserved path is copied to.
+#[derive(Debug, PartialEq, Eq)]
+enum PreserveTarget {
+    /// A path relative to `/etc` of the new deployment.
+    Etc(Utf8PathBuf),
+    /// A path relative to `/var` of the new stateroot.
+    Var(Utf8PathBuf),
+}
+
+impl PreserveTarget {
+    /// Validate a path to preserve, which must be below `/etc` or `/var`.
+    fn new(path: &Utf8Path) -> Result<Self> {
+        if !path.is_absolute() {
+            anyhow::bail!("Path to preserve must be absolute: {path}");
+        }
+        let mut components = path.components().skip(1);
+        let top = components.next();
+        let rest: Utf8PathBuf = components
+            .map(|c| match c {
+                Utf8Component::Normal(c) => Ok(c),
+                _ => Err(anyhow!("Invalid path to preserve: {path}")),
+            })
+            .collect::<Result<_>>()?;
+        if rest.as_str().is_empty() {
+            anyhow::bail!("Path to preserve must be below /etc or /var: {path}");
+        }
+        match top {
+            Some(Utf8Component::Normal("etc")) => Ok(Self::Etc(rest)),
+            Some(Utf8Component::Normal("var")) => Ok(Self::Var(rest)),
+            _ => anyhow::bail!("Path to preserve must be below /etc or /var: {path}"),
+        }
+    }
+
+    /// The destination, relative to the sysroot.
+    fn destination(&self, deployment_dir: &str, stateroot: &str) -> Utf8PathBuf {
+        match self {
+            Self::Etc(p) => Utf8Path::new(deployment_dir).join("etc").join(p),
+            Self::Var(p) => Utf8Path::new("ostree/deploy")
+                .join(stateroot)
+                .join("var")
+                .join(p),
+        }
+    }
+}
+
+/// Validate the name of a stateroot, which is used as a directory name.
+fn validate_stateroot(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        anyhow::bail!("Invalid stateroot name: {name:?}");
+    }
+    Ok(())
+}
+
+/// Generate the name of the new stateroot from the current one.
+fn default_stateroot(current: &str, now: chrono::DateTime<chrono::Utc>) -> String {
+    // Avoid accumulating suffixes across repeated resets
+    let base = current
+        .split_once("-reset-")
+        .map(|(base, _)| base)
+        .unwrap_or(current);
+    format!("{base}-reset-{}", now.format("%Y%m%d%H%M%S"))
+}
+
+/// Gather the paths to preserve; the boolean is true if the path was
+/// explicitly requested (and hence must exist).
+fn paths_to_preserve(opts: &ResetOpts) -> Vec<(Utf8PathBuf, bool)> {
+    let presets = opts
+        .preserve_network
+        .then_some(NETWORK_PATHS)
+        .into_iter()
+        .flatten()
+        .chain(opts.preserve_machine_id.then_some(&MACHINE_ID))
+        .map(|p| (Utf8PathBuf::from(*p), false));
+    opts.preserve
+        .iter()
+        .map(|p| (p.clone(), true))
+        .chain(presets)
+        .collect()
+}
+
+/// Copy the requested paths from the running system into the new deployment.
+#[context("Preserving files")]
+fn preserve(
+    sysroot: &Storage,
+    deployment: &ostree_ext::ostree::Deployment,
+    stateroot: &str,
+    paths: &[(Utf8PathBuf, bool)],
+) -> Result<()> {
+    let rootfs = &Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    let sysroot_dir = &Dir::reopen_dir(&crate::utils::sysroot_fd(sysroot))?;
+    let deployment_dir = sysroot.deployment_dirpath(deployment);
+    for (path, required) in paths {
+        let target = PreserveTarget::new(path)?;
+        let relpath = path.as_str().trim_start_matches('/');
+        if rootfs.symlink_metadata_optional(relpath)?.is_none() {
+            if *required {
+                anyhow::bail!("Path to preserve does not exist: {path}");
+            }
+            tracing::debug!("Skipping nonexistent {path}");
+            continue;
+        }
+        let dest = target.destination(&deployment_dir, stateroot);
+        if let Some(parent) = dest.parent() {
+            sysroot_dir
+                .create_dir_all(parent)
+                .with_context(|| format!("Creating {parent}"))?;
+        }
+        // Replace what the image provides with the current contents
+        sysroot_dir.remove_all_optional(&dest)?;
+        Task::new(format!("Preserving {path}"), "cp")
+            .cwd(sysroot_dir)?
+            .args(["-a", "--reflink=auto", path.as_str(), dest.as_str()])
+            .run()?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc reset`.
+#[context("Resetting")]
+pub(crate) async fn reset(opts: ResetOpts) -> Result<()> {
+    // Validate paths before doing anything else
+    let paths = paths_to_preserve(&opts);
+    for (path, _) in paths.iter() {
+        PreserveTarget::new(path)?;
+    }
+    if let Some(stateroot) = opts.stateroot.as_deref() {
+        validate_stateroot(stateroot)?;
+    }
+
+    let sysroot = &crate::cli::get_storage(LockMode::Exclusive).await?;
+    let repo = &sysroot.repo();
+    let (booted_deployment, _deployments, host) =
+        crate::status::get_status_require_booted(sysroot)?;
+    let booted = host
+        .status
+        .booted
+        .as_ref()
+        .ok_or_else(|| anyhow!("No booted deployment"))?;
+    let imgref = &booted
+        .image
+        .as_ref()
+        .ok_or_else(|| anyhow!("Booted deployment is not a container image"))?
+        .image;
+    let image = booted
+        .query_image(repo)?
+        .ok_or_else(|| anyhow!("Booted deployment is not a container image"))?;
+
+    let stateroot = match opts.stateroot.as_deref() {
+        Some(s) => s.to_owned(),
+        None => default_stateroot(&booted_deployment.osname(), chrono::Utc::now()),
+    };
+    let rootfs = &Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    if rootfs.try_exists(format!("sysroot/ostree/deploy/{stateroot}"))? {
+        anyhow::bail!("Stateroot {stateroot} already exists");
+    }
+
+    // This replaces the staged deployment, if any, so don't reboot into it
+    crate::maintenance::cancel_reboot(rootfs)?;
+    let deployment =
+        crate::deploy::stage_pristine(sysroot, &stateroot, &image, imgref, &booted_deployment)
+            .await?;
+    preserve(sysroot, &deployment, &stateroot, &paths)?;
+
+    println!("Queued factory reset for next boot: {imgref:#}");
+    println!("  Stateroot: {stateroot}");
+    println!("  Digest: {}", image.manifest_digest);
+    println!(
+        "The previous stateroot {} is retained for rollback",
+        booted_deployment.osname()
+    );
+
+    if opts.apply {
+        crate::reboot::reboot()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preserve_target() {
+        assert_eq!(
+            PreserveTarget::new(Utf8Path::new("/etc/NetworkManager/system-connections")).unwrap(),
+            PreserveTarget::Etc("NetworkManager/system-connections".into())
+        );
+        assert_eq!(
+            PreserveTarget::new(Utf8Path::new("/var/lib/myapp")).unwrap(),
+            PreserveTarget::Var("lib/myapp".into())
+        );
+        for invalid in [
+            "etc/hostname",
+            "/etc",
+            "/var/",
+            "/usr/lib/foo",
+            "/etc/../usr",
+        ] {
+            assert!(
+                PreserveTarget::new(Utf8Path::new(invalid)).is_err(),
+                "{invalid}"
+            );
+        }
+        assert_eq!(
+            PreserveTarget::Etc("hostname".into()).destination(
+                "ostree/deploy/default-reset-1/deploy/abc.0",
+                "default-reset-1"
+            ),
+            "ostree/deploy/default-reset-1/deploy/abc.0/etc/hostname"
+        );
+        assert_eq!(
+            PreserveTarget::Var("lib/myapp".into())
+                .destination("ostree/deploy/x/deploy/abc.0", "default-reset-1"),
+            "ostree/deploy/default-reset-1/var/lib/myapp"
+        );
+    }
+
+    #[test]
+    fn test_validate_stateroot() {
+        validate_stateroot("default-reset-1").unwrap();
+        for invalid in ["", ".", "..", "../default", "a/b", "/"] {
+            assert!(validate_stateroot(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_default_stateroot() {
+        let now = chrono::DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert_eq!(
+            default_stateroot("default", now),
+            "default-reset-20231114221320"
+        );
+        assert_eq!(
+            default_stateroot("default-reset-20230101000000", now),
+            "default-reset-20231114221320"
+        );
+    }
+}
@@ -0,0 +1,470 @@
+//! # Software bills of materials
+//!
+//! `bootc image sbom` outputs the SBOM(s) describing the booted image, so that
+//! compliance tooling can inventory a host without fetching the image
+//! elsewhere.  SBOMs are looked up in two places:
+//!
+//! - Well-known directories in the image itself, where build tools commonly
+//!   write them (e.g. `/usr/share/buildinfo`).
+//! - OCI artifacts attached to the image in the registry as referrers (as
+//!   pushed by e.g. `oras attach` or `cosign`).  These are found via the
+//!   [referrers API] if `oras` is installed, as skopeo does not support it;
+//!   otherwise only the [referrers tag schema] is queried.
+//!
+//! [referrers API]: https://github.com/opencontainers/distribution-spec/blob/main/spec.md#listing-referrers
+//! [referrers tag schema]: https://github.com/opencontainers/distribution-spec/blob/main/spec.md#referrers-tag-schema
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use clap::ValueEnum;
+use fn_error_context::context;
+use ostree_ext::sysroot::LockMode;
+use serde::Deserialize;
+
+use crate::cli::ImageSbomOpts;
+use crate::task::Task;
+
+/// Directories in the image which are searched for SBOMs.
+const SBOM_DIRS: &[&str] = &["usr/share/buildinfo", "usr/share/sbom", "usr/lib/sbom"];
+/// The annotation holding the file name of an artifact layer.
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// A SBOM format.
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum SbomFormat {
+    /// SPDX (JSON or tag-value)
+    Spdx,
+    /// CycloneDX (JSON or XML)
+    Cyclonedx,
+}
+
+impl std::fmt::Display for SbomFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+impl SbomFormat {
+    /// Determine the format from an OCI artifact or media type.
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        // Strip parameters such as `; version=1.5`
+        let media_type = media_type.split(';').next().unwrap_or_default().trim();
+        match media_type {
+            "application/spdx+json" | "text/spdx" | "text/spdx+json" => Some(Self::Spdx),
+            "application/vnd.cyclonedx+json" | "application/vnd.cyclonedx+xml" => {
+                Some(Self::Cyclonedx)
+            }
+            _ => None,
+        }
+    }
+
+    /// Determine the format from the contents of a file.
+    fn sniff(contents: &[u8]) -> Option<Self> {
+        let contents = std::str::from_utf8(contents).ok()?.trim_start();
+        if contents.starts_with('{') {
+            let v: serde_json::Value = serde_json::from_str(contents).ok()?;
+            if v.get("spdxVersion").is_some() {
+                Some(Self::Spdx)
+            } else if v.get("bomFormat").and_then(|f| f.as_str()) == Some("CycloneDX") {
+                Some(Self::Cyclonedx)
+            } else {
+                None
+            }
+        } else if contents.starts_with("SPDXVersion:") {
+            Some(Self::Spdx)
+        } else if contents.starts_with('<') && contents.contains("http://cyclonedx.org/schema/bom")
+        {
+            Some(Self::Cyclonedx)
+        } else {
+            None
+        }
+    }
+}
+
+/// A SBOM which was found for the image.
+#[derive(Debug)]
+struct Sbom {
+    format: SbomFormat,
+    /// Where the SBOM was found, for display.
+    origin: String,
+    /// The file name to use with `--output-dir`.
+    filename: String,
+    contents: Vec<u8>,
+}
+
+/// An OCI descriptor; only the fields we need.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+    #[serde(default)]
+    artifact_type: Option<String>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// An OCI image index or manifest; only the fields we need.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    #[serde(default)]
+    artifact_type: Option<String>,
+    #[serde(default)]
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    /// Also accept the output of `oras discover --format json`, which uses
+    /// `referrers` since oras 1.3.
+    #[serde(default, alias = "referrers")]
+    manifests: Vec<Descriptor>,
+}
+
+impl Manifest {
+    /// The artifact type, falling back to the config media type as used by
+    /// artifacts predating the `artifactType` field.
+    fn artifact_type(&self) -> Option<&str> {
+        self.artifact_type
+            .as_deref()
+            .or_else(|| self.config.as_ref().and_then(|c| c.media_type.as_deref()))
+    }
+}
+
+/// Return the repository of an image name, i.e. strip the tag or digest.
+pub(crate) fn repository_of(name: &str) -> &str {
+    let name = name.split_once('@').map(|(n, _)| n).unwrap_or(name);
+    match name.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => name,
+    }
+}
+
+/// The referrers tag schema fallback for a digest, e.g. `sha256-a0...`.
+fn referrers_tag(digest: &str) -> Result<String> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid digest: {digest}"))?;
+    Ok(format!("{algorithm}-{hex}"))
+}
+
+/// Find SBOMs in the well-known directories of the provided root.
+#[context("Searching image for SBOMs")]
+fn find_local(root: &Dir) -> Result<Vec<Sbom>> {
+    let mut r = Vec::new();
+    for dir in SBOM_DIRS {
+        let Some(d) = root.open_dir_optional(dir)? else {
+            continue;
+        };
+        let mut entries = d
+            .entries()?
+            .map(|e| e.map_err(anyhow::Error::from))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let contents = d
+                .read(name)
+                .with_context(|| format!("Reading {dir}/{name}"))?;
+            let Some(format) = SbomFormat::sniff(&contents) else {
+                tracing::debug!("Skipping non-SBOM /{dir}/{name}");
+                continue;
+            };
+            r.push(Sbom {
+                format,
+                origin: format!("/{dir}/{name}"),
+                filename: name.to_owned(),
+                contents,
+            });
+        }
+    }
+    Ok(r)
+}
+
+/// Fetch the raw manifest for a reference via `skopeo inspect`.
+fn inspect_raw(imgref: &str, authfile: Option<&Utf8Path>) -> Result<Manifest> {
+    let mut task = Task::new(format!("Inspecting {imgref}"), "skopeo")
+        .quiet()
+        .args(["inspect", "--raw"]);
+    if let Some(authfile) = authfile {
+        task = task.args(["--authfile", authfile.as_str()]);
+    }
+    let raw = task.arg(format!("docker://{imgref}")).read()?;
+    serde_json::from_str(&raw).with_context(|| format!("Parsing manifest of {imgref}"))
+}
+
+/// List the referrers of an image via `oras discover`, which uses the
+/// referrers API, falling back to the tag schema for registries without it.
+fn discover_referrers(repo: &str, digest: &str, authfile: Option<&Utf8Path>) -> Result<Manifest> {
+    let imgref = format!("{repo}@{digest}");
+    let mut task = Task::new(format!("Querying referrers of {imgref}"), "oras")
+        .quiet()
+        .args(["discover", "--format", "json"]);
+    if let Some(authfile) = authfile {
+        task = task.args(["--registry-config", authfile.as_str()]);
+    }
+    let raw = task.arg(&imgref).read()?;
+    serde_json::from_str(&raw).with_context(|| format!("Parsing referrers of {imgref}"))
+}
+
+/// List the referrers of an image via the referrers tag schema.
+fn referrers_by_tag(repo: &str, digest: &str, authfile: Option<&Utf8Path>) -> Result<Manifest> {
+    let tag = referrers_tag(digest)?;
+    match inspect_raw(&format!("{repo}:{tag}"), authfile) {
+        Ok(index) => Ok(index),
+        Err(e) => {
+            // Most commonly, there simply are no referrers
+            tracing::debug!("No referrers for {repo}@{digest}: {e:#}");
+            Ok(Manifest::default())
+        }
+    }
+}
+
+/// Find SBOMs attached to the image in the registry.
+#[context("Querying registry for SBOMs")]
+fn find_referrers(repo: &str, digest: &str, authfile: Option<&Utf8Path>) -> Result<Vec<Sbom>> {
+    let index = if crate::utils::find_in_path("oras") {
+        discover_referrers(repo, digest, authfile)?
+    } else {
+        tracing::debug!("oras not found; only using the referrers tag schema");
+        referrers_by_tag(repo, digest, authfile)?
+    };
+    let mut r = Vec::new();
+    for desc in index.manifests {
+        let artifact = format!("{repo}@{}", desc.digest);
+        // The artifact type should be in the index; otherwise, look at the manifest
+        let format = match desc.artifact_type.as_deref() {
+            Some(t) => SbomFormat::from_media_type(t),
+            None => inspect_raw(&artifact, authfile)?
+                .artifact_type()
+                .and_then(SbomFormat::from_media_type),
+        };
+        let Some(format) = format else {
+            tracing::debug!("Skipping non-SBOM referrer {}", desc.digest);
+            continue;
+        };
+        let tmpdir = tempfile::tempdir()?;
+        let ocidir = Utf8Path::from_path(tmpdir.path())
+            .ok_or_else(|| anyhow!("Non-UTF8 temporary directory"))?;
+        let mut task = Task::new(format!("Fetching {artifact}"), "skopeo")
+            .quiet()
+            .quiet_output()
+            .arg("copy");
+        if let Some(authfile) = authfile {
+            task = task.args(["--authfile", authfile.as_str()]);
+        }
+        task.arg(format!("docker://{artifact}"))
+            .arg(format!("oci:{ocidir}"))
+            .run()?;
+        let ocidir = Dir::open_ambient_dir(ocidir, cap_std::ambient_authority())?;
+        let blob_path = |digest: &str| -> Result<Utf8PathBuf> {
+            let (algorithm, hex) = digest
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid digest: {digest}"))?;
+            Ok(Utf8Path::new("blobs").join(algorithm).join(hex))
+        };
+        let manifest: Manifest = serde_json::from_slice(&ocidir.read(blob_path(&desc.digest)?)?)
+            .context("Parsing artifact manifest")?;
+        for (i, layer) in manifest.layers.iter().enumerate() {
+            let contents = ocidir.read(blob_path(&layer.digest)?)?;
+            let filename = match layer.annotations.get(TITLE_ANNOTATION) {
+                Some(title) if !title.contains('/') => title.clone(),
+                _ => {
+                    let hex = layer.digest.split_once(':').map(|(_, h)| h).unwrap_or("");
+                    format!("{}-{i}.{format}", hex.get(..12).unwrap_or(hex))
+                }
+            };
+            r.push(Sbom {
+                format,
+                origin: artifact.clone(),
+                filename,
+                contents,
+            });
+        }
+    }
+    Ok(r)
+}
+
+/// Implementation of `bootc image sbom`.
+#[context("Extracting SBOM")]
+pub(crate) async fn sbom(opts: ImageSbomOpts) -> Result<()> {
+    let sysroot = &crate::cli::get_storage(LockMode::Shared).await?;
+    let (_booted_deployment, _deployments, host) =
+        crate::status::get_status_require_booted(sysroot)?;
+    let image = host
+        .status
+        .booted
+        .as_ref()
+        .and_then(|b| b.image.as_ref())
+        .ok_or_else(|| anyhow!("Booted deployment is not a container image"))?;
+    let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+
+    let mut sboms = find_local(rootfs)?;
+    if opts.no_registry {
+        tracing::debug!("Skipping registry query");
+    } else if image.image.transport == "registry" {
+        let authfile =
+            ostree_ext::globals::get_global_authfile(rootfs)?.map(|(authfile, _fd)| authfile);
+        sboms.extend(find_referrers(
+            repository_of(&image.image.image),
+            &image.image_digest,
+            authfile.as_deref(),
+        )?);
+    } else {
+        tracing::debug!("Not querying registry for {}", image.image);
+    }
+    if let Some(format) = opts.format {
+        sboms.retain(|s| s.format == format);
+    }
+
+    if opts.list {
+        let mut stdout = std::io::stdout().lock();
+        for sbom in sboms {
+            writeln!(stdout, "{}\t{}", sbom.format, sbom.origin)?;
+        }
+        return Ok(());
+    }
+    if let Some(dir) = opts.output_dir.as_deref() {
+        if sboms.is_empty() {
+            anyhow::bail!("No SBOM found for {}", image.image);
+        }
+        std::fs::create_dir_all(dir).with_context(|| format!("Creating {dir}"))?;
+        let dir = Dir::open_ambient_dir(dir, cap_std::ambient_authority())?;
+        for sbom in sboms.iter() {
+            if dir.try_exists(&sbom.filename)? {
+                anyhow::bail!("Refusing to overwrite {}", sbom.filename);
+            }
+            dir.atomic_write(&sbom.filename, &sbom.contents)?;
+            println!("Wrote {} (from {})", sbom.filename, sbom.origin);
+        }
+        return Ok(());
+    }
+    match sboms.as_slice() {
+        [] => anyhow::bail!("No SBOM found for {}", image.image),
+        [sbom] => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&sbom.contents)?;
+            stdout.flush()?;
+            Ok(())
+        }
+        sboms => {
+            let origins = sboms
+                .iter()
+                .map(|s| format!("{} ({})", s.origin, s.format))
+                .collect::<Vec<_>>();
+            anyhow::bail!(
+                "Found multiple SBOMs: {}; use --format or --output-dir",
+                origins.join(", ")
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repository_of() {
+        let cases = [
+            ("quay.io/example/os:latest", "quay.io/example/os"),
+            ("quay.io/example/os", "quay.io/example/os"),
+            ("localhost:5000/os:42", "localhost:5000/os"),
+            ("localhost:5000/os", "localhost:5000/os"),
+            ("quay.io/example/os@sha256:abcd", "quay.io/example/os"),
+            ("quay.io/example/os:1@sha256:abcd", "quay.io/example/os"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(repository_of(name), expected, "{name}");
+        }
+        assert_eq!(referrers_tag("sha256:abcd").unwrap(), "sha256-abcd");
+        assert!(referrers_tag("abcd").is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            SbomFormat::from_media_type("application/spdx+json"),
+            Some(SbomFormat::Spdx)
+        );
+        assert_eq!(
+            SbomFormat::from_media_type("application/vnd.cyclonedx+json; version=1.5"),
+            Some(SbomFormat::Cyclonedx)
+        );
+        assert_eq!(
+            SbomFormat::from_media_type("application/vnd.dev.cosign.artifact.sig.v1+json"),
+            None
+        );
+        let spdx = br#"{"spdxVersion": "SPDX-2.3", "packages": []}"#;
+        assert_eq!(SbomFormat::sniff(spdx), Some(SbomFormat::Spdx));
+        let cdx = br#"  {"bomFormat": "CycloneDX", "specVersion": "1.5"}"#;
+        assert_eq!(SbomFormat::sniff(cdx), Some(SbomFormat::Cyclonedx));
+        let cdx_xml = br#"<?xml version="1.0"?><bom xmlns="http://cyclonedx.org/schema/bom/1.5">"#;
+        assert_eq!(SbomFormat::sniff(cdx_xml), Some(SbomFormat::Cyclonedx));
+        assert_eq!(
+            SbomFormat::sniff(b"SPDXVersion: SPDX-2.3\n"),
+            Some(SbomFormat::Spdx)
+        );
+        assert_eq!(SbomFormat::sniff(br#"{"name": "foo"}"#), None);
+        assert_eq!(SbomFormat::sniff(b"content_sets:\n- foo\n"), None);
+    }
+
+    #[test]
+    fn test_parse_referrers() {
+        // Output of `oras discover --format json`, before and since oras 1.3
+        for field in ["manifests", "referrers"] {
+            let raw = format!(
+                r#"{{"reference": "quay.io/example/os@sha256:aaaa", "{field}": [
+                    {{"reference": "quay.io/example/os@sha256:bbbb", "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                      "digest": "sha256:bbbb", "size": 42, "artifactType": "application/spdx+json"}}
+                ]}}"#
+            );
+            let index: Manifest = serde_json::from_str(&raw).unwrap();
+            let [desc] = index.manifests.as_slice() else {
+                panic!("Unexpected referrers: {index:?}");
+            };
+            assert_eq!(desc.digest, "sha256:bbbb");
+            assert_eq!(desc.artifact_type.as_deref(), Some("application/spdx+json"));
+        }
+    }
+
+    #[test]
+    fn test_find_local() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("usr/share/buildinfo")?;
+        td.write(
+            "usr/share/buildinfo/sbom.spdx.json",
+            r#"{"spdxVersion": "SPDX-2.3"}"#,
+        )?;
+        td.write("usr/share/buildinfo/content-sets.json", r#"{"repos": []}"#)?;
+        td.create_dir_all("usr/lib/sbom")?;
+        td.write("usr/lib/sbom/bom.json", r#"{"bomFormat": "CycloneDX"}"#)?;
+        let sboms = find_local(&td)?;
+        let found = sboms
+            .iter()
+            .map(|s| (s.format, s.origin.as_str(), s.filename.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                (
+                    SbomFormat::Spdx,
+                    "/usr/share/buildinfo/sbom.spdx.json",
+                    "sbom.spdx.json"
+                ),
+                (SbomFormat::Cyclonedx, "/usr/lib/sbom/bom.json", "bom.json"),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! # D-Bus service
+//!
+//! `bootc service` exports the `org.containers.bootc.Host` interface on the
+//! system bus, so that frontends (e.g. a desktop or Cockpit) can query and
+//! update the system without parsing CLI output or running as root.
+//!
+//! Methods which change the system are authorized via polkit; each method
+//! runs the corresponding `bootc` command, so their behavior is identical.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use ostree_ext::gio;
+use ostree_ext::gio::prelude::*;
+use ostree_ext::glib;
+
+/// The well-known name of the service.
+const BUS_NAME: &str = "org.containers.bootc";
+/// The path of the exported object.
+const OBJECT_PATH: &str = "/org/containers/bootc";
+/// The name of the exported interface.
+const INTERFACE: &str = "org.containers.bootc.Host";
+/// The error returned when a command fails.
+const ERROR_FAILED: &str = "org.containers.bootc.Error.Failed";
+/// The error returned when polkit denies a request.
+const ERROR_ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+/// The introspection data of the exported interface.
+const INTROSPECTION_XML: &str = r#"
+<node>
+  <interface name="org.containers.bootc.Host">
+    <method name="GetStatus">
+      <arg name="status" type="s" direction="out"/>
+    </method>
+    <method name="CheckUpdate">
+      <arg name="update" type="s" direction="out"/>
+    </method>
+    <method name="StageUpdate"/>
+    <method name="Rollback"/>
+  </interface>
+</node>
+"#;
+
+/// The methods of the exported interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    /// Return the status of the host, as JSON
+    GetStatus,
+    /// Check for an update, returning its metadata as JSON
+    CheckUpdate,
+    /// Fetch and stage an update, if available
+    StageUpdate,
+    /// Queue the rollback deployment for the next boot
+    Rollback,
+}
+
+impl Method {
+    fn from_name(name: &str) -> Option<Self> {
+        let r = match name {
+            "GetStatus" => Self::GetStatus,
+            "CheckUpdate" => Self::CheckUpdate,
+            "StageUpdate" => Self::StageUpdate,
+            "Rollback" => Self::Rollback,
+            _ => return None,
+        };
+        Some(r)
+    }
+
+    /// The polkit action authorizing this method, if any.
+    fn action(self) -> Option<&'static str> {
+        match self {
+            Self::GetStatus => None,
+            Self::CheckUpdate => Some("org.containers.bootc.check-update"),
+            Self::StageUpdate => Some("org.containers.bootc.stage-update"),
+            Self::Rollback => Some("org.containers.bootc.rollback"),
+        }
+    }
+
+    /// The arguments of the `bootc` command implementing this method.
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            Self::GetStatus => &["status", "--format=json", "--format-version=1"],
+            Self::CheckUpdate => &["upgrade", "--check", "--format=json"],
+            Self::StageUpdate => &["upgrade", "--quiet"],
+            Self::Rollback => &["rollback"],
+        }
+    }
+
+    /// Whether this method returns the output of the command.
+    fn returns_output(self) -> bool {
+        matches!(self, Self::GetStatus | Self::CheckUpdate)
+    }
+}
+
+/// Check via polkit whether the sender of a request is authorized for the action.
+async fn authorize(connection: &gio::DBusConnection, sender: &str, action: &str) -> Result<bool> {
+    const ALLOW_USER_INTERACTION: u32 = 1;
+    let subject_details = HashMap::from([("name".to_owned(), sender.to_variant())]);
+    let subject = ("system-bus-name", subject_details);
+    let details: HashMap<String, String> = HashMap::new();
+    let params = (subject, action, details, ALLOW_USER_INTERACTION, "").to_variant();
+    let reply = connection
+        .call_future(
+            Some("org.freedesktop.PolicyKit1"),
+            "/org/freedesktop/PolicyKit1/Authority",
+            "org.freedesktop.PolicyKit1.Authority",
+            "CheckAuthorization",
+            Some(&params),
+            Some(glib::VariantTy::new("((bba{ss}))")?),
+            gio::DBusCallFlags::NONE,
+            // Authorization may wait for the user to authenticate
+            i32::MAX,
+        )
+        .await
+        .context("Checking authorization")?;
+    let ((authorized, _challenge, _details),) = reply
+        .get::<((bool, bool, HashMap<String, String>),)>()
+        .ok_or_else(|| anyhow::anyhow!("Invalid reply from polkit"))?;
+    Ok(authorized)
+}
+
+/// Run `bootc` with the provided arguments, returning its standard output.
+async fn run_bootc(exe: &std::path::Path, args: &[&str]) -> Result<String> {
+    let argv = std::iter::once(exe.as_os_str())
+        .chain(args.iter().map(OsStr::new))
+        .collect::<Vec<_>>();
+    let proc = gio::Subprocess::newv(
+        &argv,
+        gio::SubprocessFlags::STDOUT_PIPE | gio::SubprocessFlags::STDERR_PIPE,
+    )?;
+    let (stdout, stderr) = proc.communicate_utf8_future(None).await?;
+    if !proc.is_successful() {
+        let stderr = stderr.as_deref().unwrap_or_default().trim();
+        anyhow::bail!("bootc {} failed: {stderr}", args.join(" "));
+    }
+    Ok(stdout.map(|s| s.to_string()).unwrap_or_default())
+}
+
+/// Authorize and execute a method call.
+async fn call(
+    exe: &std::path::Path,
+    connection: &gio::DBusConnection,
+    sender: &str,
+    method: Method,
+) -> std::result::Result<Option<glib::Variant>, (&'static str, String)> {
+    if let Some(action) = method.action() {
+        match authorize(connection, sender, action).await {
+            Ok(true) => {}
+            Ok(false) => return Err((ERROR_ACCESS_DENIED, format!("Not authorized for {action}"))),
+            Err(e) => return Err((ERROR_ACCESS_DENIED, format!("{e:#}"))),
+        }
+    }
+    tracing::debug!("{sender}: {method:?}");
+    let output = run_bootc(exe, method.args())
+        .await
+        .map_err(|e| (ERROR_FAILED, format!("{e:#}")))?;
+    Ok(method.returns_output().then(|| (output,).to_variant()))
+}
+
+/// Export the interface on the provided connection.
+fn register(connection: &gio::DBusConnection, exe: PathBuf) -> Result<()> {
+    let node = gio::DBusNodeInfo::for_xml(INTROSPECTION_XML)?;
+    let interface = node
+        .lookup_interface(INTERFACE)
+        .ok_or_else(|| anyhow::anyhow!("Missing interface {INTERFACE}"))?;
+    connection.register_object(
+        OBJECT_PATH,
+        &interface,
+        move |connection, sender, _path, _interface, method, _params, invocation| {
+            let Some(method) = Method::from_name(method) else {
+                invocation.return_dbus_error(
+                    "org.freedesktop.DBus.Error.UnknownMethod",
+                    &format!("Unknown method {method}"),
+                );
+                return;
+            };
+            let exe = exe.clone();
+            let sender = sender.to_owned();
+            glib::MainContext::ref_thread_default().spawn_local(async move {
+                match call(&exe, &connection, &sender, method).await {
+                    Ok(r) => invocation.return_value(r.as_ref()),
+                    Err((name, msg)) => invocation.return_dbus_error(name, &msg),
+                }
+            });
+        },
+        |_connection, _sender, _path, _interface, _property| ().to_variant(),
+        |_connection, _sender, _path, _interface, _property, _value| false,
+    )?;
+    Ok(())
+}
+
+/// Acquire our well-known name on the bus.
+fn request_name(connection: &gio::DBusConnection) -> Result<()> {
+    const DO_NOT_QUEUE: u32 = 4;
+    const PRIMARY_OWNER: u32 = 1;
+    let reply = connection.call_sync(
+        Some("org.freedesktop.DBus"),
+        "/org/freedesktop/DBus",
+        "org.freedesktop.DBus",
+        "RequestName",
+        Some(&(BUS_NAME, DO_NOT_QUEUE).to_variant()),
+        Some(glib::VariantTy::new("(u)")?),
+        gio::DBusCallFlags::NONE,
+        -1,
+        gio::Cancellable::NONE,
+    )?;
+    let (r,) = reply
+        .get::<(u32,)>()
+        .ok_or_else(|| anyhow::anyhow!("Invalid reply to RequestName"))?;
+    if r != PRIMARY_OWNER {
+        anyhow::bail!("Failed to acquire {BUS_NAME}; is another instance running?");
+    }
+    Ok(())
+}
+
+/// Connect to the system bus and serve requests until terminated.
+fn run(exe: PathBuf) -> Result<()> {
+    let ctx = glib::MainContext::new();
+    ctx.with_thread_default(|| {
+        let connection = gio::bus_get_sync(gio::BusType::System, gio::Cancellable::NONE)
+            .context("Connecting to the system bus")?;
+        register(&connection, exe)?;
+        request_name(&connection)?;
+        tracing::debug!("Acquired {BUS_NAME}");
+        glib::MainLoop::new(Some(&ctx), false).run();
+        Ok(())
+    })?
+}
+
+/// Implementation of `bootc service`, invoked by `bootc-dbus.service`.
+#[context("Running D-Bus service")]
+pub(crate) async fn service() -> Result<()> {
+    let exe = std::fs::read_link("/proc/self/exe")?;
+    tokio::task::spawn_blocking(move || run(exe)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_introspection() {
+        let node = gio::DBusNodeInfo::for_xml(INTROSPECTION_XML).unwrap();
+        let interface = node.lookup_interface(INTERFACE).unwrap();
+        for name in ["GetStatus", "CheckUpdate", "StageUpdate", "Rollback"] {
+            let method = Method::from_name(name).unwrap();
+            assert!(interface.lookup_method(name).is_some(), "{name}");
+            // Only reading the status is not authorized via polkit
+            assert_eq!(method.action().is_none(), method == Method::GetStatus);
+        }
+        assert_eq!(Method::from_name("Reboot"), None);
+    }
+}
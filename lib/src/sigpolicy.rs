@@ -0,0 +1,279 @@
+//! # Signature verification policy for OS images
+//!
+//! A policy in `bootc/policy.toml` (in `/etc` or `/usr/lib`) can require that
+//! OS images fetched from a registry are signed via [sigstore](https://www.sigstore.dev/)
+//! (e.g. with `cosign sign`), and/or restrict them to a set of pinned digests.
+//!
+//! Verification happens after the manifest has been fetched, but before
+//! any layers are; signatures are verified by invoking `cosign verify`
+//! on the exact manifest digest.
+
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::oci_spec::image::Digest;
+use serde::Deserialize;
+
+use crate::spec::ImageReference;
+use crate::task::Task;
+
+/// Configuration files, in order of precedence.
+const CONFIG_PATHS: &[&str] = &["etc/bootc/policy.toml", "usr/lib/bootc/policy.toml"];
+
+/// The signature verification policy.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Policy {
+    /// Requirements for images, by scope.
+    #[serde(default, rename = "image")]
+    pub(crate) images: Vec<ImagePolicy>,
+}
+
+/// The requirements for images matching a scope.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct ImagePolicy {
+    /// A registry, or a repository optionally with a tag, e.g. `quay.io/example/os`.
+    pub(crate) scope: String,
+    /// The path to a public key which must have signed the image.
+    pub(crate) key: Option<Utf8PathBuf>,
+    /// For keyless signing, the identity in the signing certificate.
+    pub(crate) certificate_identity: Option<String>,
+    /// For keyless signing, the OIDC issuer of the signing certificate.
+    pub(crate) certificate_oidc_issuer: Option<String>,
+    /// If not empty, the manifest digest of the image must be one of these.
+    #[serde(default)]
+    pub(crate) digests: Vec<String>,
+}
+
+/// Strip any tag and digest from an image name, returning the repository.
+fn image_repository(name: &str) -> &str {
+    let name = name.split_once('@').map_or(name, |(n, _)| n);
+    match name.rsplit_once(':') {
+        // A colon after the last slash is a tag; otherwise it's a registry port
+        Some((repo, tag)) if repo.contains('/') && !tag.contains('/') => repo,
+        _ => name,
+    }
+}
+
+/// Returns true if the image name is matched by the scope; this follows the
+/// semantics of `docker` scopes in `containers-policy.json`.
+fn scope_matches(scope: &str, name: &str) -> bool {
+    let name = name.split_once('@').map_or(name, |(n, _)| n);
+    if scope == name {
+        return true;
+    }
+    // A scope with a tag only matches exactly
+    if image_repository(scope) != scope {
+        return false;
+    }
+    let repo = image_repository(name);
+    repo == scope
+        || repo
+            .strip_prefix(scope)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl ImagePolicy {
+    fn validate(&self) -> Result<()> {
+        if self.scope.is_empty() {
+            anyhow::bail!("Empty scope");
+        }
+        match (
+            self.key.is_some(),
+            self.certificate_identity.is_some(),
+            self.certificate_oidc_issuer.is_some(),
+        ) {
+            (_, true, false) | (_, false, true) => anyhow::bail!(
+                "certificate-identity and certificate-oidc-issuer must be specified together"
+            ),
+            (true, true, true) => {
+                anyhow::bail!("key and certificate-identity are mutually exclusive")
+            }
+            (false, false, false) if self.digests.is_empty() => {
+                anyhow::bail!("At least one of key, certificate-identity or digests is required")
+            }
+            _ => {}
+        }
+        for digest in self.digests.iter() {
+            Digest::from_str(digest).with_context(|| format!("Parsing {digest}"))?;
+        }
+        Ok(())
+    }
+
+    /// Verify the image with the provided manifest digest against this policy.
+    fn verify(&self, name: &str, digest: &str) -> Result<()> {
+        let scope = &self.scope;
+        if !self.digests.is_empty() && !self.digests.iter().any(|d| d == digest) {
+            anyhow::bail!(
+                "{name}: digest {digest} is not one of the digests allowed by the policy for {scope}"
+            );
+        }
+        let pinned = format!("{}@{digest}", image_repository(name));
+        let mut task = Task::new_quiet("cosign").quiet_output().arg("verify");
+        if let Some(key) = self.key.as_deref() {
+            task = task.args(["--key", key.as_str()]);
+        } else if let (Some(identity), Some(issuer)) = (
+            self.certificate_identity.as_deref(),
+            self.certificate_oidc_issuer.as_deref(),
+        ) {
+            task = task.args([
+                "--certificate-identity",
+                identity,
+                "--certificate-oidc-issuer",
+                issuer,
+            ]);
+        } else {
+            // Only digest pinning was requested
+            return Ok(());
+        }
+        task.arg(&pinned).run().with_context(|| {
+            format!("Signature verification of {pinned} failed (policy scope {scope})")
+        })
+    }
+}
+
+impl Policy {
+    /// Find the most specific policy for the image name, if any.
+    fn find(&self, name: &str) -> Option<&ImagePolicy> {
+        self.images
+            .iter()
+            .filter(|p| scope_matches(&p.scope, name))
+            .max_by_key(|p| p.scope.len())
+    }
+
+    /// Verify the image with the provided manifest digest against the policy.
+    /// Only images fetched from a registry are subject to the policy.
+    #[context("Verifying {}", imgref.image)]
+    pub(crate) fn verify(&self, imgref: &ImageReference, digest: &str, quiet: bool) -> Result<()> {
+        if imgref.transport != "registry" {
+            tracing::debug!("Not verifying {} transport", imgref.transport);
+            return Ok(());
+        }
+        let Some(policy) = self.find(&imgref.image) else {
+            tracing::debug!("No signature policy for {}", imgref.image);
+            return Ok(());
+        };
+        policy.verify(&imgref.image, digest)?;
+        if !quiet {
+            println!("Verified {}@{digest}", imgref.image);
+        }
+        Ok(())
+    }
+}
+
+/// Load the signature policy from the provided root; returns `None` if
+/// there is no policy.
+#[context("Loading signature policy")]
+pub(crate) fn load_policy(root: &Dir) -> Result<Option<Policy>> {
+    for path in CONFIG_PATHS {
+        let Some(f) = root.open_optional(path)? else {
+            continue;
+        };
+        let buf = std::io::read_to_string(f)?;
+        let policy: Policy = toml::from_str(&buf).with_context(|| format!("Parsing {path}"))?;
+        for image in policy.images.iter() {
+            image
+                .validate()
+                .with_context(|| format!("{path}: Invalid policy for {}", image.scope))?;
+        }
+        return Ok(Some(policy));
+    }
+    Ok(None)
+}
+
+/// Load the signature policy of the running system.
+pub(crate) fn load_host_policy() -> Result<Option<Policy>> {
+    let root = &Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    load_policy(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std;
+
+    use super::*;
+
+    #[test]
+    fn test_scope_matches() {
+        let cases = [
+            ("quay.io", "quay.io/example/os:latest", true),
+            ("quay.io/example", "quay.io/example/os:latest", true),
+            ("quay.io/example/os", "quay.io/example/os:latest", true),
+            ("quay.io/example/os", "quay.io/example/os@sha256:abcd", true),
+            (
+                "quay.io/example/os:latest",
+                "quay.io/example/os:latest",
+                true,
+            ),
+            (
+                "quay.io/example/os:latest",
+                "quay.io/example/os:other",
+                false,
+            ),
+            ("quay.io/example/o", "quay.io/example/os:latest", false),
+            ("localhost:5000/os", "localhost:5000/os:1", true),
+            ("localhost:5000", "localhost:5000/os", true),
+            ("docker.io", "quay.io/example/os", false),
+        ];
+        for (scope, name, expected) in cases {
+            assert_eq!(scope_matches(scope, name), expected, "{scope} {name}");
+        }
+    }
+
+    #[test]
+    fn test_load_policy() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(load_policy(td)?, None);
+        td.create_dir_all("usr/lib/bootc")?;
+        td.write(
+            "usr/lib/bootc/policy.toml",
+            indoc::indoc! { r#"
+                [[image]]
+                scope = "quay.io/example"
+                key = "/usr/lib/pki/example.pub"
+
+                [[image]]
+                scope = "quay.io/example/os"
+                digests = ["sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38"]
+            "# },
+        )?;
+        let policy = load_policy(td)?.unwrap();
+        assert_eq!(policy.images.len(), 2);
+        let p = policy.find("quay.io/example/os:latest").unwrap();
+        assert_eq!(p.scope, "quay.io/example/os");
+        let p = policy.find("quay.io/example/other").unwrap();
+        assert_eq!(p.key.as_deref().unwrap(), "/usr/lib/pki/example.pub");
+        assert!(policy.find("quay.io/other/os").is_none());
+
+        // Digest pinning is enforced without invoking cosign
+        let p = policy.find("quay.io/example/os").unwrap();
+        p.verify(
+            "quay.io/example/os",
+            "sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38",
+        )?;
+        assert!(p
+            .verify(
+                "quay.io/example/os",
+                "sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34"
+            )
+            .is_err());
+
+        td.create_dir_all("etc/bootc")?;
+        for invalid in [
+            "[[image]]\nscope = \"quay.io\"\n",
+            "[[image]]\nscope = \"quay.io\"\ncertificate-identity = \"foo\"\n",
+            "[[image]]\nscope = \"\"\nkey = \"/k.pub\"\n",
+            "[[image]]\nscope = \"quay.io\"\ndigests = [\"notadigest\"]\n",
+            "[[images]]\nscope = \"quay.io\"\n",
+        ] {
+            td.write("etc/bootc/policy.toml", invalid)?;
+            assert!(load_policy(td).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@
 
 use std::fmt::Display;
 
+use anyhow::Context;
 use ostree_ext::container::OstreeImageReference;
 use ostree_ext::oci_spec::image::Digest;
 use schemars::JsonSchema;
@@ -87,6 +88,20 @@ pub struct ImageReference {
     /// Signature verification type
     #[serde(skip_serializing_if = "Option::is_none")]
     pub signature: Option<ImageSignature>,
+    /// Alternate locations serving the same image, such as registry mirrors or
+    /// a local OCI directory; if fetching the image fails, these are tried in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<ImageSource>,
+}
+
+/// An alternate location to fetch an image from
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageSource {
+    /// The container image reference
+    pub image: String,
+    /// The container image transport
+    pub transport: String,
 }
 
 /// The status of the booted image
@@ -101,6 +116,17 @@ pub struct ImageStatus {
     pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
     /// The digest of the fetched image (e.g. sha256:a0...);
     pub image_digest: String,
+    /// The kernel version in the image (from the `ostree.linux` label), if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    /// The changelog of the image (from the `containers.bootc.changelog` manifest
+    /// annotation or label), if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changelog: Option<String>,
+    /// The mirror the image was fetched from, if it was not fetched from the
+    /// image reference itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched_from: Option<ImageSource>,
 }
 
 /// A bootable entry
@@ -130,6 +156,20 @@ pub struct BootEntry {
     pub store: Option<Store>,
     /// If this boot entry is ostree based, the corresponding state
     pub ostree: Option<BootEntryOstree>,
+    /// The logically bound images referenced by this entry; only included
+    /// in format version 2 and newer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bound_images: Option<Vec<BoundImageStatus>>,
+}
+
+/// A logically bound image referenced by a boot entry
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundImageStatus {
+    /// The container image reference
+    pub image: String,
+    /// The manifest digest of the image in the bootc storage, if it is present
+    pub image_digest: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -159,6 +199,72 @@ pub struct HostStatus {
     /// The detected type of system
     #[serde(rename = "type")]
     pub ty: Option<HostType>,
+
+    /// Set if the system automatically rolled back because the previously
+    /// queued deployment failed to boot successfully.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub automatic_rollback: Option<AutomaticRollback>,
+
+    /// Set if a transient writable overlay is mounted on `/usr` (via `bootc usr-overlay`);
+    /// changes made to `/usr` will be discarded on reboot.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub usr_overlay: bool,
+
+    /// If set, a reboot into the staged deployment is scheduled at this time,
+    /// e.g. in the next maintenance window.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub planned_reboot: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// The format version of the status; this is unset for the original
+    /// format (version 1).  The fields below are only included in format
+    /// version 2 and newer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format_version: Option<u32>,
+
+    /// Whether there is a bootc compatible rollback deployment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollback_available: Option<bool>,
+
+    /// The state of the storage holding deployments and images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageStatus>,
+}
+
+/// The state of the storage holding deployments and images
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStatus {
+    /// The total size of the filesystem in bytes
+    pub total_bytes: u64,
+    /// The number of bytes in use
+    pub used_bytes: u64,
+    /// The number of bytes available to unprivileged users
+    pub available_bytes: u64,
+    /// The result of the most recent `bootc image check`, if any
+    pub last_check: Option<StorageCheck>,
+}
+
+/// The result of a storage consistency check
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageCheck {
+    /// When the check was performed
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Whether the check (and repair, if requested) succeeded
+    pub success: bool,
+    /// Whether a repair was requested
+    pub repair: bool,
+}
+
+/// Information about an automatic rollback, performed because a deployment
+/// exhausted its boot attempts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AutomaticRollback {
+    /// When the rollback was performed
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// The digest of the image which failed to boot, if known
+    pub failed_image_digest: Option<String>,
 }
 
 impl Host {
@@ -180,6 +286,31 @@ impl Host {
     }
 }
 
+impl k8sapitypes::TypedResource for Host {
+    const API_VERSION: &'static str = API_VERSION;
+    const KIND: &'static str = KIND;
+
+    fn resource(&self) -> &k8sapitypes::Resource {
+        &self.resource
+    }
+}
+
+impl Host {
+    /// Parse a host from a YAML (or JSON) document, rejecting unknown fields
+    /// and values of the wrong type; errors include the path to the offending
+    /// field.  The status is read-only and only needs to be well-formed.
+    pub(crate) fn from_str_strict(s: &str) -> anyhow::Result<Self> {
+        // Because of the flattened resource, errors from parsing the whole
+        // document lose their path; so parse the spec on its own first.
+        let input: serde_yaml::Value = serde_yaml::from_str(s)?;
+        if let Some(spec) = input.get("spec").filter(|v| !v.is_null()) {
+            let spec = serde_yaml::to_string(spec)?;
+            serde_yaml::from_str::<HostSpec>(&spec).context("spec")?;
+        }
+        k8sapitypes::from_str_strict(s, &["status"])
+    }
+}
+
 impl Default for Host {
     fn default() -> Self {
         Self::new(Default::default())
@@ -240,6 +371,72 @@ mod tests {
         assert_eq!(host.resource.api_version, "org.containers.bootc/v1");
     }
 
+    #[test]
+    fn test_host_from_str_strict() {
+        let host = Host::default();
+        let mut doc = serde_json::to_value(&host).unwrap();
+        let patch = serde_json::json!({
+            "spec": {"image": {"image": "quay.io/example/os:latest", "transport": "registry"}},
+            "status": {"unknownStatus": true},
+        });
+        k8sapitypes::merge_patch(&mut doc, &patch);
+        let new_host = Host::from_str_strict(&doc.to_string()).unwrap();
+        assert_eq!(
+            new_host.spec.image.as_ref().unwrap().image,
+            "quay.io/example/os:latest"
+        );
+        assert_eq!(new_host.status, host.status);
+
+        let patch = serde_json::json!({"spec": {"image": {"imag": "quay.io/example/os"}}});
+        let mut doc = serde_json::to_value(&host).unwrap();
+        k8sapitypes::merge_patch(&mut doc, &patch);
+        assert!(Host::from_str_strict(&doc.to_string()).is_err());
+
+        let patch = serde_json::json!({"spec": {"bootOrder": "sideways"}});
+        let mut doc = serde_json::to_value(&host).unwrap();
+        k8sapitypes::merge_patch(&mut doc, &patch);
+        let e = format!("{:#}", Host::from_str_strict(&doc.to_string()).unwrap_err());
+        assert!(e.starts_with("spec: bootOrder: unknown variant"), "{e}");
+
+        let patch = serde_json::json!({"spec": {"image": null, "imageUrl": "quay.io/example/os"}});
+        let mut doc = serde_json::to_value(&host).unwrap();
+        k8sapitypes::merge_patch(&mut doc, &patch);
+        let e = Host::from_str_strict(&doc.to_string())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "Unknown fields: spec.imageUrl");
+    }
+
+    #[test]
+    fn test_parse_mirrors() {
+        let src = indoc::indoc! { r#"
+            apiVersion: org.containers.bootc/v1
+            kind: BootcHost
+            metadata:
+              name: host
+            spec:
+              image:
+                image: quay.io/example/os:latest
+                transport: registry
+                mirrors:
+                  - image: mirror.example.com/os:latest
+                    transport: registry
+                  - image: /var/mirror/os
+                    transport: oci
+        "# };
+        let host = Host::from_str_strict(src).unwrap();
+        let image = host.spec.image.as_ref().unwrap();
+        assert_eq!(image.mirrors.len(), 2);
+        assert_eq!(image.mirrors[1].transport, "oci");
+        // Mirrors are omitted when empty
+        let image = ImageReference {
+            mirrors: Vec::new(),
+            ..image.clone()
+        };
+        let v = serde_json::to_value(&image).unwrap();
+        assert!(v.get("mirrors").is_none());
+    }
+
     #[test]
     fn test_parse_spec_v1a1_orig() {
         const SPEC_FIXTURE: &str = include_str!("fixtures/spec-v1a1-orig.yaml");
@@ -261,6 +458,31 @@ mod tests {
         assert_eq!(host.spec.image.as_ref().unwrap().signature, None);
     }
 
+    #[test]
+    fn test_parse_spec_v2() {
+        const SPEC_FIXTURE: &str = include_str!("fixtures/spec-v2.yaml");
+        let host: Host = serde_yaml::from_str(SPEC_FIXTURE).unwrap();
+        assert_eq!(host.status.format_version, Some(2));
+        assert_eq!(host.status.rollback_available, Some(false));
+        let bound = host.status.booted.as_ref().unwrap().bound_images.as_ref();
+        assert_eq!(bound.unwrap().len(), 2);
+        let storage = host.status.storage.as_ref().unwrap();
+        assert!(storage.last_check.as_ref().unwrap().success);
+
+        // The original format does not include any of the new fields
+        let mut host = host;
+        host.status.format_version = None;
+        host.status.rollback_available = None;
+        host.status.storage = None;
+        host.status.booted.as_mut().unwrap().bound_images = None;
+        let v = serde_json::to_value(&host).unwrap();
+        let status = v.get("status").unwrap();
+        for k in ["formatVersion", "rollbackAvailable", "storage"] {
+            assert!(status.get(k).is_none(), "{k}");
+        }
+        assert!(status["booted"].get("boundImages").is_none());
+    }
+
     #[test]
     fn test_parse_ostreeremote() {
         const SPEC_FIXTURE: &str = include_str!("fixtures/spec-ostree-remote.yaml");
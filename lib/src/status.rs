@@ -5,18 +5,22 @@ use std::io::Read;
 use std::io::Write;
 
 use anyhow::{Context, Result};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
 use fn_error_context::context;
 use ostree::glib;
 use ostree_container::OstreeImageReference;
 use ostree_ext::container as ostree_container;
 use ostree_ext::container_utils::ostree_booted;
-use ostree_ext::keyfileext::KeyFileExt;
+use ostree_ext::keyfileext::{map_keyfile_optional, KeyFileExt};
 use ostree_ext::oci_spec;
 use ostree_ext::ostree;
+use ostree_ext::sysroot::LockMode;
 
 use crate::cli::OutputFormat;
 use crate::spec::{BootEntry, BootOrder, Host, HostSpec, HostStatus, HostType};
-use crate::spec::{ImageReference, ImageSignature};
+use crate::spec::{BoundImageStatus, StorageStatus};
+use crate::spec::{ImageReference, ImageSignature, ImageSource};
 use crate::store::{CachedImageStatus, ContainerImageStore, Storage};
 
 impl From<ostree_container::SignatureSource> for ImageSignature {
@@ -64,10 +68,31 @@ impl From<OstreeImageReference> for ImageReference {
             signature,
             transport: transport_to_string(imgref.imgref.transport),
             image: imgref.imgref.name,
+            mirrors: Vec::new(),
         }
     }
 }
 
+impl From<ostree_container::ImageReference> for ImageSource {
+    fn from(imgref: ostree_container::ImageReference) -> Self {
+        Self {
+            transport: transport_to_string(imgref.transport),
+            image: imgref.name,
+        }
+    }
+}
+
+impl TryFrom<&ImageSource> for ostree_container::ImageReference {
+    type Error = anyhow::Error;
+
+    fn try_from(src: &ImageSource) -> Result<Self> {
+        Ok(Self {
+            transport: ostree_container::Transport::try_from(src.transport.as_str())?,
+            name: src.image.clone(),
+        })
+    }
+}
+
 impl From<ImageReference> for OstreeImageReference {
     fn from(img: ImageReference) -> Self {
         let sigverify = match img.signature {
@@ -87,7 +112,7 @@ impl From<ImageReference> for OstreeImageReference {
 
 /// Parse an ostree origin file (a keyfile) and extract the targeted
 /// container image reference.
-fn get_image_origin(origin: &glib::KeyFile) -> Result<Option<OstreeImageReference>> {
+pub(crate) fn get_image_origin(origin: &glib::KeyFile) -> Result<Option<OstreeImageReference>> {
     origin
         .optional_string("origin", ostree_container::deploy::ORIGIN_CONTAINER)
         .context("Failed to load container image from origin")?
@@ -95,6 +120,28 @@ fn get_image_origin(origin: &glib::KeyFile) -> Result<Option<OstreeImageReferenc
         .transpose()
 }
 
+/// Parse an image source stored in an origin file.
+fn parse_origin_source(s: &str) -> Result<ImageSource> {
+    let imgref = ostree_container::ImageReference::try_from(s)
+        .with_context(|| format!("Parsing image source {s}"))?;
+    Ok(imgref.into())
+}
+
+/// Parse the mirrors of the image, and the mirror it was fetched from (if
+/// any), from an ostree origin file.
+fn get_image_sources(origin: &glib::KeyFile) -> Result<(Vec<ImageSource>, Option<ImageSource>)> {
+    let mirrors = map_keyfile_optional(origin.string_list("bootc", crate::deploy::ORIGIN_MIRRORS))?
+        .unwrap_or_default()
+        .iter()
+        .map(|v| parse_origin_source(v.as_str()))
+        .collect::<Result<Vec<_>>>()?;
+    let fetched_from = origin
+        .optional_string("bootc", crate::deploy::ORIGIN_FETCHED_FROM)?
+        .map(|v| parse_origin_source(&v))
+        .transpose()?;
+    Ok((mirrors, fetched_from))
+}
+
 pub(crate) struct Deployments {
     pub(crate) staged: Option<ostree::Deployment>,
     pub(crate) rollback: Option<ostree::Deployment>,
@@ -141,7 +188,15 @@ fn boot_entry_from_deployment(
             let store = deployment.store()?;
             let store = store.as_ref().unwrap_or(&sysroot.store);
             let spec = Some(store.spec());
-            let status = store.imagestatus(sysroot, deployment, image)?;
+            let mut status = store.imagestatus(sysroot, deployment, image)?;
+            let (mirrors, fetched_from) = get_image_sources(origin)?;
+            if let Some(image) = status.image.as_mut() {
+                image.image.mirrors = mirrors.clone();
+                image.fetched_from = fetched_from;
+            }
+            if let Some(cached) = status.cached_update.as_mut() {
+                cached.image.mirrors = mirrors;
+            }
 
             (spec, status)
         } else {
@@ -165,6 +220,7 @@ fn boot_entry_from_deployment(
             // SAFETY: The deployserial is really unsigned
             deploy_serial: deployment.deployserial().try_into().unwrap(),
         }),
+        bound_images: None,
     };
     Ok(r)
 }
@@ -282,26 +338,194 @@ pub(crate) fn get_status(
         rollback,
         rollback_queued,
         ty,
+        automatic_rollback: None,
+        usr_overlay: false,
+        planned_reboot: None,
+        format_version: None,
+        rollback_available: None,
+        storage: None,
     };
     Ok((deployments, host))
 }
 
+/// Query the logically bound images of a deployment, along with their digests
+/// in the bootc storage.
+fn bound_images_status(
+    sysroot: &Storage,
+    deployment: &ostree::Deployment,
+) -> Result<Vec<BoundImageStatus>> {
+    let images = crate::boundimage::query_bound_images_for_deployment(sysroot, deployment)?;
+    if images.is_empty() {
+        return Ok(Vec::new());
+    }
+    // We only hold a shared lock, so don't initialize the storage; if it
+    // doesn't exist yet, no image has been fetched.
+    let imgstore = sysroot.get_imgstore_if_exists()?;
+    let r = images
+        .into_iter()
+        .map(|img| {
+            let image_digest = match imgstore.map(|s| s.inspect(&img.image)) {
+                Some(Ok(inspected)) => inspected.digest,
+                Some(Err(e)) => {
+                    tracing::debug!("{e:#}");
+                    None
+                }
+                None => None,
+            };
+            BoundImageStatus {
+                image: img.image,
+                image_digest,
+            }
+        })
+        .collect();
+    Ok(r)
+}
+
+/// Compute the usage of the filesystem holding the sysroot.
+#[context("Querying storage")]
+fn storage_status(sysroot: &Storage) -> Result<StorageStatus> {
+    let sysroot_fd = crate::utils::sysroot_fd(sysroot);
+    let st = rustix::fs::fstatvfs(sysroot_fd)?;
+    let last_check = crate::imgstorage::read_check_record(&Dir::reopen_dir(&sysroot_fd)?)?;
+    Ok(StorageStatus {
+        total_bytes: st.f_blocks * st.f_frsize,
+        used_bytes: st.f_blocks.saturating_sub(st.f_bfree) * st.f_frsize,
+        available_bytes: st.f_bavail * st.f_frsize,
+        last_check,
+    })
+}
+
+/// Add the data which is only included in format version 2 and newer.
+#[context("Computing extended status")]
+fn extend_status_v2(
+    sysroot: &Storage,
+    deployments: &Deployments,
+    booted_deployment: Option<&ostree::Deployment>,
+    host: &mut Host,
+) -> Result<()> {
+    let status = &mut host.status;
+    status.format_version = Some(2);
+    for (entry, deployment) in [
+        (status.staged.as_mut(), deployments.staged.as_ref()),
+        (status.booted.as_mut(), booted_deployment),
+        (status.rollback.as_mut(), deployments.rollback.as_ref()),
+    ] {
+        let (Some(entry), Some(deployment)) = (entry, deployment) else {
+            continue;
+        };
+        entry.bound_images = Some(bound_images_status(sysroot, deployment)?);
+    }
+    status.rollback_available = Some(
+        status
+            .rollback
+            .as_ref()
+            .is_some_and(|r| r.image.is_some() && !r.incompatible),
+    );
+    status.storage = Some(storage_status(sysroot)?);
+    Ok(())
+}
+
+/// Gather the full status of the host, in the provided format version; if
+/// not booted via ostree, the default (empty) status is returned.
+pub(crate) async fn get_host(format_version: u32) -> Result<Host> {
+    if !ostree_booted()? {
+        return Ok(Default::default());
+    }
+    let sysroot = super::cli::get_storage(LockMode::Shared).await?;
+    let booted_deployment = sysroot.booted_deployment();
+    let (deployments, mut host) = get_status(&sysroot, booted_deployment.as_ref())?;
+    let rootfs = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    host.status.automatic_rollback = crate::bootcount::read_rollback_record(&rootfs)?;
+    host.status.usr_overlay = crate::usroverlay::is_active(&rootfs)?;
+    if host.status.staged.is_some() {
+        host.status.planned_reboot = crate::maintenance::planned_reboot(&rootfs)?;
+    }
+    if format_version >= 2 {
+        extend_status_v2(
+            &sysroot,
+            &deployments,
+            booted_deployment.as_ref(),
+            &mut host,
+        )?;
+    }
+    Ok(host)
+}
+
+/// Exit status of a status query when the queried image is not present.
+const EXIT_NOT_PRESENT: i32 = 1;
+/// Exit status of a status query when the system is not booted via bootc.
+const EXIT_NOT_BOOTC: i32 = 77;
+
+/// A query for a single image, as made by scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Query {
+    Booted,
+    Staged,
+    UpdateAvailable,
+}
+
+impl Query {
+    fn from_opts(opts: &super::cli::StatusOpts) -> Option<Self> {
+        // With an output format, `--booted` instead filters the status
+        if opts.booted && !opts.json && opts.format.is_none() {
+            Some(Self::Booted)
+        } else if opts.staged {
+            Some(Self::Staged)
+        } else if opts.update_available {
+            Some(Self::UpdateAvailable)
+        } else {
+            None
+        }
+    }
+}
+
+/// Find the image answering the query, or the exit status if there is none.
+fn query_image(host: &Host, query: Query) -> std::result::Result<&crate::spec::ImageStatus, i32> {
+    let status = &host.status;
+    let booted_entry = status.booted.as_ref();
+    let Some(booted) = booted_entry.and_then(|b| b.image.as_ref()) else {
+        return Err(EXIT_NOT_BOOTC);
+    };
+    let staged = status.staged.as_ref().and_then(|s| s.image.as_ref());
+    let image = match query {
+        Query::Booted => Some(booted),
+        Query::Staged => staged,
+        Query::UpdateAvailable => booted_entry
+            .and_then(|b| b.cached_update.as_ref())
+            .filter(|u| u.image_digest != booted.image_digest)
+            .filter(|u| staged.map(|s| &s.image_digest) != Some(&u.image_digest)),
+    };
+    image.ok_or(EXIT_NOT_PRESENT)
+}
+
 /// Implementation of the `bootc status` CLI command.
 #[context("Status")]
 pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
-    match opts.format_version.unwrap_or_default() {
-        // For historical reasons, both 0 and 1 mean "v1".
-        0 | 1 => {}
+    let format_version = opts.format_version.unwrap_or_default();
+    match format_version {
+        // For historical reasons, both 0 and 1 mean "v1"; version 2 is a superset.
+        0..=2 => {}
         o => anyhow::bail!("Unsupported format version: {o}"),
     };
-    let host = if !ostree_booted()? {
-        Default::default()
-    } else {
-        let sysroot = super::cli::get_storage().await?;
-        let booted_deployment = sysroot.booted_deployment();
-        let (_deployments, host) = get_status(&sysroot, booted_deployment.as_ref())?;
-        host
-    };
+    let mut host = get_host(format_version).await?;
+
+    if let Some(query) = Query::from_opts(&opts) {
+        match query_image(&host, query) {
+            Ok(image) => {
+                let imageref = display_imageref(&image.image.transport, &image.image.image);
+                println!("{imageref} {}", image.image_digest);
+            }
+            Err(code) => {
+                tracing::debug!("No image for {query:?}");
+                return Err(crate::cli::ExitStatus(code).into());
+            }
+        }
+        return Ok(());
+    }
+    if opts.booted {
+        host.status.staged = None;
+        host.status.rollback = None;
+    }
 
     // If we're in JSON mode, then convert the ostree data into Rust-native
     // structures that can be serialized.
@@ -355,24 +579,38 @@ fn write_row_name(mut out: impl Write, s: &str, prefix_len: usize) -> Result<()>
 
 /// Write the data for a container image based status.
 fn human_render_imagestatus(
-    mut out: impl Write,
+    out: impl Write,
     slot: Slot,
     image: &crate::spec::ImageStatus,
 ) -> Result<()> {
-    let transport = &image.image.transport;
-    let imagename = &image.image.image;
-    // Registry is the default, so don't show that
-    let imageref = if transport == "registry" {
-        Cow::Borrowed(imagename)
-    } else {
-        // But for non-registry we include the transport
-        Cow::Owned(format!("{transport}:{imagename}"))
-    };
     let prefix = match slot {
         Slot::Staged => "  Staged image".into(),
         Slot::Booted => format!("{} Booted image", crate::glyph::Glyph::BlackCircle),
         Slot::Rollback => "  Rollback image".into(),
     };
+    human_render_image(out, &prefix, image, false)
+}
+
+/// Format an image reference for human readable output.
+fn display_imageref<'a>(transport: &str, imagename: &'a str) -> Cow<'a, str> {
+    // Registry is the default, so don't show that
+    if transport == "registry" {
+        Cow::Borrowed(imagename)
+    } else {
+        // But for non-registry we include the transport
+        Cow::Owned(format!("{transport}:{imagename}"))
+    }
+}
+
+/// Write the data for a container image, with the provided row title; the
+/// changelog is only included if requested, as it may be long.
+fn human_render_image(
+    mut out: impl Write,
+    prefix: &str,
+    image: &crate::spec::ImageStatus,
+    changelog: bool,
+) -> Result<()> {
+    let imageref = display_imageref(&image.image.transport, &image.image.image);
     let prefix_len = prefix.chars().count();
     writeln!(out, "{prefix}: {imageref}")?;
 
@@ -395,9 +633,45 @@ fn human_render_imagestatus(
         writeln!(out, "{timestamp}")?;
     }
 
+    if let Some(kernel) = image.kernel.as_deref() {
+        write_row_name(&mut out, "Kernel", prefix_len)?;
+        writeln!(out, "{kernel}")?;
+    }
+
+    if let Some(source) = image.fetched_from.as_ref() {
+        write_row_name(&mut out, "Fetched from", prefix_len)?;
+        writeln!(
+            out,
+            "{}",
+            display_imageref(&source.transport, &source.image)
+        )?;
+    }
+
+    if let Some(text) = image.changelog.as_deref().filter(|_| changelog) {
+        write_row_name(&mut out, "Changelog", prefix_len)?;
+        for (i, line) in text.trim_end().lines().enumerate() {
+            if i > 0 {
+                write!(out, "{:width$}", "", width = prefix_len + 2)?;
+            }
+            writeln!(out, "{line}")?;
+        }
+    }
+
     Ok(())
 }
 
+/// Return the update metadata cached by e.g. `bootc upgrade --check`, if that
+/// image is neither booted nor staged.
+fn available_update(host: &Host) -> Option<&crate::spec::ImageStatus> {
+    let update = host.status.booted.as_ref()?.cached_update.as_ref()?;
+    let present = [&host.status.booted, &host.status.staged]
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.image.as_ref())
+        .any(|image| image.image_digest == update.image_digest);
+    (!present).then_some(update)
+}
+
 fn human_render_ostree(mut out: impl Write, slot: Slot, ostree_commit: &str) -> Result<()> {
     // TODO consider rendering more ostree stuff here like rpm-ostree status does
     let prefix = match slot {
@@ -434,12 +708,45 @@ fn human_readable_output_booted(mut out: impl Write, host: &Host) -> Result<()>
             }
         }
     }
+    if let Some(update) = available_update(host) {
+        writeln!(out)?;
+        human_render_image(&mut out, "  Available update", update, true)?;
+    }
     Ok(())
 }
 
 /// Implementation of rendering our host structure in a "human readable" way.
 fn human_readable_output(mut out: impl Write, host: &Host) -> Result<()> {
     if host.status.booted.is_some() {
+        if let Some(rollback) = host.status.automatic_rollback.as_ref() {
+            let failed = rollback
+                .failed_image_digest
+                .as_deref()
+                .unwrap_or("unknown image");
+            writeln!(
+                out,
+                "Automatic rollback from {failed} at {}",
+                rollback
+                    .timestamp
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            )?;
+            writeln!(out)?;
+        }
+        if host.status.usr_overlay {
+            writeln!(
+                out,
+                "Note: /usr is unlocked; changes will be discarded on reboot"
+            )?;
+            writeln!(out)?;
+        }
+        if let Some(planned) = host.status.planned_reboot.as_ref() {
+            writeln!(
+                out,
+                "Reboot into the staged image planned at {}",
+                planned.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            )?;
+            writeln!(out)?;
+        }
         human_readable_output_booted(out, host)?;
     } else {
         writeln!(out, "System is not deployed via bootc.")?;
@@ -522,6 +829,121 @@ mod tests {
         similar_asserts::assert_eq!(w, expected);
     }
 
+    #[test]
+    fn test_human_readable_available_update() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-only-booted.yaml")).unwrap();
+        let booted = host.status.booted.as_mut().unwrap();
+        let image = booted.image.as_mut().unwrap();
+        image.kernel = Some("5.14.0-480.el9.x86_64".into());
+        let mut update = image.clone();
+        update.image_digest =
+            "sha256:5fa5b0ea5ca2d4a1a94df5e0cbb4ee5b3e3d2bcd0b8d23dcd7f0b1af1e0e1c05".into();
+        update.version = Some("stream9.20240814.0".into());
+        update.kernel = Some("5.14.0-494.el9.x86_64".into());
+        update.changelog = Some("- Update kernel\n- Fix foo\n".into());
+        booted.cached_update = Some(update);
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        let expected = indoc::indoc! { r"
+          ● Booted image: quay.io/centos-bootc/centos-bootc:stream9
+                  Digest: sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38
+                 Version: stream9.20240807.0
+                  Kernel: 5.14.0-480.el9.x86_64
+
+          Available update: quay.io/centos-bootc/centos-bootc:stream9
+                    Digest: sha256:5fa5b0ea5ca2d4a1a94df5e0cbb4ee5b3e3d2bcd0b8d23dcd7f0b1af1e0e1c05
+                   Version: stream9.20240814.0
+                    Kernel: 5.14.0-494.el9.x86_64
+                 Changelog: - Update kernel
+                            - Fix foo
+        "};
+        similar_asserts::assert_eq!(w, expected);
+
+        // Once the update is staged, it's no longer shown
+        host.status.staged = host.status.booted.clone().map(|mut staged| {
+            staged.image = staged.cached_update.take();
+            staged
+        });
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(!w.contains("Available update"));
+    }
+
+    #[test]
+    fn test_query_image() {
+        let host: Host = Default::default();
+        for query in [Query::Booted, Query::Staged, Query::UpdateAvailable] {
+            assert_eq!(query_image(&host, query), Err(EXIT_NOT_BOOTC));
+        }
+        // Booted via ostree, but not a container image
+        let host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-ostree-to-bootc.yaml")).unwrap();
+        assert_eq!(query_image(&host, Query::Booted), Err(EXIT_NOT_BOOTC));
+
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        let booted = host.status.booted.as_ref().unwrap().image.clone().unwrap();
+        let staged = host.status.staged.as_ref().unwrap().image.clone().unwrap();
+        assert_eq!(query_image(&host, Query::Booted), Ok(&booted));
+        assert_eq!(query_image(&host, Query::Staged), Ok(&staged));
+        assert_eq!(
+            query_image(&host, Query::UpdateAvailable),
+            Err(EXIT_NOT_PRESENT)
+        );
+        // An update which was found by a check, and is already staged
+        host.status.booted.as_mut().unwrap().cached_update = Some(staged.clone());
+        assert_eq!(
+            query_image(&host, Query::UpdateAvailable),
+            Err(EXIT_NOT_PRESENT)
+        );
+        host.status.staged = None;
+        assert_eq!(query_image(&host, Query::Staged), Err(EXIT_NOT_PRESENT));
+        assert_eq!(query_image(&host, Query::UpdateAvailable), Ok(&staged));
+    }
+
+    #[test]
+    fn test_human_readable_usr_overlay() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-only-booted.yaml")).unwrap();
+        host.status.usr_overlay = true;
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        let expected = indoc::indoc! { r"
+          Note: /usr is unlocked; changes will be discarded on reboot
+
+          ● Booted image: quay.io/centos-bootc/centos-bootc:stream9
+                  Digest: sha256:47e5ed613a970b6574bfa954ab25bb6e85656552899aa518b5961d9645102b38
+                 Version: stream9.20240807.0
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
+    #[test]
+    fn test_human_readable_planned_reboot() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        host.status.planned_reboot = Some("2026-10-17T02:00:00Z".parse().unwrap());
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        let expected = indoc::indoc! { r"
+          Reboot into the staged image planned at 2026-10-17T02:00:00Z
+
+            Staged image: quay.io/example/someimage:latest
+                  Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566
+                 Version: nightly (2023-10-14 19:22:15 UTC)
+        
+          ● Booted image: quay.io/example/someimage:latest
+                  Digest: sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34
+                 Version: nightly (2023-09-30 19:22:16 UTC)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
     #[test]
     fn test_human_readable_staged_rollback_spec() {
         // staged/rollback image, no booted
@@ -543,6 +965,43 @@ mod tests {
         similar_asserts::assert_eq!(w, expected);
     }
 
+    #[test]
+    fn test_image_sources() {
+        let origin = glib::KeyFile::new();
+        assert_eq!(get_image_sources(&origin).unwrap(), (Vec::new(), None));
+        origin.set_string_list(
+            "bootc",
+            crate::deploy::ORIGIN_MIRRORS,
+            &[
+                "docker://mirror.example.com/os:latest",
+                "oci:/var/mirror/os",
+            ],
+        );
+        origin.set_string(
+            "bootc",
+            crate::deploy::ORIGIN_FETCHED_FROM,
+            "oci:/var/mirror/os",
+        );
+        let (mirrors, fetched_from) = get_image_sources(&origin).unwrap();
+        let oci = ImageSource {
+            image: "/var/mirror/os".into(),
+            transport: "oci".into(),
+        };
+        assert_eq!(
+            mirrors,
+            [
+                ImageSource {
+                    image: "mirror.example.com/os:latest".into(),
+                    transport: "registry".into(),
+                },
+                oci.clone()
+            ]
+        );
+        assert_eq!(fetched_from, Some(oci.clone()));
+        let imgref = ostree_container::ImageReference::try_from(&oci).unwrap();
+        assert_eq!(imgref.to_string(), "oci:/var/mirror/os");
+    }
+
     #[test]
     fn test_convert_signatures() {
         use std::str::FromStr;
@@ -82,6 +82,20 @@ impl Storage {
         let imgstore = crate::imgstorage::Storage::create(&sysroot_dir, &self.run)?;
         Ok(self.imgstore.get_or_init(|| imgstore))
     }
+
+    /// Access the image storage if it has been initialized; unlike
+    /// [`Self::get_ensure_imgstore`], this is safe with only a shared lock.
+    pub(crate) fn get_imgstore_if_exists(&self) -> Result<Option<&crate::imgstorage::Storage>> {
+        if let Some(imgstore) = self.imgstore.get() {
+            return Ok(Some(imgstore));
+        }
+        let sysroot_dir = Dir::reopen_dir(&crate::utils::sysroot_fd(&self.sysroot))?;
+        if !sysroot_dir.try_exists(crate::imgstorage::SUBPATH)? {
+            return Ok(None);
+        }
+        let imgstore = crate::imgstorage::Storage::open(&sysroot_dir, &self.run)?;
+        Ok(Some(self.imgstore.get_or_init(|| imgstore)))
+    }
 }
 
 impl ContainerImageStore for ostree::Deployment {
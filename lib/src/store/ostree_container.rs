@@ -2,7 +2,7 @@ use anyhow::{Context, Result};
 
 use ostree_ext::container as ostree_container;
 use ostree_ext::oci_spec;
-use ostree_ext::oci_spec::image::{Digest, ImageConfiguration};
+use ostree_ext::oci_spec::image::{Digest, ImageConfiguration, ImageManifest};
 use ostree_ext::ostree;
 use ostree_ext::sysroot::SysrootLock;
 
@@ -27,10 +27,19 @@ impl super::ContainerImageStoreImpl for OstreeContainerStore {
         let csum = deployment.csum();
         let imgstate = ostree_container::store::query_image_commit(repo, &csum)?;
         let cached = imgstate.cached_update.map(|cached| {
-            create_imagestatus(image.clone(), &cached.manifest_digest, &cached.config)
+            create_imagestatus(
+                image.clone(),
+                &cached.manifest_digest,
+                &cached.manifest,
+                &cached.config,
+            )
         });
-        let imagestatus =
-            create_imagestatus(image, &imgstate.manifest_digest, &imgstate.configuration);
+        let imagestatus = create_imagestatus(
+            image,
+            &imgstate.manifest_digest,
+            &imgstate.manifest,
+            &imgstate.configuration,
+        );
 
         Ok(CachedImageStatus {
             image: Some(imagestatus),
@@ -43,6 +52,7 @@ impl super::ContainerImageStoreImpl for OstreeContainerStore {
 fn create_imagestatus(
     image: ImageReference,
     manifest_digest: &Digest,
+    manifest: &ImageManifest,
     config: &ImageConfiguration,
 ) -> ImageStatus {
     let labels = labels_of_config(config);
@@ -55,11 +65,16 @@ fn create_imagestatus(
         .and_then(try_deserialize_timestamp);
 
     let version = ostree_container::version_for_config(config).map(ToOwned::to_owned);
+    let kernel = crate::metadata::image_kernel(config).map(ToOwned::to_owned);
+    let changelog = crate::metadata::image_changelog(manifest, config).map(ToOwned::to_owned);
     ImageStatus {
         image,
         version,
         timestamp,
         image_digest: manifest_digest.to_string(),
+        kernel,
+        changelog,
+        fetched_from: None,
     }
 }
 
@@ -0,0 +1,83 @@
+//! # Transient writable `/usr`
+//!
+//! `bootc usr-overlay` mounts a writable overlayfs on top of `/usr` of the
+//! booted deployment, with the upper directory on a tmpfs.  The state lives
+//! under `/run`, so both the overlay and any changes made in it are discarded
+//! on reboot.
+//!
+//! The deployment is also marked as unlocked in the runtime state of ostree,
+//! as `ostree admin unlock` would do, so that ostree is aware of the overlay.
+
+use anyhow::Result;
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::{gio, ostree};
+use rustix::fs::AtFlags;
+
+/// The mount point of the tmpfs holding the overlay upper and work directories.
+const STATE_DIR: &str = "/run/bootc/usr-overlay";
+/// The mounted directory.
+const USR: &str = "/usr";
+/// The runtime state of ostree deployments, relative to the root.
+const OSTREE_DEPLOYMENT_STATE: &str = "run/ostree/deployment-state";
+/// The flag file in the runtime state of a deployment with which ostree tracks
+/// that the deployment is unlocked in development mode.
+const OSTREE_UNLOCKED_DEVELOPMENT: &str = "unlocked-development";
+
+/// Returns true if a transient overlay is mounted on `/usr` in the provided root.
+pub(crate) fn is_active(root: &Dir) -> Result<bool> {
+    let upper = Utf8Path::new(STATE_DIR).join("upper");
+    if !root.try_exists(upper.as_str().trim_start_matches('/'))? {
+        return Ok(false);
+    }
+    // The overlay may have been (lazily) unmounted, leaving the state behind; in
+    // that case /usr is on the same filesystem as the root again.
+    let root_st = rustix::fs::fstat(root)?;
+    let usr_st = rustix::fs::statat(root, "usr", AtFlags::empty())?;
+    Ok(root_st.st_dev != usr_st.st_dev)
+}
+
+/// Record the deployment as unlocked in development mode, in the runtime state of ostree.
+#[context("Marking deployment {deployment_id} as unlocked")]
+fn mark_unlocked(root: &Dir, deployment_id: &str) -> Result<()> {
+    let dir = format!("{OSTREE_DEPLOYMENT_STATE}/{deployment_id}");
+    root.create_dir_all(&dir)?;
+    root.atomic_write(format!("{dir}/{OSTREE_UNLOCKED_DEVELOPMENT}"), "")?;
+    Ok(())
+}
+
+/// Implementation of `bootc usr-overlay`.
+#[context("Mounting /usr overlay")]
+pub(crate) fn usroverlay(root: &Dir) -> Result<()> {
+    if is_active(root)? {
+        println!("A transient overlay is already mounted on {USR}");
+        return Ok(());
+    }
+    let sysroot = ostree::Sysroot::new_default();
+    sysroot.load(gio::Cancellable::NONE)?;
+    let booted = sysroot.require_booted_deployment()?;
+    let deployment_id = format!("{}.{}", booted.csum(), booted.deployserial());
+    crate::mount::mount_transient_overlay(USR.into(), STATE_DIR.into())?;
+    mark_unlocked(root, &deployment_id)?;
+    println!("Mounted a transient writable overlay on {USR}; changes will be discarded on reboot");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std;
+
+    use super::*;
+
+    #[test]
+    fn test_mark_unlocked() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        mark_unlocked(td, "abcd.0")?;
+        assert!(td.try_exists("run/ostree/deployment-state/abcd.0/unlocked-development")?);
+        // This is idempotent
+        mark_unlocked(td, "abcd.0")?;
+        Ok(())
+    }
+}
@@ -50,21 +50,6 @@ pub(crate) fn deployment_fd(
     sysroot_dir.open_dir(&dirpath).map_err(Into::into)
 }
 
-/// Given an mount option string list like foo,bar=baz,something=else,ro parse it and find
-/// the first entry like $optname=
-/// This will not match a bare `optname` without an equals.
-#[cfg(feature = "install")]
-pub(crate) fn find_mount_option<'a>(
-    option_string_list: &'a str,
-    optname: &'_ str,
-) -> Option<&'a str> {
-    option_string_list
-        .split(',')
-        .filter_map(|k| k.split_once('='))
-        .filter_map(|(k, v)| (k == optname).then_some(v))
-        .next()
-}
-
 /// Given a target directory, if it's a read-only mount, then remount it writable
 #[context("Opening {target} with writable mount")]
 #[cfg(feature = "install")]
@@ -188,6 +173,12 @@ where
     r
 }
 
+/// Returns true if the binary is found in `$PATH`.
+pub(crate) fn find_in_path(name: &str) -> bool {
+    let path = std::env::var_os("PATH").unwrap_or_else(|| "/usr/bin:/usr/sbin".into());
+    std::env::split_paths(&path).any(|d| d.join(name).is_file())
+}
+
 /// Given a possibly tagged image like quay.io/foo/bar:latest and a digest 0ab32..., return
 /// the digested form quay.io/foo/bar:latest@sha256:0ab32...
 /// If the image already has a digest, it will be replaced.
@@ -214,14 +205,6 @@ fn test_digested_pullspec() {
     );
 }
 
-#[test]
-fn test_find_mount_option() {
-    const V1: &str = "rw,relatime,compress=foo,subvol=blah,fast";
-    assert_eq!(find_mount_option(V1, "subvol").unwrap(), "blah");
-    assert_eq!(find_mount_option(V1, "rw"), None);
-    assert_eq!(find_mount_option(V1, "somethingelse"), None);
-}
-
 #[test]
 fn test_sigpolicy_from_opts() {
     assert_eq!(
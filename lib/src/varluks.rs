@@ -0,0 +1,312 @@
+//! # Encrypted `/var`
+//!
+//! This module places `/var` on a LUKS2 volume whose key is bound to the TPM2
+//! device, for systems that need data-at-rest protection for their local state
+//! but not for the (public) operating system content.  The volume is set up
+//! either at installation time via `bootc install to-disk --encrypt-var`, or
+//! on an installed system via `bootc encrypt-var`.
+//!
+//! At boot, the volume is unlocked via `/etc/crypttab` and mounted via
+//! `/etc/fstab`; ostree skips setting up the stateroot `/var` when it is
+//! a separate mount.
+
+use std::io::Write;
+use std::os::fd::AsRawFd;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use clap::ValueEnum;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use ostree_ext::ostree::gio;
+use ostree_ext::sysroot::LockMode;
+
+use crate::install::baseline::{self, Filesystem};
+use crate::install::MountSpec;
+use crate::task::Task;
+
+/// The name of the device mapper device, i.e. `/dev/mapper/var`.
+pub(crate) const LUKS_NAME: &str = "var";
+/// The filesystem label.
+const LABEL: &str = "var";
+const CRYPTTAB: &str = "etc/crypttab";
+const FSTAB: &str = "etc/fstab";
+
+/// Options for `bootc encrypt-var`.
+#[derive(Debug, clap::Parser, PartialEq, Eq)]
+pub(crate) struct EncryptVarOpts {
+    /// The block device to use for `/var`; all data on it will be lost.
+    pub(crate) device: Utf8PathBuf,
+
+    /// Automatically wipe all existing data on the device.
+    #[clap(long)]
+    pub(crate) wipe: bool,
+
+    /// The filesystem type; defaults to that of the root filesystem.
+    #[clap(long, value_enum)]
+    pub(crate) filesystem: Option<Filesystem>,
+
+    /// Bind the LUKS key to the provided TPM2 PCRs, separated by `+` (e.g. `0+7`).
+    #[clap(long, value_delimiter = '+')]
+    pub(crate) tpm2_pcrs: Vec<u32>,
+
+    /// Enroll a generated recovery key in addition to the TPM2 binding; it is
+    /// printed once, and should be stored securely.
+    #[clap(long)]
+    pub(crate) luks_recovery_key: bool,
+}
+
+/// An encrypted `/var` volume.
+#[derive(Debug)]
+pub(crate) struct EncryptedVar {
+    /// The UUID of the LUKS volume.
+    luks_uuid: String,
+    /// The filesystem type inside the volume.
+    fstype: Filesystem,
+}
+
+impl EncryptedVar {
+    /// Create a TPM2-bound LUKS2 volume on the device with a filesystem in it;
+    /// the volume is left open.
+    #[context("Creating encrypted /var on {dev}")]
+    pub(crate) fn create(
+        dev: &Utf8Path,
+        fstype: Filesystem,
+        tpm2_pcrs: Option<&str>,
+        passphrase: Option<&str>,
+        recovery_key: bool,
+        wipe: bool,
+    ) -> Result<Self> {
+        let luks_uuid =
+            baseline::luks_format_tpm2(LUKS_NAME, dev, tpm2_pcrs, passphrase, recovery_key)?;
+        let mapped = baseline::luks_open(LUKS_NAME, dev)?;
+        if let Err(e) = baseline::mkfs(&mapped, fstype, LABEL, wipe, []) {
+            close_logged();
+            return Err(e);
+        }
+        Ok(Self { luks_uuid, fstype })
+    }
+
+    /// The kernel argument which enables unlocking the volume when others are
+    /// selected via `luks.uuid=` (which disables processing of the remaining
+    /// entries in `/etc/crypttab`).
+    pub(crate) fn luks_karg(&self) -> String {
+        format!("luks.uuid={}", self.luks_uuid)
+    }
+
+    /// The path of the opened volume.
+    fn mapped_device(&self) -> String {
+        format!("/dev/mapper/{LUKS_NAME}")
+    }
+
+    /// The `/etc/crypttab` entry unlocking the volume via the TPM2 device.
+    pub(crate) fn crypttab_entry(&self) -> String {
+        format!(
+            "{LUKS_NAME} UUID={} none tpm2-device=auto,headless=true",
+            self.luks_uuid
+        )
+    }
+
+    /// The `/etc/fstab` entry mounting the volume on `/var`.
+    pub(crate) fn mount_spec(&self) -> MountSpec {
+        MountSpec {
+            source: self.mapped_device(),
+            target: "/var".into(),
+            fstype: self.fstype.to_string(),
+            options: None,
+        }
+    }
+
+    /// Copy the contents of the provided (stateroot) `/var` into the volume.
+    #[context("Populating encrypted /var")]
+    pub(crate) fn populate(
+        &self,
+        source: &Utf8Path,
+        sepolicy: Option<&ostree::SePolicy>,
+    ) -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mnt = Utf8Path::from_path(tmpdir.path())
+            .ok_or_else(|| anyhow::anyhow!("Non-UTF8 temporary directory"))?;
+        crate::mount::mount(&self.mapped_device(), mnt)?;
+        let target = Dir::open_ambient_dir(&mnt, cap_std::ambient_authority())?;
+        crate::lsm::ensure_dir_labeled(&target, "", Some("/var".into()), 0o755.into(), sepolicy)?;
+        drop(target);
+        // The trailing `/.` copies the contents, rather than the directory itself
+        Task::new("Copying /var", "cp")
+            .args(["-a", "--reflink=auto"])
+            .arg(format!("{source}/."))
+            .arg(mnt)
+            .run()?;
+        crate::mount::unmount(mnt, Default::default())
+    }
+}
+
+/// Close the opened volume.
+fn close() -> Result<()> {
+    Task::new_and_run(
+        format!("Closing {LUKS_NAME} LUKS device"),
+        "cryptsetup",
+        ["close", LUKS_NAME],
+    )
+}
+
+/// Close the opened volume after an error, which takes precedence over a
+/// failure to close it.
+fn close_logged() {
+    if let Err(e) = close() {
+        tracing::warn!("{e:#}");
+    }
+}
+
+/// Returns true if the fstab contents have an entry for `/var`.
+fn fstab_has_var(fstab: &str) -> bool {
+    fstab
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.starts_with('#'))
+        .any(|l| l.split_whitespace().nth(1) == Some("/var"))
+}
+
+/// Returns true if the crypttab contents have an entry with our volume name.
+fn crypttab_has_var(crypttab: &str) -> bool {
+    crypttab
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.starts_with('#'))
+        .any(|l| l.split_whitespace().next() == Some(LUKS_NAME))
+}
+
+/// Append a line to a configuration file in `/etc`, creating it if necessary.
+fn append_line(root: &Dir, path: &str, line: &str) -> Result<()> {
+    let mut contents = root.read_to_string_optional(path)?.unwrap_or_default();
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(line);
+    contents.push('\n');
+    root.atomic_replace_with(path, |w| w.write_all(contents.as_bytes()))
+        .with_context(|| format!("Writing {path}"))
+}
+
+/// Implementation of `bootc encrypt-var`.
+#[context("Encrypting /var")]
+pub(crate) async fn encrypt_var(opts: EncryptVarOpts) -> Result<()> {
+    let sysroot = &crate::cli::get_storage(LockMode::Exclusive).await?;
+    let booted = sysroot.require_booted_deployment()?;
+    let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let fstab = rootfs.read_to_string_optional(FSTAB)?.unwrap_or_default();
+    if fstab_has_var(&fstab) {
+        anyhow::bail!("/var is already a separate filesystem");
+    }
+    let crypttab = rootfs
+        .read_to_string_optional(CRYPTTAB)?
+        .unwrap_or_default();
+    if crypttab_has_var(&crypttab) {
+        anyhow::bail!("A volume named {LUKS_NAME} already exists in /{CRYPTTAB}");
+    }
+    let fstype = match opts.filesystem {
+        Some(fstype) => fstype,
+        None => {
+            let fs = crate::mount::inspect_filesystem("/sysroot".into())?;
+            Filesystem::from_str(&fs.fstype, false)
+                .map_err(|e| anyhow::anyhow!("Unsupported root filesystem: {e}"))?
+        }
+    };
+    let tpm2_pcrs = (!opts.tpm2_pcrs.is_empty())
+        .then(|| baseline::tpm2_pcrs_arg(&opts.tpm2_pcrs))
+        .transpose()?;
+
+    let sepolicy = if crate::lsm::selinux_enabled()? {
+        Some(ostree::SePolicy::new_at(
+            rootfs.as_raw_fd(),
+            gio::Cancellable::NONE,
+        )?)
+    } else {
+        None
+    };
+
+    let device = baseline::prepare_device(&opts.device, opts.wipe)?;
+    let var = EncryptedVar::create(
+        device.path().as_str().into(),
+        fstype,
+        tpm2_pcrs.as_deref(),
+        None,
+        opts.luks_recovery_key,
+        opts.wipe,
+    )?;
+    let stateroot_var = format!("/sysroot/ostree/deploy/{}/var", booted.osname());
+    if let Err(e) = var.populate(stateroot_var.as_str().into(), sepolicy.as_ref()) {
+        close_logged();
+        return Err(e);
+    }
+    close()?;
+
+    // Changes to /etc are carried over to future deployments, including a
+    // staged one, as its /etc is merged on shutdown.
+    append_line(rootfs, CRYPTTAB, &var.crypttab_entry())?;
+    append_line(rootfs, FSTAB, &var.mount_spec().to_fstab())?;
+    // When volumes are selected via luks.uuid=, ours needs to be too
+    let karg = var.luks_karg();
+    for (name, deployment) in
+        crate::kargs::target_deployments(sysroot, Some(crate::cli::KargsTarget::All))?
+    {
+        let mut kargs = crate::kargs::deployment_kargs(&deployment);
+        if !kargs.iter().any(|arg| arg.starts_with("luks.uuid=")) || kargs.contains(&karg) {
+            continue;
+        }
+        kargs.push(karg.clone());
+        sysroot
+            .deployment_set_kargs_in_place(
+                &deployment,
+                Some(&kargs.join(" ")),
+                gio::Cancellable::NONE,
+            )
+            .with_context(|| format!("Updating kernel arguments of {name} deployment"))?;
+        println!("Added {karg} to the kernel arguments of the {name} deployment");
+    }
+    println!(
+        "Encrypted /var on {} will be used from the next boot",
+        opts.device
+    );
+    println!("Note: changes to /var made until then will not be carried over");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries() {
+        let var = EncryptedVar {
+            luks_uuid: "6d6ff5b6-1b9d-4b2f-9c36-4b1a1d1e6f01".into(),
+            fstype: Filesystem::Xfs,
+        };
+        assert_eq!(
+            var.crypttab_entry(),
+            "var UUID=6d6ff5b6-1b9d-4b2f-9c36-4b1a1d1e6f01 none tpm2-device=auto,headless=true"
+        );
+        assert_eq!(
+            var.mount_spec().to_fstab(),
+            "/dev/mapper/var /var xfs defaults 0 0"
+        );
+    }
+
+    #[test]
+    fn test_existing_entries() {
+        let fstab = indoc::indoc! { "
+            # /var /var xfs defaults 0 0
+            UUID=abc /boot xfs ro 0 0
+        " };
+        assert!(!fstab_has_var(fstab));
+        assert!(fstab_has_var("LABEL=data /var xfs defaults 0 0\n"));
+        assert!(!fstab_has_var(
+            "LABEL=data /var/lib/data xfs defaults 0 0\n"
+        ));
+        assert!(!crypttab_has_var("luks-root UUID=abc none\n"));
+        assert!(crypttab_has_var("var UUID=abc none tpm2-device=auto\n"));
+    }
+}
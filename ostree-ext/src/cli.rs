@@ -171,6 +171,10 @@ pub(crate) enum ContainerOpts {
         #[clap(long)]
         compression_fast: bool,
 
+        /// Write layers in the zstd:chunked format, allowing clients to fetch only changed files
+        #[clap(long)]
+        zstd_chunked: bool,
+
         /// Path to a JSON-formatted content meta object.
         #[clap(long)]
         contentmeta: Option<Utf8PathBuf>,
@@ -316,6 +320,10 @@ pub(crate) enum ContainerImageOpts {
         /// Compress at the fastest level (e.g. gzip level 1)
         #[clap(long)]
         compression_fast: bool,
+
+        /// Write the ostree layers in the zstd:chunked format
+        #[clap(long)]
+        zstd_chunked: bool,
     },
 
     /// Replace the detached metadata (e.g. to add a signature)
@@ -753,6 +761,7 @@ async fn container_export(
     container_config: Option<Utf8PathBuf>,
     cmd: Option<Vec<String>>,
     compression_fast: bool,
+    zstd_chunked: bool,
     contentmeta: Option<Utf8PathBuf>,
 ) -> Result<()> {
     let container_config = if let Some(container_config) = container_config {
@@ -829,6 +838,7 @@ async fn container_export(
         container_config,
         authfile,
         skip_compression: compression_fast, // TODO rename this in the struct at the next semver break
+        zstd_chunked,
         contentmeta: contentmeta_data.as_ref(),
         max_layers,
         created,
@@ -1038,6 +1048,7 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 config,
                 cmd,
                 compression_fast,
+                zstd_chunked,
                 contentmeta,
             } => {
                 let labels: Result<BTreeMap<_, _>> = labels
@@ -1061,6 +1072,7 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     config,
                     cmd,
                     compression_fast,
+                    zstd_chunked,
                     contentmeta,
                 )
                 .await
@@ -1089,11 +1101,13 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     dest_imgref,
                     authfile,
                     compression_fast,
+                    zstd_chunked,
                 } => {
                     let repo = &parse_repo(&repo)?;
                     let opts = ExportToOCIOpts {
                         authfile,
                         skip_compression: compression_fast,
+                        zstd_chunked,
                         ..Default::default()
                     };
                     let digest = ostree_container::store::export(
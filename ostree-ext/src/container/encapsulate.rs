@@ -1,5 +1,6 @@
 //! APIs for creating container images from OSTree commits
 
+use super::zstd_chunked;
 use super::{ImageReference, SignatureSource, OSTREE_COMMIT_LABEL};
 use super::{OstreeImageReference, Transport, COMPONENT_SEPARATOR, CONTENT_ANNOTATION};
 use crate::chunking::{Chunk, Chunking, ObjectMetaSized};
@@ -81,26 +82,61 @@ fn commit_meta_to_labels<'a>(
     Ok(())
 }
 
+/// A layer, along with the zstd:chunked annotations for it if applicable.
+type ExportedLayer = (Layer, Option<HashMap<String, String>>);
+
 fn export_chunks(
     repo: &ostree::Repo,
     commit: &str,
     ociw: &mut OciDir,
     chunks: Vec<Chunk>,
     opts: &ExportOpts,
-) -> Result<Vec<(Layer, String, Vec<String>)>> {
+) -> Result<Vec<(ExportedLayer, String, Vec<String>)>> {
     chunks
         .into_iter()
         .enumerate()
         .map(|(i, chunk)| -> Result<_> {
-            let mut w = ociw.create_layer(Some(opts.compression()))?;
-            ostree_tar::export_chunk(repo, commit, chunk.content, &mut w)
-                .with_context(|| format!("Exporting chunk {i}"))?;
-            let w = w.into_inner()?;
-            Ok((w.complete()?, chunk.name, chunk.packages))
+            let layer = if opts.zstd_chunked {
+                let (layer, annotations) =
+                    zstd_chunked::create_layer(ociw, opts.zstd_level(), |w| {
+                        ostree_tar::export_chunk(repo, commit, chunk.content, w)
+                    })
+                    .with_context(|| format!("Exporting chunk {i}"))?;
+                (layer, Some(annotations))
+            } else {
+                let mut w = ociw.create_layer(Some(opts.compression()))?;
+                ostree_tar::export_chunk(repo, commit, chunk.content, &mut w)
+                    .with_context(|| format!("Exporting chunk {i}"))?;
+                (w.into_inner()?.complete()?, None)
+            };
+            Ok((layer, chunk.name, chunk.packages))
         })
         .collect()
 }
 
+/// Add a layer to the manifest and configuration. If the layer is in zstd:chunked
+/// format, its annotations are added and the media type is fixed up.
+fn push_layer(
+    ociw: &mut OciDir,
+    manifest: &mut oci_image::ImageManifest,
+    imgcfg: &mut oci_image::ImageConfiguration,
+    (layer, chunked): ExportedLayer,
+    description: &str,
+    annotations: Option<HashMap<String, String>>,
+) {
+    let is_chunked = chunked.is_some();
+    let annotations = match chunked {
+        Some(chunked) => Some(annotations.into_iter().flatten().chain(chunked).collect()),
+        None => annotations,
+    };
+    ociw.push_layer(manifest, imgcfg, layer, description, annotations);
+    if is_chunked {
+        // SAFETY: We just pushed a layer
+        let desc = manifest.layers_mut().last_mut().unwrap();
+        desc.set_media_type(oci_image::MediaType::ImageLayerZstd);
+    }
+}
+
 /// Write an ostree commit to an OCI blob
 #[context("Writing ostree root to blob")]
 #[allow(clippy::too_many_arguments)]
@@ -116,25 +152,30 @@ pub(crate) fn export_chunked(
     description: &str,
 ) -> Result<()> {
     let layers = export_chunks(repo, commit, ociw, chunking.take_chunks(), opts)?;
-    let compression = Some(opts.compression());
 
     // In V1, the ostree layer comes first
-    let mut w = ociw.create_layer(compression)?;
-    ostree_tar::export_final_chunk(repo, commit, chunking.remainder, &mut w)?;
-    let w = w.into_inner()?;
-    let ostree_layer = w.complete()?;
+    let ostree_layer = if opts.zstd_chunked {
+        let (layer, annotations) = zstd_chunked::create_layer(ociw, opts.zstd_level(), |w| {
+            ostree_tar::export_final_chunk(repo, commit, chunking.remainder, w)
+        })?;
+        (layer, Some(annotations))
+    } else {
+        let mut w = ociw.create_layer(Some(opts.compression()))?;
+        ostree_tar::export_final_chunk(repo, commit, chunking.remainder, &mut w)?;
+        (w.into_inner()?.complete()?, None)
+    };
 
     // Then, we have a label that points to the last chunk.
     // Note in the pathological case of a single layer chunked v1 image, this could be the ostree layer.
     let last_digest = layers
         .last()
-        .map(|v| &v.0)
-        .unwrap_or(&ostree_layer)
+        .map(|v| &v.0 .0)
+        .unwrap_or(&ostree_layer.0)
         .uncompressed_sha256
         .clone();
 
     // Add the ostree layer
-    ociw.push_layer(manifest, imgcfg, ostree_layer, description, None);
+    push_layer(ociw, manifest, imgcfg, ostree_layer, description, None);
     // Add the component/content layers
     let mut buf = [0; 8];
     let sep = COMPONENT_SEPARATOR.encode_utf8(&mut buf);
@@ -142,7 +183,8 @@ pub(crate) fn export_chunked(
         let mut annotation_component_layer = HashMap::new();
         packages.sort();
         annotation_component_layer.insert(CONTENT_ANNOTATION.to_string(), packages.join(sep));
-        ociw.push_layer(
+        push_layer(
+            ociw,
             manifest,
             imgcfg,
             layer,
@@ -390,6 +432,9 @@ pub struct ExportOpts<'m, 'o> {
     pub contentmeta: Option<&'o ObjectMetaSized>,
     /// Sets the created tag in the image manifest.
     pub created: Option<String>,
+    /// Write layers in the zstd:chunked format instead of gzip, which allows clients
+    /// to fetch only the files they don't already have.
+    pub zstd_chunked: bool,
 }
 
 impl ExportOpts<'_, '_> {
@@ -401,6 +446,15 @@ impl ExportOpts<'_, '_> {
             Compression::default()
         }
     }
+
+    /// Return the zstd compression level to use, as configured by the export options.
+    fn zstd_level(&self) -> i32 {
+        if self.skip_compression {
+            1
+        } else {
+            zstd::DEFAULT_COMPRESSION_LEVEL
+        }
+    }
 }
 
 /// Given an OSTree repository and ref, generate a container image.
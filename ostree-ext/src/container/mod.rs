@@ -481,6 +481,7 @@ mod skopeo;
 pub mod store;
 mod update_detachedmeta;
 pub use update_detachedmeta::*;
+mod zstd_chunked;
 
 use crate::isolation;
 
@@ -61,8 +61,9 @@ const OSTREE_BASE_DEPLOYMENT_REFS: &[&str] = &["ostree/0", "ostree/1"];
 /// A layering violation we'll carry for a bit to band-aid over https://github.com/coreos/rpm-ostree/issues/4185
 const RPMOSTREE_BASE_REFS: &[&str] = &["rpmostree/base"];
 
-/// Convert e.g. sha256:12345... into `/ostree/container/blob/sha256_2B12345...`.
-fn ref_for_blob_digest(d: &str) -> Result<String> {
+/// Convert e.g. sha256:12345... into `/ostree/container/blob/sha256_2B12345...`,
+/// the ref for the commit caching a layer.
+pub fn ref_for_blob_digest(d: &str) -> Result<String> {
     refescape::prefix_escape_for_ref(LAYER_PREFIX, d)
 }
 
@@ -1289,6 +1290,8 @@ pub struct ExportToOCIOpts {
     pub authfile: Option<std::path::PathBuf>,
     /// Output progress to stdout
     pub progress_to_stdout: bool,
+    /// Write the ostree layers in the zstd:chunked format instead of gzip.
+    pub zstd_chunked: bool,
 }
 
 /// The way we store "chunk" layers in ostree is by writing a commit
@@ -1353,6 +1356,7 @@ pub(crate) fn export_to_oci(
     let opts = ExportOpts {
         skip_compression: opts.skip_compression,
         authfile: opts.authfile,
+        zstd_chunked: opts.zstd_chunked,
         ..Default::default()
     };
 
@@ -1436,6 +1440,7 @@ pub async fn export(
         let opts = ExportToOCIOpts {
             skip_compression: true,
             progress_to_stdout: opts.progress_to_stdout,
+            zstd_chunked: opts.zstd_chunked,
             ..Default::default()
         };
         export_to_oci(repo, src_imgref, &td, None, opts)?;
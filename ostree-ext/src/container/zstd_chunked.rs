@@ -0,0 +1,611 @@
+//! Support for writing layers in the zstd:chunked format
+//!
+//! A zstd:chunked layer is a valid zstd-compressed tarball, except that the
+//! content of each file is compressed in its own zstd frame. At the end of the
+//! blob (in zstd skippable frames, which decompressors ignore) there is a table
+//! of contents describing each file and the location of its frame, along with
+//! "tar-split" data which allows reconstructing the original tar stream.
+//! Together, these allow clients such as containers/storage to fetch only
+//! the files they don't already have via HTTP range requests.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Seek, Write};
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use containers_image_proxy::oci_spec::image::Sha256Digest;
+use fn_error_context::context;
+use ocidir::{Layer, OciDir};
+use openssl::sha::Sha256;
+use ostree::glib;
+use serde::Serialize;
+
+/// Layer annotation holding the digest of the compressed table of contents.
+pub(crate) const MANIFEST_CHECKSUM_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.manifest-checksum";
+/// Layer annotation holding the position of the table of contents,
+/// as `offset:length:uncompressed_length:type`.
+pub(crate) const MANIFEST_POSITION_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.manifest-position";
+/// Layer annotation holding the position of the tar-split data,
+/// as `offset:length:uncompressed_length`.
+pub(crate) const TARSPLIT_POSITION_ANNOTATION: &str =
+    "io.github.containers.zstd-chunked.tarsplit-position";
+/// The (only) table of contents format.
+const MANIFEST_TYPE_CRFS: u64 = 1;
+/// The magic number for zstd skippable frames.
+const ZSTD_SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A50;
+/// The size of the header of a skippable frame (magic plus length).
+const ZSTD_SKIPPABLE_FRAME_HEADER_SIZE: u64 = 8;
+/// The magic number at the end of the footer.
+const FOOTER_MAGIC: &[u8; 8] = b"GNUlInUx";
+/// The size of the footer.
+const FOOTER_SIZE: usize = 64;
+/// tar-split entry type for a file (header plus content).
+const TARSPLIT_TYPE_FILE: u8 = 1;
+/// tar-split entry type for raw bytes of the tar stream.
+const TARSPLIT_TYPE_SEGMENT: u8 = 2;
+/// The reversed ISO polynomial, as used by the CRC-64 checksums in tar-split.
+const CRC64_ISO_POLY: u64 = 0xD800000000000000;
+
+fn is_zero<T: Default + PartialEq>(v: &T) -> bool {
+    *v == T::default()
+}
+
+/// The table of contents.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Toc {
+    version: u32,
+    entries: Vec<TocEntry>,
+    tar_split_digest: String,
+}
+
+/// An entry in the table of contents.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TocEntry {
+    #[serde(rename = "type")]
+    entry_type: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_name: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    mode: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    size: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    uid: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    gid: u64,
+    #[serde(rename = "modtime", skip_serializing_if = "Option::is_none")]
+    mod_time: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_major: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_minor: u32,
+    /// Values are base64 encoded
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    xattrs: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    end_offset: u64,
+}
+
+/// An entry in the tar-split data; one is serialized as JSON per line.
+#[derive(Debug, Serialize)]
+struct TarSplitEntry {
+    #[serde(rename = "type")]
+    entry_type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Used instead of `name` if it is not valid UTF-8; base64 encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name_raw: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    size: u64,
+    /// Raw bytes for segments, the CRC-64 of the content for files; base64 encoded
+    payload: Option<String>,
+    position: u64,
+}
+
+/// A file in the source tar stream.
+#[derive(Debug)]
+struct SourceEntry {
+    /// Where the headers for this file start (just after the content of the previous one)
+    header_start: u64,
+    /// Where the content of this file starts
+    content_start: u64,
+    /// The size of the content
+    size: u64,
+    /// The raw path
+    name: Vec<u8>,
+    /// The table of contents entry, if this is a file type we describe there
+    toc: Option<TocEntry>,
+}
+
+/// An incremental CRC-64 (ISO) checksum.
+struct Crc64 {
+    table: [u64; 256],
+    crc: u64,
+}
+
+impl Crc64 {
+    fn new() -> Self {
+        let mut table = [0u64; 256];
+        for (i, v) in table.iter_mut().enumerate() {
+            let mut crc = i as u64;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ CRC64_ISO_POLY
+                } else {
+                    crc >> 1
+                };
+            }
+            *v = crc;
+        }
+        Self { table, crc: 0 }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        let mut crc = !self.crc;
+        for &b in buf {
+            crc = self.table[usize::from(crc as u8 ^ b)] ^ (crc >> 8);
+        }
+        self.crc = !crc;
+    }
+}
+
+/// A writer which tracks how many bytes have been written.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a sequence of zstd frames, where a new frame is started on demand
+/// after the previous one was ended.
+struct FrameWriter<W: Write> {
+    /// Set when no frame is in progress
+    idle: Option<CountingWriter<W>>,
+    /// Set when a frame is in progress
+    encoder: Option<zstd::stream::write::Encoder<'static, CountingWriter<W>>>,
+    level: i32,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn new(inner: W, level: i32) -> Self {
+        Self {
+            idle: Some(CountingWriter { inner, count: 0 }),
+            encoder: None,
+            level,
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        if self.encoder.is_none() {
+            // SAFETY: If there's no encoder, we're idle
+            let inner = self.idle.take().unwrap();
+            self.encoder = Some(zstd::stream::write::Encoder::new(inner, self.level)?);
+        }
+        // SAFETY: We just ensured there's a frame in progress
+        self.encoder.as_mut().unwrap().write_all(buf)?;
+        Ok(())
+    }
+
+    /// Finish the current frame (if any), returning the current offset in the output.
+    fn end_frame(&mut self) -> Result<u64> {
+        if let Some(encoder) = self.encoder.take() {
+            self.idle = Some(encoder.finish()?);
+        }
+        // SAFETY: We just ended any frame in progress
+        Ok(self.idle.as_ref().unwrap().count)
+    }
+
+    fn into_inner(mut self) -> Result<CountingWriter<W>> {
+        self.end_frame()?;
+        // SAFETY: We just ended any frame in progress
+        Ok(self.idle.take().unwrap())
+    }
+}
+
+/// Append the data to the output as a zstd skippable frame.
+fn write_skippable_frame(out: &mut impl Write, data: &[u8]) -> Result<()> {
+    let len = u32::try_from(data.len()).context("Skippable frame too large")?;
+    out.write_all(&ZSTD_SKIPPABLE_FRAME_MAGIC.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+fn sha256_digest(buf: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(openssl::sha::sha256(buf)))
+}
+
+/// Invoke the callback on successive blocks of the given range of the file.
+fn read_range(
+    src: &File,
+    start: u64,
+    len: u64,
+    mut f: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buf = vec![0u8; 128 * 1024];
+    let mut pos = start;
+    let end = start + len;
+    while pos < end {
+        let n = buf.len().min((end - pos) as usize);
+        let buf = &mut buf[..n];
+        src.read_exact_at(buf, pos)?;
+        f(buf)?;
+        pos += n as u64;
+    }
+    Ok(())
+}
+
+/// Build the table of contents entry for a tar entry, if it has a type we describe.
+fn toc_entry_for<R: std::io::Read>(entry: &mut tar::Entry<R>) -> Result<Option<TocEntry>> {
+    use tar::EntryType;
+    let header = entry.header();
+    let entry_type = match header.entry_type() {
+        EntryType::Regular | EntryType::Continuous => "reg",
+        EntryType::Directory => "dir",
+        EntryType::Symlink => "symlink",
+        EntryType::Link => "hardlink",
+        EntryType::Char => "char",
+        EntryType::Block => "block",
+        EntryType::Fifo => "fifo",
+        _ => return Ok(None),
+    };
+    let mod_time = DateTime::from_timestamp(header.mtime()?.try_into()?, 0)
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    let mut r = TocEntry {
+        entry_type,
+        name: String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+        link_name: entry
+            .link_name_bytes()
+            .map(|v| String::from_utf8_lossy(&v).into_owned()),
+        mode: header.mode()?,
+        size: entry.size(),
+        uid: header.uid()?,
+        gid: header.gid()?,
+        mod_time,
+        dev_major: header.device_major()?.unwrap_or_default(),
+        dev_minor: header.device_minor()?.unwrap_or_default(),
+        ..Default::default()
+    };
+    if let Some(extensions) = entry.pax_extensions()? {
+        for ext in extensions {
+            let ext = ext?;
+            if let Some(name) = ext.key()?.strip_prefix("SCHILY.xattr.") {
+                let value = glib::base64_encode(ext.value_bytes());
+                r.xattrs.insert(name.to_owned(), value.to_string());
+            }
+        }
+    }
+    Ok(Some(r))
+}
+
+/// Parse the entries of the tar stream, recording where each one is.
+fn parse_source(src: &File) -> Result<(Vec<SourceEntry>, u64)> {
+    let mut archive = tar::Archive::new(BufReader::new(src));
+    let mut r = Vec::new();
+    let mut prev_end = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let content_start = entry.raw_file_position();
+        let size = entry.size();
+        r.push(SourceEntry {
+            header_start: prev_end,
+            content_start,
+            size,
+            name: entry.path_bytes().into_owned(),
+            toc: toc_entry_for(&mut entry)?,
+        });
+        // Any padding is included in the headers of the next entry
+        prev_end = content_start + size;
+    }
+    Ok((r, prev_end))
+}
+
+/// Convert the uncompressed tar stream into zstd:chunked format, returning the
+/// (hex) digest of the uncompressed stream and the annotations for the layer.
+fn write_chunked(
+    mut src: File,
+    out: impl Write,
+    level: i32,
+) -> Result<(String, HashMap<String, String>)> {
+    src.rewind()?;
+    let (entries, trailer_start) = parse_source(&src)?;
+    let total_size = src.metadata()?.len();
+
+    let mut uncompressed = Sha256::new();
+    let mut w = FrameWriter::new(out, level);
+    let mut tarsplit = Vec::new();
+    let mut toc = Vec::new();
+    let mut position = 0;
+    let mut push_tarsplit = |e: TarSplitEntry| -> Result<()> {
+        serde_json::to_writer(&mut tarsplit, &e)?;
+        tarsplit.push(b'\n');
+        Ok(())
+    };
+    let segment = |payload: &[u8], position: u64| TarSplitEntry {
+        entry_type: TARSPLIT_TYPE_SEGMENT,
+        name: None,
+        name_raw: None,
+        size: 0,
+        payload: Some(glib::base64_encode(payload).to_string()),
+        position,
+    };
+
+    for entry in entries {
+        // The headers (and padding of the previous entry) go in the current frame
+        let mut headers = Vec::new();
+        read_range(
+            &src,
+            entry.header_start,
+            entry.content_start - entry.header_start,
+            |buf| {
+                headers.extend_from_slice(buf);
+                Ok(())
+            },
+        )?;
+        uncompressed.update(&headers);
+        w.write_all(&headers)?;
+        if !headers.is_empty() {
+            push_tarsplit(segment(&headers, position))?;
+            position += 1;
+        }
+
+        // And the content gets its own frame
+        let mut crc = Crc64::new();
+        let mut digest = Sha256::new();
+        let (offset, end_offset) = if entry.size > 0 {
+            let offset = w.end_frame()?;
+            read_range(&src, entry.content_start, entry.size, |buf| {
+                crc.update(buf);
+                digest.update(buf);
+                uncompressed.update(buf);
+                w.write_all(buf)
+            })?;
+            (offset, w.end_frame()?)
+        } else {
+            (0, 0)
+        };
+        if let Some(mut t) = entry.toc {
+            if t.entry_type == "reg" {
+                t.digest = Some(format!("sha256:{}", hex::encode(digest.finish())));
+                t.offset = offset;
+                t.end_offset = end_offset;
+            }
+            toc.push(t);
+        }
+
+        let (name, name_raw) = match String::from_utf8(entry.name) {
+            Ok(v) => (Some(v), None),
+            Err(e) => (None, Some(glib::base64_encode(e.as_bytes()).to_string())),
+        };
+        let payload = (entry.size > 0).then(|| glib::base64_encode(&crc.crc.to_be_bytes()));
+        push_tarsplit(TarSplitEntry {
+            entry_type: TARSPLIT_TYPE_FILE,
+            name,
+            name_raw,
+            size: entry.size,
+            payload: payload.map(|v| v.to_string()),
+            position,
+        })?;
+        position += 1;
+    }
+
+    // The end of archive marker
+    let mut trailer = Vec::new();
+    read_range(&src, trailer_start, total_size - trailer_start, |buf| {
+        trailer.extend_from_slice(buf);
+        Ok(())
+    })?;
+    uncompressed.update(&trailer);
+    w.write_all(&trailer)?;
+    if !trailer.is_empty() {
+        push_tarsplit(segment(&trailer, position))?;
+    }
+    let mut out = w.into_inner()?;
+
+    let tarsplit_compressed = zstd::encode_all(tarsplit.as_slice(), level)?;
+    let toc = Toc {
+        version: 1,
+        entries: toc,
+        tar_split_digest: sha256_digest(&tarsplit_compressed),
+    };
+    let manifest = serde_json::to_vec(&toc)?;
+    let manifest_compressed = zstd::encode_all(manifest.as_slice(), level)?;
+
+    let manifest_offset = out.count + ZSTD_SKIPPABLE_FRAME_HEADER_SIZE;
+    write_skippable_frame(&mut out, &manifest_compressed)?;
+    let tarsplit_offset = out.count + ZSTD_SKIPPABLE_FRAME_HEADER_SIZE;
+    write_skippable_frame(&mut out, &tarsplit_compressed)?;
+
+    let mut footer = Vec::with_capacity(FOOTER_SIZE);
+    for v in [
+        manifest_offset,
+        manifest_compressed.len() as u64,
+        manifest.len() as u64,
+        MANIFEST_TYPE_CRFS,
+        tarsplit_offset,
+        tarsplit_compressed.len() as u64,
+        tarsplit.len() as u64,
+    ] {
+        footer.extend_from_slice(&v.to_le_bytes());
+    }
+    footer.extend_from_slice(FOOTER_MAGIC);
+    write_skippable_frame(&mut out, &footer)?;
+    out.flush()?;
+
+    let annotations = [
+        (
+            MANIFEST_CHECKSUM_ANNOTATION,
+            sha256_digest(&manifest_compressed),
+        ),
+        (
+            MANIFEST_POSITION_ANNOTATION,
+            format!(
+                "{manifest_offset}:{}:{}:{MANIFEST_TYPE_CRFS}",
+                manifest_compressed.len(),
+                manifest.len()
+            ),
+        ),
+        (
+            TARSPLIT_POSITION_ANNOTATION,
+            format!(
+                "{tarsplit_offset}:{}:{}",
+                tarsplit_compressed.len(),
+                tarsplit.len()
+            ),
+        ),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_owned(), v))
+    .collect();
+    Ok((hex::encode(uncompressed.finish()), annotations))
+}
+
+/// Write a zstd:chunked layer into the OCI directory, using the callback to generate
+/// the tar stream. Returns the layer along with the annotations which must be added
+/// to its descriptor.
+#[context("Writing zstd:chunked layer")]
+pub(crate) fn create_layer(
+    ociw: &OciDir,
+    level: i32,
+    f: impl FnOnce(&mut tar::Builder<File>) -> Result<()>,
+) -> Result<(Layer, HashMap<String, String>)> {
+    // We need to make two passes over the tar stream, so spool it to a temporary file
+    let mut tar = tar::Builder::new(tempfile::tempfile()?);
+    f(&mut tar)?;
+    let src = tar.into_inner()?;
+    let mut blob = ociw.create_blob()?;
+    let (diffid, annotations) = write_chunked(src, &mut blob, level)?;
+    let layer = Layer {
+        blob: blob.complete()?,
+        uncompressed_sha256: Sha256Digest::from_str(&diffid)?,
+    };
+    Ok((layer, annotations))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn read_json(blob: &[u8], position: &str) -> serde_json::Value {
+        let mut parts = position.split(':').map(|v| v.parse::<usize>().unwrap());
+        let (offset, len, uncompressed_len) = (
+            parts.next().unwrap(),
+            parts.next().unwrap(),
+            parts.next().unwrap(),
+        );
+        let buf = zstd::decode_all(&blob[offset..offset + len]).unwrap();
+        assert_eq!(buf.len(), uncompressed_len);
+        // tar-split is JSON lines; just parse the first one
+        let buf = buf.split(|&b| b == b'\n').next().unwrap();
+        serde_json::from_slice(buf).unwrap()
+    }
+
+    #[test]
+    fn test_crc64() {
+        let mut crc = Crc64::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.crc, 0xB90956C775A41001);
+    }
+
+    #[test]
+    fn test_write_chunked() -> Result<()> {
+        let content = b"hello world\n".repeat(1000);
+        let mut tar = tar::Builder::new(tempfile::tempfile()?);
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Directory);
+        h.set_mode(0o755);
+        h.set_size(0);
+        tar.append_data(&mut h, "usr", std::io::empty())?;
+        let mut h = tar::Header::new_gnu();
+        h.set_mode(0o644);
+        h.set_mtime(1700000000);
+        h.set_size(content.len() as u64);
+        tar.append_data(&mut h, "usr/hello", content.as_slice())?;
+        let mut h = tar::Header::new_gnu();
+        h.set_mode(0o644);
+        h.set_size(0);
+        tar.append_data(&mut h, "usr/empty", std::io::empty())?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Link);
+        h.set_size(0);
+        tar.append_link(&mut h, "usr/hello2", "usr/hello")?;
+        let mut src = tar.into_inner()?;
+        let mut orig = Vec::new();
+        src.rewind()?;
+        src.read_to_end(&mut orig)?;
+
+        let mut blob = Vec::new();
+        let (diffid, annotations) = write_chunked(src, &mut blob, 3)?;
+
+        // It's still a valid zstd stream of the original tarball
+        assert_eq!(zstd::decode_all(blob.as_slice())?, orig);
+        assert_eq!(diffid, hex::encode(openssl::sha::sha256(&orig)));
+
+        // Verify the footer
+        let footer = &blob[blob.len() - FOOTER_SIZE..];
+        assert_eq!(&footer[56..], FOOTER_MAGIC);
+        let manifest_position = &annotations[MANIFEST_POSITION_ANNOTATION];
+        let manifest_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        assert!(manifest_position.starts_with(&format!("{manifest_offset}:")));
+        assert!(manifest_position.ends_with(":1"));
+
+        // And the table of contents
+        let toc = read_json(&blob, manifest_position);
+        assert_eq!(toc["version"], 1);
+        let entries = toc["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0]["type"], "dir");
+        assert_eq!(entries[0]["mode"], 0o755);
+        let hello = &entries[1];
+        assert_eq!(hello["type"], "reg");
+        assert_eq!(hello["name"], "usr/hello");
+        assert_eq!(hello["modtime"], "2023-11-14T22:13:20Z");
+        assert_eq!(
+            hello["digest"].as_str().unwrap(),
+            sha256_digest(&content).as_str()
+        );
+        let offset = hello["offset"].as_u64().unwrap() as usize;
+        let end_offset = hello["endOffset"].as_u64().unwrap() as usize;
+        assert_eq!(zstd::decode_all(&blob[offset..end_offset])?, content);
+        assert_eq!(entries[2]["name"], "usr/empty");
+        assert!(entries[2].get("offset").is_none());
+        assert_eq!(entries[3]["type"], "hardlink");
+        assert_eq!(entries[3]["linkName"], "usr/hello");
+
+        // The tar-split data starts with the raw header of the first entry
+        let tarsplit = read_json(&blob, &annotations[TARSPLIT_POSITION_ANNOTATION]);
+        assert_eq!(tarsplit["type"], TARSPLIT_TYPE_SEGMENT);
+        assert_eq!(tarsplit["position"], 0);
+        Ok(())
+    }
+}
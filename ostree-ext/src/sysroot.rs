@@ -1,24 +1,95 @@
 //! Helpers for interacting with sysroots.
 
 use std::ops::Deref;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use ostree::gio;
+
+/// The path to the lock file used by libostree, relative to the sysroot.
+const SYSROOT_LOCKFILE: &str = "ostree/lock";
+
+/// How often we retry acquiring a contended lock.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How often we repeat the "waiting" message while a lock is contended.
+const LOCK_WAIT_NOTIFY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The kind of lock to acquire on a sysroot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockMode {
+    /// A lock which may be held concurrently by multiple readers, but
+    /// which excludes any writer.  Code holding this lock must not
+    /// mutate the sysroot.
+    Shared,
+    /// A lock which excludes all other holders; this is the lock
+    /// used by libostree for all sysroot mutations.
+    #[default]
+    Exclusive,
+}
+
+impl std::fmt::Display for LockMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockMode::Shared => f.write_str("shared"),
+            LockMode::Exclusive => f.write_str("exclusive"),
+        }
+    }
+}
+
+/// Options for acquiring a sysroot lock.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct LockOpts {
+    /// The kind of lock to acquire.
+    pub mode: LockMode,
+    /// If set, give up with an error after waiting this long.  By default,
+    /// we wait indefinitely.
+    pub timeout: Option<Duration>,
+}
+
+impl LockOpts {
+    /// Options for a lock of the provided mode, with no timeout.
+    pub fn new(mode: LockMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /// Set a timeout for acquiring the lock.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[derive(Debug)]
+enum LockState {
+    /// We hold the libostree sysroot lock.
+    Exclusive,
+    /// We hold a shared lock on the sysroot lock file; it is released
+    /// when the file descriptor is closed.
+    Shared(#[allow(dead_code)] OwnedFd),
+    /// The lock is held externally.
+    Unowned,
+}
 
 /// A locked system root.
 #[derive(Debug)]
 pub struct SysrootLock {
     /// The underlying sysroot value.
     pub sysroot: ostree::Sysroot,
-    /// True if we didn't actually lock
-    unowned: bool,
+    state: LockState,
 }
 
 impl Drop for SysrootLock {
     fn drop(&mut self) {
-        if self.unowned {
-            return;
+        match self.state {
+            LockState::Exclusive => self.sysroot.unlock(),
+            LockState::Shared(_) | LockState::Unowned => {}
         }
-        self.sysroot.unlock();
     }
 }
 
@@ -30,24 +101,115 @@ impl Deref for SysrootLock {
     }
 }
 
+/// Try to acquire a shared lock on the sysroot lock file without blocking.
+///
+/// libostree takes its exclusive lock using open file description locks
+/// (see `glnx_make_lock_file`), so we must use the same lock type here;
+/// classic `flock()` locks would not conflict with it, and process-associated
+/// `fcntl()` locks are dropped whenever libostree closes any descriptor for
+/// the same file.
+#[allow(unsafe_code)]
+fn try_lock_shared(fd: BorrowedFd) -> std::io::Result<bool> {
+    // SAFETY: `flock` is a plain C struct for which all-zeroes is valid.
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_RDLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    // SAFETY: The descriptor is valid for the duration of this call, and
+    // `lock` outlives it.
+    let r = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_OFD_SETLK, &lock) };
+    if r == 0 {
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EAGAIN) | Some(libc::EACCES) => Ok(false),
+        _ => Err(e),
+    }
+}
+
 impl SysrootLock {
-    /// Asynchronously acquire a sysroot lock.  If the lock cannot be acquired
-    /// immediately, a status message will be printed to standard output.
+    /// Asynchronously acquire an exclusive sysroot lock, waiting indefinitely.
+    /// If the lock cannot be acquired immediately, a status message will be
+    /// printed to standard error.
     /// The lock will be unlocked when this object is dropped.
     pub async fn new_from_sysroot(sysroot: &ostree::Sysroot) -> Result<Self> {
-        let mut printed = false;
+        Self::new_with_opts(sysroot, &LockOpts::default()).await
+    }
+
+    /// Asynchronously acquire a sysroot lock as configured by `opts`.
+    ///
+    /// If the lock is contended, a status message is printed to standard
+    /// error, and repeated periodically for as long as we keep waiting.
+    /// If a timeout is configured and expires, an error is returned.
+    /// The lock will be unlocked when this object is dropped.
+    pub async fn new_with_opts(sysroot: &ostree::Sysroot, opts: &LockOpts) -> Result<Self> {
+        let shared_fd = match opts.mode {
+            LockMode::Shared => {
+                sysroot.ensure_initialized(gio::Cancellable::NONE)?;
+                let sysroot_fd = crate::container::deploy::sysroot_fd(sysroot);
+                let fd = rustix::fs::openat(
+                    sysroot_fd,
+                    SYSROOT_LOCKFILE,
+                    rustix::fs::OFlags::RDONLY
+                        | rustix::fs::OFlags::CREATE
+                        | rustix::fs::OFlags::CLOEXEC,
+                    rustix::fs::Mode::from_raw_mode(0o600),
+                )
+                .with_context(|| format!("Opening {SYSROOT_LOCKFILE}"))?;
+                Some(fd)
+            }
+            LockMode::Exclusive => None,
+        };
+        let start = Instant::now();
+        let mut last_notified: Option<Instant> = None;
         loop {
-            if sysroot.try_lock()? {
+            let acquired = match shared_fd.as_ref() {
+                Some(fd) => try_lock_shared(fd.as_fd()).context("Acquiring shared sysroot lock")?,
+                None => sysroot.try_lock()?,
+            };
+            if acquired {
+                if last_notified.is_some() {
+                    tracing::debug!("Acquired {} sysroot lock", opts.mode);
+                }
+                let state = match shared_fd {
+                    Some(fd) => LockState::Shared(fd),
+                    None => LockState::Exclusive,
+                };
                 return Ok(Self {
                     sysroot: sysroot.clone(),
-                    unowned: false,
+                    state,
                 });
             }
-            if !printed {
-                println!("Waiting for sysroot lock...");
-                printed = true;
+            let elapsed = start.elapsed();
+            if let Some(timeout) = opts.timeout {
+                if elapsed >= timeout {
+                    anyhow::bail!(
+                        "Timed out after {}s waiting for {} sysroot lock",
+                        timeout.as_secs(),
+                        opts.mode
+                    );
+                }
+            }
+            match last_notified {
+                None => {
+                    eprintln!("Waiting for {} sysroot lock...", opts.mode);
+                    last_notified = Some(Instant::now());
+                }
+                Some(t) if t.elapsed() >= LOCK_WAIT_NOTIFY_INTERVAL => {
+                    eprintln!(
+                        "Still waiting for {} sysroot lock ({}s elapsed)...",
+                        opts.mode,
+                        elapsed.as_secs()
+                    );
+                    last_notified = Some(Instant::now());
+                }
+                Some(_) => {}
             }
-            tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+            let mut delay = LOCK_POLL_INTERVAL;
+            if let Some(timeout) = opts.timeout {
+                delay = delay.min(timeout.saturating_sub(elapsed));
+            }
+            tokio::time::sleep(delay).await;
         }
     }
 
@@ -56,7 +218,16 @@ impl SysrootLock {
     pub fn from_assumed_locked(sysroot: &ostree::Sysroot) -> Self {
         Self {
             sysroot: sysroot.clone(),
-            unowned: true,
+            state: LockState::Unowned,
+        }
+    }
+
+    /// The kind of lock held, or `None` if the lock is held externally.
+    pub fn mode(&self) -> Option<LockMode> {
+        match self.state {
+            LockState::Exclusive => Some(LockMode::Exclusive),
+            LockState::Shared(_) => Some(LockMode::Shared),
+            LockState::Unowned => None,
         }
     }
 }